@@ -0,0 +1,39 @@
+use std::cell::RefCell;
+use std::error::Error;
+use std::rc::Rc;
+
+use log::LevelFilter;
+use mqtt_bridge::bridge::{BridgeOptions, MqttBridge};
+use protocol::client_std::ConnectOptions;
+use smart_home_lib::devices::socket_tcp::socket_std::SocketTcp;
+use smart_home_lib::devices::thermometer_udp::thermo_udp_thread::ThermometerUdp;
+
+const DEFAULT_BROKER_PORT: u16 = 1883;
+const DEFAULT_SOCKET_ADDR: &str = "127.0.0.1:55331";
+const DEFAULT_THERMOMETER_ADDR: &str = "127.0.0.1:34255";
+
+/// Bridge the smart socket and thermometer to the broker:
+/// `mqtt_bridge [broker host[:port]] [socket addr] [thermometer udp addr]`.
+/// Socket key is taken from the `STP_KEYS` key file or `STP_PSK` env variable.
+fn main() -> Result<(), Box<dyn Error>> {
+    env_logger::builder().filter_level(LevelFilter::Info).parse_default_env().init();
+    let mut args = std::env::args().skip(1);
+    let broker = args.next().unwrap_or("127.0.0.1".to_string());
+    let socket_addr = args.next().unwrap_or(DEFAULT_SOCKET_ADDR.to_string());
+    let thermometer_addr = args.next().unwrap_or(DEFAULT_THERMOMETER_ADDR.to_string());
+    let (host, port) = match broker.rsplit_once(':') {
+        Some((host, port)) => { (host.to_string(), port.parse()?) }
+        None => { (broker, DEFAULT_BROKER_PORT) }
+    };
+
+    let options = ConnectOptions::from_env(&socket_addr)?;
+    let socket = SocketTcp::connect_with(socket_addr.as_str(), &options)?;
+    let thermometer = ThermometerUdp::new(thermometer_addr.as_str())?;
+
+    let mut bridge = MqttBridge::new(BridgeOptions::new("smart_home_bridge", host.as_str(), port));
+    bridge.add_socket("Living room", "Socket", Rc::new(RefCell::new(socket)))?;
+    bridge.add_thermometer("Living room", "Thermometer", Rc::new(RefCell::new(thermometer)))?;
+    println!("Bridging {} and {} to {}:{}", socket_addr, thermometer_addr, host, port);
+    bridge.run_until(|| false)?;
+    Ok(())
+}
@@ -0,0 +1,476 @@
+use std::collections::HashMap;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rumqttc::{Client, Connection, Event, LastWill, MqttOptions, Outgoing, Packet, QoS, RecvTimeoutError};
+use smart_home_lib::common::traits::Described;
+use smart_home_lib::common::types::SmartPointer;
+use smart_home_lib::devices::socket::SocketTrait;
+use smart_home_lib::devices::thermometer::TemperatureSensorTrait;
+use smart_home_lib::house::room_static::{Device, DeviceTypes, Room};
+
+use crate::discovery::{socket_configs, thermometer_configs, DiscoveryConfig};
+use crate::errors::BridgeError;
+
+pub const PAYLOAD_ON: &str = "ON";
+pub const PAYLOAD_OFF: &str = "OFF";
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+
+/// Pause before the next connection attempt of [`MqttBridge::run_until`]
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// Time given to send the last messages on [`MqttBridge::disconnect`]
+pub const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Broker and topic layout of [`MqttBridge`]
+#[derive(Debug, Clone)]
+pub struct BridgeOptions {
+    pub mqtt: MqttOptions,
+    /// Root of the device topics, `home` by default
+    pub topic_prefix: String,
+    /// Home Assistant discovery prefix, `None` disables discovery
+    pub discovery_prefix: Option<String>,
+    /// Devices are read at this interval, changed values are published
+    pub poll_interval: Duration,
+}
+
+impl BridgeOptions {
+    pub fn new<Id: Into<String>, Host: Into<String>>(client_id: Id, host: Host, port: u16) -> Self {
+        let mut mqtt = MqttOptions::new(client_id, host, port);
+        mqtt.set_keep_alive(Duration::from_secs(30));
+        Self {
+            mqtt,
+            topic_prefix: "home".to_string(),
+            discovery_prefix: Some("homeassistant".to_string()),
+            poll_interval: Duration::from_secs(5),
+        }
+    }
+
+    pub fn with_topic_prefix<Prefix: Into<String>>(mut self, prefix: Prefix) -> Self {
+        self.topic_prefix = prefix.into();
+        self
+    }
+
+    pub fn with_discovery_prefix(mut self, prefix: Option<String>) -> Self {
+        self.discovery_prefix = prefix;
+        self
+    }
+
+    pub fn with_poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+}
+
+/// Topic name part: lowercase, anything but letters and digits replaced with `_`
+pub fn slug(name: &str) -> String {
+    name.trim().chars()
+        .map(|c| if c.is_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+        .collect()
+}
+
+/// Topics of one device under `<prefix>/<room>/<device>`
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceTopics {
+    pub room: String,
+    pub name: String,
+    /// Home Assistant unique id, `<prefix>_<room>_<device>`
+    pub unique_id: String,
+    pub base: String,
+}
+
+impl DeviceTopics {
+    pub fn new(prefix: &str, room: &str, name: &str) -> Self {
+        Self {
+            room: room.to_string(),
+            name: name.to_string(),
+            unique_id: format!("{}_{}_{}", slug(prefix), slug(room), slug(name)),
+            base: format!("{}/{}/{}", prefix, slug(room), slug(name)),
+        }
+    }
+
+    /// `ON` or `OFF`
+    pub fn state(&self) -> String {
+        format!("{}/state", self.base)
+    }
+
+    /// Watts, not published while unknown
+    pub fn power(&self) -> String {
+        format!("{}/power", self.base)
+    }
+
+    /// Degrees Celsius, not published while unknown
+    pub fn temperature(&self) -> String {
+        format!("{}/temperature", self.base)
+    }
+
+    /// `online` or `offline` if the device failed to answer
+    pub fn availability(&self) -> String {
+        format!("{}/availability", self.base)
+    }
+
+    /// Commands `ON` and `OFF` to the socket
+    pub fn set(&self) -> String {
+        format!("{}/set", self.base)
+    }
+}
+
+enum Bridged {
+    Socket(SmartPointer<dyn SocketTrait>),
+    Thermometer(SmartPointer<dyn TemperatureSensorTrait>),
+}
+
+struct BridgedDevice {
+    topics: DeviceTopics,
+    device: Bridged,
+}
+
+/// Publishes state, power and temperature of the devices to an MQTT broker,
+/// turns sockets on and off by `<prefix>/<room>/<device>/set` messages.
+/// Messages are retained, so late subscribers get the last values.
+/// Devices are not thread safe, the bridge runs on the thread owning them.
+pub struct MqttBridge {
+    options: BridgeOptions,
+    devices: Vec<BridgedDevice>,
+    /// Last payload of every topic, only changes are published
+    published: HashMap<String, String>,
+    connection: Option<(Client, Connection)>,
+    next_poll: Instant,
+}
+
+impl MqttBridge {
+    pub fn new(options: BridgeOptions) -> Self {
+        Self { options, devices: Vec::new(), published: HashMap::new(), connection: None, next_poll: Instant::now() }
+    }
+
+    /// Fails with [`BridgeError::DuplicateTopic`] if another device has the same topics
+    pub fn add_socket(&mut self, room: &str, name: &str, socket: SmartPointer<dyn SocketTrait>) -> Result<(), BridgeError> {
+        self.add(room, name, Bridged::Socket(socket))
+    }
+
+    /// Fails with [`BridgeError::DuplicateTopic`] if another device has the same topics
+    pub fn add_thermometer(&mut self, room: &str, name: &str, thermometer: SmartPointer<dyn TemperatureSensorTrait>) -> Result<(), BridgeError> {
+        self.add(room, name, Bridged::Thermometer(thermometer))
+    }
+
+    /// Every device of the room, named by its description. No device is added if two of them have the same topics.
+    pub fn add_room<T>(&mut self, room: &mut Room<T>) -> Result<(), BridgeError>
+    where
+        T: DeviceTypes,
+        T::Socket: 'static,
+        T::Thermometer: 'static,
+    {
+        let room_name = room.name();
+        let mut devices = Vec::new();
+        room.visit_mut(|device| {
+            match device {
+                Device::Socket(socket) => {
+                    let name = socket.borrow_mut().description();
+                    devices.push((name, Bridged::Socket((**socket).clone())));
+                }
+                Device::Thermometer(thermometer) => {
+                    let name = thermometer.borrow_mut().description();
+                    devices.push((name, Bridged::Thermometer((**thermometer).clone())));
+                }
+            }
+        });
+        let added = self.devices.len();
+        for (name, device) in devices {
+            if let Err(e) = self.add(&room_name, &name, device) {
+                self.devices.truncate(added);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Names differing only in case or punctuation have the same slug, so the same topics
+    fn add(&mut self, room: &str, name: &str, device: Bridged) -> Result<(), BridgeError> {
+        let topics = DeviceTopics::new(&self.options.topic_prefix, room, name);
+        if self.devices.iter().any(|bridged| bridged.topics.base == topics.base) {
+            return Err(BridgeError::DuplicateTopic(topics.base));
+        }
+        self.devices.push(BridgedDevice { topics, device });
+        Ok(())
+    }
+
+    /// Topics of the bridged devices, in the order of adding
+    pub fn devices(&self) -> impl Iterator<Item = &DeviceTopics> {
+        self.devices.iter().map(|device| &device.topics)
+    }
+
+    /// `online` while the bridge is connected, `offline` is the last will
+    pub fn availability_topic(&self) -> String {
+        format!("{}/bridge/availability", self.options.topic_prefix)
+    }
+
+    /// Serve until `stop` returns `true`, reconnecting on broker failures, then disconnect
+    pub fn run_until<F: FnMut() -> bool>(&mut self, mut stop: F) -> Result<(), BridgeError> {
+        while !stop() {
+            match self.step() {
+                Ok(()) => {}
+                Err(BridgeError::Connection(e)) => {
+                    log::warn!("{}, reconnecting in {:?}", e, RECONNECT_DELAY);
+                    thread::sleep(RECONNECT_DELAY);
+                }
+                Err(e) => { return Err(e) }
+            }
+        }
+        self.disconnect()
+    }
+
+    /// Handle broker messages until the next poll of the devices, then poll them.
+    /// Connects on the first call. After a connection error the next call reconnects.
+    pub fn step(&mut self) -> Result<(), BridgeError> {
+        loop {
+            let left = self.next_poll.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break;
+            }
+            let event = match self.connection().1.recv_timeout(left) {
+                Ok(event) => { event? }
+                Err(RecvTimeoutError::Timeout) => { break }
+                Err(RecvTimeoutError::Disconnected) => { return Err(BridgeError::Closed) }
+            };
+            match event {
+                Event::Incoming(Packet::ConnAck(_)) => { self.on_connected()? }
+                Event::Incoming(Packet::Publish(publish)) => {
+                    let payload = String::from_utf8_lossy(&publish.payload).into_owned();
+                    self.on_command(&publish.topic, &payload)?;
+                }
+                _ => {}
+            }
+        }
+        self.next_poll = Instant::now() + self.options.poll_interval;
+        self.poll_devices()
+    }
+
+    /// Publish the bridge is offline and close the connection. The next step connects again.
+    pub fn disconnect(&mut self) -> Result<(), BridgeError> {
+        let Some((client, mut connection)) = self.connection.take() else {
+            return Ok(());
+        };
+        client.try_publish(self.availability_topic(), QoS::AtLeastOnce, true, OFFLINE)?;
+        client.try_disconnect()?;
+        let deadline = Instant::now() + DISCONNECT_TIMEOUT;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match connection.recv_timeout(left) {
+                Ok(Ok(Event::Outgoing(Outgoing::Disconnect))) => { return Ok(()) }
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => { return Err(e.into()) }
+                Err(_) => { return Err(BridgeError::Closed) }
+            }
+        }
+    }
+
+    fn connection(&mut self) -> &mut (Client, Connection) {
+        if self.connection.is_none() {
+            let mut mqtt = self.options.mqtt.clone();
+            mqtt.set_last_will(LastWill::new(self.availability_topic(), OFFLINE, QoS::AtLeastOnce, true));
+            // every device publishes up to 4 values and 2 discovery configs between connection polls
+            mqtt.set_request_channel_capacity(16 + self.devices.len() * 8);
+            let capacity = mqtt.request_channel_capacity();
+            self.connection = Some(Client::new(mqtt, capacity));
+        }
+        self.connection.as_mut().expect("connected above")
+    }
+
+    fn client(&mut self) -> &Client {
+        &self.connection().0
+    }
+
+    /// Announce the bridge and devices again, the broker may have lost retained messages
+    fn on_connected(&mut self) -> Result<(), BridgeError> {
+        self.published.clear();
+        let availability = self.availability_topic();
+        self.publish(availability, ONLINE.to_string())?;
+        let commands = format!("{}/+/+/set", self.options.topic_prefix);
+        self.client().try_subscribe(commands, QoS::AtLeastOnce)?;
+        for config in self.discovery_configs() {
+            self.publish(config.topic, config.payload)?;
+        }
+        self.next_poll = Instant::now();
+        Ok(())
+    }
+
+    fn discovery_configs(&self) -> Vec<DiscoveryConfig> {
+        let Some(prefix) = &self.options.discovery_prefix else {
+            return Vec::new();
+        };
+        let availability = self.availability_topic();
+        self.devices.iter().flat_map(|device| {
+            match device.device {
+                Bridged::Socket(_) => { socket_configs(prefix, &availability, &device.topics) }
+                Bridged::Thermometer(_) => { thermometer_configs(prefix, &availability, &device.topics) }
+            }
+        }).collect()
+    }
+
+    /// `ON` or `OFF` published to the `set` topic of a socket. Unknown topics and payloads are ignored.
+    fn on_command(&mut self, topic: &str, payload: &str) -> Result<(), BridgeError> {
+        let Some(index) = self.devices.iter().position(|device| device.topics.set() == topic) else {
+            return Ok(());
+        };
+        let Bridged::Socket(socket) = &self.devices[index].device else {
+            return Ok(());
+        };
+        let turned = match payload.trim().to_ascii_uppercase().as_str() {
+            PAYLOAD_ON => { socket.borrow_mut().turn_on() }
+            PAYLOAD_OFF => { socket.borrow_mut().turn_off() }
+            _ => {
+                log::warn!("{}: unknown command `{}`", topic, payload);
+                return Ok(());
+            }
+        };
+        if let Err(e) = turned {
+            log::warn!("{}: {}", topic, e);
+        }
+        self.poll_device(index)
+    }
+
+    fn poll_devices(&mut self) -> Result<(), BridgeError> {
+        for index in 0..self.devices.len() {
+            self.poll_device(index)?;
+        }
+        Ok(())
+    }
+
+    fn poll_device(&mut self, index: usize) -> Result<(), BridgeError> {
+        let BridgedDevice { topics, device } = &self.devices[index];
+        let values = match device {
+            Bridged::Socket(socket) => {
+                let mut socket = socket.borrow_mut();
+                socket.current_state().and_then(|on| {
+                    let state = (topics.state(), if on { PAYLOAD_ON } else { PAYLOAD_OFF }.to_string());
+                    let power = socket.power_consumption_wt()?.map(|power| (topics.power(), power.to_string()));
+                    Ok([Some(state), power])
+                })
+            }
+            Bridged::Thermometer(thermometer) => {
+                thermometer.borrow().temperature_deg_celsius()
+                    .map(|temperature| [temperature.map(|temperature| (topics.temperature(), temperature.to_string())), None])
+            }
+        };
+        let availability = topics.availability();
+        match values {
+            Ok(values) => {
+                for (topic, payload) in values.into_iter().flatten() {
+                    self.publish(topic, payload)?;
+                }
+                self.publish(availability, ONLINE.to_string())
+            }
+            Err(e) => {
+                log::warn!("{}: {}", self.devices[index].topics.base, e);
+                self.publish(availability, OFFLINE.to_string())
+            }
+        }
+    }
+
+    /// Retained publish if the payload differs from the last one of the topic
+    fn publish(&mut self, topic: String, payload: String) -> Result<(), BridgeError> {
+        if self.published.get(&topic) == Some(&payload) {
+            return Ok(());
+        }
+        self.client().try_publish(topic.as_str(), QoS::AtLeastOnce, true, payload.as_bytes())?;
+        self.published.insert(topic, payload);
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use smart_home_lib::common::traits::device::Switchable;
+    use smart_home_lib::devices::stubs::socket_stub::SocketStub;
+    use smart_home_lib::devices::stubs::thermometer_stub::ThermometerStub;
+    use smart_home_lib::house::room_static::SpWrapper;
+
+    use crate::test_broker::TestBroker;
+
+    use super::*;
+
+    struct Stubs;
+
+    impl DeviceTypes for Stubs {
+        type Socket = SocketStub;
+        type Thermometer = ThermometerStub;
+    }
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    /// Step the bridge until the broker retains the payload
+    fn wait_retained(bridge: &mut MqttBridge, broker: &TestBroker, topic: &str, payload: &str) {
+        let deadline = Instant::now() + WAIT;
+        while broker.retained(topic).as_deref() != Some(payload.as_bytes()) {
+            assert!(Instant::now() < deadline, "{} is {:?}, expected {}", topic, broker.retained(topic), payload);
+            bridge.step().unwrap();
+        }
+    }
+
+    #[test]
+    fn publish_and_control_room() {
+        let broker = TestBroker::start().unwrap();
+        let room = Room::<Stubs>::new("Living room".to_string());
+        let socket = SocketStub::new("Lamp".to_string());
+        let thermometer: SpWrapper<ThermometerStub> = ThermometerStub::new("Sensor".to_string()).into();
+        room.borrow_mut().add_device(SpWrapper::from(socket.clone()).into());
+        room.borrow_mut().add_device(thermometer.into());
+
+        let options = BridgeOptions::new("bridge", "127.0.0.1", broker.addr().port()).with_poll_interval(Duration::from_millis(20));
+        let mut bridge = MqttBridge::new(options);
+        bridge.add_room(&mut room.borrow_mut()).unwrap();
+        let topics: Vec<_> = bridge.devices().map(|device| device.base.clone()).collect();
+        assert_eq!(topics, ["home/living_room/lamp", "home/living_room/sensor"]);
+
+        wait_retained(&mut bridge, &broker, "home/bridge/availability", ONLINE);
+        wait_retained(&mut bridge, &broker, "home/living_room/lamp/state", PAYLOAD_OFF);
+        wait_retained(&mut bridge, &broker, "home/living_room/lamp/power", "0");
+        wait_retained(&mut bridge, &broker, "home/living_room/sensor/temperature", "0");
+        wait_retained(&mut bridge, &broker, "home/living_room/sensor/availability", ONLINE);
+        assert!(broker.retained("homeassistant/switch/home_living_room_lamp/config").is_some());
+        assert!(broker.retained("homeassistant/sensor/home_living_room_sensor_temperature/config").is_some());
+
+        broker.publish("home/living_room/lamp/set", "on");
+        wait_retained(&mut bridge, &broker, "home/living_room/lamp/state", PAYLOAD_ON);
+        wait_retained(&mut bridge, &broker, "home/living_room/lamp/power", "2000");
+        assert!(socket.borrow_mut().current_state().unwrap());
+
+        socket.borrow_mut().online(false);
+        wait_retained(&mut bridge, &broker, "home/living_room/lamp/availability", OFFLINE);
+        socket.borrow_mut().online(true);
+        wait_retained(&mut bridge, &broker, "home/living_room/lamp/availability", ONLINE);
+
+        bridge.run_until(|| true).unwrap();
+        let deadline = Instant::now() + WAIT;
+        while broker.retained("home/bridge/availability").unwrap() != OFFLINE.as_bytes() {
+            assert!(Instant::now() < deadline, "bridge is not offline");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn reject_duplicate_topics() {
+        let room = Room::<Stubs>::new("Kitchen".to_string());
+        room.borrow_mut().add_device(SpWrapper::from(SocketStub::new("Kettle".to_string())).into());
+        room.borrow_mut().add_device(SpWrapper::from(SocketStub::new("KETTLE".to_string())).into());
+        let mut bridge = MqttBridge::new(BridgeOptions::new("bridge", "127.0.0.1", 1883));
+        let err = bridge.add_room(&mut room.borrow_mut()).unwrap_err();
+        assert!(matches!(&err, BridgeError::DuplicateTopic(base) if base == "home/kitchen/kettle"), "{}", err);
+        assert_eq!(bridge.devices().count(), 0);
+
+        let thermometer: SpWrapper<ThermometerStub> = ThermometerStub::new("Kettle".to_string()).into();
+        bridge.add_socket("Kitchen", "Kettle", SocketStub::new("Kettle".to_string())).unwrap();
+        assert!(bridge.add_thermometer("kitchen", "KETTLE", (*thermometer).clone()).is_err());
+        bridge.add_thermometer("Hall", "Kettle", (*thermometer).clone()).unwrap();
+        assert_eq!(bridge.devices().count(), 2);
+    }
+
+    #[test]
+    fn topic_names() {
+        assert_eq!(slug(" Kid's room 2 "), "kid_s_room_2");
+        let topics = DeviceTopics::new("flat", "Kitchen", "Kettle");
+        assert_eq!(topics.set(), "flat/kitchen/kettle/set");
+        assert_eq!(topics.unique_id, "flat_kitchen_kettle");
+    }
+}
@@ -0,0 +1,84 @@
+use serde_json::{json, Value};
+
+use crate::bridge::{DeviceTopics, PAYLOAD_OFF, PAYLOAD_ON};
+
+/// Retained message announcing one Home Assistant entity
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoveryConfig {
+    pub topic: String,
+    pub payload: String,
+}
+
+/// Switch entity and power sensor of the socket
+pub fn socket_configs(discovery_prefix: &str, bridge_availability: &str, device: &DeviceTopics) -> Vec<DiscoveryConfig> {
+    let switch = json!({
+        "name": null,
+        "unique_id": device.unique_id,
+        "state_topic": device.state(),
+        "command_topic": device.set(),
+        "payload_on": PAYLOAD_ON,
+        "payload_off": PAYLOAD_OFF,
+    });
+    let power = sensor(&format!("{}_power", device.unique_id), "Power", &device.power(), "power", "W");
+    vec![
+        config(discovery_prefix, "switch", &device.unique_id, switch, bridge_availability, device),
+        config(discovery_prefix, "sensor", &format!("{}_power", device.unique_id), power, bridge_availability, device),
+    ]
+}
+
+/// Temperature sensor entity of the thermometer
+pub fn thermometer_configs(discovery_prefix: &str, bridge_availability: &str, device: &DeviceTopics) -> Vec<DiscoveryConfig> {
+    let object_id = format!("{}_temperature", device.unique_id);
+    let temperature = sensor(&object_id, "Temperature", &device.temperature(), "temperature", "°C");
+    vec![config(discovery_prefix, "sensor", &object_id, temperature, bridge_availability, device)]
+}
+
+fn sensor(unique_id: &str, name: &str, state_topic: &str, device_class: &str, unit: &str) -> Value {
+    json!({
+        "name": name,
+        "unique_id": unique_id,
+        "state_topic": state_topic,
+        "device_class": device_class,
+        "unit_of_measurement": unit,
+        "state_class": "measurement",
+    })
+}
+
+/// Entity is available while both the bridge and the device are online
+fn config(discovery_prefix: &str, component: &str, object_id: &str, mut entity: Value, bridge_availability: &str, device: &DeviceTopics) -> DiscoveryConfig {
+    entity["availability"] = json!([{ "topic": bridge_availability }, { "topic": device.availability() }]);
+    entity["availability_mode"] = json!("all");
+    entity["device"] = json!({
+        "identifiers": [device.unique_id],
+        "name": device.name,
+        "suggested_area": device.room,
+    });
+    DiscoveryConfig { topic: format!("{}/{}/{}/config", discovery_prefix, component, object_id), payload: entity.to_string() }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn home_assistant_payloads() {
+        let device = DeviceTopics::new("home", "Living room", "Floor lamp");
+        let configs = socket_configs("homeassistant", "home/bridge/availability", &device);
+        assert_eq!(configs[0].topic, "homeassistant/switch/home_living_room_floor_lamp/config");
+        assert_eq!(configs[1].topic, "homeassistant/sensor/home_living_room_floor_lamp_power/config");
+        let switch: Value = serde_json::from_str(&configs[0].payload).unwrap();
+        assert_eq!(switch["command_topic"], "home/living_room/floor_lamp/set");
+        assert_eq!(switch["state_topic"], "home/living_room/floor_lamp/state");
+        assert_eq!(switch["availability"][1]["topic"], "home/living_room/floor_lamp/availability");
+        assert_eq!(switch["device"]["suggested_area"], "Living room");
+        let power: Value = serde_json::from_str(&configs[1].payload).unwrap();
+        assert_eq!(power["unit_of_measurement"], "W");
+        assert_eq!(power["device"], switch["device"]);
+
+        let configs = thermometer_configs("homeassistant", "home/bridge/availability", &device);
+        let temperature: Value = serde_json::from_str(&configs[0].payload).unwrap();
+        assert_eq!(temperature["state_topic"], "home/living_room/floor_lamp/temperature");
+        assert_eq!(temperature["device_class"], "temperature");
+    }
+}
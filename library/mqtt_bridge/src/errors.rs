@@ -0,0 +1,30 @@
+use rumqttc::{ClientError, ConnectionError};
+use thiserror::Error;
+
+/// Error of [`crate::bridge::MqttBridge`]. Errors of rumqttc are large, so boxed.
+#[derive(Debug, Error)]
+pub enum BridgeError {
+    /// Request was not queued to the broker connection
+    #[error("MQTT client error: {0}")]
+    Client(Box<ClientError>),
+    /// Broker is unreachable or dropped the connection, the bridge reconnects on the next step
+    #[error("MQTT connection error: {0}")]
+    Connection(Box<ConnectionError>),
+    #[error("MQTT connection is closed")]
+    Closed,
+    /// Another device is already published to the topic
+    #[error("Device topic {0} is already used")]
+    DuplicateTopic(String),
+}
+
+impl From<ClientError> for BridgeError {
+    fn from(e: ClientError) -> Self {
+        BridgeError::Client(Box::new(e))
+    }
+}
+
+impl From<ConnectionError> for BridgeError {
+    fn from(e: ConnectionError) -> Self {
+        BridgeError::Connection(Box::new(e))
+    }
+}
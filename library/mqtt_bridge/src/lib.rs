@@ -0,0 +1,5 @@
+pub mod bridge;
+pub mod discovery;
+pub mod errors;
+#[cfg(test)]
+mod test_broker;
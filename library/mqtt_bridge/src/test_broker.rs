@@ -0,0 +1,170 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use bytes::BytesMut;
+use rumqttc::mqttbytes::{matches, Error};
+use rumqttc::mqttbytes::v4::read;
+use rumqttc::{ConnAck, ConnectReturnCode, LastWill, Packet, PingResp, PubAck, Publish, QoS, SubAck, SubscribeReasonCode, UnsubAck};
+use smart_home_lib::common::local_server::LocalServer;
+
+const MAX_PACKET_SIZE: usize = 1024 * 1024;
+
+/// MQTT 3.1.1 broker on a local port for tests, no broker installation needed.
+/// Supports QoS 0 and 1 publishing, retained messages, wildcards and last will.
+/// Messages are delivered with QoS 0. Stops on drop.
+pub struct TestBroker {
+    server: LocalServer,
+    state: Arc<BrokerState>,
+}
+
+#[derive(Default)]
+struct BrokerState {
+    clients: Mutex<Vec<Subscriber>>,
+    retained: Mutex<BTreeMap<String, Vec<u8>>>,
+    next_id: AtomicU64,
+}
+
+struct Subscriber {
+    id: u64,
+    filters: Vec<String>,
+    stream: Arc<Mutex<TcpStream>>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl TestBroker {
+    /// Listen on a free port of 127.0.0.1
+    pub fn start() -> io::Result<Self> {
+        let state = Arc::new(BrokerState::default());
+        let serving = state.clone();
+        let server = LocalServer::start(move |stream| {
+            let _ = serve(&serving, stream);
+        })?;
+        Ok(Self { server, state })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.server.addr()
+    }
+
+    /// Payload of the retained message of the topic
+    pub fn retained(&self, topic: &str) -> Option<Vec<u8>> {
+        lock(&self.state.retained).get(topic).cloned()
+    }
+
+    /// Deliver the message to the subscribers as if a client published it
+    pub fn publish<Payload: Into<Vec<u8>>>(&self, topic: &str, payload: Payload) {
+        self.state.route(&Publish::new(topic, QoS::AtMostOnce, payload));
+    }
+}
+
+impl BrokerState {
+    /// Retain if asked and send to every client with a matching subscription
+    fn route(&self, publish: &Publish) {
+        if publish.retain {
+            let mut retained = lock(&self.retained);
+            match publish.payload.is_empty() {
+                true => { retained.remove(&publish.topic) }
+                false => { retained.insert(publish.topic.clone(), publish.payload.to_vec()) }
+            };
+        }
+        let delivered = Publish::from_bytes(publish.topic.clone(), QoS::AtMostOnce, publish.payload.clone());
+        for client in lock(&self.clients).iter() {
+            if client.filters.iter().any(|filter| matches(&publish.topic, filter)) {
+                let _ = send(&client.stream, |buf| delivered.write(buf));
+            }
+        }
+    }
+
+    fn subscriber<F: FnOnce(&mut Subscriber)>(&self, id: u64, update: F) {
+        if let Some(client) = lock(&self.clients).iter_mut().find(|client| client.id == id) {
+            update(client);
+        }
+    }
+}
+
+fn send<F>(stream: &Mutex<TcpStream>, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut BytesMut) -> Result<usize, Error>,
+{
+    let mut buf = BytesMut::new();
+    write(&mut buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    lock(stream).write_all(&buf)
+}
+
+/// Session of one client, the will is published if the connection breaks without DISCONNECT
+fn serve(state: &BrokerState, stream: TcpStream) -> io::Result<()> {
+    let mut reader = stream.try_clone()?;
+    let stream = Arc::new(Mutex::new(stream));
+    let id = state.next_id.fetch_add(1, Ordering::Relaxed);
+    lock(&state.clients).push(Subscriber { id, filters: Vec::new(), stream: stream.clone() });
+    let mut will = None;
+    let result = session(state, id, &mut reader, &stream, &mut will);
+    lock(&state.clients).retain(|client| client.id != id);
+    if let Some(will) = will {
+        let mut publish = Publish::from_bytes(will.topic, will.qos, will.message);
+        publish.retain = will.retain;
+        state.route(&publish);
+    }
+    result
+}
+
+fn session(state: &BrokerState, id: u64, reader: &mut TcpStream, stream: &Mutex<TcpStream>, will: &mut Option<LastWill>) -> io::Result<()> {
+    let mut buf = BytesMut::new();
+    let mut chunk = [0; 4096];
+    loop {
+        let packet = match read(&mut buf, MAX_PACKET_SIZE) {
+            Ok(packet) => { packet }
+            Err(Error::InsufficientBytes(_)) => {
+                let n = reader.read(&mut chunk)?;
+                if n == 0 {
+                    return Ok(());
+                }
+                buf.extend_from_slice(&chunk[..n]);
+                continue;
+            }
+            Err(e) => { return Err(io::Error::new(io::ErrorKind::InvalidData, e)) }
+        };
+        match packet {
+            Packet::Connect(connect) => {
+                *will = connect.last_will;
+                send(stream, |buf| ConnAck::new(ConnectReturnCode::Success, false).write(buf))?;
+            }
+            Packet::Subscribe(subscribe) => {
+                let filters: Vec<String> = subscribe.filters.into_iter().map(|filter| filter.path).collect();
+                let codes = vec![SubscribeReasonCode::Success(QoS::AtMostOnce); filters.len()];
+                send(stream, |buf| SubAck::new(subscribe.pkid, codes).write(buf))?;
+                for (topic, payload) in lock(&state.retained).iter() {
+                    if filters.iter().any(|filter| matches(topic, filter)) {
+                        let mut publish = Publish::new(topic, QoS::AtMostOnce, payload.clone());
+                        publish.retain = true;
+                        send(stream, |buf| publish.write(buf))?;
+                    }
+                }
+                state.subscriber(id, |client| client.filters.extend(filters));
+            }
+            Packet::Unsubscribe(unsubscribe) => {
+                state.subscriber(id, |client| client.filters.retain(|filter| !unsubscribe.topics.contains(filter)));
+                send(stream, |buf| UnsubAck::new(unsubscribe.pkid).write(buf))?;
+            }
+            Packet::Publish(publish) => {
+                if publish.qos == QoS::AtLeastOnce {
+                    send(stream, |buf| PubAck::new(publish.pkid).write(buf))?;
+                }
+                state.route(&publish);
+            }
+            Packet::PingReq => { send(stream, |buf| PingResp.write(buf))? }
+            Packet::Disconnect => {
+                *will = None;
+                return Ok(());
+            }
+            _ => {}
+        }
+    }
+}
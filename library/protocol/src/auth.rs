@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsStr;
+use std::fmt::{Debug, Formatter};
+use std::io;
+use std::path::Path;
+
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::errors::{ConnectError, ConnectResult};
+
+/// Client answer to the challenge: `auth;<hex hmac>`
+pub const AUTH_REQUEST: &str = "auth";
+pub const AUTH_ACCEPTED: &str = "auth_ok";
+pub const AUTH_REJECTED: &str = "auth_failed";
+const AUTH_SEPARATOR: char = ';';
+/// Length of the server nonce in bytes
+pub const NONCE_LEN: usize = 32;
+/// Env variable with the hex encoded device key
+pub const PSK_ENV: &str = "STP_PSK";
+/// Env variable with the path of the [`KeyStore`] file
+pub const KEYS_ENV: &str = "STP_KEYS";
+
+/// Pre-shared key of a device. Client proves the knowledge of the key by HMAC-SHA256 over the server nonce.
+#[derive(Clone, PartialEq, Eq)]
+pub struct PreSharedKey {
+    key: Vec<u8>,
+}
+
+impl PreSharedKey {
+    pub fn new<Data: AsRef<[u8]>>(key: Data) -> Self {
+        Self { key: key.as_ref().to_vec() }
+    }
+
+    pub fn from_hex(key: &str) -> Result<Self, String> {
+        let key = hex::decode(key.trim()).map_err(|e| format!("Bad key: {}", e))?;
+        if key.is_empty() {
+            return Err("Empty key".to_string());
+        }
+        Ok(Self { key })
+    }
+
+    /// Key from `STP_PSK` env variable, `None` if it is not set
+    pub fn from_env() -> Result<Option<Self>, String> {
+        env::var(PSK_ENV).ok().map(|key| Self::from_psk_var(&key)).transpose()
+    }
+
+    /// Key from the value of `STP_PSK`
+    fn from_psk_var(key: &str) -> Result<Self, String> {
+        Self::from_hex(key).map_err(|e| format!("{}: {}", PSK_ENV, e))
+    }
+
+    /// Hex encoded HMAC of the hex encoded nonce
+    pub fn sign(&self, challenge: &str) -> Result<String, String> {
+        let nonce = hex::decode(challenge).map_err(|e| format!("Bad challenge: {}", e))?;
+        Ok(hex::encode(hmac::sign(&self.hmac_key(), &nonce)))
+    }
+
+    /// Check HMAC of the nonce in constant time
+    pub fn verify(&self, challenge: &str, mac: &str) -> bool {
+        match (hex::decode(challenge), hex::decode(mac)) {
+            (Ok(nonce), Ok(mac)) => { hmac::verify(&self.hmac_key(), &nonce, &mac).is_ok() }
+            _ => { false }
+        }
+    }
+
+    /// Client message answering the challenge
+    pub fn auth_request_msg(&self, challenge: &str) -> Result<String, String> {
+        Ok(format!("{}{}{}", AUTH_REQUEST, AUTH_SEPARATOR, self.sign(challenge)?))
+    }
+
+    /// Check client message answering the challenge
+    pub fn check_auth_request(&self, challenge: &str, msg: &str) -> bool {
+        match msg.split_once(AUTH_SEPARATOR) {
+            Some((AUTH_REQUEST, mac)) => { self.verify(challenge, mac) }
+            _ => { false }
+        }
+    }
+
+    fn hmac_key(&self) -> hmac::Key {
+        hmac::Key::new(hmac::HMAC_SHA256, &self.key)
+    }
+}
+
+impl Debug for PreSharedKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PreSharedKey(***)")
+    }
+}
+
+/// Random hex encoded nonce, sent by the server in handshake
+pub fn new_challenge() -> String {
+    let mut nonce = [0u8; NONCE_LEN];
+    SystemRandom::new().fill(&mut nonce).expect("system random generator is available");
+    hex::encode(nonce)
+}
+
+/// Keys of devices by device name or address.
+/// File layout: `<device> <hex key>` per line, `#` starts a comment.
+#[derive(Debug, Clone, Default)]
+pub struct KeyStore {
+    keys: HashMap<String, PreSharedKey>,
+}
+
+impl KeyStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut store = Self::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (device, key) = line.split_once(char::is_whitespace)
+                .ok_or(format!("Line {}: `<device> <hex key>` expected", n + 1))?;
+            let key = PreSharedKey::from_hex(key).map_err(|e| format!("Line {}: {}", n + 1, e))?;
+            store.insert(device, key);
+        }
+        Ok(store)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        Self::parse(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Keys from the file given by `STP_KEYS` env variable, `None` if it is not set
+    pub fn from_env() -> Result<Option<Self>, String> {
+        env::var_os(KEYS_ENV).map(|path| Self::from_keys_var(&path)).transpose()
+    }
+
+    /// Keys from the file given by the value of `STP_KEYS`
+    fn from_keys_var(path: &OsStr) -> Result<Self, String> {
+        Self::load(path).map_err(|e| format!("{}: {}", path.to_string_lossy(), e))
+    }
+
+    pub fn insert(&mut self, device: &str, key: PreSharedKey) {
+        self.keys.insert(device.to_string(), key);
+    }
+
+    pub fn get(&self, device: &str) -> Option<&PreSharedKey> {
+        self.keys.get(device)
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+/// Key of the device from env variables: its entry in the `STP_KEYS` file, `STP_PSK` otherwise
+pub fn key_from_env(device: &str) -> Result<Option<PreSharedKey>, String> {
+    key_from_vars(device, env::var_os(KEYS_ENV).as_deref(), env::var(PSK_ENV).ok().as_deref())
+}
+
+/// Key of the device from the values of `STP_KEYS` and `STP_PSK`, see [`key_from_env`]
+pub fn key_from_vars(device: &str, keys_path: Option<&OsStr>, psk: Option<&str>) -> Result<Option<PreSharedKey>, String> {
+    if let Some(path) = keys_path {
+        if let Some(key) = KeyStore::from_keys_var(path)?.get(device) {
+            return Ok(Some(key.clone()));
+        }
+    }
+    psk.map(PreSharedKey::from_psk_var).transpose()
+}
+
+/// Client message answering the server challenge, `None` if the server does not require authentication
+pub(crate) fn answer_challenge(challenge: Option<&str>, key: Option<&PreSharedKey>) -> ConnectResult<Option<String>> {
+    match (challenge, key) {
+        (Some(challenge), Some(key)) => { key.auth_request_msg(challenge).map(Some).map_err(ConnectError::BadHandshake) }
+        (Some(_), None) => { Err(ConnectError::Unauthorized("server requires pre-shared key".to_string())) }
+        (None, _) => { Ok(None) }
+    }
+}
+
+pub(crate) fn check_auth_reply(reply: &str) -> ConnectResult<()> {
+    match reply {
+        AUTH_ACCEPTED => { Ok(()) }
+        AUTH_REJECTED => { Err(ConnectError::Unauthorized("key rejected by server".to_string())) }
+        reply => { Err(ConnectError::BadHandshake(format!("Unexpected authentication reply `{}`", reply))) }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_and_verify() {
+        let key = PreSharedKey::new("secret");
+        let challenge = new_challenge();
+        assert_eq!(challenge.len(), NONCE_LEN * 2);
+        assert_ne!(challenge, new_challenge());
+        let msg = key.auth_request_msg(&challenge).unwrap();
+        assert!(key.check_auth_request(&challenge, &msg));
+        assert!(!key.check_auth_request(&new_challenge(), &msg));
+        assert!(!PreSharedKey::new("guess").check_auth_request(&challenge, &msg));
+        assert!(!key.check_auth_request(&challenge, "auth;zz"));
+        assert!(!key.check_auth_request(&challenge, "hi_server;1;switch"));
+        assert!(key.sign("not hex").is_err());
+    }
+
+    #[test]
+    fn parse_key_store() {
+        let store = KeyStore::parse("# devices\n127.0.0.1:55331  00ff10 # kitchen\n\nsocket@hall abcd\n").unwrap();
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.get("127.0.0.1:55331"), Some(&PreSharedKey::new([0x00, 0xff, 0x10])));
+        assert_eq!(store.get("socket@hall"), Some(&PreSharedKey::new([0xab, 0xcd])));
+        assert!(store.get("socket@kitchen").is_none());
+        assert!(KeyStore::parse("127.0.0.1:55331").is_err());
+        assert!(KeyStore::parse("127.0.0.1:55331 xyz").is_err());
+        assert_eq!(format!("{:?}", PreSharedKey::new("secret")), "PreSharedKey(***)");
+    }
+
+    #[test]
+    fn keys_from_vars() {
+        let path = env::temp_dir().join(format!("stp-keys-{}", std::process::id()));
+        std::fs::write(&path, "127.0.0.1:55331 00ff10\n").unwrap();
+        let keys = Some(path.as_os_str());
+        assert_eq!(key_from_vars("127.0.0.1:55331", keys, Some("abcd")), Ok(Some(PreSharedKey::new([0x00, 0xff, 0x10]))));
+        assert_eq!(key_from_vars("127.0.0.1:55332", keys, Some("abcd")), Ok(Some(PreSharedKey::new([0xab, 0xcd]))));
+        assert!(key_from_vars("127.0.0.1:55332", keys, Some("xyz")).unwrap_err().starts_with(PSK_ENV));
+        assert_eq!(key_from_vars("127.0.0.1:55332", keys, None), Ok(None));
+        assert_eq!(key_from_vars("127.0.0.1:55332", None, None), Ok(None));
+        let _ = std::fs::remove_file(&path);
+        assert!(key_from_vars("127.0.0.1:55331", keys, None).is_err());
+    }
+}
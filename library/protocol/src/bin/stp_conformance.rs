@@ -0,0 +1,18 @@
+use std::error::Error;
+use std::process::ExitCode;
+
+use protocol::client_std::ConnectOptions;
+use protocol::conformance;
+
+const DEFAULT_ADDR: &str = "127.0.0.1:55331";
+
+/// Check the smart socket server: `stp_conformance [addr]`.
+/// Device key is taken from the `STP_KEYS` key file or `STP_PSK` env variable.
+fn main() -> Result<ExitCode, Box<dyn Error>> {
+    let addr = std::env::args().nth(1).unwrap_or(DEFAULT_ADDR.to_string());
+    let options = ConnectOptions::from_env(&addr)?;
+    println!("Checking STP server at {}", addr);
+    let report = conformance::check_address(addr.as_str(), &options)?;
+    println!("{}", report);
+    Ok(if report.passed() { ExitCode::SUCCESS } else { ExitCode::FAILURE })
+}
@@ -1,91 +1,642 @@
 use std::io;
 use std::io::{ErrorKind, Read, Write};
-use std::net::{TcpStream, ToSocketAddrs};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
 use std::ops::Deref;
-use std::time::Duration;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::Path;
+use std::time::{Duration, Instant};
 
-use thiserror::Error;
+use rustls::{ClientConnection, StreamOwned};
 
-use crate::errors::{ConnectResult, RecvError, RecvResult, SendError, SendResult};
+use crate::auth;
+use crate::auth::PreSharedKey;
+use crate::errors::{ConnectResult, RecvError, RecvResult, SendResult, TlsError};
+use crate::heartbeat::Heartbeat;
+use crate::message::{Event, Request, Response};
 use crate::protocol;
+use crate::protocol::{Capability, Frame, FrameDecoder, FrameKind, Hello};
+use crate::reconnect::{Attempts, Backoff, ConnectionState};
+use crate::record::Recorder;
+use crate::session::{ClientHandshake, ClientState, HANDSHAKE_TIMEOUT, handshake_payload, message_payload, Progress, Received, Session, Timeout};
+use crate::stream::StpStream;
+use crate::tls::TlsClientConfig;
+use crate::transport::Transport;
 
-#[derive(Debug)]
-pub struct ClientStp {
-    stream: TcpStream,
+pub use crate::errors::{RequestError, RequestResult};
+
+/// Connection parameters of [`ClientStp`], shared by blocking and async clients
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
+    pub capabilities: Vec<Capability>,
+    pub tls: Option<TlsClientConfig>,
+    /// Pre-shared key of the device, required if the server asks for authentication
+    pub key: Option<PreSharedKey>,
+    /// Ping the idle server while waiting for responses or events
+    pub heartbeat: Option<Heartbeat>,
+    pub recorder: Option<Recorder>,
 }
 
-impl ClientStp {
-    fn handshake(mut stream: TcpStream) -> ConnectResult<Self> {
-        let msg = protocol::handshake_request_msg();
-        stream.write_all(msg.as_bytes())?;
-        stream.set_read_timeout(Some(Duration::from_secs(1)))?;
-        let mut buff = [0; 256];
-        let _n = stream.read(&mut buff)?;
-        let resp_raw_mgs = String::from_utf8(Vec::from(buff)).expect("Error to convert msg to string");
-        if !resp_raw_mgs.contains(protocol::handshake_respond_msg().as_str()) {
-            "Unexpected response".to_string();
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self { capabilities: Capability::ALL.to_vec(), tls: None, key: None, heartbeat: None, recorder: None }
+    }
+}
+
+impl ConnectOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Default options with the key of the device from env variables, see [`crate::auth::key_from_env`]
+    pub fn from_env(device: &str) -> Result<Self, String> {
+        let options = Self::new();
+        match auth::key_from_env(device)? {
+            Some(key) => { Ok(options.with_key(key)) }
+            None => { Ok(options) }
         }
-        Ok(Self { stream })
     }
 
+    pub fn with_capabilities(mut self, capabilities: &[Capability]) -> Self {
+        self.capabilities = capabilities.to_vec();
+        self
+    }
+
+    pub fn with_tls(mut self, tls: TlsClientConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    pub fn with_key(mut self, key: PreSharedKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    pub fn with_heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+        self.heartbeat = Some(heartbeat);
+        self
+    }
+
+    /// Record frames of the connection, handshake included
+    pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+}
+
+#[derive(Debug)]
+pub struct ClientStp<S: Transport = TcpStream> {
+    stream: StpStream<S>,
+    decoder: FrameDecoder,
+    session: Session,
+    state: ClientState,
+}
+
+impl ClientStp {
+    /// Connect and request all capabilities known by this library version
     pub fn connect<Addr>(addr: Addr) -> ConnectResult<Self>
     where
         Addr: ToSocketAddrs,
     {
-        let stream = TcpStream::connect(addr)?;
-        Self::handshake(stream)
+        Self::connect_with(addr, &ConnectOptions::new())
+    }
+
+    pub fn connect_with_capabilities<Addr>(addr: Addr, capabilities: &[Capability]) -> ConnectResult<Self>
+    where
+        Addr: ToSocketAddrs,
+    {
+        Self::connect_with(addr, &ConnectOptions::new().with_capabilities(capabilities))
+    }
+
+    /// Connect over TLS and request all capabilities known by this library version
+    pub fn connect_tls<Addr>(addr: Addr, tls: &TlsClientConfig) -> ConnectResult<Self>
+    where
+        Addr: ToSocketAddrs,
+    {
+        Self::connect_with(addr, &ConnectOptions::new().with_tls(tls.clone()))
+    }
+
+    pub fn connect_with<Addr>(addr: Addr, options: &ConnectOptions) -> ConnectResult<Self>
+    where
+        Addr: ToSocketAddrs,
+    {
+        Self::connect_over(TcpStream::connect(addr)?, options)
+    }
+}
+
+#[cfg(unix)]
+impl ClientStp<UnixStream> {
+    /// Connect to the local hub over Unix domain socket
+    pub fn connect_unix<P: AsRef<Path>>(path: P, options: &ConnectOptions) -> ConnectResult<Self> {
+        Self::connect_over(UnixStream::connect(path)?, options)
+    }
+}
+
+impl<S: Transport> ClientStp<S> {
+    /// Handshake over already connected transport, wrapped in TLS if configured
+    pub fn connect_over(transport: S, options: &ConnectOptions) -> ConnectResult<Self> {
+        let stream = match &options.tls {
+            Some(tls) => {
+                let connection = ClientConnection::new(tls.config(), tls.server_name()).map_err(TlsError::from)?;
+                StpStream::TlsClient(Box::new(StreamOwned::new(connection, transport)))
+            }
+            None => { StpStream::Plain(transport) }
+        };
+        let stream = match &options.recorder {
+            Some(recorder) => { stream.recorded(recorder.clone()) }
+            None => { stream }
+        };
+        Self::handshake(stream, options)
+    }
+
+    fn handshake(mut stream: StpStream<S>, options: &ConnectOptions) -> ConnectResult<Self> {
+        let mut decoder = FrameDecoder::new();
+        // TLS handshake is performed by the first write, so the timeout is set before
+        let prev_timeout = stream.get_ref().read_timeout()?;
+        stream.get_ref().set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let hello = exchange_hello(&mut stream, &mut decoder, options);
+        stream.get_ref().set_read_timeout(prev_timeout)?;
+        let session = Session::new(hello?, options.heartbeat);
+        Ok(Self { stream, decoder, session, state: ClientState::default() })
+    }
+
+    pub fn is_tls(&self) -> bool {
+        self.stream.is_tls()
+    }
+
+    /// Protocol version agreed with the server
+    pub fn protocol_version(&self) -> u16 {
+        self.session.hello().version
+    }
+
+    /// Capabilities supported by both client and server
+    pub fn capabilities(&self) -> &[Capability] {
+        &self.session.hello().capabilities
     }
 
     pub fn send_request<Data: AsRef<str>>(&mut self, msg: Data) -> RequestResult {
-        send_str(&self.stream, msg)?;
-        let resp = read_srt(&self.stream)?;
-        Ok(resp)
+        let request = self.state.request_frame(&self.session, msg.as_ref());
+        send_frame(&mut self.stream, request.kind, request.payload)?;
+        loop {
+            let frame = recv_frame(&mut self.stream, &mut self.decoder, &mut self.session)?;
+            // events pushed before the response are kept for `next_event`
+            if let Some(resp) = self.state.on_response_frame(&self.session, frame)? {
+                return Ok(resp);
+            }
+        }
+    }
+
+    pub fn request(&mut self, request: &Request) -> Result<Response, RequestError> {
+        let resp = self.send_request(request.encode())?;
+        Ok(Response::decode(&resp)?)
+    }
+
+    /// Wait for the next event pushed by the server. Subscribe with [`Request::Subscribe`] first.
+    /// `None` if no event received during `timeout`, `timeout = None` waits forever.
+    /// [`RecvError::PeerTimeout`] if heartbeat is enabled and the server stopped answering.
+    pub fn next_event(&mut self, timeout: Option<Duration>) -> Result<Option<Event>, RecvError> {
+        if let Some(event) = self.state.pop_event() {
+            return Ok(Some(event));
+        }
+        let prev_timeout = self.stream.get_ref().read_timeout()?;
+        self.stream.get_ref().set_read_timeout(timeout)?;
+        let event = self.recv_event();
+        self.stream.get_ref().set_read_timeout(prev_timeout)?;
+        match event {
+            Ok(event) => { Ok(Some(event)) }
+            Err(RecvError::Io(e)) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => { Ok(None) }
+            Err(e) => { Err(e) }
+        }
+    }
+
+    fn recv_event(&mut self) -> Result<Event, RecvError> {
+        loop {
+            let frame = recv_frame(&mut self.stream, &mut self.decoder, &mut self.session)?;
+            if let Some(event) = self.state.on_event_frame(&self.session, frame)? {
+                return Ok(event);
+            }
+        }
+    }
+
+    /// Event already received with responses, without waiting
+    pub fn try_event(&mut self) -> Option<Event> {
+        self.state.pop_event()
+    }
+
+    /// Bytes as is, bypassing the session, for malformed input checks
+    pub(crate) fn write_raw(&mut self, data: &[u8]) -> io::Result<()> {
+        self.stream.write_all(data)
+    }
+
+    /// Next frame as is, pongs and events included
+    pub(crate) fn read_raw(&mut self) -> Result<Frame, RecvError> {
+        read_frame(&mut self.stream, &mut self.decoder)
     }
 }
 
-impl Deref for ClientStp {
-    type Target = TcpStream;
+impl<S: Transport> Deref for ClientStp<S> {
+    type Target = S;
 
     fn deref(&self) -> &Self::Target {
-        &self.stream
+        self.stream.get_ref()
     }
 }
 
 
-pub fn send_str<Writer: Write, Data: AsRef<str>>(mut writer: Writer, msg: Data) -> SendResult {
-    let coded_msg = protocol::wrap_message(msg);
-    writer.write_all(coded_msg.as_bytes())?;
+type Connector<S> = Box<dyn FnMut() -> ConnectResult<ClientStp<S>> + Send>;
+type StateListener = Box<dyn FnMut(ConnectionState) + Send>;
+
+/// Client reconnecting with [`Backoff`] when the connection is lost.
+/// Handshake is repeated on every reconnection, subscriptions are not restored.
+pub struct ReconnectingClient<S: Transport = TcpStream> {
+    connector: Connector<S>,
+    client: Option<ClientStp<S>>,
+    backoff: Backoff,
+    attempts: Attempts,
+    state: ConnectionState,
+    state_listener: Option<StateListener>,
+}
+
+impl ReconnectingClient {
+    pub fn connect_with<Addr>(addr: Addr, options: &ConnectOptions) -> ConnectResult<Self>
+    where
+        Addr: ToSocketAddrs,
+    {
+        let addrs = addr.to_socket_addrs()?.collect::<Vec<SocketAddr>>();
+        let options = options.clone();
+        Self::with_connector(move || ClientStp::connect_with(&addrs[..], &options))
+    }
+}
+
+impl<S: Transport> ReconnectingClient<S> {
+    /// `connector` opens a new connection. The first connection is opened immediately.
+    pub fn with_connector<F>(mut connector: F) -> ConnectResult<Self>
+    where
+        F: FnMut() -> ConnectResult<ClientStp<S>> + Send + 'static,
+    {
+        let client = connector()?;
+        Ok(Self {
+            connector: Box::new(connector),
+            client: Some(client),
+            backoff: Backoff::default(),
+            attempts: Attempts::default(),
+            state: ConnectionState::Connected,
+            state_listener: None,
+        })
+    }
+
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Called on every change of the connection state
+    pub fn with_state_listener<F>(mut self, listener: F) -> Self
+    where
+        F: FnMut(ConnectionState) + Send + 'static,
+    {
+        self.state_listener = Some(Box::new(listener));
+        self
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    /// Current connection, `None` if the connection is lost
+    pub fn client(&mut self) -> Option<&mut ClientStp<S>> {
+        self.client.as_mut()
+    }
+
+    /// Drop the current connection and connect again. Never waits for the backoff delay:
+    /// until it is over after a failed attempt, fails fast with [`crate::errors::ConnectError::Backoff`].
+    /// Goes offline when attempts are exhausted or the server rejects the key.
+    pub fn reconnect(&mut self) -> ConnectResult<&mut ClientStp<S>> {
+        self.client = None;
+        let attempt = self.attempts.next(Instant::now())?;
+        self.set_state(ConnectionState::Reconnecting { attempt });
+        match (self.connector)() {
+            Ok(client) => {
+                self.attempts.succeeded();
+                self.set_state(ConnectionState::Connected);
+                Ok(self.client.insert(client))
+            }
+            Err(e) => {
+                if self.attempts.failed(&self.backoff, &e, Instant::now()) {
+                    self.set_state(ConnectionState::Offline);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Send the request, reconnecting if the connection is lost.
+    /// The request is repeated once on a new connection, only if it was not sent.
+    pub fn request(&mut self, request: &Request) -> Result<Response, RequestError> {
+        let mut retried = false;
+        loop {
+            let client = match self.client.as_mut() {
+                Some(client) => { client }
+                None => { self.reconnect()? }
+            };
+            let pipelined = client.capabilities().contains(&Capability::Pipeline);
+            match client.request(request) {
+                // without request ids a late response would be taken for the next request
+                Err(e) if e.is_connection_lost() || (e.is_timeout() && !pipelined) => {
+                    self.client = None;
+                    if retried || !e.is_not_sent() {
+                        return Err(e);
+                    }
+                    retried = true;
+                }
+                res => { return res }
+            }
+        }
+    }
+
+    fn set_state(&mut self, state: ConnectionState) {
+        if self.state == state {
+            return;
+        }
+        self.state = state;
+        if let Some(listener) = self.state_listener.as_mut() {
+            listener(state);
+        }
+    }
+}
+
+
+pub fn send_frame<Writer: Write, Data: AsRef<[u8]>>(mut writer: Writer, kind: FrameKind, msg: Data) -> SendResult {
+    let coded_msg = protocol::wrap_frame(kind, msg);
+    writer.write_all(&coded_msg)?;
     Ok(())
 }
 
-pub fn read_srt<Reader: Read>(mut reader: Reader) -> RecvResult {
+pub fn send_str<Writer: Write, Data: AsRef<str>>(writer: Writer, msg: Data) -> SendResult {
+    send_frame(writer, FrameKind::Message, msg.as_ref())
+}
+
+/// Read one frame. Bytes of the following frames stay in the decoder.
+pub fn read_frame<Reader: Read>(mut reader: Reader, decoder: &mut FrameDecoder) -> Result<Frame, RecvError> {
     let mut buff: Vec<u8> = vec![0; 1024];
-    let rlen = reader.read(&mut buff)?;
-    if rlen == 0 {
-        return Err(RecvError::from(io::Error::from(ErrorKind::BrokenPipe)));
-    }
-    let raw_str = String::from_utf8(buff).expect("error utf8 to str");
-    return match protocol::unwrap_message(&raw_str).map_err(RecvError::Other) {
-        Ok(msgs) => {
-            match msgs.len() {
-                2.. => Ok(msgs.iter().map(|v| v.to_string() + ",").collect::<String>()),
-                1 => Ok(msgs[0].clone()),
-                _ => Err(RecvError::BadEncoding)
+    loop {
+        if let Some(frame) = decoder.decode()? {
+            return Ok(frame);
+        }
+        let rlen = reader.read(&mut buff)?;
+        if rlen == 0 {
+            return Err(RecvError::from(io::Error::from(ErrorKind::BrokenPipe)));
+        }
+        decoder.extend(&buff[..rlen]);
+    }
+}
+
+/// Client side of the handshake, returns parameters agreed with the server
+fn exchange_hello<S: Transport>(stream: &mut StpStream<S>, decoder: &mut FrameDecoder, options: &ConnectOptions) -> ConnectResult<Hello> {
+    let mut handshake = ClientHandshake::new(&options.capabilities, options.key.clone());
+    send_str(&mut *stream, handshake.request())?;
+    loop {
+        let msg = handshake_payload(read_frame(&mut *stream, decoder))?;
+        let step = handshake.on_message(&msg);
+        if let Some(reply) = step.send {
+            send_str(&mut *stream, reply)?;
+        }
+        if let Progress::Done(hello) = step.progress? {
+            return Ok(hello);
+        }
+    }
+}
+
+/// Read the next request, response or event, answering pings of the peer.
+/// With heartbeat, pings the idle peer and closes the connection when it stops answering.
+/// Read timeout of the transport is kept, `WouldBlock` or `TimedOut` error is returned when it expires.
+pub(crate) fn recv_frame<S: Transport>(stream: &mut StpStream<S>, decoder: &mut FrameDecoder, session: &mut Session) -> Result<Frame, RecvError> {
+    let timeout = stream.get_ref().read_timeout()?;
+    let frame = recv_until(stream, decoder, session, timeout.map(|timeout| Instant::now() + timeout));
+    stream.get_ref().set_read_timeout(timeout)?;
+    frame
+}
+
+fn recv_until<S: Transport>(stream: &mut StpStream<S>, decoder: &mut FrameDecoder, session: &mut Session, deadline: Option<Instant>) -> Result<Frame, RecvError> {
+    loop {
+        if let Some(heartbeat) = session.deadline() {
+            let wake_up = deadline.map_or(heartbeat, |deadline| deadline.min(heartbeat));
+            // zero timeout means blocking forever
+            let wait = wake_up.saturating_duration_since(Instant::now()).max(Duration::from_millis(1));
+            stream.get_ref().set_read_timeout(Some(wait))?;
+        }
+        match read_frame(&mut *stream, decoder) {
+            Ok(frame) => {
+                match session.on_frame(frame) {
+                    Received::Frame(frame) => { return Ok(frame) }
+                    Received::Reply(reply) => { send_frame(&mut *stream, reply.kind, reply.payload)? }
+                    Received::Nothing => {}
+                }
             }
+            Err(RecvError::Io(e)) if session.deadline().is_some() && matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                let now = Instant::now();
+                match session.on_timeout(now) {
+                    Timeout::Wait => {}
+                    Timeout::Send(frame) => { send_frame(&mut *stream, frame.kind, frame.payload)? }
+                    Timeout::Close(e) => {
+                        let _ = stream.get_ref().shutdown();
+                        return Err(e);
+                    }
+                }
+                if deadline.is_some_and(|deadline| now >= deadline) {
+                    return Err(RecvError::Io(e));
+                }
+            }
+            Err(e) => { return Err(e) }
         }
-        Err(e) => { Err(e) }
-    };
+    }
 }
 
+/// Read payload of the next message frame
+pub fn read_srt<Reader: Read>(reader: Reader, decoder: &mut FrameDecoder) -> RecvResult {
+    let frame = read_frame(reader, decoder)?;
+    message_payload(frame)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    /// Reader returns data by small chunks, like a slow TCP stream
+    struct ChunkedReader {
+        data: Vec<u8>,
+        chunk: usize,
+    }
+
+    impl Read for ChunkedReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.chunk.min(buf.len()).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data.drain(..n);
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn read_split_and_coalesced_frames() {
+        let big = "x".repeat(3000);
+        let data = [protocol::wrap_message("first"), protocol::wrap_message(&big), protocol::wrap_message("third")].concat();
+        let mut reader = ChunkedReader { data, chunk: 7 };
+        let mut decoder = FrameDecoder::new();
+        assert_eq!(read_srt(&mut reader, &mut decoder).unwrap(), "first");
+        assert_eq!(read_srt(&mut reader, &mut decoder).unwrap(), big);
+        assert_eq!(read_srt(&mut reader, &mut decoder).unwrap(), "third");
+        assert!(matches!(read_srt(&mut reader, &mut decoder), Err(RecvError::Io(e)) if e.kind() == ErrorKind::BrokenPipe));
+    }
+
+    #[test]
+    fn read_bad_encoding() {
+        let mut reader = ChunkedReader { data: protocol::wrap_message([0xff, 0xfe]), chunk: 1024 };
+        let mut decoder = FrameDecoder::new();
+        assert!(matches!(read_srt(&mut reader, &mut decoder), Err(RecvError::BadEncoding)));
+    }
+
+    fn fast_backoff() -> Backoff {
+        Backoff::new(Duration::from_millis(1), Duration::from_millis(10)).with_max_attempts(Some(2))
+    }
+
+    #[test]
+    fn reconnect_after_server_restart() {
+        use std::sync::{mpsc, Arc, Mutex};
+
+        use crate::server_std::ServerStp;
+        use crate::transport::memory_listener;
+
+        let (listener, connector) = memory_listener();
+        let server = ServerStp::new(listener);
+        let (dropped_tx, dropped_rx) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            for state in [true, false] {
+                // connection is dropped after one request
+                let mut connection = server.incoming().next().unwrap().unwrap();
+                connection.recv().unwrap();
+                connection.reply(&Response::State(state)).unwrap();
+                drop(connection);
+                let _ = dropped_tx.send(());
+            }
+        });
+        let states = Arc::new(Mutex::new(Vec::new()));
+        let states_cloned = states.clone();
+        let mut client = ReconnectingClient::with_connector(move || ClientStp::connect_over(connector.connect()?, &ConnectOptions::new()))
+            .unwrap()
+            .with_backoff(fast_backoff())
+            .with_state_listener(move |state| states_cloned.lock().unwrap().push(state));
+        assert_eq!(client.request(&Request::GetState).unwrap(), Response::State(true));
+        // a request already sent is not repeated, so the second one goes to the closed connection
+        dropped_rx.recv().unwrap();
+        assert_eq!(client.request(&Request::GetState).unwrap(), Response::State(false));
+        assert_eq!(client.state(), ConnectionState::Connected);
+        assert_eq!(*states.lock().unwrap(), vec![ConnectionState::Reconnecting { attempt: 1 }, ConnectionState::Connected]);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn keep_read_timeout_after_handshake() {
+        use crate::server_std::ServerStp;
+        use crate::transport::memory_listener;
+
+        let (listener, connector) = memory_listener();
+        let server = ServerStp::new(listener);
+        let handle = thread::spawn(move || {
+            for _ in 0..2 {
+                server.incoming().next().unwrap().unwrap();
+            }
+        });
+        for timeout in [None, Some(Duration::from_secs(5))] {
+            let stream = connector.connect().unwrap();
+            stream.set_read_timeout(timeout).unwrap();
+            let client = ClientStp::connect_over(stream, &ConnectOptions::new()).unwrap();
+            assert_eq!(client.stream.get_ref().read_timeout().unwrap(), timeout);
+        }
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn detect_dead_server() {
+        use std::sync::mpsc;
+
+        use crate::server_std::ServerStp;
+        use crate::transport::memory_listener;
+
+        let (listener, connector) = memory_listener();
+        let server = ServerStp::new(listener);
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            // connection stays open, but nothing is read
+            let _connection = server.incoming().next().unwrap().unwrap();
+            let _ = done_rx.recv();
+        });
+        let options = ConnectOptions::new().with_heartbeat(Heartbeat::new(Duration::from_millis(20), 3));
+        let mut client = ClientStp::connect_over(connector.connect().unwrap(), &options).unwrap();
+        let started = Instant::now();
+        assert!(matches!(client.next_event(None), Err(RecvError::PeerTimeout(3))));
+        assert!(started.elapsed() >= Duration::from_millis(60));
+        done_tx.send(()).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn offline_when_attempts_exhausted() {
+        use crate::errors::ConnectError;
+        use crate::server_std::ServerStp;
+        use crate::transport::memory_listener;
 
-pub type RequestResult = Result<String, RequestError>;
+        let (listener, connector) = memory_listener();
+        let server = ServerStp::new(listener);
+        let handle = thread::spawn(move || {
+            let _ = server.incoming().next().unwrap().unwrap();
+        });
+        let mut connected = false;
+        let mut client = ReconnectingClient::with_connector(move || {
+            if connected {
+                return Err(ConnectError::Io(io::Error::from(ErrorKind::ConnectionRefused)));
+            }
+            connected = true;
+            ClientStp::connect_over(connector.connect()?, &ConnectOptions::new())
+        }).unwrap().with_backoff(Backoff::new(Duration::from_millis(50), Duration::from_millis(50)).with_max_attempts(Some(2)));
+        handle.join().unwrap();
+        // the first attempt is made at once, the next one only after the backoff delay
+        assert!(matches!(client.request(&Request::TurnOn), Err(RequestError::Connect(ConnectError::Io(_)))));
+        assert_eq!(client.state(), ConnectionState::Reconnecting { attempt: 1 });
+        assert!(matches!(client.request(&Request::TurnOn), Err(RequestError::Connect(ConnectError::Backoff(_)))));
+        thread::sleep(Duration::from_millis(70));
+        assert!(matches!(client.request(&Request::TurnOn), Err(RequestError::Connect(ConnectError::Io(_)))));
+        assert_eq!(client.state(), ConnectionState::Offline);
+        assert!(client.client().is_none());
+    }
+
+    #[test]
+    fn slow_response_is_not_repeated() {
+        use crate::server_std::ServerStp;
+        use crate::transport::memory_listener;
 
-#[derive(Debug, Error)]
-pub enum RequestError {
-    #[error(transparent)]
-    Send(#[from] SendError),
-    #[error(transparent)]
-    Recv(#[from] RecvError),
-    #[error("IO error: {0}")]
-    Io(#[from] io::Error),
-}
\ No newline at end of file
+        let (listener, connector) = memory_listener();
+        let server = ServerStp::new(listener);
+        let handle = thread::spawn(move || {
+            let mut connection = server.incoming().next().unwrap().unwrap();
+            assert_eq!(connection.recv().unwrap(), Request::TurnOn);
+            thread::sleep(Duration::from_millis(100));
+            connection.reply(&Response::Ok).unwrap();
+            assert_eq!(connection.recv().unwrap(), Request::GetState);
+            connection.reply(&Response::State(true)).unwrap();
+        });
+        let mut client = ReconnectingClient::with_connector(move || ClientStp::connect_over(connector.connect()?, &ConnectOptions::new())).unwrap();
+        client.client().unwrap().set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+        let error = client.request(&Request::TurnOn).unwrap_err();
+        assert!(error.is_timeout() && !error.is_connection_lost());
+        assert!(client.client().is_some());
+        client.client().unwrap().set_read_timeout(None).unwrap();
+        // the late response to the pipelined request is skipped
+        assert_eq!(client.request(&Request::GetState).unwrap(), Response::State(true));
+        handle.join().unwrap();
+    }
+}
@@ -1,90 +1,569 @@
+use std::collections::VecDeque;
+use std::future::Future;
 use std::io;
 use std::io::ErrorKind;
+use std::net::SocketAddr;
 use std::ops::Deref;
+#[cfg(unix)]
+use std::path::Path;
 use std::pin::Pin;
+use std::time::Instant;
 
-use thiserror::Error;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio_rustls::TlsConnector;
+use tokio_util::codec::Framed;
 
-use crate::errors::{ConnectResult, RecvError, RecvResult, SendError, SendResult};
-use crate::protocol;
+use crate::client_std::ConnectOptions;
+use crate::codec::StpCodec;
+use crate::errors::{ConnectResult, RecvError, SendError, SendResult};
+use crate::message::{Event, Request, Response};
+use crate::protocol::{Capability, Frame, FrameKind, Hello};
+use crate::reconnect::{Attempts, Backoff, ConnectionState};
+use crate::session::{ClientHandshake, ClientState, decode_event, HANDSHAKE_TIMEOUT, handshake_payload, next_request_id, Progress, Received, response_payload, Session, Timeout, UNKNOWN_REQUEST_ID};
+use crate::stream::AsyncStpStream;
+use crate::tls::TlsClientConfig;
+use crate::transport::AsyncTransport;
+
+pub use crate::errors::{RequestError, RequestResult};
 
 #[derive(Debug)]
-pub struct ClientStp {
-    stream: Pin<Box<TcpStream>>,
+pub struct ClientStp<S: AsyncTransport = TcpStream> {
+    framed: Framed<AsyncStpStream<S>, StpCodec>,
+    session: Session,
+    state: ClientState,
 }
 
 impl ClientStp {
-    async fn handshake(mut stream: Pin<Box<TcpStream>>) -> ConnectResult<Self> {
-        let msg = protocol::handshake_request_msg();
-        stream.write_all(msg.as_bytes()).await?;
-        let mut buff = [0; 256];
-        let _n = stream.read(&mut buff).await?;
-        let resp_raw_mgs = String::from_utf8(Vec::from(buff)).expect("Error to convert msg to string");
-        if !resp_raw_mgs.contains(protocol::handshake_respond_msg().as_str()) {
-            "Unexpected response".to_string();
-        }
-        Ok(Self { stream })
+    /// Connect and request all capabilities known by this library version
+    pub async fn connect<Addr>(addr: Addr) -> ConnectResult<Self>
+    where
+        Addr: ToSocketAddrs,
+    {
+        Self::connect_with(addr, &ConnectOptions::new()).await
     }
 
-    pub async fn connect<Addr>(addr: Addr) -> ConnectResult<Self>
+    pub async fn connect_with_capabilities<Addr>(addr: Addr, capabilities: &[Capability]) -> ConnectResult<Self>
+    where
+        Addr: ToSocketAddrs,
+    {
+        Self::connect_with(addr, &ConnectOptions::new().with_capabilities(capabilities)).await
+    }
+
+    /// Connect over TLS and request all capabilities known by this library version
+    pub async fn connect_tls<Addr>(addr: Addr, tls: &TlsClientConfig) -> ConnectResult<Self>
+    where
+        Addr: ToSocketAddrs,
+    {
+        Self::connect_with(addr, &ConnectOptions::new().with_tls(tls.clone())).await
+    }
+
+    pub async fn connect_with<Addr>(addr: Addr, options: &ConnectOptions) -> ConnectResult<Self>
     where
         Addr: ToSocketAddrs,
     {
-        let stream = TcpStream::connect(addr).await?;
-        let stream = Pin::new(Box::new(stream));
-        Self::handshake(stream).await
+        Self::connect_over(TcpStream::connect(addr).await?, options).await
+    }
+}
+
+#[cfg(unix)]
+impl ClientStp<UnixStream> {
+    /// Connect to the local hub over Unix domain socket
+    pub async fn connect_unix<P: AsRef<Path>>(path: P, options: &ConnectOptions) -> ConnectResult<Self> {
+        Self::connect_over(UnixStream::connect(path).await?, options).await
+    }
+}
+
+impl<S: AsyncTransport> ClientStp<S> {
+    /// Handshake over already connected transport, wrapped in TLS if configured
+    pub async fn connect_over(transport: S, options: &ConnectOptions) -> ConnectResult<Self> {
+        let stream = match &options.tls {
+            Some(tls) => {
+                let stream = TlsConnector::from(tls.config()).connect(tls.server_name(), transport).await?;
+                AsyncStpStream::Tls(Box::new(stream.into()))
+            }
+            None => { AsyncStpStream::Plain(transport) }
+        };
+        let stream = match &options.recorder {
+            Some(recorder) => { stream.recorded(recorder.clone()) }
+            None => { stream }
+        };
+        Self::handshake(stream, options).await
+    }
+
+    async fn handshake(stream: AsyncStpStream<S>, options: &ConnectOptions) -> ConnectResult<Self> {
+        let mut framed = Framed::new(stream, StpCodec::new());
+        let mut handshake = ClientHandshake::new(&options.capabilities, options.key.clone());
+        send_message(&mut framed, handshake.request()).await?;
+        let hello = loop {
+            let msg = handshake_message(&mut framed).await?;
+            let step = handshake.on_message(&msg);
+            if let Some(reply) = step.send {
+                send_message(&mut framed, reply).await?;
+            }
+            if let Progress::Done(hello) = step.progress? {
+                break hello;
+            }
+        };
+        let session = Session::new(hello, options.heartbeat);
+        Ok(Self { framed, session, state: ClientState::default() })
+    }
+
+    pub fn is_tls(&self) -> bool {
+        self.framed.get_ref().is_tls()
+    }
+
+    /// Protocol version agreed with the server
+    pub fn protocol_version(&self) -> u16 {
+        self.session.hello().version
+    }
+
+    /// Capabilities supported by both client and server
+    pub fn capabilities(&self) -> &[Capability] {
+        &self.session.hello().capabilities
     }
 
     pub async fn send_request<Data: AsRef<str>>(&mut self, msg: Data) -> RequestResult {
-        send_str(&mut self.stream, msg).await?;
-        let resp = read_srt(&mut self.stream).await?;
-        Ok(resp)
+        let request = self.state.request_frame(&self.session, msg.as_ref());
+        self.framed.send(request).await.map_err(SendError::from)?;
+        loop {
+            let frame = recv_frame(&mut self.framed, &mut self.session).await?;
+            // events pushed before the response are kept for `next_event`
+            if let Some(resp) = self.state.on_response_frame(&self.session, frame)? {
+                return Ok(resp);
+            }
+        }
+    }
+
+    pub async fn request(&mut self, request: &Request) -> Result<Response, RequestError> {
+        let resp = self.send_request(request.encode()).await?;
+        Ok(Response::decode(&resp)?)
+    }
+
+    /// Wait for the next event pushed by the server. Subscribe with [`Request::Subscribe`] first.
+    /// Cancel safe, so it may be used with `tokio::time::timeout` or `tokio::select!`.
+    /// [`RecvError::PeerTimeout`] if heartbeat is enabled and the server stopped answering.
+    pub async fn next_event(&mut self) -> Result<Event, RecvError> {
+        if let Some(event) = self.state.pop_event() {
+            return Ok(event);
+        }
+        loop {
+            let frame = recv_frame(&mut self.framed, &mut self.session).await?;
+            if let Some(event) = self.state.on_event_frame(&self.session, frame)? {
+                return Ok(event);
+            }
+        }
+    }
+
+    /// Event already received with responses, without waiting
+    pub fn try_event(&mut self) -> Option<Event> {
+        self.state.pop_event()
+    }
+}
+
+impl<S: AsyncTransport + 'static> ClientStp<S> {
+    /// Move the connection to the background task serving cloneable [`ClientHandle`]s.
+    /// Events received before are dropped.
+    pub fn into_handle(self) -> ClientHandle {
+        let (commands, commands_rx) = mpsc::unbounded_channel();
+        let (events, _) = broadcast::channel(EVENTS_CAPACITY);
+        let hello = self.session.hello().clone();
+        tokio::spawn(run_pipeline(self, commands_rx, events.clone()));
+        ClientHandle { commands, events, hello }
     }
 }
 
-impl Deref for ClientStp {
-    type Target = TcpStream;
+impl<S: AsyncTransport> Deref for ClientStp<S> {
+    type Target = S;
 
     fn deref(&self) -> &Self::Target {
-        &self.stream
+        self.framed.get_ref().get_ref()
     }
 }
 
 
-pub async fn send_str<Writer: AsyncWrite + Unpin, Data: AsRef<str>>(writer: &mut Writer, msg: Data) -> SendResult {
-    let coded_msg = protocol::wrap_message(msg);
-    writer.write_all(coded_msg.as_bytes()).await?;
-    Ok(())
+/// Events buffered for every slow receiver of [`ClientHandle::events`]
+const EVENTS_CAPACITY: usize = 64;
+
+#[derive(Debug)]
+struct Command {
+    msg: String,
+    reply: oneshot::Sender<RequestResult>,
 }
 
-pub async fn read_srt<Reader: AsyncRead + Unpin>(reader: &mut Reader) -> RecvResult {
-    let mut buff: Vec<u8> = vec![0; 1024];
-    let rlen = reader.read(&mut buff).await?;
-    if rlen == 0 {
-        return Err(RecvError::from(io::Error::from(ErrorKind::BrokenPipe)));
+/// Cloneable client, requests of all clones are pipelined over one connection.
+/// The background task routes responses to waiting callers: by request id if [`Capability::Pipeline`]
+/// is negotiated, in order otherwise. The connection is closed when all clones are dropped.
+#[derive(Debug, Clone)]
+pub struct ClientHandle {
+    commands: mpsc::UnboundedSender<Command>,
+    events: broadcast::Sender<Event>,
+    hello: Hello,
+}
+
+impl ClientHandle {
+    /// Protocol version agreed with the server
+    pub fn protocol_version(&self) -> u16 {
+        self.hello.version
+    }
+
+    /// Capabilities supported by both client and server
+    pub fn capabilities(&self) -> &[Capability] {
+        &self.hello.capabilities
+    }
+
+    /// Connection is open, requests fail with [`RequestError::is_connection_lost`] otherwise
+    pub fn is_connected(&self) -> bool {
+        !self.commands.is_closed()
+    }
+
+    /// Cancel safe, the response to the cancelled request is dropped
+    pub async fn send_request<Data: AsRef<str>>(&self, msg: Data) -> RequestResult {
+        let (reply, reply_rx) = oneshot::channel();
+        self.commands.send(Command { msg: msg.as_ref().to_string(), reply }).map_err(|_| pipeline_closed())?;
+        reply_rx.await.map_err(|_| pipeline_closed())?
+    }
+
+    pub async fn request(&self, request: &Request) -> Result<Response, RequestError> {
+        let resp = self.send_request(request.encode()).await?;
+        Ok(Response::decode(&resp)?)
+    }
+
+    /// Events pushed by the server from now on. Subscribe with [`Request::Subscribe`] first.
+    pub fn events(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
     }
-    let raw_str = String::from_utf8(buff).expect("error utf8 to str");
-    return match protocol::unwrap_message(&raw_str).map_err(RecvError::Other) {
-        Ok(msgs) => {
-            match msgs.len() {
-                2.. => Ok(msgs.iter().map(|v| v.to_string() + ",").collect::<String>()),
-                1 => Ok(msgs[0].clone()),
-                _ => Err(RecvError::BadEncoding)
+}
+
+fn pipeline_closed() -> RequestError {
+    RequestError::Io(io::Error::from(ErrorKind::BrokenPipe))
+}
+
+/// Sends requests of [`ClientHandle`]s and routes received frames until the connection is lost
+async fn run_pipeline<S: AsyncTransport>(mut client: ClientStp<S>, mut commands: mpsc::UnboundedReceiver<Command>, events: broadcast::Sender<Event>) {
+    let mut pending: VecDeque<(u32, oneshot::Sender<RequestResult>)> = VecDeque::new();
+    let mut request_id = 0;
+    let error = loop {
+        let frame = tokio::select! {
+            command = commands.recv() => {
+                let Some(command) = command else {
+                    // all handles are dropped
+                    return;
+                };
+                request_id = next_request_id(request_id);
+                let frame = client.session.tag(request_id, Frame { kind: FrameKind::Message, payload: command.msg.into_bytes() });
+                if let Err(e) = client.framed.send(frame).await {
+                    break RecvError::from(SendError::from(e));
+                }
+                pending.push_back((request_id, command.reply));
+                continue;
+            }
+            frame = recv_frame(&mut client.framed, &mut client.session) => { frame }
+        };
+        let frame = match frame {
+            Ok(frame) => { frame }
+            Err(e) => { break e }
+        };
+        if frame.kind == FrameKind::Event {
+            if let Ok(event) = decode_event(frame.payload) {
+                let _ = events.send(event);
             }
+            continue;
         }
-        Err(e) => { Err(e) }
+        let Ok((id, frame)) = client.session.untag(frame) else {
+            continue;
+        };
+        let position = match id {
+            Some(id) if id != UNKNOWN_REQUEST_ID => { pending.iter().position(|(pending_id, _)| *pending_id == id) }
+            _ => { (!pending.is_empty()).then_some(0) }
+        };
+        if let Some((_, reply)) = position.and_then(|position| pending.remove(position)) {
+            // the caller may have cancelled the request
+            let _ = reply.send(response_payload(frame).map_err(RequestError::from));
+        }
+    };
+    for (_, reply) in pending {
+        let lost = match &error {
+            RecvError::PeerTimeout(missed) => { RecvError::PeerTimeout(*missed) }
+            e => { RecvError::Io(io::Error::new(ErrorKind::BrokenPipe, e.to_string())) }
+        };
+        let _ = reply.send(Err(lost.into()));
+    }
+}
+
+
+type Connector<S> = Box<dyn FnMut() -> Pin<Box<dyn Future<Output=ConnectResult<ClientStp<S>>> + Send>> + Send>;
+type StateListener = Box<dyn FnMut(ConnectionState) + Send>;
+
+/// Client reconnecting with [`Backoff`] when the connection is lost.
+/// Handshake is repeated on every reconnection, subscriptions are not restored.
+pub struct ReconnectingClient<S: AsyncTransport = TcpStream> {
+    connector: Connector<S>,
+    client: Option<ClientStp<S>>,
+    backoff: Backoff,
+    attempts: Attempts,
+    state: ConnectionState,
+    state_listener: Option<StateListener>,
+}
+
+impl ReconnectingClient {
+    pub async fn connect_with<Addr>(addr: Addr, options: &ConnectOptions) -> ConnectResult<Self>
+    where
+        Addr: ToSocketAddrs,
+    {
+        let addrs = tokio::net::lookup_host(addr).await?.collect::<Vec<SocketAddr>>();
+        let options = options.clone();
+        Self::with_connector(move || {
+            let addrs = addrs.clone();
+            let options = options.clone();
+            async move { ClientStp::connect_with(&addrs[..], &options).await }
+        }).await
+    }
+}
+
+impl<S: AsyncTransport> ReconnectingClient<S> {
+    /// `connector` opens a new connection. The first connection is opened immediately.
+    pub async fn with_connector<F, Fut>(mut connector: F) -> ConnectResult<Self>
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output=ConnectResult<ClientStp<S>>> + Send + 'static,
+    {
+        let client = connector().await?;
+        Ok(Self {
+            connector: Box::new(move || Box::pin(connector())),
+            client: Some(client),
+            backoff: Backoff::default(),
+            attempts: Attempts::default(),
+            state: ConnectionState::Connected,
+            state_listener: None,
+        })
+    }
+
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Called on every change of the connection state
+    pub fn with_state_listener<F>(mut self, listener: F) -> Self
+    where
+        F: FnMut(ConnectionState) + Send + 'static,
+    {
+        self.state_listener = Some(Box::new(listener));
+        self
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    /// Current connection, `None` if the connection is lost
+    pub fn client(&mut self) -> Option<&mut ClientStp<S>> {
+        self.client.as_mut()
+    }
+
+    /// Drop the current connection and connect again. Never waits for the backoff delay:
+    /// until it is over after a failed attempt, fails fast with [`crate::errors::ConnectError::Backoff`].
+    /// Goes offline when attempts are exhausted or the server rejects the key.
+    pub async fn reconnect(&mut self) -> ConnectResult<&mut ClientStp<S>> {
+        self.client = None;
+        let attempt = self.attempts.next(Instant::now())?;
+        self.set_state(ConnectionState::Reconnecting { attempt });
+        match (self.connector)().await {
+            Ok(client) => {
+                self.attempts.succeeded();
+                self.set_state(ConnectionState::Connected);
+                Ok(self.client.insert(client))
+            }
+            Err(e) => {
+                if self.attempts.failed(&self.backoff, &e, Instant::now()) {
+                    self.set_state(ConnectionState::Offline);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Send the request, reconnecting if the connection is lost.
+    /// The request is repeated once on a new connection, only if it was not sent.
+    pub async fn request(&mut self, request: &Request) -> Result<Response, RequestError> {
+        let mut retried = false;
+        loop {
+            let client = match self.client.as_mut() {
+                Some(client) => { client }
+                None => { self.reconnect().await? }
+            };
+            let pipelined = client.capabilities().contains(&Capability::Pipeline);
+            match client.request(request).await {
+                // without request ids a late response would be taken for the next request
+                Err(e) if e.is_connection_lost() || (e.is_timeout() && !pipelined) => {
+                    self.client = None;
+                    if retried || !e.is_not_sent() {
+                        return Err(e);
+                    }
+                    retried = true;
+                }
+                res => { return res }
+            }
+        }
+    }
+
+    fn set_state(&mut self, state: ConnectionState) {
+        if self.state == state {
+            return;
+        }
+        self.state = state;
+        if let Some(listener) = self.state_listener.as_mut() {
+            listener(state);
+        }
+    }
+}
+
+
+pub(crate) async fn send_message<T, Data>(framed: &mut Framed<T, StpCodec>, msg: Data) -> SendResult
+where
+    T: AsyncWrite + Unpin,
+    Data: AsRef<str>,
+{
+    Ok(framed.send(msg.as_ref().to_string()).await?)
+}
+
+/// Next frame of any kind, the end of stream is reported as `BrokenPipe`
+pub(crate) async fn next_frame<T: AsyncRead + Unpin>(framed: &mut Framed<T, StpCodec>) -> Result<Frame, RecvError> {
+    match framed.next().await {
+        Some(frame) => { Ok(frame?) }
+        None => { Err(RecvError::from(io::Error::from(ErrorKind::BrokenPipe))) }
+    }
+}
+
+/// Handshake message of the peer, waiting at most [`HANDSHAKE_TIMEOUT`]
+pub(crate) async fn handshake_message<T: AsyncRead + Unpin>(framed: &mut Framed<T, StpCodec>) -> ConnectResult<String> {
+    let frame = match tokio::time::timeout(HANDSHAKE_TIMEOUT, next_frame(framed)).await {
+        Ok(frame) => { frame }
+        Err(_) => { Err(RecvError::from(io::Error::from(ErrorKind::TimedOut))) }
     };
+    handshake_payload(frame)
 }
 
+/// Read the next request, response or event, answering pings of the peer. Cancel safe.
+/// With heartbeat, pings the idle peer and closes the connection when it stops answering.
+pub(crate) async fn recv_frame<T>(framed: &mut Framed<T, StpCodec>, session: &mut Session) -> Result<Frame, RecvError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        let frame = match session.deadline() {
+            Some(deadline) => {
+                match tokio::time::timeout_at(tokio::time::Instant::from_std(deadline), next_frame(framed)).await {
+                    Ok(frame) => { frame? }
+                    Err(_) => {
+                        match session.on_timeout(Instant::now()) {
+                            Timeout::Wait => {}
+                            Timeout::Send(frame) => { framed.send(frame).await? }
+                            Timeout::Close(e) => {
+                                let _ = SinkExt::<Frame>::close(framed).await;
+                                return Err(e);
+                            }
+                        }
+                        continue;
+                    }
+                }
+            }
+            None => { next_frame(framed).await? }
+        };
+        match session.on_frame(frame) {
+            Received::Frame(frame) => { return Ok(frame) }
+            Received::Reply(reply) => { framed.send(reply).await? }
+            Received::Nothing => {}
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use crate::protocol;
+    use crate::session::message_payload;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn read_split_and_coalesced_frames() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let big = "x".repeat(3000);
+        let big_cloned = big.clone();
+        let writer = tokio::spawn(async move {
+            let data = [protocol::wrap_message("first"), protocol::wrap_message(&big_cloned), protocol::wrap_message("third")].concat();
+            for chunk in data.chunks(7) {
+                server.write_all(chunk).await.unwrap();
+            }
+        });
+        let mut framed = Framed::new(&mut client, StpCodec::new());
+        assert_eq!(message_payload(next_frame(&mut framed).await.unwrap()).unwrap(), "first");
+        assert_eq!(message_payload(next_frame(&mut framed).await.unwrap()).unwrap(), big);
+        assert_eq!(message_payload(next_frame(&mut framed).await.unwrap()).unwrap(), "third");
+        writer.await.unwrap();
+        assert!(matches!(next_frame(&mut framed).await, Err(RecvError::Io(e)) if e.kind() == ErrorKind::BrokenPipe));
+    }
+
+    #[tokio::test]
+    async fn detect_dead_server() {
+        use crate::heartbeat::Heartbeat;
+        use crate::server_tokio::ServerStp;
+        use crate::transport::async_memory_listener;
+
+        let (listener, connector) = async_memory_listener();
+        let server = ServerStp::new(listener);
+        let (done_tx, done_rx) = tokio::sync::oneshot::channel::<()>();
+        tokio::spawn(async move {
+            // connection stays open, but nothing is read
+            let _connection = server.accept().await.unwrap();
+            let _ = done_rx.await;
+        });
+        let options = ConnectOptions::new().with_heartbeat(Heartbeat::new(std::time::Duration::from_millis(20), 3));
+        let mut client = ClientStp::connect_over(connector.connect().unwrap(), &options).await.unwrap();
+        assert!(matches!(client.next_event().await, Err(RecvError::PeerTimeout(3))));
+        // the connection is closed, no more waiting
+        assert!(client.next_event().await.is_err());
+        done_tx.send(()).unwrap();
+    }
 
-pub type RequestResult = Result<String, RequestError>;
+    #[tokio::test]
+    async fn reconnect_after_server_restart() {
+        use crate::server_tokio::ServerStp;
+        use crate::transport::async_memory_listener;
 
-#[derive(Debug, Error)]
-pub enum RequestError {
-    #[error(transparent)]
-    Send(#[from] SendError),
-    #[error(transparent)]
-    Recv(#[from] RecvError),
-}
\ No newline at end of file
+        let (listener, connector) = async_memory_listener();
+        let server = ServerStp::new(listener);
+        let (dropped_tx, mut dropped_rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            for power in [Some(10.0), None] {
+                // connection is dropped after one request
+                let mut connection = server.accept().await.unwrap();
+                connection.recv().await.unwrap();
+                connection.reply(&Response::PowerConsumptionWt(power)).await.unwrap();
+                drop(connection);
+                let _ = dropped_tx.send(());
+            }
+        });
+        let (states_tx, mut states_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut client = ReconnectingClient::with_connector(move || {
+            let stream = connector.connect();
+            async move { ClientStp::connect_over(stream?, &ConnectOptions::new()).await }
+        }).await.unwrap()
+            .with_backoff(Backoff::new(std::time::Duration::from_millis(1), std::time::Duration::from_millis(10)))
+            .with_state_listener(move |state| states_tx.send(state).unwrap());
+        assert_eq!(client.request(&Request::GetPowerConsumptionWt).await.unwrap(), Response::PowerConsumptionWt(Some(10.0)));
+        // a request already sent is not repeated, so the second one goes to the closed connection
+        dropped_rx.recv().await.unwrap();
+        assert_eq!(client.request(&Request::GetPowerConsumptionWt).await.unwrap(), Response::PowerConsumptionWt(None));
+        assert_eq!(states_rx.recv().await, Some(ConnectionState::Reconnecting { attempt: 1 }));
+        assert_eq!(states_rx.recv().await, Some(ConnectionState::Connected));
+    }
+}
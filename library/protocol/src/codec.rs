@@ -0,0 +1,135 @@
+use bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::errors::CodecError;
+use crate::protocol;
+use crate::protocol::{FRAME_HEADER_LEN, FRAME_VERSION, Frame, FrameKind};
+
+/// Default limit of the frame payload
+pub const DEFAULT_MAX_FRAME_LEN: usize = 1024 * 1024;
+
+/// STP frames codec for `tokio_util::codec::Framed`.
+/// Strings are encoded as [`FrameKind::Message`] frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StpCodec {
+    max_frame_len: usize,
+}
+
+impl Default for StpCodec {
+    fn default() -> Self {
+        Self { max_frame_len: DEFAULT_MAX_FRAME_LEN }
+    }
+}
+
+impl StpCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Larger frames are rejected both ways with [`CodecError::FrameTooLarge`]
+    pub fn with_max_frame_len(mut self, max_frame_len: usize) -> Self {
+        self.max_frame_len = max_frame_len;
+        self
+    }
+
+    pub fn max_frame_len(&self) -> usize {
+        self.max_frame_len
+    }
+
+    fn check_len(&self, len: usize) -> Result<(), CodecError> {
+        if len > self.max_frame_len {
+            return Err(CodecError::FrameTooLarge { len, max: self.max_frame_len });
+        }
+        Ok(())
+    }
+}
+
+impl Decoder for StpCodec {
+    type Item = Frame;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let Some((kind, len)) = protocol::parse_header(src).map_err(CodecError::BadFrame)? else {
+            return Ok(None);
+        };
+        // checked before buffering the payload
+        self.check_len(len)?;
+        let frame_len = FRAME_HEADER_LEN + len;
+        if src.len() < frame_len {
+            src.reserve(frame_len - src.len());
+            return Ok(None);
+        }
+        src.advance(FRAME_HEADER_LEN);
+        let payload = src.split_to(len).to_vec();
+        Ok(Some(Frame { kind, payload }))
+    }
+}
+
+impl Encoder<Frame> for StpCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.check_len(frame.payload.len())?;
+        dst.reserve(FRAME_HEADER_LEN + frame.payload.len());
+        dst.put_u8(FRAME_VERSION);
+        dst.put_u8(frame.kind.as_byte());
+        dst.put_u32(frame.payload.len() as u32);
+        dst.put_slice(&frame.payload);
+        Ok(())
+    }
+}
+
+impl Encoder<String> for StpCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, msg: String, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.encode(Frame { kind: FrameKind::Message, payload: msg.into_bytes() }, dst)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::{SinkExt, StreamExt};
+    use tokio_util::codec::Framed;
+
+    use super::*;
+
+    #[test]
+    fn decode_partial_and_coalesced() {
+        let raw = [protocol::wrap_message("first"), protocol::wrap_frame(FrameKind::Event, "second")].concat();
+        let mut codec = StpCodec::new();
+        let mut buf = BytesMut::from(&raw[..3]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(&raw[3..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Frame { kind: FrameKind::Message, payload: b"first".to_vec() }));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Frame { kind: FrameKind::Event, payload: b"second".to_vec() }));
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn reject_large_and_bad_frames() {
+        let mut codec = StpCodec::new().with_max_frame_len(4);
+        // the header is enough to reject the frame
+        let mut buf = BytesMut::from(&protocol::wrap_message("too long")[..FRAME_HEADER_LEN]);
+        assert!(matches!(codec.decode(&mut buf), Err(CodecError::FrameTooLarge { len: 8, max: 4 })));
+        assert!(matches!(codec.encode("too long".to_string(), &mut BytesMut::new()), Err(CodecError::FrameTooLarge { .. })));
+        let mut buf = BytesMut::from(&[FRAME_VERSION, 42, 0, 0, 0, 0][..]);
+        assert!(matches!(codec.decode(&mut buf), Err(CodecError::BadFrame(_))));
+    }
+
+    #[tokio::test]
+    async fn framed_roundtrip() {
+        let (client, server) = tokio::io::duplex(4096);
+        let mut client = Framed::new(client, StpCodec::new());
+        let mut server = Framed::new(server, StpCodec::new());
+        let big = "x".repeat(1000);
+        client.send(big.clone()).await.unwrap();
+        client.send(Frame { kind: FrameKind::Ping, payload: vec![] }).await.unwrap();
+        assert_eq!(server.next().await.unwrap().unwrap().payload, big.into_bytes());
+        assert_eq!(server.next().await.unwrap().unwrap().kind, FrameKind::Ping);
+        drop(client);
+        assert!(server.next().await.is_none());
+    }
+}
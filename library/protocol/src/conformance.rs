@@ -0,0 +1,388 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::{ErrorKind, Read};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use crate::client_std::{ClientStp, ConnectOptions};
+use crate::errors::RecvError;
+use crate::message::{ErrorCode, Event, Request, Response};
+use crate::protocol;
+use crate::protocol::{Capability, FRAME_VERSION, FrameKind, MIN_PROTOCOL_VERSION};
+use crate::transport::Transport;
+
+/// Time given to the server for every expected reply
+pub const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Passed,
+    Failed(String),
+    /// Not applicable to the server, e.g. the capability is not supported
+    Skipped(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckResult {
+    pub name: &'static str,
+    pub outcome: Outcome,
+}
+
+/// Outcome of every check of [`check_server`], printable as a pass/fail report
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConformanceReport {
+    pub results: Vec<CheckResult>,
+}
+
+impl ConformanceReport {
+    /// No check failed
+    pub fn passed(&self) -> bool {
+        !self.results.iter().any(|result| matches!(result.outcome, Outcome::Failed(_)))
+    }
+
+    /// Test helper, panics with the report if a check failed
+    pub fn assert_passed(&self) {
+        assert!(self.passed(), "STP conformance failed:\n{}", self);
+    }
+
+    fn count(&self, f: fn(&Outcome) -> bool) -> usize {
+        self.results.iter().filter(|result| f(&result.outcome)).count()
+    }
+}
+
+impl Display for ConformanceReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for result in &self.results {
+            match &result.outcome {
+                Outcome::Passed => { writeln!(f, "PASS  {}", result.name)? }
+                Outcome::Failed(reason) => { writeln!(f, "FAIL  {}: {}", result.name, reason)? }
+                Outcome::Skipped(reason) => { writeln!(f, "SKIP  {}: {}", result.name, reason)? }
+            }
+        }
+        write!(
+            f,
+            "{} passed, {} failed, {} skipped",
+            self.count(|o| *o == Outcome::Passed),
+            self.count(|o| matches!(o, Outcome::Failed(_))),
+            self.count(|o| matches!(o, Outcome::Skipped(_))),
+        )
+    }
+}
+
+/// Check the smart socket server listening on the address
+pub fn check_address<Addr: ToSocketAddrs>(addr: Addr, options: &ConnectOptions) -> io::Result<ConformanceReport> {
+    let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+    Ok(check_server(|| TcpStream::connect(&addrs[..]), options))
+}
+
+/// Run all checks, every one over new connections opened by `connect`.
+/// Servers serving clients one by one are supported, a connection is closed before the next one is opened.
+pub fn check_server<S, F>(mut connect: F, options: &ConnectOptions) -> ConformanceReport
+where
+    S: Transport,
+    F: FnMut() -> io::Result<S>,
+{
+    let mut checker = Checker { connect: &mut connect, options };
+    let results = vec![
+        check("handshake", checker.handshake()),
+        check("bad_handshake", checker.bad_handshake()),
+        check("commands", checker.commands()),
+        check("error_replies", checker.error_replies()),
+        check("malformed_input", checker.malformed_input()),
+        check("ping", checker.ping()),
+        check("subscribe", checker.subscribe()),
+        check("reconnection", checker.reconnection()),
+    ];
+    ConformanceReport { results }
+}
+
+type CheckResultOf<T = ()> = Result<T, String>;
+
+struct Checker<'a, S> {
+    connect: &'a mut dyn FnMut() -> io::Result<S>,
+    options: &'a ConnectOptions,
+}
+
+impl<S: Transport> Checker<'_, S> {
+    fn client_with(&mut self, options: &ConnectOptions) -> CheckResultOf<ClientStp<S>> {
+        let transport = (self.connect)().map_err(|e| format!("connect: {}", e))?;
+        let client = ClientStp::connect_over(transport, options).map_err(|e| format!("handshake: {}", e))?;
+        client.set_read_timeout(Some(CHECK_TIMEOUT)).map_err(|e| e.to_string())?;
+        Ok(client)
+    }
+
+    fn client(&mut self) -> CheckResultOf<ClientStp<S>> {
+        let options = self.options.clone();
+        self.client_with(&options)
+    }
+
+    /// Client without request ids, raw frames are sent as is
+    fn raw_client(&mut self) -> CheckResultOf<ClientStp<S>> {
+        let capabilities: Vec<_> = self.options.capabilities.iter().copied().filter(|c| *c != Capability::Pipeline).collect();
+        let options = self.options.clone().with_capabilities(&capabilities);
+        self.client_with(&options)
+    }
+
+    fn handshake(&mut self) -> Outcome {
+        outcome(self.client().and_then(|client| {
+            if client.protocol_version() < MIN_PROTOCOL_VERSION {
+                return Err(format!("protocol version {} is not supported", client.protocol_version()));
+            }
+            for capability in [Capability::Switch, Capability::Power] {
+                if !client.capabilities().contains(&capability) {
+                    return Err(format!("socket capability `{}` is not supported", capability));
+                }
+            }
+            Ok(())
+        }))
+    }
+
+    /// The server has to drop the client not speaking STP
+    fn bad_handshake(&mut self) -> Outcome {
+        if self.options.tls.is_some() {
+            return Outcome::Skipped("not checked over TLS".to_string());
+        }
+        outcome((|| {
+            let mut transport = (self.connect)().map_err(|e| format!("connect: {}", e))?;
+            transport.set_read_timeout(Some(CHECK_TIMEOUT)).map_err(|e| e.to_string())?;
+            transport.write_all(&protocol::wrap_message("hello")).map_err(|e| e.to_string())?;
+            expect_closed(&mut transport)
+        })())
+    }
+
+    fn commands(&mut self) -> Outcome {
+        outcome(self.client().and_then(|mut client| {
+            let mut expect = |request: Request, check: &dyn Fn(&Response) -> bool| {
+                match client.request(&request) {
+                    Ok(response) if check(&response) => { Ok(()) }
+                    Ok(response) => { Err(format!("{:?}: unexpected response {:?}", request, response)) }
+                    Err(e) => { Err(format!("{:?}: {}", request, e)) }
+                }
+            };
+            expect(Request::TurnOn, &|r| *r == Response::Ok)?;
+            expect(Request::GetState, &|r| *r == Response::State(true))?;
+            expect(Request::GetPowerConsumptionWt, &|r| matches!(r, Response::PowerConsumptionWt(_)))?;
+            expect(Request::TurnOff, &|r| *r == Response::Ok)?;
+            expect(Request::GetState, &|r| *r == Response::State(false))?;
+            expect(Request::GetDescription, &|r| matches!(r, Response::Description(d) if !d.is_empty()))
+        }))
+    }
+
+    /// Unknown command is answered with [`ErrorCode::BadRequest`], the connection stays usable
+    fn error_replies(&mut self) -> Outcome {
+        outcome(self.client().and_then(|mut client| {
+            expect_bad_request(&mut client, r#"{"cmd":"self_destruct"}"#)?;
+            expect_usable(&mut client)
+        }))
+    }
+
+    /// Not JSON request gets an error reply, broken frames get the client disconnected
+    fn malformed_input(&mut self) -> Outcome {
+        outcome((|| {
+            let mut client = self.client()?;
+            expect_bad_request(&mut client, "turn on, please")?;
+            expect_usable(&mut client)?;
+            drop(client);
+
+            // not UTF-8: the error reply or disconnect
+            let mut client = self.raw_client()?;
+            client.write_raw(&protocol::wrap_message([0xff, 0xfe, 0xfd])).map_err(|e| e.to_string())?;
+            match client.read_raw() {
+                Ok(frame) if frame.kind == FrameKind::Error => {}
+                Ok(frame) => { return Err(format!("not UTF-8 request: unexpected {:?} frame", frame.kind)) }
+                Err(e) => { closed(e).map_err(|e| format!("not UTF-8 request: {}", e))? }
+            }
+            drop(client);
+
+            let mut client = self.raw_client()?;
+            client.write_raw(&[FRAME_VERSION, 0xee, 0, 0, 0, 0]).map_err(|e| e.to_string())?;
+            expect_closed_client(&mut client).map_err(|e| format!("unknown frame kind: {}", e))
+        })())
+    }
+
+    /// Ping is answered with pong carrying the same payload
+    fn ping(&mut self) -> Outcome {
+        outcome(self.raw_client().and_then(|mut client| {
+            client.write_raw(&protocol::wrap_frame(FrameKind::Ping, "conformance")).map_err(|e| e.to_string())?;
+            loop {
+                let frame = client.read_raw().map_err(|e| e.to_string())?;
+                match frame.kind {
+                    FrameKind::Pong if frame.payload == b"conformance" => { return Ok(()) }
+                    FrameKind::Pong => { return Err("pong payload differs from ping".to_string()) }
+                    FrameKind::Event => {}
+                    kind => { return Err(format!("unexpected {:?} frame", kind)) }
+                }
+            }
+        }))
+    }
+
+    fn subscribe(&mut self) -> Outcome {
+        let mut client = match self.client() {
+            Ok(client) => { client }
+            Err(e) => { return Outcome::Failed(e) }
+        };
+        if !client.capabilities().contains(&Capability::Subscribe) {
+            return Outcome::Skipped("subscribe capability is not supported".to_string());
+        }
+        outcome((|| {
+            let request = |client: &mut ClientStp<S>, request: &Request| {
+                client.request(request).map_err(|e| format!("{:?}: {}", request, e))
+            };
+            request(&mut client, &Request::TurnOff)?;
+            if request(&mut client, &Request::Subscribe { power_threshold_wt: 1.0 })? != Response::Ok {
+                return Err("subscribe is not confirmed".to_string());
+            }
+            request(&mut client, &Request::TurnOn)?;
+            let deadline = Instant::now() + CHECK_TIMEOUT;
+            loop {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                match client.next_event(Some(timeout.max(Duration::from_millis(1)))) {
+                    Ok(Some(Event::StateChanged(true))) => { break }
+                    Ok(Some(_)) if Instant::now() < deadline => {}
+                    Ok(_) => { return Err("no state change event after turning on".to_string()) }
+                    Err(e) => { return Err(e.to_string()) }
+                }
+            }
+            if request(&mut client, &Request::Unsubscribe)? != Response::Ok {
+                return Err("unsubscribe is not confirmed".to_string());
+            }
+            Ok(())
+        })())
+    }
+
+    /// Clients reconnecting after a clean close and after a close with the request in flight are served
+    fn reconnection(&mut self) -> Outcome {
+        outcome((|| {
+            for attempt in 1..=3 {
+                let mut client = self.client().map_err(|e| format!("attempt {}: {}", attempt, e))?;
+                expect_usable(&mut client).map_err(|e| format!("attempt {}: {}", attempt, e))?;
+            }
+            let mut client = self.client()?;
+            client.send_request(Request::GetState.encode()).map_err(|e| e.to_string())?;
+            drop(client);
+            let mut client = self.client().map_err(|e| format!("after abandoned request: {}", e))?;
+            expect_usable(&mut client).map_err(|e| format!("after abandoned request: {}", e))
+        })())
+    }
+}
+
+fn check(name: &'static str, outcome: Outcome) -> CheckResult {
+    CheckResult { name, outcome }
+}
+
+fn outcome(result: CheckResultOf) -> Outcome {
+    match result {
+        Ok(()) => { Outcome::Passed }
+        Err(reason) => { Outcome::Failed(reason) }
+    }
+}
+
+fn expect_bad_request<S: Transport>(client: &mut ClientStp<S>, msg: &str) -> CheckResultOf {
+    match client.send_request(msg) {
+        Err(e) if e.remote().is_some_and(|reply| reply.code == ErrorCode::BadRequest) => { Ok(()) }
+        Err(e) => { Err(format!("`{}`: {}, expected bad_request error reply", msg, e)) }
+        Ok(response) => { Err(format!("`{}`: response `{}`, expected bad_request error reply", msg, response)) }
+    }
+}
+
+fn expect_usable<S: Transport>(client: &mut ClientStp<S>) -> CheckResultOf {
+    match client.request(&Request::GetState) {
+        Ok(Response::State(_)) => { Ok(()) }
+        Ok(response) => { Err(format!("GetState: unexpected response {:?}", response)) }
+        Err(e) => { Err(format!("GetState: {}", e)) }
+    }
+}
+
+/// Error replies are allowed before the close
+fn expect_closed_client<S: Transport>(client: &mut ClientStp<S>) -> CheckResultOf {
+    loop {
+        match client.read_raw() {
+            Ok(frame) if matches!(frame.kind, FrameKind::Error | FrameKind::Event) => {}
+            Ok(frame) => { return Err(format!("unexpected {:?} frame instead of disconnect", frame.kind)) }
+            Err(e) => { return closed(e) }
+        }
+    }
+}
+
+fn expect_closed<S: Read>(transport: &mut S) -> CheckResultOf {
+    let mut buff = [0u8; 256];
+    loop {
+        match transport.read(&mut buff) {
+            Ok(0) => { return Ok(()) }
+            // the handshake reply or the error reply
+            Ok(_) => {}
+            Err(e) => { return closed(RecvError::Io(e)) }
+        }
+    }
+}
+
+/// Read failed because the server closed the connection, not because it is silent
+fn closed(e: RecvError) -> CheckResultOf {
+    match e {
+        RecvError::Io(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+            Err(format!("connection is not closed in {:?}", CHECK_TIMEOUT))
+        }
+        _ => { Ok(()) }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use crate::handler::{DeviceState, Handler, HandlerResult};
+    use crate::message::ErrorReply;
+    use crate::server_std::ServerStp;
+    use crate::transport::memory_listener;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Socket {
+        on: bool,
+        /// Firmware bug: the state is never reported as on
+        stuck: bool,
+    }
+
+    impl Handler for Socket {
+        fn handle(&mut self, request: Request) -> HandlerResult {
+            match request {
+                Request::TurnOn => { self.on = true }
+                Request::TurnOff => { self.on = false }
+                Request::GetState => { return Ok(Response::State(self.on && !self.stuck)) }
+                Request::GetPowerConsumptionWt => { return Ok(Response::PowerConsumptionWt(Some(if self.on { 1500.0 } else { 0.0 }))) }
+                Request::GetDescription => { return Ok(Response::Description("Test socket".to_string())) }
+                _ => { return Err(ErrorReply::new(ErrorCode::Unsupported, "not a device request")) }
+            }
+            Ok(Response::Ok)
+        }
+
+        fn state(&mut self) -> Option<DeviceState> {
+            Some(DeviceState { on: self.on, power_wt: None })
+        }
+    }
+
+    #[test]
+    fn conforming_server() {
+        let (listener, connector) = memory_listener();
+        thread::spawn(move || ServerStp::new(listener).serve(Socket::default()));
+        let report = check_server(|| connector.connect(), &ConnectOptions::new());
+        report.assert_passed();
+        assert_eq!(report.results.len(), 8);
+        assert!(report.to_string().ends_with("8 passed, 0 failed, 0 skipped"));
+    }
+
+    #[test]
+    fn report_failures() {
+        let (listener, connector) = memory_listener();
+        let server = ServerStp::new(listener).with_capabilities(&[Capability::Switch, Capability::Power]);
+        thread::spawn(move || server.serve(Socket { stuck: true, ..Socket::default() }));
+        let report = check_server(|| connector.connect(), &ConnectOptions::new());
+        assert!(!report.passed());
+        let text = report.to_string();
+        assert!(text.contains("FAIL  commands: GetState: unexpected response State(false)"), "{}", text);
+        assert!(text.contains("SKIP  subscribe"), "{}", text);
+        assert!(text.contains("PASS  reconnection"), "{}", text);
+    }
+}
@@ -1,7 +1,10 @@
 use std::io;
+use std::time::Duration;
 
 use thiserror::Error;
 
+use crate::message::ErrorReply;
+
 pub type ConnectResult<T> = Result<T, ConnectError>;
 
 /// Connection error. Includes IO and handshake error.
@@ -11,6 +14,25 @@ pub enum ConnectError {
     BadHandshake(String),
     #[error("IO error: {0}")]
     Io(#[from] io::Error),
+    #[error(transparent)]
+    Tls(#[from] TlsError),
+    #[error("Authentication failed: {0}")]
+    Unauthorized(String),
+    /// Server refused to serve the client, e.g. too many connections
+    #[error("connection rejected: {0}")]
+    Rejected(ErrorReply),
+    /// Reconnecting client waits for the backoff delay after a failed attempt
+    #[error("connection lost, next attempt in {0:?}")]
+    Backoff(Duration),
+}
+
+impl From<SendError> for ConnectError {
+    fn from(e: SendError) -> Self {
+        match e {
+            SendError::Io(e) => { ConnectError::Io(e) }
+            e => { ConnectError::BadHandshake(e.to_string()) }
+        }
+    }
 }
 
 pub type SendResult = Result<(), SendError>;
@@ -20,6 +42,8 @@ pub type SendResult = Result<(), SendError>;
 pub enum SendError {
     #[error("IO error: {0}")]
     Io(#[from] io::Error),
+    #[error("frame payload of {len} bytes exceeds the limit of {max} bytes")]
+    FrameTooLarge { len: usize, max: usize },
 }
 
 pub type RecvResult = Result<String, RecvError>;
@@ -31,6 +55,141 @@ pub enum RecvError {
     Io(#[from] io::Error),
     #[error("bad encoding")]
     BadEncoding,
+    #[error("bad message: {0}")]
+    BadMessage(String),
     #[error("Some error`{0}`")]
     Other(String),
-}
\ No newline at end of file
+    /// Peer stopped answering heartbeats, the connection is closed
+    #[error("peer missed {0} heartbeats")]
+    PeerTimeout(u32),
+    /// No request from the client during the idle timeout, the connection is closed
+    #[error("connection idle for {0:?}")]
+    IdleTimeout(Duration),
+    /// Server stopped serving, the connection is closed
+    #[error("server is shutting down")]
+    ShuttingDown,
+    /// Client sent requests faster than allowed, the connection is closed
+    #[error("request rate limit exceeded")]
+    RateLimited,
+    #[error("frame payload of {len} bytes exceeds the limit of {max} bytes")]
+    FrameTooLarge { len: usize, max: usize },
+    /// Peer failed to perform the request
+    #[error("request failed: {0}")]
+    Remote(ErrorReply),
+}
+
+impl From<SendError> for RecvError {
+    fn from(e: SendError) -> Self {
+        match e {
+            SendError::Io(e) => { RecvError::Io(e) }
+            SendError::FrameTooLarge { len, max } => { RecvError::FrameTooLarge { len, max } }
+        }
+    }
+}
+
+/// Frame encoding or decoding error of [`crate::codec::StpCodec`]
+#[derive(Debug, Error)]
+pub enum CodecError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("frame payload of {len} bytes exceeds the limit of {max} bytes")]
+    FrameTooLarge { len: usize, max: usize },
+    #[error("bad frame: {0}")]
+    BadFrame(String),
+}
+
+impl From<CodecError> for RecvError {
+    fn from(e: CodecError) -> Self {
+        match e {
+            CodecError::Io(e) => { RecvError::Io(e) }
+            CodecError::FrameTooLarge { len, max } => { RecvError::FrameTooLarge { len, max } }
+            CodecError::BadFrame(e) => { RecvError::Other(e) }
+        }
+    }
+}
+
+impl From<CodecError> for SendError {
+    fn from(e: CodecError) -> Self {
+        match e {
+            CodecError::Io(e) => { SendError::Io(e) }
+            CodecError::FrameTooLarge { len, max } => { SendError::FrameTooLarge { len, max } }
+            CodecError::BadFrame(e) => { SendError::Io(io::Error::new(io::ErrorKind::InvalidData, e)) }
+        }
+    }
+}
+
+pub type TlsResult<T> = Result<T, TlsError>;
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+/// TLS configuration error
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("TLS error: {0}")]
+    Rustls(#[from] rustls::Error),
+    #[error("bad certificate verifier: {0}")]
+    Verifier(String),
+    #[error("bad PEM file: {0}")]
+    Pem(String),
+    #[error("invalid server name `{0}`")]
+    ServerName(String),
+}
+
+pub type LimitResult<T> = Result<T, LimitError>;
+
+/// Bad connection limits
+#[derive(Debug, Error, PartialEq)]
+pub enum LimitError {
+    #[error("rate limit must be a finite positive number, got {0}")]
+    BadRate(f64),
+    #[error("rate limit burst must allow at least one request")]
+    ZeroBurst,
+}
+
+pub type RequestResult = Result<String, RequestError>;
+
+/// Request error of blocking and async clients
+#[derive(Debug, Error)]
+pub enum RequestError {
+    #[error(transparent)]
+    Send(#[from] SendError),
+    #[error(transparent)]
+    Recv(#[from] RecvError),
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Connect(#[from] ConnectError),
+}
+
+impl RequestError {
+    /// Connection is broken and the client has to reconnect.
+    /// Read timeout is not, the response may still arrive.
+    pub fn is_connection_lost(&self) -> bool {
+        match self {
+            RequestError::Recv(RecvError::Io(e)) | RequestError::Io(e) => { !is_timeout(e) }
+            // a frame may be written partially, so write timeout breaks the connection too
+            RequestError::Send(SendError::Io(_)) | RequestError::Recv(RecvError::PeerTimeout(_)) => { true }
+            _ => { false }
+        }
+    }
+
+    /// No response during the read timeout of the transport
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, RequestError::Recv(RecvError::Io(e)) | RequestError::Io(e) if is_timeout(e))
+    }
+
+    /// Request was not sent, so it is safe to repeat it on a new connection
+    pub fn is_not_sent(&self) -> bool {
+        matches!(self, RequestError::Send(_))
+    }
+
+    /// Error reply of the server. The connection is fine, unless the code is [`crate::message::ErrorCode::LimitExceeded`]
+    pub fn remote(&self) -> Option<&ErrorReply> {
+        match self {
+            RequestError::Recv(RecvError::Remote(reply)) => { Some(reply) }
+            _ => { None }
+        }
+    }
+}
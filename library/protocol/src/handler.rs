@@ -0,0 +1,63 @@
+use std::future::Future;
+use std::time::Duration;
+
+use crate::message::{ErrorCode, ErrorReply, Request, Response};
+
+/// Period of pushing events to subscribed clients by `serve`
+pub const NOTIFY_PERIOD: Duration = Duration::from_millis(200);
+
+/// Response or the error reply sent in [`crate::protocol::FrameKind::Error`] frame
+pub type HandlerResult = Result<Response, ErrorReply>;
+
+/// Device values reported to subscribed clients
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DeviceState {
+    pub on: bool,
+    pub power_wt: Option<f32>,
+}
+
+/// Device behind the blocking [`crate::server_std::ServerStp::serve`].
+/// Subscriptions are handled by the server, the handler gets device requests only.
+pub trait Handler {
+    fn handle(&mut self, request: Request) -> HandlerResult;
+
+    /// Current values for subscribed clients, `None` if the device is offline
+    fn state(&mut self) -> Option<DeviceState> {
+        None
+    }
+}
+
+/// Device behind the async [`crate::server_tokio::ServerStp::serve`], shared by all connections
+pub trait AsyncHandler: Send + Sync + 'static {
+    fn handle(&self, request: Request) -> impl Future<Output=HandlerResult> + Send;
+
+    /// Current values for subscribed clients, `None` if the device is offline
+    fn state(&self) -> impl Future<Output=Option<DeviceState>> + Send {
+        async { None }
+    }
+}
+
+/// Requests served by the connection itself
+pub(crate) enum Route {
+    Device(Request),
+    Subscribe(f32),
+    Unsubscribe,
+}
+
+impl From<Request> for Route {
+    fn from(request: Request) -> Self {
+        match request {
+            Request::Subscribe { power_threshold_wt } => { Route::Subscribe(power_threshold_wt) }
+            Request::Unsubscribe => { Route::Unsubscribe }
+            request => { Route::Device(request) }
+        }
+    }
+}
+
+pub(crate) fn unknown_request(e: &str) -> ErrorReply {
+    ErrorReply::new(ErrorCode::BadRequest, format!("Unknown request: {}", e))
+}
+
+pub(crate) fn subscribe_not_negotiated() -> ErrorReply {
+    ErrorReply::new(ErrorCode::Unsupported, "Subscribe capability not negotiated")
+}
@@ -0,0 +1,104 @@
+use std::time::{Duration, Instant};
+
+/// Keepalive settings. The idle peer is pinged every `interval`,
+/// the connection is closed when `max_missed` pings in a row stay unanswered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub max_missed: u32,
+}
+
+impl Heartbeat {
+    pub fn new(interval: Duration, max_missed: u32) -> Self {
+        Self { interval, max_missed }
+    }
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(10), 3)
+    }
+}
+
+/// What to do when the heartbeat deadline is reached
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeartbeatAction {
+    /// Deadline is not reached yet
+    Wait,
+    Ping,
+    /// Peer missed the given count of pings, repeated on every following timeout
+    Dead(u32),
+}
+
+/// Heartbeat state of one connection
+#[derive(Debug, Clone)]
+pub struct HeartbeatMonitor {
+    heartbeat: Heartbeat,
+    last_activity: Instant,
+    missed: u32,
+}
+
+impl HeartbeatMonitor {
+    pub fn new(heartbeat: Heartbeat) -> Self {
+        Self { heartbeat, last_activity: Instant::now(), missed: 0 }
+    }
+
+    /// Any frame received from the peer
+    pub fn received(&mut self) {
+        self.last_activity = Instant::now();
+        self.missed = 0;
+    }
+
+    /// Time of the next ping if nothing is received
+    pub fn deadline(&self) -> Instant {
+        self.last_activity + self.heartbeat.interval
+    }
+
+    /// Pings sent since the last received frame
+    pub fn missed(&self) -> u32 {
+        self.missed
+    }
+
+    pub fn on_timeout(&mut self, now: Instant) -> HeartbeatAction {
+        if now < self.deadline() {
+            return HeartbeatAction::Wait;
+        }
+        if self.missed >= self.heartbeat.max_missed {
+            return HeartbeatAction::Dead(self.missed);
+        }
+        self.missed += 1;
+        self.last_activity = now;
+        HeartbeatAction::Ping
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ping_then_dead() {
+        let mut monitor = HeartbeatMonitor::new(Heartbeat::new(Duration::from_secs(1), 2));
+        let start = Instant::now();
+        assert_eq!(monitor.on_timeout(start), HeartbeatAction::Wait);
+        let mut now = monitor.deadline();
+        assert_eq!(monitor.on_timeout(now), HeartbeatAction::Ping);
+        assert_eq!(monitor.on_timeout(now), HeartbeatAction::Wait);
+        now += Duration::from_secs(1);
+        assert_eq!(monitor.on_timeout(now), HeartbeatAction::Ping);
+        assert_eq!(monitor.missed(), 2);
+        now += Duration::from_secs(1);
+        assert_eq!(monitor.on_timeout(now), HeartbeatAction::Dead(2));
+        assert_eq!(monitor.on_timeout(now), HeartbeatAction::Dead(2));
+    }
+
+    #[test]
+    fn received_resets_missed() {
+        let mut monitor = HeartbeatMonitor::new(Heartbeat::new(Duration::from_millis(10), 1));
+        assert_eq!(monitor.on_timeout(monitor.deadline()), HeartbeatAction::Ping);
+        monitor.received();
+        assert_eq!(monitor.missed(), 0);
+        assert_eq!(monitor.on_timeout(monitor.deadline()), HeartbeatAction::Ping);
+    }
+}
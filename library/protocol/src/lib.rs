@@ -1,6 +1,22 @@
 pub mod protocol;
 pub mod errors;
+pub mod message;
+pub mod subscription;
 pub mod client_std;
 pub mod server_std;
 pub mod client_tokio;
-pub mod server_tokio;
\ No newline at end of file
+pub mod server_tokio;
+pub mod tls;
+pub mod stream;
+pub mod auth;
+pub mod transport;
+pub mod reconnect;
+pub mod heartbeat;
+pub mod codec;
+pub mod session;
+pub mod handler;
+pub mod limits;
+pub mod metrics;
+pub mod record;
+pub mod replay;
+pub mod conformance;
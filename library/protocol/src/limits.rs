@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Instant;
+
+use crate::codec::DEFAULT_MAX_FRAME_LEN;
+use crate::errors::{LimitError, LimitResult};
+use crate::message::{ErrorCode, ErrorReply};
+
+/// Limits of every client connection, shared by std and tokio servers.
+/// The client breaking a limit gets [`ErrorCode::LimitExceeded`] error reply and is disconnected.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConnectionLimits {
+    /// Payload limit of frames received from the client
+    pub max_frame_len: usize,
+    /// Requests rate of one connection, `None` - unlimited
+    pub rate_limit: Option<RateLimit>,
+    /// Connections from one IP address served at once, `None` - unlimited
+    pub max_connections_per_ip: Option<usize>,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self { max_frame_len: DEFAULT_MAX_FRAME_LEN, rate_limit: None, max_connections_per_ip: None }
+    }
+}
+
+impl ConnectionLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_frame_len(mut self, max_frame_len: usize) -> Self {
+        self.max_frame_len = max_frame_len;
+        self
+    }
+
+    pub fn with_rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.rate_limit = Some(rate_limit);
+        self
+    }
+
+    pub fn with_max_connections_per_ip(mut self, max_connections: usize) -> Self {
+        self.max_connections_per_ip = Some(max_connections);
+        self
+    }
+}
+
+/// Token bucket parameters: up to `burst` requests at once, refilled with `per_second` tokens
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    per_second: f64,
+    burst: u32,
+}
+
+impl RateLimit {
+    /// Burst of one second of requests. `per_second` has to be a finite positive number.
+    pub fn new(per_second: f64) -> LimitResult<Self> {
+        if !per_second.is_finite() || per_second <= 0.0 {
+            return Err(LimitError::BadRate(per_second));
+        }
+        Ok(Self { per_second, burst: (per_second.ceil() as u32).max(1) })
+    }
+
+    /// At least one request
+    pub fn with_burst(mut self, burst: u32) -> LimitResult<Self> {
+        if burst == 0 {
+            return Err(LimitError::ZeroBurst);
+        }
+        self.burst = burst;
+        Ok(self)
+    }
+
+    pub fn per_second(&self) -> f64 {
+        self.per_second
+    }
+
+    pub fn burst(&self) -> u32 {
+        self.burst
+    }
+}
+
+/// Requests rate of one connection
+#[derive(Debug)]
+pub struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Starts full
+    pub fn new(limit: RateLimit, now: Instant) -> Self {
+        Self { limit, tokens: limit.burst as f64, updated: now }
+    }
+
+    /// Take a token for the request, `false` if the bucket is empty
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.per_second).min(self.limit.burst as f64);
+        self.updated = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// Open connections of every peer IP, shared by connections of one server
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PeerConnections {
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl PeerConnections {
+    /// Count the new connection, the error reply if the peer already has `max` ones.
+    /// Connections without IP address or limit are not counted.
+    pub(crate) fn admit(&self, ip: Option<IpAddr>, max: Option<usize>) -> Result<Option<PeerGuard>, ErrorReply> {
+        let (Some(ip), Some(max)) = (ip, max) else {
+            return Ok(None);
+        };
+        let mut counts = self.counts.lock().unwrap_or_else(PoisonError::into_inner);
+        let count = counts.entry(ip).or_default();
+        if *count >= max {
+            return Err(ErrorReply::new(ErrorCode::LimitExceeded, format!("Too many connections from {}", ip)));
+        }
+        *count += 1;
+        Ok(Some(PeerGuard { counts: self.counts.clone(), ip }))
+    }
+}
+
+/// Connection counted in [`PeerConnections`] until dropped
+#[derive(Debug)]
+pub(crate) struct PeerGuard {
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    ip: IpAddr,
+}
+
+impl Drop for PeerGuard {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
+
+pub(crate) fn rate_limited() -> ErrorReply {
+    ErrorReply::new(ErrorCode::LimitExceeded, "Request rate limit exceeded")
+}
+
+pub(crate) fn frame_too_large(len: usize, max: usize) -> ErrorReply {
+    ErrorReply::new(ErrorCode::LimitExceeded, format!("Frame of {} bytes exceeds the limit of {} bytes", len, max))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(RateLimit::new(10.0).unwrap().with_burst(2).unwrap(), start);
+        assert!(bucket.try_take(start));
+        assert!(bucket.try_take(start));
+        assert!(!bucket.try_take(start));
+        // one token per 100 ms
+        assert!(!bucket.try_take(start + Duration::from_millis(50)));
+        assert!(bucket.try_take(start + Duration::from_millis(110)));
+        // refill is capped by the burst
+        let later = start + Duration::from_secs(10);
+        assert!(bucket.try_take(later) && bucket.try_take(later));
+        assert!(!bucket.try_take(later));
+    }
+
+    #[test]
+    fn reject_bad_rate() {
+        for rate in [0.0, -1.0, f64::INFINITY] {
+            assert_eq!(RateLimit::new(rate), Err(LimitError::BadRate(rate)));
+        }
+        assert!(matches!(RateLimit::new(f64::NAN), Err(LimitError::BadRate(rate)) if rate.is_nan()));
+        assert_eq!(RateLimit::new(0.5).unwrap().burst(), 1);
+        assert_eq!(RateLimit::new(10.0).unwrap().with_burst(0), Err(LimitError::ZeroBurst));
+    }
+
+    #[test]
+    fn peer_connections() {
+        let peers = PeerConnections::default();
+        let ip = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        let first = peers.admit(ip, Some(2)).unwrap();
+        let _second = peers.admit(ip, Some(2)).unwrap();
+        assert_eq!(peers.admit(ip, Some(2)).unwrap_err().code, ErrorCode::LimitExceeded);
+        assert!(peers.admit(Some(IpAddr::V4(Ipv4Addr::UNSPECIFIED)), Some(2)).is_ok());
+        assert!(peers.admit(None, Some(2)).unwrap().is_none());
+        drop(first);
+        assert!(peers.admit(ip, Some(2)).is_ok());
+    }
+}
@@ -0,0 +1,205 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::errors::RecvError;
+
+/// Smart socket command, sent by client
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum Request {
+    TurnOn,
+    TurnOff,
+    GetState,
+    GetPowerConsumptionWt,
+    GetDescription,
+    /// Ask the server to push [`Event`]s on state change
+    /// and on power change not less than the threshold
+    Subscribe { power_threshold_wt: f32 },
+    Unsubscribe,
+}
+
+/// Reply of the smart socket server to a [`Request`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum Response {
+    /// Command performed
+    Ok,
+    /// `true` - socket is on
+    State(bool),
+    /// `None` - power consumption is unknown
+    PowerConsumptionWt(Option<f32>),
+    Description(String),
+}
+
+/// Reason of the failed request, see [`ErrorReply`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// Device does not respond
+    Offline,
+    /// Request is not supported by the device or capability is not negotiated
+    Unsupported,
+    /// Request is not decodable
+    BadRequest,
+    Internal,
+    /// Client broke a limit of the server and is disconnected
+    LimitExceeded,
+}
+
+/// Sent in [`crate::protocol::FrameKind::Error`] frame instead of the [`Response`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Error)]
+#[error("{code}: {message}")]
+pub struct ErrorReply {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+/// Notification pushed by the server to a subscribed client
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", content = "value", rename_all = "snake_case")]
+pub enum Event {
+    StateChanged(bool),
+    PowerChanged(Option<f32>),
+}
+
+impl Request {
+    /// Name of the command, as encoded in `cmd` field
+    pub fn command(&self) -> &'static str {
+        match self {
+            Request::TurnOn => { "turn_on" }
+            Request::TurnOff => { "turn_off" }
+            Request::GetState => { "get_state" }
+            Request::GetPowerConsumptionWt => { "get_power_consumption_wt" }
+            Request::GetDescription => { "get_description" }
+            Request::Subscribe { .. } => { "subscribe" }
+            Request::Unsubscribe => { "unsubscribe" }
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).expect("request is always serializable")
+    }
+
+    pub fn decode(msg: &str) -> Result<Self, RecvError> {
+        serde_json::from_str(msg).map_err(|e| RecvError::BadMessage(e.to_string()))
+    }
+}
+
+impl Response {
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).expect("response is always serializable")
+    }
+
+    pub fn decode(msg: &str) -> Result<Self, RecvError> {
+        serde_json::from_str(msg).map_err(|e| RecvError::BadMessage(e.to_string()))
+    }
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::Offline => { "offline" }
+            ErrorCode::Unsupported => { "unsupported" }
+            ErrorCode::BadRequest => { "bad_request" }
+            ErrorCode::Internal => { "internal" }
+            ErrorCode::LimitExceeded => { "limit_exceeded" }
+        }
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl ErrorReply {
+    pub fn new<Msg: Into<String>>(code: ErrorCode, message: Msg) -> Self {
+        Self { code, message: message.into() }
+    }
+
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).expect("error reply is always serializable")
+    }
+
+    pub fn decode(msg: &str) -> Result<Self, RecvError> {
+        serde_json::from_str(msg).map_err(|e| RecvError::BadMessage(e.to_string()))
+    }
+}
+
+impl Event {
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).expect("event is always serializable")
+    }
+
+    pub fn decode(msg: &str) -> Result<Self, RecvError> {
+        serde_json::from_str(msg).map_err(|e| RecvError::BadMessage(e.to_string()))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_encoding() {
+        assert_eq!(r#"{"cmd":"turn_on"}"#, Request::TurnOn.encode());
+        assert_eq!(r#"{"cmd":"get_power_consumption_wt"}"#, Request::GetPowerConsumptionWt.encode());
+        assert_eq!(r#"{"cmd":"subscribe","power_threshold_wt":10.0}"#, Request::Subscribe { power_threshold_wt: 10.0 }.encode());
+        let requests = [
+            Request::TurnOn,
+            Request::TurnOff,
+            Request::GetState,
+            Request::GetPowerConsumptionWt,
+            Request::GetDescription,
+            Request::Subscribe { power_threshold_wt: 0.5 },
+            Request::Unsubscribe,
+        ];
+        for req in requests {
+            assert_eq!(req, Request::decode(&req.encode()).unwrap());
+        }
+    }
+
+    #[test]
+    fn response_encoding() {
+        assert_eq!(r#"{"type":"ok"}"#, Response::Ok.encode());
+        assert_eq!(r#"{"type":"state","value":true}"#, Response::State(true).encode());
+        let responses = [
+            Response::Ok,
+            Response::State(false),
+            Response::PowerConsumptionWt(Some(2000.5)),
+            Response::PowerConsumptionWt(None),
+            Response::Description("socket@kitchen".to_string()),
+        ];
+        for resp in responses {
+            assert_eq!(resp, Response::decode(&resp.encode()).unwrap());
+        }
+    }
+
+    #[test]
+    fn error_reply_encoding() {
+        let reply = ErrorReply::new(ErrorCode::BadRequest, "unknown command");
+        assert_eq!(r#"{"code":"bad_request","message":"unknown command"}"#, reply.encode());
+        assert_eq!(reply, ErrorReply::decode(&reply.encode()).unwrap());
+        assert_eq!("offline: Device not respond", ErrorReply::new(ErrorCode::Offline, "Device not respond").to_string());
+    }
+
+    #[test]
+    fn event_encoding() {
+        assert_eq!(r#"{"event":"state_changed","value":true}"#, Event::StateChanged(true).encode());
+        for event in [Event::StateChanged(false), Event::PowerChanged(Some(12.5)), Event::PowerChanged(None)] {
+            assert_eq!(event, Event::decode(&event.encode()).unwrap());
+        }
+    }
+
+    #[test]
+    fn malformed_message() {
+        assert!(matches!(Request::decode("turn_on"), Err(RecvError::BadMessage(_))));
+        assert!(matches!(Request::decode(r#"{"cmd":"explode"}"#), Err(RecvError::BadMessage(_))));
+        assert!(matches!(Response::decode("state: on"), Err(RecvError::BadMessage(_))));
+    }
+}
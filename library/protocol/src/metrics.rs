@@ -0,0 +1,187 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::message::ErrorCode;
+
+/// Upper bounds of the request latency histogram buckets, seconds
+pub const LATENCY_BUCKETS: [f64; 10] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0];
+
+/// Command label of requests not decodable as [`crate::message::Request`]
+pub const UNKNOWN_COMMAND: &str = "unknown";
+
+/// Counters of one server, updated by all its connections. Clones share the same counters.
+#[derive(Debug, Clone, Default)]
+pub struct ServerMetrics {
+    inner: Arc<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    connections_accepted: AtomicU64,
+    handshake_failures: AtomicU64,
+    requests: Mutex<BTreeMap<&'static str, u64>>,
+    error_replies: Mutex<BTreeMap<&'static str, u64>>,
+    request_latency: Mutex<Histogram>,
+}
+
+impl ServerMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let counters = &self.inner;
+        let to_owned = |map: &Mutex<BTreeMap<&'static str, u64>>| {
+            lock(map).iter().map(|(label, count)| (label.to_string(), *count)).collect()
+        };
+        MetricsSnapshot {
+            connections_accepted: counters.connections_accepted.load(Ordering::Relaxed),
+            handshake_failures: counters.handshake_failures.load(Ordering::Relaxed),
+            requests: to_owned(&counters.requests),
+            error_replies: to_owned(&counters.error_replies),
+            request_latency: lock(&counters.request_latency).clone(),
+        }
+    }
+
+    /// Current values in Prometheus text exposition format
+    pub fn render_prometheus(&self) -> String {
+        self.snapshot().to_prometheus()
+    }
+
+    pub(crate) fn connection_accepted(&self) {
+        self.inner.connections_accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn handshake_failed(&self) {
+        self.inner.handshake_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn request(&self, command: &'static str) {
+        *lock(&self.inner.requests).entry(command).or_default() += 1;
+    }
+
+    pub(crate) fn error_reply(&self, code: ErrorCode) {
+        *lock(&self.inner.error_replies).entry(code.as_str()).or_default() += 1;
+    }
+
+    pub(crate) fn request_latency(&self, latency: Duration) {
+        lock(&self.inner.request_latency).observe(latency);
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Latency histogram with [`LATENCY_BUCKETS`]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Histogram {
+    /// Observations of every bucket, not cumulative. Longer ones are counted in `count` only.
+    pub buckets: [u64; LATENCY_BUCKETS.len()],
+    pub count: u64,
+    pub sum: Duration,
+}
+
+impl Histogram {
+    pub fn observe(&mut self, value: Duration) {
+        let secs = value.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| secs <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+/// Values of [`ServerMetrics`] at some moment
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MetricsSnapshot {
+    /// Accepted clients, including the ones failed handshake
+    pub connections_accepted: u64,
+    pub handshake_failures: u64,
+    /// Received requests by command, see [`crate::message::Request::command`]
+    pub requests: BTreeMap<String, u64>,
+    /// Sent error replies by [`ErrorCode`]
+    pub error_replies: BTreeMap<String, u64>,
+    /// Time from receiving the request to sending the response or error reply
+    pub request_latency: Histogram,
+}
+
+impl MetricsSnapshot {
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        counter(&mut out, "stp_connections_accepted_total", "Accepted client connections");
+        let _ = writeln!(out, "stp_connections_accepted_total {}", self.connections_accepted);
+        counter(&mut out, "stp_handshake_failures_total", "Connections failed handshake");
+        let _ = writeln!(out, "stp_handshake_failures_total {}", self.handshake_failures);
+        counter(&mut out, "stp_requests_total", "Received requests by command");
+        for (command, count) in &self.requests {
+            let _ = writeln!(out, "stp_requests_total{{command=\"{}\"}} {}", command, count);
+        }
+        counter(&mut out, "stp_error_replies_total", "Sent error replies by code");
+        for (code, count) in &self.error_replies {
+            let _ = writeln!(out, "stp_error_replies_total{{code=\"{}\"}} {}", code, count);
+        }
+        let latency = &self.request_latency;
+        let _ = writeln!(out, "# HELP stp_request_duration_seconds Request handling time");
+        let _ = writeln!(out, "# TYPE stp_request_duration_seconds histogram");
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(latency.buckets) {
+            cumulative += count;
+            let _ = writeln!(out, "stp_request_duration_seconds_bucket{{le=\"{}\"}} {}", bound, cumulative);
+        }
+        let _ = writeln!(out, "stp_request_duration_seconds_bucket{{le=\"+Inf\"}} {}", latency.count);
+        let _ = writeln!(out, "stp_request_duration_seconds_sum {}", latency.sum.as_secs_f64());
+        let _ = writeln!(out, "stp_request_duration_seconds_count {}", latency.count);
+        out
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_prometheus() {
+        let metrics = ServerMetrics::new();
+        metrics.connection_accepted();
+        metrics.connection_accepted();
+        metrics.handshake_failed();
+        metrics.request("turn_on");
+        metrics.request("turn_on");
+        metrics.request(UNKNOWN_COMMAND);
+        metrics.error_reply(ErrorCode::BadRequest);
+        metrics.request_latency(Duration::from_micros(300));
+        metrics.request_latency(Duration::from_millis(7));
+        metrics.request_latency(Duration::from_secs(3));
+
+        let snapshot = metrics.clone().snapshot();
+        assert_eq!(snapshot.connections_accepted, 2);
+        assert_eq!(snapshot.requests["turn_on"], 2);
+        assert_eq!(snapshot.request_latency.count, 3);
+        assert_eq!(snapshot.request_latency.buckets[0], 1);
+        assert_eq!(snapshot.request_latency.buckets[4], 1);
+
+        let text = metrics.render_prometheus();
+        assert!(text.contains("# TYPE stp_requests_total counter\n"));
+        assert!(text.contains("stp_connections_accepted_total 2\n"));
+        assert!(text.contains("stp_handshake_failures_total 1\n"));
+        assert!(text.contains("stp_requests_total{command=\"turn_on\"} 2\n"));
+        assert!(text.contains("stp_requests_total{command=\"unknown\"} 1\n"));
+        assert!(text.contains("stp_error_replies_total{code=\"bad_request\"} 1\n"));
+        assert!(text.contains("stp_request_duration_seconds_bucket{le=\"0.0005\"} 1\n"));
+        assert!(text.contains("stp_request_duration_seconds_bucket{le=\"0.01\"} 2\n"));
+        assert!(text.contains("stp_request_duration_seconds_bucket{le=\"1\"} 2\n"));
+        assert!(text.contains("stp_request_duration_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(text.contains("stp_request_duration_seconds_count 3\n"));
+    }
+}
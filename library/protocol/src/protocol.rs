@@ -1,28 +1,223 @@
-use regex::Regex;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use bytes::BytesMut;
+use serde::{Deserialize, Serialize};
+use tokio_util::codec::Decoder;
+
+use crate::codec::StpCodec;
+use crate::errors::CodecError;
 
 pub const HANDSHAKE_REQUEST: &str = "hi_server";
 pub const HANDSHAKE_RESPOND: &str = "hi_client";
-pub const WRAPPING_SYMBOLS: &str = "@@";
+const HANDSHAKE_SEPARATOR: char = ';';
 
+/// Current protocol version, sent by both sides in handshake.
+/// Version 2 replies failed requests with [`FrameKind::Error`] frames.
+pub const PROTOCOL_VERSION: u16 = 2;
+/// Oldest protocol version this side still able to talk
+pub const MIN_PROTOCOL_VERSION: u16 = 2;
 
-//todo:  Добавить кастомные ошибки. сервер и клиент
-pub fn handshake_request_msg() -> String {
-    wrap_message(HANDSHAKE_REQUEST)
+/// Version of the frame layout, first byte of every frame
+pub const FRAME_VERSION: u8 = 2;
+/// Frame header: version byte + kind byte + payload length (u32, big endian)
+pub const FRAME_HEADER_LEN: usize = 6;
+
+/// Type of the frame payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FrameKind {
+    /// Handshake, request or response
+    Message,
+    /// Unsolicited notification pushed by the server
+    Event,
+    /// Keepalive request, answered with [`FrameKind::Pong`] carrying the same payload
+    Ping,
+    Pong,
+    /// Request failed, carries [`crate::message::ErrorReply`] instead of the response
+    Error,
+}
+
+impl FrameKind {
+    pub fn as_byte(&self) -> u8 {
+        match self {
+            FrameKind::Message => { 0 }
+            FrameKind::Event => { 1 }
+            FrameKind::Ping => { 2 }
+            FrameKind::Pong => { 3 }
+            FrameKind::Error => { 4 }
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(FrameKind::Message),
+            1 => Some(FrameKind::Event),
+            2 => Some(FrameKind::Ping),
+            3 => Some(FrameKind::Pong),
+            4 => Some(FrameKind::Error),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    pub payload: Vec<u8>,
+}
+
+/// Optional protocol features, negotiated in handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Capability {
+    Switch,
+    Power,
+    Subscribe,
+    /// Requests, responses and error replies are tagged with request id, so requests may be pipelined
+    Pipeline,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 4] = [Capability::Switch, Capability::Power, Capability::Subscribe, Capability::Pipeline];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Capability::Switch => { "switch" }
+            Capability::Power => { "power" }
+            Capability::Subscribe => { "subscribe" }
+            Capability::Pipeline => { "pipeline" }
+        }
+    }
+}
+
+impl Display for Capability {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for Capability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Capability::ALL.into_iter().find(|c| c.as_str() == s).ok_or(format!("Unknown capability `{}`", s))
+    }
+}
+
+/// Handshake message: `hi_server;<version>;<capability>,<capability>...`.
+/// Response has the same layout with `hi_client` prefix,
+/// followed by `;<nonce>` if the server requires authentication, see [`crate::auth`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u16,
+    pub capabilities: Vec<Capability>,
+    /// Hex encoded nonce to sign with the pre-shared key
+    pub challenge: Option<String>,
 }
 
-pub fn handshake_respond_msg() -> String {
-    wrap_message(HANDSHAKE_RESPOND)
+impl Hello {
+    pub fn new(capabilities: &[Capability]) -> Self {
+        Self { version: PROTOCOL_VERSION, capabilities: capabilities.to_vec(), challenge: None }
+    }
+
+    pub fn with_challenge(mut self, challenge: String) -> Self {
+        self.challenge = Some(challenge);
+        self
+    }
+
+    pub fn request_msg(&self) -> String {
+        self.encode(HANDSHAKE_REQUEST)
+    }
+
+    pub fn respond_msg(&self) -> String {
+        self.encode(HANDSHAKE_RESPOND)
+    }
+
+    pub fn parse_request(msg: &str) -> Result<Self, String> {
+        Self::decode(HANDSHAKE_REQUEST, msg)
+    }
+
+    pub fn parse_respond(msg: &str) -> Result<Self, String> {
+        Self::decode(HANDSHAKE_RESPOND, msg)
+    }
+
+    /// Common version and capabilities of both sides.
+    /// Error if version of the other side is not supported.
+    pub fn negotiate(&self, other: &Hello) -> Result<Hello, String> {
+        let version = self.version.min(other.version);
+        if version < MIN_PROTOCOL_VERSION {
+            return Err(format!("Unsupported protocol version {}, expected at least {}", version, MIN_PROTOCOL_VERSION));
+        }
+        let capabilities = self.capabilities.iter().filter(|c| other.capabilities.contains(c)).copied().collect();
+        Ok(Hello { version, capabilities, challenge: None })
+    }
+
+    fn encode(&self, prefix: &str) -> String {
+        let capabilities = self.capabilities.iter().map(Capability::as_str).collect::<Vec<_>>().join(",");
+        let msg = format!("{}{sep}{}{sep}{}", prefix, self.version, capabilities, sep = HANDSHAKE_SEPARATOR);
+        match &self.challenge {
+            Some(challenge) => { format!("{}{}{}", msg, HANDSHAKE_SEPARATOR, challenge) }
+            None => { msg }
+        }
+    }
+
+    fn decode(prefix: &str, msg: &str) -> Result<Self, String> {
+        let mut parts = msg.split(HANDSHAKE_SEPARATOR);
+        if parts.next() != Some(prefix) {
+            return Err(format!("Handshake `{}` expected, got `{}`", prefix, msg));
+        }
+        let version = parts.next()
+            .and_then(|v| v.parse::<u16>().ok())
+            .ok_or(format!("Bad protocol version in `{}`", msg))?;
+        // unknown capabilities are skipped, the other side may be newer
+        let capabilities = parts.next().unwrap_or("")
+            .split(',')
+            .filter_map(|c| c.parse::<Capability>().ok())
+            .collect();
+        let challenge = parts.next().filter(|c| !c.is_empty()).map(str::to_string);
+        Ok(Self { version, capabilities, challenge })
+    }
 }
 
-pub type UMsgResult = Result<Vec<String>, String>;
+//todo:  Добавить кастомные ошибки. сервер и клиент
+pub type UMsgResult = Result<Vec<Vec<u8>>, String>;
+
+/// Result of parsing single frame: frame and count of consumed bytes.
+/// `None` if buffer does not contain the whole frame yet.
+pub type UFrameResult = Result<Option<(Frame, usize)>, String>;
 
-pub fn unwrap_message(raw_msg: &str) -> UMsgResult {
-    // get text1,text2,... from @@text1@@@@text2@@ string
-    let re = Regex::new(r#"@@([^@]+)@@"#).unwrap();
-    let mut parsed_msgs: Vec<String> = Vec::new();
-    for msg_capture in re.captures_iter(raw_msg) {
-        let msg_body = msg_capture.get(1).map_or("", |m| m.as_str());
-        parsed_msgs.push(msg_body.to_string())
+/// Kind and payload length from the frame header, `None` if the header is incomplete
+pub fn parse_header(raw_msg: &[u8]) -> Result<Option<(FrameKind, usize)>, String> {
+    if raw_msg.len() < FRAME_HEADER_LEN {
+        return Ok(None);
+    }
+    if raw_msg[0] != FRAME_VERSION {
+        return Err(format!("Unsupported frame version {}", raw_msg[0]));
+    }
+    let kind = FrameKind::from_byte(raw_msg[1]).ok_or(format!("Unknown frame kind {}", raw_msg[1]))?;
+    let len = u32::from_be_bytes([raw_msg[2], raw_msg[3], raw_msg[4], raw_msg[5]]) as usize;
+    Ok(Some((kind, len)))
+}
+
+pub fn unwrap_frame(raw_msg: &[u8]) -> UFrameResult {
+    let Some((kind, len)) = parse_header(raw_msg)? else {
+        return Ok(None);
+    };
+    let frame_len = FRAME_HEADER_LEN + len;
+    if raw_msg.len() < frame_len {
+        return Ok(None);
+    }
+    let payload = raw_msg[FRAME_HEADER_LEN..frame_len].to_vec();
+    Ok(Some((Frame { kind, payload }, frame_len)))
+}
+
+pub fn unwrap_message(raw_msg: &[u8]) -> UMsgResult {
+    // get [payload1, payload2, ...] from |ver|kind|len|payload1|ver|kind|len|payload2|... bytes
+    let mut parsed_msgs: Vec<Vec<u8>> = Vec::new();
+    let mut rest = raw_msg;
+    while let Some((frame, consumed)) = unwrap_frame(rest)? {
+        parsed_msgs.push(frame.payload);
+        rest = &rest[consumed..];
     }
     if !parsed_msgs.is_empty() {
         Ok(parsed_msgs)
@@ -31,13 +226,54 @@ pub fn unwrap_message(raw_msg: &str) -> UMsgResult {
     }
 }
 
-pub fn wrap_message<Data: AsRef<str>>(msg: Data) -> String {
-    let mut wrapped = WRAPPING_SYMBOLS.to_string();
-    wrapped.insert_str(0, msg.as_ref().to_string().as_str());
-    wrapped.insert_str(0, WRAPPING_SYMBOLS);
+/// Stateful frame decoder of blocking streams, same decoding as [`StpCodec`].
+/// Accumulates bytes across reads and yields one frame per call, leftover bytes are kept for the next call.
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buff: BytesMut,
+    codec: StpCodec,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_frame_len(max_frame_len: usize) -> Self {
+        Self { buff: BytesMut::new(), codec: StpCodec::new().with_max_frame_len(max_frame_len) }
+    }
+
+    pub fn extend(&mut self, data: &[u8]) {
+        self.buff.extend_from_slice(data);
+    }
+
+    pub fn decode(&mut self) -> Result<Option<Frame>, CodecError> {
+        self.codec.decode(&mut self.buff)
+    }
+
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, String> {
+        self.decode().map_err(|e| e.to_string())
+    }
+
+    /// Count of received bytes not yet returned as frame
+    pub fn pending(&self) -> usize {
+        self.buff.len()
+    }
+}
+
+pub fn wrap_frame<Data: AsRef<[u8]>>(kind: FrameKind, msg: Data) -> Vec<u8> {
+    let msg = msg.as_ref();
+    let mut wrapped = Vec::with_capacity(FRAME_HEADER_LEN + msg.len());
+    wrapped.push(FRAME_VERSION);
+    wrapped.push(kind.as_byte());
+    wrapped.extend_from_slice(&(msg.len() as u32).to_be_bytes());
+    wrapped.extend_from_slice(msg);
     wrapped
 }
 
+pub fn wrap_message<Data: AsRef<[u8]>>(msg: Data) -> Vec<u8> {
+    wrap_frame(FrameKind::Message, msg)
+}
 
 #[cfg(test)]
 mod tests {
@@ -45,51 +281,142 @@ mod tests {
 
     #[test]
     fn check_wrapping() {
-        assert_eq!("@@abc@@", wrap_message("abc"));
+        assert_eq!(vec![FRAME_VERSION, 0, 0, 0, 0, 3, b'a', b'b', b'c'], wrap_message("abc"));
+        assert_eq!(vec![FRAME_VERSION, 1, 0, 0, 0, 1, b'e'], wrap_frame(FrameKind::Event, "e"));
+        assert_eq!(vec![FRAME_VERSION, 2, 0, 0, 0, 0], wrap_frame(FrameKind::Ping, ""));
     }
 
     #[test]
     fn check_handshake_request_msg() {
-        assert_eq!("@@hi_server@@", handshake_request_msg());
+        let hello = Hello { version: 1, capabilities: vec![Capability::Switch, Capability::Power], challenge: None };
+        assert_eq!("hi_server;1;switch,power", hello.request_msg());
+        assert_eq!(Ok(hello), Hello::parse_request("hi_server;1;switch,power"));
     }
 
     #[test]
     fn check_handshake_resp_msg() {
-        assert_eq!("@@hi_client@@", handshake_respond_msg());
+        let hello = Hello { version: 3, capabilities: vec![], challenge: None };
+        assert_eq!("hi_client;3;", hello.respond_msg());
+        assert_eq!(Ok(hello), Hello::parse_respond("hi_client;3;"));
+        let hello = Hello::new(&[Capability::Switch]).with_challenge("00ff".to_string());
+        assert_eq!(format!("hi_client;{};switch;00ff", PROTOCOL_VERSION), hello.respond_msg());
+        assert_eq!(Ok(hello.clone()), Hello::parse_respond(&hello.respond_msg()));
+        assert!(Hello::parse_respond("hi_server;3;").is_err());
+        assert!(Hello::parse_respond("hi_client").is_err());
+        assert!(Hello::parse_respond("hi_client;x;switch").is_err());
+    }
+
+    #[test]
+    fn check_handshake_unknown_capability() {
+        let hello = Hello::parse_request("hi_server;2;switch,teleport,subscribe").unwrap();
+        assert_eq!(hello.capabilities, vec![Capability::Switch, Capability::Subscribe]);
+    }
+
+    #[test]
+    fn check_negotiation() {
+        let client = Hello { version: PROTOCOL_VERSION + 1, capabilities: vec![Capability::Switch, Capability::Subscribe], challenge: None };
+        let server = Hello::new(&[Capability::Power, Capability::Switch]);
+        let negotiated = client.negotiate(&server).unwrap();
+        assert_eq!(negotiated.version, PROTOCOL_VERSION);
+        assert_eq!(negotiated.capabilities, vec![Capability::Switch]);
+
+        let outdated = Hello { version: MIN_PROTOCOL_VERSION - 1, capabilities: Capability::ALL.to_vec(), challenge: None };
+        assert!(server.negotiate(&outdated).is_err());
     }
 
 
     #[test]
     fn check_unwrap_once() {
-        if let Ok(msg) = unwrap_message("@@some_text@@") {
+        if let Ok(msg) = unwrap_message(&wrap_message("some_text")) {
             assert_eq!(msg.len(), 1);
-            assert_eq!(msg[0], "some_text".to_string());
+            assert_eq!(msg[0], b"some_text".to_vec());
         } else { panic!() }
     }
 
     #[test]
     fn check_unwrap_multiple() {
-        if let Ok(msg) = unwrap_message("@@some_text1@@@@some_text2@@@@some_text3@@") {
+        let raw = [wrap_message("some_text1"), wrap_message("some_text2"), wrap_message("some_text3")].concat();
+        if let Ok(msg) = unwrap_message(&raw) {
             assert_eq!(msg.len(), 3);
-            assert_eq!(msg[0], "some_text1".to_string());
-            assert_eq!(msg[1], "some_text2".to_string());
-            assert_eq!(msg[2], "some_text3".to_string());
+            assert_eq!(msg[0], b"some_text1".to_vec());
+            assert_eq!(msg[1], b"some_text2".to_vec());
+            assert_eq!(msg[2], b"some_text3".to_vec());
         } else { panic!() }
     }
 
     #[test]
     fn check_unwrap_corrupted_last() {
-        if let Ok(msg) = unwrap_message("@@some_text1@@@@some_text2@@@@some_text3@") {
+        let mut raw = [wrap_message("some_text1"), wrap_message("some_text2"), wrap_message("some_text3")].concat();
+        raw.pop();
+        if let Ok(msg) = unwrap_message(&raw) {
             assert_eq!(msg.len(), 2);
-            assert_eq!(msg[0], "some_text1".to_string());
-            assert_eq!(msg[1], "some_text2".to_string());
+            assert_eq!(msg[0], b"some_text1".to_vec());
+            assert_eq!(msg[1], b"some_text2".to_vec());
         } else { panic!() }
     }
 
     #[test]
     fn check_unwrap_parsing_err() {
-        if let Err(msg) = unwrap_message("@some_text1@@") {
+        if let Err(msg) = unwrap_message(&[FRAME_VERSION, 0, 0]) {
             assert_eq!(msg, "Parsing error".to_string());
+        } else { panic!() }
+    }
+
+    #[test]
+    fn check_unwrap_bad_version() {
+        assert!(unwrap_message(&[FRAME_VERSION + 1, 0, 0, 0, 0, 0]).is_err());
+        assert!(unwrap_message(&[FRAME_VERSION, 42, 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn check_payload_with_delimiters() {
+        let payloads: [&[u8]; 4] = [b"socket@kitchen", b"@@", "розетка".as_bytes(), &[0, 255, 64, 64, 0]];
+        for payload in payloads {
+            let msg = unwrap_message(&wrap_message(payload)).unwrap();
+            assert_eq!(msg, vec![payload.to_vec()]);
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn check_decoder_partial() {
+        let raw = wrap_message("partial message");
+        let mut decoder = FrameDecoder::new();
+        for byte in &raw[..raw.len() - 1] {
+            decoder.extend(&[*byte]);
+            assert_eq!(decoder.next_frame(), Ok(None));
+        }
+        decoder.extend(&raw[raw.len() - 1..]);
+        assert_eq!(decoder.next_frame(), Ok(Some(Frame { kind: FrameKind::Message, payload: b"partial message".to_vec() })));
+        assert_eq!(decoder.pending(), 0);
+    }
+
+    #[test]
+    fn check_decoder_coalesced() {
+        let second = wrap_message("second");
+        let raw = [wrap_frame(FrameKind::Event, "first"), second[..3].to_vec()].concat();
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&raw);
+        assert_eq!(decoder.next_frame(), Ok(Some(Frame { kind: FrameKind::Event, payload: b"first".to_vec() })));
+        assert_eq!(decoder.next_frame(), Ok(None));
+        assert_eq!(decoder.pending(), 3);
+        decoder.extend(&second[3..]);
+        assert_eq!(decoder.next_frame(), Ok(Some(Frame { kind: FrameKind::Message, payload: b"second".to_vec() })));
+        assert_eq!(decoder.next_frame(), Ok(None));
+    }
+
+    #[test]
+    fn check_decoder_large_frame() {
+        let payload = vec![b'x'; 10_000];
+        let mut decoder = FrameDecoder::new();
+        for chunk in wrap_message(&payload).chunks(1024) {
+            decoder.extend(chunk);
+        }
+        assert_eq!(decoder.next_frame().unwrap().unwrap().payload, payload);
+    }
+
+    #[test]
+    fn check_empty_payload() {
+        let msg = unwrap_message(&wrap_message("")).unwrap();
+        assert_eq!(msg, vec![Vec::<u8>::new()]);
+    }
+}
@@ -0,0 +1,193 @@
+use std::time::{Duration, Instant};
+
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::errors::ConnectError;
+
+/// Connection state of the reconnecting client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    /// Connection lost, `attempt` is the last reconnection attempt
+    Reconnecting { attempt: u32 },
+    /// All reconnection attempts failed. Attempts start over after the backoff delay.
+    Offline,
+}
+
+/// Exponential backoff with jitter between reconnection attempts
+#[derive(Debug, Clone, PartialEq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    /// At least `1.0`, set by [`Backoff::with_multiplier`]
+    multiplier: f64,
+    /// Delay is randomized in range `delay * (1 ± jitter)`, `0.0..=1.0`, set by [`Backoff::with_jitter`]
+    jitter: f64,
+    /// `None` - retry forever
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(5),
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_attempts: Some(8),
+        }
+    }
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self { initial, max, ..Self::default() }
+    }
+
+    /// Growth of the delay per attempt, values below `1.0` are taken as `1.0`
+    pub fn with_multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier.max(1.0);
+        self
+    }
+
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = if jitter.is_nan() { 0.0 } else { jitter.clamp(0.0, 1.0) };
+        self
+    }
+
+    pub fn multiplier(&self) -> f64 {
+        self.multiplier
+    }
+
+    pub fn jitter(&self) -> f64 {
+        self.jitter
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: Option<u32>) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// Delay before the attempt, attempts are counted from 1. Never longer than `max`.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exp = self.multiplier.powi(attempt.saturating_sub(1).min(i32::MAX as u32) as i32);
+        let max = self.max.as_secs_f64();
+        let delay = (self.initial.as_secs_f64() * exp).min(max);
+        let factor = 1.0 + self.jitter * (2.0 * random_unit() - 1.0);
+        // `max` itself may be beyond the range of `f64` seconds, e.g. `Duration::MAX`
+        Duration::try_from_secs_f64((delay * factor).clamp(0.0, max)).unwrap_or(self.max)
+    }
+
+    pub fn is_exhausted(&self, attempt: u32) -> bool {
+        self.max_attempts.is_some_and(|max| attempt > max)
+    }
+}
+
+/// Reconnection attempts of a client. The client never waits for the backoff delay,
+/// an attempt before the delay is over fails fast with [`ConnectError::Backoff`].
+#[derive(Debug, Default)]
+pub(crate) struct Attempts {
+    failed: u32,
+    retry_at: Option<Instant>,
+}
+
+impl Attempts {
+    /// Number of the next attempt, counted from 1
+    pub fn next(&self, now: Instant) -> Result<u32, ConnectError> {
+        match self.retry_at {
+            Some(retry_at) if retry_at > now => { Err(ConnectError::Backoff(retry_at - now)) }
+            _ => { Ok(self.failed + 1) }
+        }
+    }
+
+    pub fn succeeded(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Schedule the next attempt. `true` if the client gives up and goes offline,
+    /// attempts then start over after the delay.
+    pub fn failed(&mut self, backoff: &Backoff, error: &ConnectError, now: Instant) -> bool {
+        self.failed += 1;
+        self.retry_at = Some(now + backoff.delay(self.failed));
+        let offline = matches!(error, ConnectError::Unauthorized(_)) || backoff.is_exhausted(self.failed + 1);
+        if offline {
+            self.failed = 0;
+        }
+        offline
+    }
+}
+
+/// Random value in `0.0..=1.0`
+fn random_unit() -> f64 {
+    let mut bytes = [0u8; 4];
+    match SystemRandom::new().fill(&mut bytes) {
+        Ok(_) => { u32::from_be_bytes(bytes) as f64 / u32::MAX as f64 }
+        Err(_) => { 0.5 }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exponential_delay() {
+        let backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1)).with_jitter(0.0);
+        assert_eq!(backoff.delay(1), Duration::from_millis(100));
+        assert_eq!(backoff.delay(2), Duration::from_millis(200));
+        assert_eq!(backoff.delay(4), Duration::from_millis(800));
+        assert_eq!(backoff.delay(5), Duration::from_secs(1));
+        assert_eq!(backoff.delay(100), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn jitter_range() {
+        let backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1)).with_jitter(0.5);
+        for _ in 0..100 {
+            let delay = backoff.delay(1);
+            assert!(delay >= Duration::from_millis(50) && delay <= Duration::from_millis(150), "{:?}", delay);
+        }
+    }
+
+    #[test]
+    fn delay_within_max() {
+        let backoff = Backoff::new(Duration::from_secs(1), Duration::MAX).with_max_attempts(None);
+        assert!(backoff.delay(u32::MAX) > Duration::from_secs(1));
+        let backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1)).with_jitter(f64::NAN);
+        assert_eq!(backoff.jitter(), 0.0);
+        assert_eq!(backoff.delay(100), Duration::from_secs(1));
+        let backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1)).with_jitter(1.0);
+        assert!(backoff.delay(100) <= Duration::from_secs(1));
+        let backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1)).with_jitter(0.0).with_multiplier(-2.0);
+        assert_eq!(backoff.multiplier(), 1.0);
+        assert_eq!(backoff.delay(3), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn attempts_limit() {
+        let backoff = Backoff::default().with_max_attempts(Some(2));
+        assert!(!backoff.is_exhausted(2));
+        assert!(backoff.is_exhausted(3));
+        assert!(!Backoff::default().with_max_attempts(None).is_exhausted(u32::MAX));
+    }
+
+    #[test]
+    fn attempts_without_waiting() {
+        let backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1)).with_jitter(0.0).with_max_attempts(Some(2));
+        let refused = ConnectError::Io(std::io::ErrorKind::ConnectionRefused.into());
+        let now = Instant::now();
+        let mut attempts = Attempts::default();
+        assert_eq!(attempts.next(now).unwrap(), 1);
+        assert!(!attempts.failed(&backoff, &refused, now));
+        assert!(matches!(attempts.next(now), Err(ConnectError::Backoff(delay)) if delay == Duration::from_millis(100)));
+        let now = now + Duration::from_millis(100);
+        assert_eq!(attempts.next(now).unwrap(), 2);
+        assert!(attempts.failed(&backoff, &refused, now));
+        assert!(attempts.next(now).is_err());
+        assert_eq!(attempts.next(now + Duration::from_millis(200)).unwrap(), 1);
+        attempts.succeeded();
+        assert_eq!(attempts.next(now).unwrap(), 1);
+        assert!(attempts.failed(&backoff, &ConnectError::Unauthorized("key".to_string()), now));
+    }
+}
@@ -0,0 +1,266 @@
+use std::fmt::{Debug, Formatter};
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::{Frame, FrameDecoder, FrameKind};
+
+/// Direction of the recorded frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    ToServer,
+    ToClient,
+}
+
+/// Side of the connection the recorder is attached to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+impl Side {
+    /// Direction of frames sent by this side
+    pub(crate) fn written(&self) -> Direction {
+        match self {
+            Side::Client => { Direction::ToServer }
+            Side::Server => { Direction::ToClient }
+        }
+    }
+
+    pub(crate) fn read(&self) -> Direction {
+        match self {
+            Side::Client => { Direction::ToClient }
+            Side::Server => { Direction::ToServer }
+        }
+    }
+}
+
+/// One line of the recording file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedFrame {
+    /// Microseconds since the start of the recording
+    pub at_us: u64,
+    pub direction: Direction,
+    pub kind: FrameKind,
+    /// Hex encoded, request ids of pipelined sessions are binary
+    #[serde(with = "hex")]
+    pub payload: Vec<u8>,
+}
+
+impl RecordedFrame {
+    pub fn frame(&self) -> Frame {
+        Frame { kind: self.kind, payload: self.payload.clone() }
+    }
+
+    /// Keepalive frames depend on timing and are not replayed
+    pub fn is_heartbeat(&self) -> bool {
+        matches!(self.kind, FrameKind::Ping | FrameKind::Pong)
+    }
+}
+
+/// Frames of one connection in JSON lines format, handshake included
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Recording {
+    pub frames: Vec<RecordedFrame>,
+}
+
+impl Recording {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::read_from(BufReader::new(File::open(path)?))
+    }
+
+    pub fn read_from<R: BufRead>(reader: R) -> io::Result<Self> {
+        let mut frames = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            frames.push(serde_json::from_str(&line).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?);
+        }
+        Ok(Self { frames })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        for frame in &self.frames {
+            write_line(&mut out, frame)?;
+        }
+        out.flush()
+    }
+}
+
+fn write_line<W: Write>(out: &mut W, frame: &RecordedFrame) -> io::Result<()> {
+    serde_json::to_writer(&mut *out, frame)?;
+    out.write_all(b"\n")
+}
+
+/// Writes frames of a connection, see [`crate::client_std::ConnectOptions::with_recorder`].
+/// Frames are recorded after TLS decryption.
+/// Recording is best effort: write errors are ignored, undecodable bytes are skipped.
+#[derive(Clone)]
+pub struct Recorder {
+    side: Side,
+    state: Arc<Mutex<RecorderState>>,
+}
+
+impl Debug for Recorder {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Recorder").field("side", &self.side).finish_non_exhaustive()
+    }
+}
+
+struct RecorderState {
+    out: Box<dyn Write + Send>,
+    start: Instant,
+    to_server: FrameDecoder,
+    to_client: FrameDecoder,
+}
+
+impl Recorder {
+    /// Record to a new file
+    pub fn create<P: AsRef<Path>>(path: P, side: Side) -> io::Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?), side))
+    }
+
+    pub fn new<W: Write + Send + 'static>(out: W, side: Side) -> Self {
+        let state = RecorderState {
+            out: Box::new(out),
+            start: Instant::now(),
+            to_server: FrameDecoder::with_max_frame_len(usize::MAX),
+            to_client: FrameDecoder::with_max_frame_len(usize::MAX),
+        };
+        Self { side, state: Arc::new(Mutex::new(state)) }
+    }
+
+    /// Data read by the side of the recorder
+    pub(crate) fn read(&self, data: &[u8]) {
+        self.record(self.side.read(), data)
+    }
+
+    pub(crate) fn written(&self, data: &[u8]) {
+        self.record(self.side.written(), data)
+    }
+
+    fn record(&self, direction: Direction, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let state = &mut *state;
+        let decoder = match direction {
+            Direction::ToServer => { &mut state.to_server }
+            Direction::ToClient => { &mut state.to_client }
+        };
+        decoder.extend(data);
+        loop {
+            let frame = match decoder.decode() {
+                Ok(Some(frame)) => { frame }
+                Ok(None) => { break }
+                Err(_) => {
+                    *decoder = FrameDecoder::with_max_frame_len(usize::MAX);
+                    break;
+                }
+            };
+            let at_us = state.start.elapsed().as_micros() as u64;
+            let recorded = RecordedFrame { at_us, direction, kind: frame.kind, payload: frame.payload };
+            let _ = write_line(&mut state.out, &recorded).and_then(|_| state.out.flush());
+        }
+    }
+}
+
+/// Records every accepted connection to `<n>.stp.jsonl` file in the directory, `n` counts from 1
+#[derive(Debug)]
+pub(crate) struct RecordingDir {
+    dir: PathBuf,
+    accepted: AtomicU64,
+}
+
+impl RecordingDir {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir, accepted: AtomicU64::new(0) }
+    }
+
+    /// `None` if the file is not created, the connection is served without recording then
+    pub fn next_recorder(&self) -> Option<Recorder> {
+        let n = self.accepted.fetch_add(1, Ordering::Relaxed) + 1;
+        let path = self.dir.join(format!("{}.stp.jsonl", n));
+        match Recorder::create(&path, Side::Server) {
+            Ok(recorder) => { Some(recorder) }
+            Err(e) => {
+                log::warn!("recording to {} failed: {}", path.display(), e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client_std::ConnectOptions;
+    use crate::client_tokio::ClientStp;
+    use crate::handler::{AsyncHandler, HandlerResult};
+    use crate::message::{Request, Response};
+    use crate::server_tokio::{ServerStp, ShutdownHandle};
+    use crate::tls::{TlsClientConfig, TlsServerConfig};
+    use crate::tls::tests::test_pki;
+    use crate::transport::async_memory_listener;
+
+    use super::*;
+
+    struct Echo;
+
+    impl AsyncHandler for Echo {
+        async fn handle(&self, _request: Request) -> HandlerResult {
+            Ok(Response::Ok)
+        }
+    }
+
+    #[tokio::test]
+    async fn record_server_connections() {
+        let dir = std::env::temp_dir().join(format!("stp-recordings-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pki = test_pki();
+        let tls = TlsServerConfig::new(vec![pki.server_cert.clone()], pki.server_key.clone_key()).unwrap();
+        let (listener, connector) = async_memory_listener();
+        let shutdown = ShutdownHandle::new();
+        tokio::spawn(ServerStp::new(listener).with_tls(tls).with_recordings(&dir).serve(shutdown.clone(), Echo));
+        let options = ConnectOptions::new().with_tls(TlsClientConfig::new(vec![pki.ca.clone()], "localhost").unwrap());
+        for _ in 0..2 {
+            let mut client = ClientStp::connect_over(connector.connect().unwrap(), &options).await.unwrap();
+            assert_eq!(client.request(&Request::TurnOn).await.unwrap(), Response::Ok);
+        }
+        shutdown.shutdown();
+
+        // frames are recorded decrypted
+        let recording = Recording::load(dir.join("2.stp.jsonl")).unwrap();
+        let directions: Vec<_> = recording.frames.iter().map(|frame| frame.direction).collect();
+        assert_eq!(directions, [Direction::ToServer, Direction::ToClient, Direction::ToServer, Direction::ToClient]);
+        assert!(recording.frames[0].payload.starts_with(b"hi_server"));
+        assert!(recording.frames[2].payload.ends_with(Request::TurnOn.encode().as_bytes()));
+        let path = dir.join("copy.stp.jsonl");
+        recording.save(&path).unwrap();
+        assert_eq!(Recording::load(&path).unwrap(), recording);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn serve_when_recording_failed() {
+        let dir = std::env::temp_dir().join(format!("stp-missing-{}", std::process::id()));
+        let (listener, connector) = async_memory_listener();
+        let shutdown = ShutdownHandle::new();
+        tokio::spawn(ServerStp::new(listener).with_recordings(&dir).serve(shutdown.clone(), Echo));
+        let mut client = ClientStp::connect_over(connector.connect().unwrap(), &ConnectOptions::new()).await.unwrap();
+        assert_eq!(client.request(&Request::TurnOn).await.unwrap(), Response::Ok);
+        shutdown.shutdown();
+        assert!(!dir.exists());
+    }
+}
@@ -0,0 +1,200 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::ErrorKind;
+use std::time::Duration;
+
+use crate::client_std::{read_frame, send_frame};
+use crate::errors::RecvError;
+use crate::protocol::{Frame, FrameDecoder, FrameKind};
+use crate::record::{Recording, Side};
+use crate::transport::Transport;
+
+/// Time given to the peer for every expected frame
+pub const REPLAY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Recorded frame differs from the one sent by the peer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    /// Index of the frame in the recording
+    pub index: usize,
+    pub expected: Frame,
+    /// `None` if the peer sent nothing in [`REPLAY_TIMEOUT`] or closed the connection
+    pub actual: Option<Frame>,
+}
+
+impl Display for Mismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "frame {}: expected {}, got ", self.index, describe(&self.expected))?;
+        match &self.actual {
+            Some(actual) => { write!(f, "{}", describe(actual)) }
+            None => { write!(f, "nothing") }
+        }
+    }
+}
+
+fn describe(frame: &Frame) -> String {
+    format!("{:?} `{}`", frame.kind, String::from_utf8_lossy(&frame.payload).escape_debug())
+}
+
+#[derive(Debug, Default)]
+pub struct ReplayReport {
+    /// Frames of the peer compared with the recording
+    pub compared: usize,
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl ReplayReport {
+    pub fn is_ok(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+/// Play requests of the recording against a live server and compare responses, handshake included.
+/// Events and heartbeats are not compared. Sessions with authentication are not replayable, the challenge differs.
+pub fn replay_client<S: Transport>(recording: &Recording, stream: S) -> io::Result<ReplayReport> {
+    replay(recording, stream, Side::Client)
+}
+
+/// Fake server: answer the client with responses and events of the recording, comparing the client requests to the recorded ones
+pub fn replay_server<S: Transport>(recording: &Recording, stream: S) -> io::Result<ReplayReport> {
+    replay(recording, stream, Side::Server)
+}
+
+/// Frames of the peer compared with the recording
+fn is_compared(kind: FrameKind) -> bool {
+    matches!(kind, FrameKind::Message | FrameKind::Error)
+}
+
+fn replay<S: Transport>(recording: &Recording, mut stream: S, side: Side) -> io::Result<ReplayReport> {
+    stream.set_read_timeout(Some(REPLAY_TIMEOUT))?;
+    let mut decoder = FrameDecoder::with_max_frame_len(usize::MAX);
+    let mut report = ReplayReport::default();
+    for (index, recorded) in recording.frames.iter().enumerate() {
+        if recorded.is_heartbeat() {
+            continue;
+        }
+        if recorded.direction == side.written() {
+            // the client gets recorded events, the server pushes its own
+            if side == Side::Server || recorded.kind != FrameKind::Event {
+                send_frame(&mut stream, recorded.kind, &recorded.payload).map_err(|e| io::Error::other(e.to_string()))?;
+            }
+            continue;
+        }
+        if !is_compared(recorded.kind) {
+            continue;
+        }
+        let expected = recorded.frame();
+        let actual = next_compared(&mut stream, &mut decoder)?;
+        report.compared += 1;
+        match actual {
+            Some(actual) if actual == expected => {}
+            actual => {
+                let stop = actual.is_none();
+                report.mismatches.push(Mismatch { index, expected, actual });
+                if stop {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Next frame of the peer to compare, answering pings. `None` if the peer is silent or gone.
+fn next_compared<S: Transport>(stream: &mut S, decoder: &mut FrameDecoder) -> io::Result<Option<Frame>> {
+    loop {
+        let frame = match read_frame(&mut *stream, decoder) {
+            Ok(frame) => { frame }
+            Err(RecvError::Io(e)) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::BrokenPipe) => {
+                return Ok(None);
+            }
+            Err(RecvError::Io(e)) => { return Err(e) }
+            Err(e) => { return Err(io::Error::new(ErrorKind::InvalidData, e.to_string())) }
+        };
+        match frame.kind {
+            FrameKind::Ping => {
+                send_frame(&mut *stream, FrameKind::Pong, frame.payload).map_err(|e| io::Error::other(e.to_string()))?;
+            }
+            kind if is_compared(kind) => { return Ok(Some(frame)) }
+            _ => {}
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use crate::client_std::{ClientStp, ConnectOptions};
+    use crate::handler::{Handler, HandlerResult};
+    use crate::message::{ErrorCode, ErrorReply, Request, Response};
+    use crate::record::{Direction, Recorder};
+    use crate::server_std::ServerStp;
+    use crate::transport::{memory_listener, memory_pair};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Switch {
+        on: bool,
+    }
+
+    impl Handler for Switch {
+        fn handle(&mut self, request: Request) -> HandlerResult {
+            match request {
+                Request::TurnOn => {
+                    self.on = true;
+                    Ok(Response::Ok)
+                }
+                Request::GetState => { Ok(Response::State(self.on)) }
+                _ => { Err(ErrorReply::new(ErrorCode::Unsupported, "switch only")) }
+            }
+        }
+    }
+
+    fn session(client: &mut ClientStp<impl Transport>) {
+        assert_eq!(client.request(&Request::TurnOn).unwrap(), Response::Ok);
+        assert_eq!(client.request(&Request::GetState).unwrap(), Response::State(true));
+        assert_eq!(client.request(&Request::TurnOff).unwrap_err().remote().unwrap().code, ErrorCode::Unsupported);
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let (listener, connector) = memory_listener();
+        thread::spawn(move || ServerStp::new(listener).serve(Switch::default()));
+        let path = std::env::temp_dir().join(format!("stp-record-{}.stp.jsonl", std::process::id()));
+        let recorder = Recorder::create(&path, Side::Client).unwrap();
+        let mut client = ClientStp::connect_over(connector.connect().unwrap(), &ConnectOptions::new().with_recorder(recorder)).unwrap();
+        session(&mut client);
+        drop(client);
+        let recording = Recording::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        // handshake and three requests
+        assert_eq!(recording.frames.len(), 8);
+        assert_eq!(recording.frames[0].direction, Direction::ToServer);
+        assert_eq!(recording.frames[7].kind, FrameKind::Error);
+
+        let report = replay_client(&recording, connector.connect().unwrap()).unwrap();
+        assert!(report.is_ok(), "{:?}", report);
+        assert_eq!(report.compared, 4);
+
+        // the server turned off in the recording
+        let mut changed = recording.clone();
+        let request_id = changed.frames[5].payload[..4].to_vec();
+        changed.frames[5].payload = [request_id, Response::State(false).encode().into_bytes()].concat();
+        let report = replay_client(&changed, connector.connect().unwrap()).unwrap();
+        assert_eq!(report.mismatches.len(), 1);
+        assert_eq!(report.mismatches[0].index, 5);
+        assert!(report.mismatches[0].to_string().starts_with("frame 5: expected Message"));
+
+        // fake server answers the same session
+        let (client_end, server_end) = memory_pair();
+        let fake = thread::spawn(move || replay_server(&recording, server_end).unwrap());
+        let mut client = ClientStp::connect_over(client_end, &ConnectOptions::new()).unwrap();
+        session(&mut client);
+        let report = fake.join().unwrap();
+        assert!(report.is_ok(), "{:?}", report);
+        assert_eq!(report.compared, 4);
+    }
+}
@@ -1,14 +1,45 @@
 use std::io;
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
 use std::ops::Deref;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+#[cfg(unix)]
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Instant;
 
+use rustls::{ServerConnection, StreamOwned};
 use thiserror::Error;
 
-use crate::client_std::{read_srt, send_str};
-use crate::errors::{ConnectError, ConnectResult, RecvResult, SendResult};
+use crate::auth::PreSharedKey;
+use crate::client_std::{read_srt, recv_frame, send_frame, send_str};
+use crate::errors::{ConnectError, ConnectResult, RecvError, RecvResult, SendResult, TlsError};
+use crate::handler;
+use crate::handler::{Handler, HandlerResult, NOTIFY_PERIOD, Route};
+use crate::heartbeat::Heartbeat;
+use crate::limits;
+use crate::limits::{ConnectionLimits, PeerConnections, PeerGuard, TokenBucket};
+use crate::message::{ErrorReply, Event, Request, Response};
+use crate::metrics::{ServerMetrics, UNKNOWN_COMMAND};
+use crate::protocol::{Capability, Frame, FrameDecoder, FrameKind};
+use crate::record::RecordingDir;
+use crate::session::{HANDSHAKE_TIMEOUT, message_payload, Progress, ServerHandshake, Session, UNKNOWN_REQUEST_ID};
+use crate::stream::StpStream;
+use crate::subscription::Subscription;
+use crate::tls::TlsServerConfig;
+use crate::transport::{Listener, Transport};
 
-pub struct ServerStp {
-    tcp: TcpListener,
+pub struct ServerStp<L: Listener = TcpListener> {
+    listener: L,
+    capabilities: Vec<Capability>,
+    key: Option<PreSharedKey>,
+    tls: Option<TlsServerConfig>,
+    heartbeat: Option<Heartbeat>,
+    limits: ConnectionLimits,
+    peers: PeerConnections,
+    metrics: ServerMetrics,
+    recordings: Option<RecordingDir>,
 }
 
 impl ServerStp {
@@ -16,30 +47,171 @@ impl ServerStp {
     where
         Addr: ToSocketAddrs,
     {
-        let tcp = TcpListener::bind(addr)?;
-        Ok(Self { tcp })
+        Ok(Self::new(TcpListener::bind(addr)?))
     }
-    pub fn incoming(&self) -> impl Iterator<Item=ConnectResult<StpConnection>> + '_ {
-        self.tcp.incoming().map(|s| {
+
+    /// Accept TLS connections only, STP handshake goes inside the encrypted stream
+    pub fn bind_tls<Addr>(addr: Addr, tls: TlsServerConfig) -> BindResult
+    where
+        Addr: ToSocketAddrs,
+    {
+        Ok(Self::bind(addr)?.with_tls(tls))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+#[cfg(unix)]
+impl ServerStp<UnixListener> {
+    /// Serve local clients over Unix domain socket
+    pub fn bind_unix<P: AsRef<Path>>(path: P) -> BindResult<UnixListener> {
+        Ok(Self::new(UnixListener::bind(path)?))
+    }
+}
+
+impl<L: Listener> ServerStp<L> {
+    /// Serve connections accepted by the listener, e.g. in-memory one for tests
+    pub fn new(listener: L) -> Self {
+        Self {
+            listener,
+            capabilities: Capability::ALL.to_vec(),
+            key: None,
+            tls: None,
+            heartbeat: None,
+            limits: ConnectionLimits::default(),
+            peers: PeerConnections::default(),
+            metrics: ServerMetrics::new(),
+            recordings: None,
+        }
+    }
+
+    /// Accept TLS connections only
+    pub fn with_tls(mut self, tls: TlsServerConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Restrict capabilities offered to clients in handshake
+    pub fn with_capabilities(mut self, capabilities: &[Capability]) -> Self {
+        self.capabilities = capabilities.to_vec();
+        self
+    }
+
+    /// Require clients to prove the knowledge of the device key in handshake
+    pub fn with_key(mut self, key: PreSharedKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// Ping idle clients while receiving requests, see [`StpConnection::revc_request`]
+    pub fn with_heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+        self.heartbeat = Some(heartbeat);
+        self
+    }
+
+    /// Limit frame size, requests rate and connections per IP of clients
+    pub fn with_connection_limits(mut self, limits: ConnectionLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Record every connection to `<n>.stp.jsonl` file in the directory, `n` counts from 1.
+    /// A connection is served without recording if its file is not created.
+    pub fn with_recordings<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.recordings = Some(RecordingDir::new(dir.into()));
+        self
+    }
+
+    /// Counters of connections and requests, updated while serving
+    pub fn metrics(&self) -> ServerMetrics {
+        self.metrics.clone()
+    }
+
+    pub fn incoming(&self) -> impl Iterator<Item=ConnectResult<StpConnection<L::Stream>>> + '_ {
+        std::iter::repeat_with(|| self.listener.accept()).map(|s| {
             match s {
-                Ok(s) => Self::try_handshake(s),
+                Ok(s) => self.try_handshake(s),
                 Err(e) => Err(ConnectError::Io(e)),
             }
         })
     }
 
-    pub fn try_handshake(stream: TcpStream) -> ConnectResult<StpConnection> {
-        let handshake_req_msg = read_srt(&stream).map_err(|e| ConnectError::BadHandshake(e.to_string()))?;
-        if !handshake_req_msg.eq(crate::protocol::HANDSHAKE_REQUEST) {
-            return Err(ConnectError::BadHandshake("Handshake request not matched".to_string()));
+    /// Clients above [`ConnectionLimits::max_connections_per_ip`] get the error reply and fail with [`ConnectError::Rejected`]
+    pub fn try_handshake(&self, stream: L::Stream) -> ConnectResult<StpConnection<L::Stream>> {
+        self.metrics.connection_accepted();
+        let connection = self.handshake(stream);
+        if connection.is_err() {
+            self.metrics.handshake_failed();
+        }
+        connection
+    }
+
+    fn handshake(&self, stream: L::Stream) -> ConnectResult<StpConnection<L::Stream>> {
+        let peer_ip = L::peer_ip(&stream);
+        let stream = match &self.tls {
+            Some(tls) => {
+                let connection = ServerConnection::new(tls.config()).map_err(TlsError::from)?;
+                StpStream::TlsServer(Box::new(StreamOwned::new(connection, stream)))
+            }
+            None => { StpStream::Plain(stream) }
+        };
+        let mut stream = match self.recordings.as_ref().and_then(RecordingDir::next_recorder) {
+            Some(recorder) => { stream.recorded(recorder) }
+            None => { stream }
+        };
+        let prev_timeout = stream.get_ref().read_timeout()?;
+        stream.get_ref().set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let peer = match self.peers.admit(peer_ip, self.limits.max_connections_per_ip) {
+            Ok(peer) => { peer }
+            Err(reply) => {
+                let _ = send_frame(&mut stream, FrameKind::Error, reply.encode());
+                return Err(ConnectError::Rejected(reply));
+            }
+        };
+        let mut decoder = FrameDecoder::with_max_frame_len(self.limits.max_frame_len);
+        let mut handshake = ServerHandshake::new(&self.capabilities, self.key.clone());
+        let hello = loop {
+            let msg = read_srt(&mut stream, &mut decoder).map_err(|e| ConnectError::BadHandshake(e.to_string()))?;
+            let step = handshake.on_message(&msg);
+            if let Some(reply) = step.send {
+                let _ = send_str(&mut stream, reply);
+            }
+            if let Progress::Done(hello) = step.progress? {
+                break hello;
+            }
+        };
+        stream.get_ref().set_read_timeout(prev_timeout)?;
+        let session = Session::new(hello, self.heartbeat);
+        let rate = self.limits.rate_limit.map(|limit| TokenBucket::new(limit, Instant::now()));
+        Ok(StpConnection {
+            stream,
+            decoder,
+            session,
+            request_id: UNKNOWN_REQUEST_ID,
+            subscription: None,
+            rate,
+            _peer: peer,
+            metrics: self.metrics.clone(),
+            request_started: None,
+        })
+    }
+
+    /// Serve clients one by one with the handler until the listener fails.
+    /// Failed handshakes are skipped, the client is disconnected on a receive error.
+    pub fn serve<H: Handler>(&self, mut handler: H) -> io::Result<()> {
+        loop {
+            let stream = self.listener.accept()?;
+            if let Ok(connection) = self.try_handshake(stream) {
+                serve_connection(connection, &mut handler);
+            }
         }
-        let _ = send_str(&stream, crate::protocol::HANDSHAKE_RESPOND);
-        Ok(StpConnection { stream })
     }
 }
 
 
-type BindResult = Result<ServerStp, BindError>;
+type BindResult<L = TcpListener> = Result<ServerStp<L>, BindError>;
 
 /// Bind to socket error
 #[derive(Debug, Error)]
@@ -48,24 +220,445 @@ pub enum BindError {
     Io(#[from] io::Error),
 }
 
-pub struct StpConnection {
-    stream: TcpStream,
+pub struct StpConnection<S: Transport = TcpStream> {
+    stream: StpStream<S>,
+    decoder: FrameDecoder,
+    session: Session,
+    /// Id of the last received request, echoed in the response
+    request_id: u32,
+    subscription: Option<Subscription>,
+    rate: Option<TokenBucket>,
+    /// Counted in connections of the peer IP while open
+    _peer: Option<PeerGuard>,
+    metrics: ServerMetrics,
+    /// Receiving time of the request awaiting the response
+    request_started: Option<Instant>,
 }
 
-impl StpConnection {
+impl<S: Transport> StpConnection<S> {
+    /// Protocol version agreed with the client
+    pub fn protocol_version(&self) -> u16 {
+        self.session.hello().version
+    }
+
+    /// Capabilities supported by both client and server
+    pub fn capabilities(&self) -> &[Capability] {
+        &self.session.hello().capabilities
+    }
+
+    pub fn has_capability(&self, capability: Capability) -> bool {
+        self.session.hello().capabilities.contains(&capability)
+    }
+
+    pub fn is_tls(&self) -> bool {
+        self.stream.is_tls()
+    }
+
+    /// Response to the last received request
     pub fn send_response<Resp: AsRef<str>>(&mut self, response: Resp) -> SendResult {
-        send_str(&mut self.stream, response)
+        self.send_tagged(Frame { kind: FrameKind::Message, payload: response.as_ref().as_bytes().to_vec() })
     }
 
+    /// Wait for the next request, answering pings of the client.
+    /// With heartbeat, fails with [`RecvError::PeerTimeout`] and closes the connection when the client stops answering.
+    /// Closes the connection breaking [`ConnectionLimits`] after the error reply.
     pub fn revc_request(&mut self) -> RecvResult {
-        read_srt(&mut self.stream)
+        self.request_id = UNKNOWN_REQUEST_ID;
+        let frame = match recv_frame(&mut self.stream, &mut self.decoder, &mut self.session) {
+            Ok(frame) => { frame }
+            Err(RecvError::FrameTooLarge { len, max }) => { return Err(self.reject(&limits::frame_too_large(len, max), RecvError::FrameTooLarge { len, max })) }
+            Err(e) => { return Err(e) }
+        };
+        let (id, frame) = self.session.untag(frame)?;
+        self.request_id = id.unwrap_or(UNKNOWN_REQUEST_ID);
+        if self.rate.as_mut().is_some_and(|rate| !rate.try_take(Instant::now())) {
+            return Err(self.reject(&limits::rate_limited(), RecvError::RateLimited));
+        }
+        self.request_started = Some(Instant::now());
+        message_payload(frame)
+    }
+
+    /// Send the error reply and close the connection
+    fn reject(&mut self, reply: &ErrorReply, e: RecvError) -> RecvError {
+        let _ = self.reply_error(reply);
+        let _ = self.stream.get_ref().shutdown();
+        e
+    }
+
+    /// Received requests are counted in [`ServerMetrics`] by command
+    pub fn recv(&mut self) -> Result<Request, RecvError> {
+        let req = self.revc_request()?;
+        let request = Request::decode(&req);
+        self.metrics.request(request.as_ref().map_or(UNKNOWN_COMMAND, Request::command));
+        request
+    }
+
+    pub fn reply(&mut self, response: &Response) -> SendResult {
+        self.send_response(response.encode())
+    }
+
+    /// Report the failed request instead of the response
+    pub fn reply_error(&mut self, error: &ErrorReply) -> SendResult {
+        self.metrics.error_reply(error.code);
+        self.send_tagged(Frame { kind: FrameKind::Error, payload: error.encode().into_bytes() })
+    }
+
+    fn send_tagged(&mut self, frame: Frame) -> SendResult {
+        if let Some(started) = self.request_started.take() {
+            self.metrics.request_latency(started.elapsed());
+        }
+        let frame = self.session.tag(self.request_id, frame);
+        send_frame(&mut self.stream, frame.kind, frame.payload)
+    }
+
+    /// Start pushing events to the client, see [`StpConnection::notify`]
+    pub fn subscribe(&mut self, power_threshold_wt: f32) {
+        self.subscription = Some(Subscription::new(power_threshold_wt));
+    }
+
+    pub fn unsubscribe(&mut self) {
+        self.subscription = None;
+    }
+
+    pub fn is_subscribed(&self) -> bool {
+        self.subscription.is_some()
+    }
+
+    /// Report current device values. Pushes events to the subscribed client if values changed.
+    pub fn notify(&mut self, state: bool, power_wt: Option<f32>) -> SendResult {
+        let events = match self.subscription.as_mut() {
+            Some(subscription) => { subscription.update(state, power_wt) }
+            None => { return Ok(()) }
+        };
+        for event in events {
+            self.send_event(&event)?;
+        }
+        Ok(())
+    }
+
+    pub fn send_event(&mut self, event: &Event) -> SendResult {
+        send_frame(&mut self.stream, FrameKind::Event, event.encode())
+    }
+
+    fn route<H: Handler>(&mut self, request: Request, handler: &mut H) -> HandlerResult {
+        match Route::from(request) {
+            Route::Device(request) => { handler.handle(request) }
+            Route::Subscribe(power_threshold_wt) if self.has_capability(Capability::Subscribe) => {
+                self.subscribe(power_threshold_wt);
+                Ok(Response::Ok)
+            }
+            Route::Subscribe(_) => { Err(handler::subscribe_not_negotiated()) }
+            Route::Unsubscribe => {
+                self.unsubscribe();
+                Ok(Response::Ok)
+            }
+        }
+    }
+}
+
+fn serve_connection<S: Transport, H: Handler>(mut connection: StpConnection<S>, handler: &mut H) {
+    // wake up periodically to push events to the subscribed client
+    if connection.set_read_timeout(Some(NOTIFY_PERIOD)).is_err() {
+        return;
+    }
+    loop {
+        let response = match connection.recv() {
+            Ok(request) => { Some(connection.route(request, handler)) }
+            Err(RecvError::BadMessage(e)) => { Some(Err(handler::unknown_request(&e))) }
+            Err(RecvError::Io(e)) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => { None }
+            Err(_) => { return }
+        };
+        let sent = match response {
+            Some(Ok(response)) => { connection.reply(&response) }
+            Some(Err(error)) => { connection.reply_error(&error) }
+            None => { Ok(()) }
+        };
+        if sent.is_err() {
+            return;
+        }
+        if !connection.is_subscribed() {
+            continue;
+        }
+        if let Some(state) = handler.state() {
+            if connection.notify(state.on, state.power_wt).is_err() {
+                return;
+            }
+        }
     }
 }
 
-impl Deref for StpConnection {
-    type Target = TcpStream;
+impl<S: Transport> Deref for StpConnection<S> {
+    type Target = S;
 
     fn deref(&self) -> &Self::Target {
-        &self.stream
+        self.stream.get_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::client_std::{ClientStp, ConnectOptions};
+    use crate::handler::DeviceState;
+    use crate::limits::RateLimit;
+    use crate::message::{ErrorCode, Event};
+    use crate::protocol;
+    use crate::transport::memory_listener;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Lamp {
+        on: bool,
     }
-}
\ No newline at end of file
+
+    impl Handler for Lamp {
+        fn handle(&mut self, request: Request) -> HandlerResult {
+            match request {
+                Request::TurnOn => {
+                    self.on = true;
+                    Ok(Response::Ok)
+                }
+                Request::GetState => { Ok(Response::State(self.on)) }
+                _ => { Err(ErrorReply::new(ErrorCode::Unsupported, "dimming")) }
+            }
+        }
+
+        fn state(&mut self) -> Option<DeviceState> {
+            Some(DeviceState { on: self.on, power_wt: None })
+        }
+    }
+
+    #[test]
+    fn negotiate_capabilities() {
+        let server = ServerStp::bind("127.0.0.1:0").unwrap().with_capabilities(&[Capability::Switch, Capability::Power]);
+        let addr = server.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let connection = server.incoming().next().unwrap().unwrap();
+            connection.capabilities().to_vec()
+        });
+        let client = ClientStp::connect_with_capabilities(addr, &[Capability::Power, Capability::Subscribe]).unwrap();
+        assert_eq!(client.protocol_version(), protocol::PROTOCOL_VERSION);
+        assert_eq!(client.capabilities(), &[Capability::Power]);
+        assert_eq!(handle.join().unwrap(), vec![Capability::Power]);
+    }
+
+    #[test]
+    fn push_events() {
+        let server = ServerStp::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let mut connection = server.incoming().next().unwrap().unwrap();
+            assert_eq!(connection.recv().unwrap(), Request::Subscribe { power_threshold_wt: 50.0 });
+            connection.subscribe(50.0);
+            connection.reply(&Response::Ok).unwrap();
+            connection.notify(false, Some(0.0)).unwrap();
+            // pushed before the response of the next request
+            assert_eq!(connection.recv().unwrap(), Request::TurnOn);
+            connection.notify(true, Some(20.0)).unwrap();
+            connection.reply(&Response::Ok).unwrap();
+            connection.notify(true, Some(2000.0)).unwrap();
+        });
+        let mut client = ClientStp::connect(addr).unwrap();
+        assert_eq!(client.request(&Request::Subscribe { power_threshold_wt: 50.0 }).unwrap(), Response::Ok);
+        assert_eq!(client.next_event(None).unwrap(), Some(Event::StateChanged(false)));
+        assert_eq!(client.next_event(None).unwrap(), Some(Event::PowerChanged(Some(0.0))));
+        assert_eq!(client.request(&Request::TurnOn).unwrap(), Response::Ok);
+        assert_eq!(client.try_event(), Some(Event::StateChanged(true)));
+        assert_eq!(client.try_event(), None);
+        assert_eq!(client.next_event(Some(Duration::from_secs(1))).unwrap(), Some(Event::PowerChanged(Some(2000.0))));
+        handle.join().unwrap();
+        assert!(client.next_event(Some(Duration::from_millis(50))).is_err());
+    }
+
+    #[test]
+    fn reject_unsupported_version() {
+        let server = ServerStp::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            server.incoming().next().unwrap().map(|_| ())
+        });
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let outdated = format!("hi_server;{};switch", protocol::MIN_PROTOCOL_VERSION - 1);
+        stream.write_all(&protocol::wrap_message(outdated)).unwrap();
+        assert!(matches!(handle.join().unwrap(), Err(ConnectError::BadHandshake(_))));
+    }
+
+    #[test]
+    fn reject_garbage_handshake() {
+        let server = ServerStp::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let metrics = server.metrics();
+        let handle = thread::spawn(move || {
+            server.incoming().next().unwrap().map(|_| ())
+        });
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(&protocol::wrap_message("hello")).unwrap();
+        assert!(matches!(handle.join().unwrap(), Err(ConnectError::BadHandshake(_))));
+        assert_eq!(metrics.snapshot().handshake_failures, 1);
+    }
+
+    #[test]
+    fn authenticate_with_key() {
+        let key = PreSharedKey::new("kitchen socket key");
+        let server = ServerStp::bind("127.0.0.1:0").unwrap().with_key(key.clone());
+        let addr = server.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            server.incoming().take(3).map(|c| c.map(|_| ())).collect::<Vec<_>>()
+        });
+        let client = ClientStp::connect_with(addr, &ConnectOptions::new().with_key(key)).unwrap();
+        assert_eq!(client.capabilities(), &Capability::ALL);
+        let wrong_key = ConnectOptions::new().with_key(PreSharedKey::new("guess"));
+        assert!(matches!(ClientStp::connect_with(addr, &wrong_key), Err(ConnectError::Unauthorized(_))));
+        assert!(matches!(ClientStp::connect(addr), Err(ConnectError::Unauthorized(_))));
+        let results = handle.join().unwrap();
+        assert!(results[0].is_ok());
+        assert!(matches!(results[1], Err(ConnectError::Unauthorized(_))));
+        // client without key drops the connection on the challenge
+        assert!(results[2].is_err());
+    }
+
+    #[test]
+    fn serve_in_memory() {
+        let (listener, connector) = memory_listener();
+        let server = ServerStp::new(listener).with_capabilities(&[Capability::Switch]);
+        let handle = thread::spawn(move || {
+            let mut connection = server.incoming().next().unwrap().unwrap();
+            assert_eq!(connection.recv().unwrap(), Request::TurnOff);
+            connection.reply(&Response::Ok).unwrap();
+        });
+        let mut client = ClientStp::connect_over(connector.connect().unwrap(), &ConnectOptions::new()).unwrap();
+        assert_eq!(client.capabilities(), &[Capability::Switch]);
+        assert_eq!(client.request(&Request::TurnOff).unwrap(), Response::Ok);
+        handle.join().unwrap();
+        assert!(client.next_event(Some(Duration::from_millis(50))).is_err());
+    }
+
+    fn fast_heartbeat() -> Heartbeat {
+        Heartbeat::new(Duration::from_millis(20), 2)
+    }
+
+    #[test]
+    fn heartbeat_keeps_idle_connection() {
+        let (listener, connector) = memory_listener();
+        let server = ServerStp::new(listener).with_heartbeat(fast_heartbeat());
+        let handle = thread::spawn(move || {
+            let mut connection = server.incoming().next().unwrap().unwrap();
+            assert_eq!(connection.recv().unwrap(), Request::GetState);
+            connection.reply(&Response::State(true)).unwrap();
+        });
+        let options = ConnectOptions::new().with_heartbeat(fast_heartbeat());
+        let mut client = ClientStp::connect_over(connector.connect().unwrap(), &options).unwrap();
+        assert_eq!(client.next_event(Some(Duration::from_millis(200))).unwrap(), None);
+        assert_eq!(client.request(&Request::GetState).unwrap(), Response::State(true));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn detect_dead_client() {
+        let (listener, connector) = memory_listener();
+        let server = ServerStp::new(listener).with_heartbeat(fast_heartbeat());
+        let handle = thread::spawn(move || {
+            let mut connection = server.incoming().next().unwrap().unwrap();
+            connection.recv()
+        });
+        // client does not read, so pings stay unanswered
+        let mut client = ClientStp::connect_over(connector.connect().unwrap(), &ConnectOptions::new()).unwrap();
+        assert!(matches!(handle.join().unwrap(), Err(RecvError::PeerTimeout(2))));
+        assert!(client.next_event(None).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn serve_unix_socket() {
+        let path = std::env::temp_dir().join(format!("stp-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let server = ServerStp::bind_unix(&path).unwrap();
+        let handle = thread::spawn(move || {
+            let mut connection = server.incoming().next().unwrap().unwrap();
+            assert_eq!(connection.recv().unwrap(), Request::GetState);
+            connection.reply(&Response::State(true)).unwrap();
+        });
+        let mut client = ClientStp::connect_unix(&path, &ConnectOptions::new()).unwrap();
+        assert_eq!(client.request(&Request::GetState).unwrap(), Response::State(true));
+        handle.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn serve_with_handler() {
+        let (listener, connector) = memory_listener();
+        let server = ServerStp::new(listener);
+        let metrics = server.metrics();
+        let handle = thread::spawn(move || server.serve(Lamp::default()));
+        let mut client = ClientStp::connect_over(connector.connect().unwrap(), &ConnectOptions::new()).unwrap();
+        assert_eq!(client.request(&Request::Subscribe { power_threshold_wt: 10.0 }).unwrap(), Response::Ok);
+        assert_eq!(client.next_event(Some(Duration::from_secs(1))).unwrap(), Some(Event::StateChanged(false)));
+        assert_eq!(client.request(&Request::TurnOn).unwrap(), Response::Ok);
+        assert_eq!(client.request(&Request::GetState).unwrap(), Response::State(true));
+        let err = client.send_request("dim").unwrap_err();
+        assert_eq!(err.remote().unwrap().code, ErrorCode::BadRequest);
+        assert_eq!(client.request(&Request::TurnOff).unwrap_err().remote().unwrap().code, ErrorCode::Unsupported);
+        drop(client);
+        // served one by one, the next client gets the same device
+        let options = ConnectOptions::new().with_capabilities(&[Capability::Switch]);
+        let mut client = ClientStp::connect_over(connector.connect().unwrap(), &options).unwrap();
+        assert_eq!(client.request(&Request::GetState).unwrap(), Response::State(true));
+        let err = client.request(&Request::Subscribe { power_threshold_wt: 10.0 }).unwrap_err();
+        assert_eq!(err.remote().unwrap().code, ErrorCode::Unsupported);
+        drop(client);
+        drop(connector);
+        assert_eq!(handle.join().unwrap().unwrap_err().kind(), ErrorKind::ConnectionAborted);
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.connections_accepted, 2);
+        assert_eq!(snapshot.handshake_failures, 0);
+        assert_eq!(snapshot.requests["subscribe"], 2);
+        assert_eq!(snapshot.requests["get_state"], 2);
+        assert_eq!(snapshot.requests[UNKNOWN_COMMAND], 1);
+        assert_eq!(snapshot.error_replies["unsupported"], 2);
+        assert_eq!(snapshot.error_replies["bad_request"], 1);
+        assert_eq!(snapshot.request_latency.count, 7);
+    }
+
+    #[test]
+    fn enforce_limits() {
+        let (listener, connector) = memory_listener();
+        let limits = ConnectionLimits::new().with_max_frame_len(64).with_rate_limit(RateLimit::new(0.01).unwrap().with_burst(2).unwrap());
+        thread::spawn(move || ServerStp::new(listener).with_connection_limits(limits).serve(Lamp::default()));
+        let mut client = ClientStp::connect_over(connector.connect().unwrap(), &ConnectOptions::new()).unwrap();
+        let err = client.send_request("x".repeat(100)).unwrap_err();
+        assert_eq!(err.remote().unwrap().code, ErrorCode::LimitExceeded);
+        assert!(client.request(&Request::GetState).unwrap_err().is_connection_lost());
+
+        let mut client = ClientStp::connect_over(connector.connect().unwrap(), &ConnectOptions::new()).unwrap();
+        assert_eq!(client.request(&Request::TurnOn).unwrap(), Response::Ok);
+        assert_eq!(client.request(&Request::GetState).unwrap(), Response::State(true));
+        let err = client.request(&Request::GetState).unwrap_err();
+        assert_eq!(err.remote().unwrap().code, ErrorCode::LimitExceeded);
+        assert!(client.request(&Request::GetState).unwrap_err().is_connection_lost());
+    }
+
+    #[test]
+    fn limit_connections_per_ip() {
+        let server = ServerStp::bind("127.0.0.1:0").unwrap().with_connection_limits(ConnectionLimits::new().with_max_connections_per_ip(1));
+        let addr = server.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let first = server.incoming().next().unwrap().unwrap();
+            assert!(matches!(server.incoming().next().unwrap(), Err(ConnectError::Rejected(_))));
+            drop(first);
+            assert!(server.incoming().next().unwrap().is_ok());
+        });
+        let first = ClientStp::connect(addr).unwrap();
+        match ClientStp::connect(addr) {
+            Err(ConnectError::Rejected(reply)) => { assert_eq!(reply.code, ErrorCode::LimitExceeded) }
+            other => { panic!("rejection expected, got {:?}", other.map(|_| ())) }
+        }
+        drop(first);
+        ClientStp::connect(addr).unwrap();
+        handle.join().unwrap();
+    }
+}
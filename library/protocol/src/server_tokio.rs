@@ -1,14 +1,57 @@
+use std::future::Future;
 use std::io;
+use std::net::SocketAddr;
 use std::ops::Deref;
+#[cfg(unix)]
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
+use futures::{SinkExt, stream, Stream};
 use thiserror::Error;
 use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::sync::{Semaphore, watch};
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::codec::Framed;
 
-use crate::client_tokio::{read_srt, send_str};
-use crate::errors::{ConnectError, ConnectResult, RecvResult, SendResult};
+use crate::auth::PreSharedKey;
+use crate::client_tokio::{handshake_message, recv_frame, send_message};
+use crate::codec::StpCodec;
+use crate::errors::{ConnectError, ConnectResult, RecvError, RecvResult, SendResult};
+use crate::handler;
+use crate::handler::{AsyncHandler, HandlerResult, NOTIFY_PERIOD, Route};
+use crate::heartbeat::Heartbeat;
+use crate::limits;
+use crate::limits::{ConnectionLimits, PeerConnections, PeerGuard, TokenBucket};
+use crate::message::{ErrorReply, Event, Request, Response};
+use crate::metrics::{ServerMetrics, UNKNOWN_COMMAND};
+use crate::protocol::{Capability, Frame, FrameKind};
+use crate::record::RecordingDir;
+use crate::session::{message_payload, Progress, ServerHandshake, Session, UNKNOWN_REQUEST_ID};
+use crate::stream::AsyncStpStream;
+use crate::subscription::Subscription;
+use crate::tls::TlsServerConfig;
+use crate::transport::{AsyncListener, AsyncTransport};
 
-pub struct ServerStp {
-    tcp: TcpListener,
+/// Pause after a failed accept before the next one
+pub const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+pub struct ServerStp<L: AsyncListener = TcpListener> {
+    listener: L,
+    capabilities: Vec<Capability>,
+    key: Option<PreSharedKey>,
+    tls: Option<TlsAcceptor>,
+    heartbeat: Option<Heartbeat>,
+    limits: ServerLimits,
+    connection_limits: ConnectionLimits,
+    peers: PeerConnections,
+    metrics: ServerMetrics,
+    recordings: Option<RecordingDir>,
 }
 
 impl ServerStp {
@@ -16,27 +59,302 @@ impl ServerStp {
     where
         Addr: ToSocketAddrs,
     {
-        let tcp = TcpListener::bind(addr).await?;
-        Ok(Self { tcp })
+        Ok(Self::new(TcpListener::bind(addr).await?))
     }
 
-    pub async fn incoming(&self) -> ConnectResult<StpConnection> {
-        let (s, _) = self.tcp.accept().await?;
-        Self::try_handshake(s).await
+    /// Accept TLS connections only, STP handshake goes inside the encrypted stream
+    pub async fn bind_tls<Addr>(addr: Addr, tls: TlsServerConfig) -> BindResult
+    where
+        Addr: ToSocketAddrs,
+    {
+        Ok(Self::bind(addr).await?.with_tls(tls))
     }
 
-    pub async fn try_handshake(mut stream: TcpStream) -> ConnectResult<StpConnection> {
-        let handshake_req_msg = read_srt(&mut stream).await.map_err(|e| ConnectError::BadHandshake(e.to_string()))?;
-        if !handshake_req_msg.eq(crate::protocol::HANDSHAKE_REQUEST) {
-            return Err(ConnectError::BadHandshake("Handshake request not matched".to_string()));
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+#[cfg(unix)]
+impl ServerStp<UnixListener> {
+    /// Serve local clients over Unix domain socket
+    pub fn bind_unix<P: AsRef<Path>>(path: P) -> BindResult<UnixListener> {
+        Ok(Self::new(UnixListener::bind(path)?))
+    }
+}
+
+impl<L: AsyncListener> ServerStp<L> {
+    /// Serve connections accepted by the listener, e.g. in-memory one for tests
+    pub fn new(listener: L) -> Self {
+        Self {
+            listener,
+            capabilities: Capability::ALL.to_vec(),
+            key: None,
+            tls: None,
+            heartbeat: None,
+            limits: ServerLimits::default(),
+            connection_limits: ConnectionLimits::default(),
+            peers: PeerConnections::default(),
+            metrics: ServerMetrics::new(),
+            recordings: None,
         }
-        let _ = send_str(&mut stream, crate::protocol::HANDSHAKE_RESPOND).await;
-        Ok(StpConnection { stream })
+    }
+
+    /// Accept TLS connections only
+    pub fn with_tls(mut self, tls: TlsServerConfig) -> Self {
+        self.tls = Some(TlsAcceptor::from(tls.config()));
+        self
+    }
+
+    /// Restrict capabilities offered to clients in handshake
+    pub fn with_capabilities(mut self, capabilities: &[Capability]) -> Self {
+        self.capabilities = capabilities.to_vec();
+        self
+    }
+
+    /// Require clients to prove the knowledge of the device key in handshake
+    pub fn with_key(mut self, key: PreSharedKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// Ping idle clients while receiving requests, see [`StpConnection::revc_request`]
+    pub fn with_heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+        self.heartbeat = Some(heartbeat);
+        self
+    }
+
+    pub fn with_limits(mut self, limits: ServerLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Limit frame size, requests rate and connections per IP of clients
+    pub fn with_connection_limits(mut self, limits: ConnectionLimits) -> Self {
+        self.connection_limits = limits;
+        self
+    }
+
+    /// Record every connection to `<n>.stp.jsonl` file in the directory, `n` counts from 1.
+    /// A connection is served without recording if its file is not created.
+    pub fn with_recordings<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.recordings = Some(RecordingDir::new(dir.into()));
+        self
+    }
+
+    /// Counters of connections and requests, updated while serving
+    pub fn metrics(&self) -> ServerMetrics {
+        self.metrics.clone()
+    }
+
+    /// Accept the next client and perform handshake
+    pub async fn accept(&self) -> ConnectResult<StpConnection<L::Stream>> {
+        let s = self.listener.accept().await?;
+        self.try_handshake(s).await
+    }
+
+    /// Endless stream of accepted clients, has to be pinned before polling
+    pub fn incoming(&self) -> impl Stream<Item=ConnectResult<StpConnection<L::Stream>>> + '_ {
+        stream::unfold(self, |server| async move { Some((server.accept().await, server)) })
+    }
+
+    /// Clients above [`ConnectionLimits::max_connections_per_ip`] get the error reply and fail with [`ConnectError::Rejected`]
+    pub async fn try_handshake(&self, stream: L::Stream) -> ConnectResult<StpConnection<L::Stream>> {
+        self.metrics.connection_accepted();
+        let connection = self.handshake(stream).await;
+        if connection.is_err() {
+            self.metrics.handshake_failed();
+        }
+        connection
+    }
+
+    async fn handshake(&self, stream: L::Stream) -> ConnectResult<StpConnection<L::Stream>> {
+        let peer_ip = L::peer_ip(&stream);
+        let stream = match &self.tls {
+            Some(acceptor) => { AsyncStpStream::Tls(Box::new(acceptor.accept(stream).await?.into())) }
+            None => { AsyncStpStream::Plain(stream) }
+        };
+        let stream = match self.recordings.as_ref().and_then(RecordingDir::next_recorder) {
+            Some(recorder) => { stream.recorded(recorder) }
+            None => { stream }
+        };
+        let mut framed = Framed::new(stream, StpCodec::new().with_max_frame_len(self.connection_limits.max_frame_len));
+        let peer = match self.peers.admit(peer_ip, self.connection_limits.max_connections_per_ip) {
+            Ok(peer) => { peer }
+            Err(reply) => {
+                let _ = framed.send(Frame { kind: FrameKind::Error, payload: reply.encode().into_bytes() }).await;
+                let _ = SinkExt::<Frame>::close(&mut framed).await;
+                return Err(ConnectError::Rejected(reply));
+            }
+        };
+        let mut handshake = ServerHandshake::new(&self.capabilities, self.key.clone());
+        let hello = loop {
+            let msg = handshake_message(&mut framed).await?;
+            let step = handshake.on_message(&msg);
+            if let Some(reply) = step.send {
+                let _ = send_message(&mut framed, reply).await;
+            }
+            if let Progress::Done(hello) = step.progress? {
+                break hello;
+            }
+        };
+        Ok(StpConnection {
+            framed,
+            session: Session::new(hello, self.heartbeat),
+            request_id: UNKNOWN_REQUEST_ID,
+            subscription: None,
+            idle_timeout: self.limits.idle_timeout,
+            last_request: Instant::now(),
+            shutdown: None,
+            rate: self.connection_limits.rate_limit.map(|limit| TokenBucket::new(limit, std::time::Instant::now())),
+            _peer: peer,
+            metrics: self.metrics.clone(),
+            request_started: None,
+        })
     }
 }
 
+impl<L> ServerStp<L>
+where
+    L: AsyncListener + Send + Sync + 'static,
+    L::Stream: 'static,
+{
+    /// Accept clients until shutdown, every connection is passed to `handler` in a separate task.
+    /// Clients above [`ServerLimits::max_connections`] wait in the listener backlog, failed handshakes are dropped.
+    /// Failed accepts are logged and retried after [`ACCEPT_BACKOFF`].
+    ///
+    /// On shutdown stops accepting, lets connections finish requests in flight during the drain timeout
+    /// and aborts the rest. Waiting [`StpConnection::recv`] fails with [`RecvError::ShuttingDown`].
+    pub async fn run<F, Fut>(self, shutdown: ShutdownHandle, handler: F) -> io::Result<()>
+    where
+        F: Fn(StpConnection<L::Stream>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output=()> + Send + 'static,
+    {
+        let server = Arc::new(self);
+        let handler = Arc::new(handler);
+        let permits = Arc::new(Semaphore::new(server.limits.max_connections));
+        let mut tasks = JoinSet::new();
+        loop {
+            let accepted = tokio::select! {
+                _ = shutdown.wait() => { break }
+                accepted = async {
+                    let permit = permits.clone().acquire_owned().await.expect("semaphore is never closed");
+                    server.listener.accept().await.map(|stream| (stream, permit))
+                } => { accepted }
+            };
+            let (stream, permit) = match accepted {
+                Ok(accepted) => { accepted }
+                Err(e) => {
+                    // e.g. out of file descriptors, the listener is still usable
+                    log::warn!("accept failed: {}", e);
+                    tokio::select! {
+                        _ = shutdown.wait() => { break }
+                        _ = tokio::time::sleep(ACCEPT_BACKOFF) => { continue }
+                    }
+                }
+            };
+            while tasks.try_join_next().is_some() {}
+            let (server, handler, shutdown) = (server.clone(), handler.clone(), shutdown.clone());
+            tasks.spawn(async move {
+                let _permit = permit;
+                // silent clients must not hold the slot forever
+                let handshake = match server.limits.idle_timeout {
+                    Some(timeout) => { tokio::time::timeout(timeout, server.try_handshake(stream)).await.ok() }
+                    None => { Some(server.try_handshake(stream).await) }
+                };
+                match handshake {
+                    Some(Ok(mut connection)) => {
+                        connection.shutdown = Some(shutdown);
+                        handler(connection).await;
+                    }
+                    Some(Err(_)) => {}
+                    // counted as accepted by the interrupted handshake
+                    None => { server.metrics.handshake_failed() }
+                }
+            });
+        }
+        let drain = async { while tasks.join_next().await.is_some() {} };
+        if tokio::time::timeout(server.limits.drain_timeout, drain).await.is_err() {
+            tasks.shutdown().await;
+        }
+        Ok(())
+    }
 
-type BindResult = Result<ServerStp, BindError>;
+    /// [`ServerStp::run`] with requests of every connection dispatched to the shared handler.
+    /// The client is disconnected on a receive error.
+    pub async fn serve<H: AsyncHandler>(self, shutdown: ShutdownHandle, handler: H) -> io::Result<()> {
+        let handler = Arc::new(handler);
+        self.run(shutdown, move |connection| serve_connection(connection, handler.clone())).await
+    }
+}
+
+/// Limits of served connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServerLimits {
+    /// Connections served at once by [`ServerStp::run`]
+    pub max_connections: usize,
+    /// Close the connection without requests during this time, `None` - never
+    pub idle_timeout: Option<Duration>,
+    /// Time given to connections to finish requests on shutdown
+    pub drain_timeout: Duration,
+}
+
+impl Default for ServerLimits {
+    fn default() -> Self {
+        Self { max_connections: 1024, idle_timeout: None, drain_timeout: Duration::from_secs(5) }
+    }
+}
+
+impl ServerLimits {
+    pub fn new(max_connections: usize) -> Self {
+        Self { max_connections, ..Self::default() }
+    }
+
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    pub fn with_drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.drain_timeout = drain_timeout;
+        self
+    }
+}
+
+/// Stops [`ServerStp::run`], clones control the same server
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    tx: Arc<watch::Sender<bool>>,
+}
+
+impl Default for ShutdownHandle {
+    fn default() -> Self {
+        Self { tx: Arc::new(watch::Sender::new(false)) }
+    }
+}
+
+impl ShutdownHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn shutdown(&self) {
+        self.tx.send_replace(true);
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        *self.tx.borrow()
+    }
+
+    /// Resolves once shutdown is requested
+    pub async fn wait(&self) {
+        let mut rx = self.tx.subscribe();
+        let _ = rx.wait_for(|shutdown| *shutdown).await;
+    }
+}
+
+
+type BindResult<L = TcpListener> = Result<ServerStp<L>, BindError>;
 
 /// Bind to socket error
 #[derive(Debug, Error)]
@@ -45,24 +363,533 @@ pub enum BindError {
     Io(#[from] io::Error),
 }
 
-pub struct StpConnection {
-    stream: TcpStream,
+pub struct StpConnection<S: AsyncTransport = TcpStream> {
+    framed: Framed<AsyncStpStream<S>, StpCodec>,
+    session: Session,
+    /// Id of the last received request, echoed in the response
+    request_id: u32,
+    subscription: Option<Subscription>,
+    idle_timeout: Option<Duration>,
+    last_request: Instant,
+    shutdown: Option<ShutdownHandle>,
+    rate: Option<TokenBucket>,
+    /// Counted in connections of the peer IP while open
+    _peer: Option<PeerGuard>,
+    metrics: ServerMetrics,
+    /// Receiving time of the request awaiting the response
+    request_started: Option<std::time::Instant>,
 }
 
-impl StpConnection {
+impl<S: AsyncTransport> StpConnection<S> {
+    /// Protocol version agreed with the client
+    pub fn protocol_version(&self) -> u16 {
+        self.session.hello().version
+    }
+
+    /// Capabilities supported by both client and server
+    pub fn capabilities(&self) -> &[Capability] {
+        &self.session.hello().capabilities
+    }
+
+    pub fn has_capability(&self, capability: Capability) -> bool {
+        self.session.hello().capabilities.contains(&capability)
+    }
+
+    pub fn is_tls(&self) -> bool {
+        self.framed.get_ref().is_tls()
+    }
+
+    /// Response to the last received request
     pub async fn send_response<Resp: AsRef<str>>(&mut self, response: Resp) -> SendResult {
-        send_str(&mut self.stream, response).await
+        self.send_tagged(Frame { kind: FrameKind::Message, payload: response.as_ref().as_bytes().to_vec() }).await
     }
 
+    /// Wait for the next request, answering pings of the client. Cancel safe.
+    /// With heartbeat, fails with [`RecvError::PeerTimeout`] and closes the connection when the client stops answering.
+    /// Also closes the connection idle longer than [`ServerLimits::idle_timeout`] or on shutdown of [`ServerStp::run`],
+    /// and the connection breaking [`ConnectionLimits`] after the error reply.
     pub async fn revc_request(&mut self) -> RecvResult {
-        read_srt(&mut self.stream).await
+        self.request_id = UNKNOWN_REQUEST_ID;
+        let idle_deadline = self.idle_timeout.map(|timeout| self.last_request + timeout);
+        let frame = tokio::select! {
+            biased;
+            frame = recv_frame(&mut self.framed, &mut self.session) => { frame }
+            _ = tokio::time::sleep_until(idle_deadline.unwrap_or_else(Instant::now)), if idle_deadline.is_some() => {
+                Err(RecvError::IdleTimeout(self.idle_timeout.unwrap_or_default()))
+            }
+            _ = shutdown_requested(self.shutdown.as_ref()) => { Err(RecvError::ShuttingDown) }
+        };
+        let frame = match frame {
+            Ok(frame) => { frame }
+            Err(RecvError::FrameTooLarge { len, max }) => {
+                return Err(self.reject(&limits::frame_too_large(len, max), RecvError::FrameTooLarge { len, max }).await);
+            }
+            Err(e) => {
+                let _ = SinkExt::<Frame>::close(&mut self.framed).await;
+                return Err(e);
+            }
+        };
+        self.last_request = Instant::now();
+        let (id, frame) = self.session.untag(frame)?;
+        self.request_id = id.unwrap_or(UNKNOWN_REQUEST_ID);
+        if self.rate.as_mut().is_some_and(|rate| !rate.try_take(std::time::Instant::now())) {
+            return Err(self.reject(&limits::rate_limited(), RecvError::RateLimited).await);
+        }
+        self.request_started = Some(std::time::Instant::now());
+        message_payload(frame)
+    }
+
+    /// Send the error reply and close the connection
+    async fn reject(&mut self, reply: &ErrorReply, e: RecvError) -> RecvError {
+        let _ = self.reply_error(reply).await;
+        let _ = SinkExt::<Frame>::close(&mut self.framed).await;
+        e
+    }
+
+    /// Received requests are counted in [`ServerMetrics`] by command
+    pub async fn recv(&mut self) -> Result<Request, RecvError> {
+        let req = self.revc_request().await?;
+        let request = Request::decode(&req);
+        self.metrics.request(request.as_ref().map_or(UNKNOWN_COMMAND, Request::command));
+        request
+    }
+
+    pub async fn reply(&mut self, response: &Response) -> SendResult {
+        self.send_response(response.encode()).await
+    }
+
+    /// Report the failed request instead of the response
+    pub async fn reply_error(&mut self, error: &ErrorReply) -> SendResult {
+        self.metrics.error_reply(error.code);
+        self.send_tagged(Frame { kind: FrameKind::Error, payload: error.encode().into_bytes() }).await
+    }
+
+    async fn send_tagged(&mut self, frame: Frame) -> SendResult {
+        if let Some(started) = self.request_started.take() {
+            self.metrics.request_latency(started.elapsed());
+        }
+        Ok(self.framed.send(self.session.tag(self.request_id, frame)).await?)
+    }
+
+    /// Start pushing events to the client, see [`StpConnection::notify`]
+    pub fn subscribe(&mut self, power_threshold_wt: f32) {
+        self.subscription = Some(Subscription::new(power_threshold_wt));
+    }
+
+    pub fn unsubscribe(&mut self) {
+        self.subscription = None;
+    }
+
+    pub fn is_subscribed(&self) -> bool {
+        self.subscription.is_some()
+    }
+
+    /// Report current device values. Pushes events to the subscribed client if values changed.
+    pub async fn notify(&mut self, state: bool, power_wt: Option<f32>) -> SendResult {
+        let events = match self.subscription.as_mut() {
+            Some(subscription) => { subscription.update(state, power_wt) }
+            None => { return Ok(()) }
+        };
+        for event in events {
+            self.send_event(&event).await?;
+        }
+        Ok(())
+    }
+
+    async fn route<H: AsyncHandler>(&mut self, request: Request, handler: &H) -> HandlerResult {
+        match Route::from(request) {
+            Route::Device(request) => { handler.handle(request).await }
+            Route::Subscribe(power_threshold_wt) if self.has_capability(Capability::Subscribe) => {
+                self.subscribe(power_threshold_wt);
+                Ok(Response::Ok)
+            }
+            Route::Subscribe(_) => { Err(handler::subscribe_not_negotiated()) }
+            Route::Unsubscribe => {
+                self.unsubscribe();
+                Ok(Response::Ok)
+            }
+        }
+    }
+
+    pub async fn send_event(&mut self, event: &Event) -> SendResult {
+        Ok(self.framed.send(Frame { kind: FrameKind::Event, payload: event.encode().into_bytes() }).await?)
+    }
+}
+
+/// Never resolves without the handle
+async fn shutdown_requested(shutdown: Option<&ShutdownHandle>) {
+    match shutdown {
+        Some(shutdown) => { shutdown.wait().await }
+        None => { std::future::pending().await }
     }
 }
 
-impl Deref for StpConnection {
-    type Target = TcpStream;
+async fn serve_connection<S: AsyncTransport, H: AsyncHandler>(mut connection: StpConnection<S>, handler: Arc<H>) {
+    // wake up periodically to push events to the subscribed client
+    let mut notify_tick = tokio::time::interval(NOTIFY_PERIOD);
+    loop {
+        tokio::select! {
+            request = connection.recv() => {
+                let response = match request {
+                    Ok(request) => { connection.route(request, handler.as_ref()).await }
+                    Err(RecvError::BadMessage(e)) => { Err(handler::unknown_request(&e)) }
+                    Err(_) => { return }
+                };
+                let sent = match response {
+                    Ok(response) => { connection.reply(&response).await }
+                    Err(error) => { connection.reply_error(&error).await }
+                };
+                if sent.is_err() {
+                    return;
+                }
+            }
+            _ = notify_tick.tick() => {
+                if !connection.is_subscribed() {
+                    continue;
+                }
+                if let Some(state) = handler.state().await {
+                    if connection.notify(state.on, state.power_wt).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<S: AsyncTransport> Deref for StpConnection<S> {
+    type Target = S;
 
     fn deref(&self) -> &Self::Target {
-        &self.stream
+        self.framed.get_ref().get_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use tokio::io::{AsyncWriteExt, DuplexStream};
+
+    use std::sync::Mutex;
+
+    use crate::client_std::ConnectOptions;
+    use crate::client_tokio::ClientStp;
+    use crate::handler::DeviceState;
+    use crate::limits::RateLimit;
+    use crate::message::{ErrorCode, Event};
+    use crate::protocol;
+    use crate::transport::{async_memory_listener, AsyncMemoryListener};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Lamp {
+        on: Mutex<bool>,
     }
-}
\ No newline at end of file
+
+    impl AsyncHandler for Lamp {
+        async fn handle(&self, request: Request) -> HandlerResult {
+            match request {
+                Request::TurnOn => {
+                    *self.on.lock().unwrap() = true;
+                    Ok(Response::Ok)
+                }
+                Request::GetState => { Ok(Response::State(*self.on.lock().unwrap())) }
+                _ => { Err(ErrorReply::new(ErrorCode::Unsupported, "dimming")) }
+            }
+        }
+
+        async fn state(&self) -> Option<DeviceState> {
+            Some(DeviceState { on: *self.on.lock().unwrap(), power_wt: None })
+        }
+    }
+
+    #[tokio::test]
+    async fn negotiate_capabilities() {
+        let server = ServerStp::bind("127.0.0.1:0").await.unwrap().with_capabilities(&[Capability::Switch, Capability::Subscribe]);
+        let addr = server.local_addr().unwrap();
+        let handle = tokio::spawn(async move {
+            let mut incoming = std::pin::pin!(server.incoming());
+            let connection = incoming.next().await.unwrap().unwrap();
+            connection.capabilities().to_vec()
+        });
+        let client = ClientStp::connect(addr).await.unwrap();
+        assert_eq!(client.capabilities(), &[Capability::Switch, Capability::Subscribe]);
+        assert_eq!(handle.await.unwrap(), vec![Capability::Switch, Capability::Subscribe]);
+    }
+
+    #[tokio::test]
+    async fn push_events() {
+        let server = ServerStp::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut connection = server.accept().await.unwrap();
+            if let Request::Subscribe { power_threshold_wt } = connection.recv().await.unwrap() {
+                connection.subscribe(power_threshold_wt);
+            }
+            connection.reply(&Response::Ok).await.unwrap();
+            connection.notify(true, None).await.unwrap();
+            connection.notify(true, None).await.unwrap();
+            connection.notify(false, None).await.unwrap();
+            let _ = connection.recv().await;
+        });
+        let mut client = ClientStp::connect(addr).await.unwrap();
+        assert_eq!(client.request(&Request::Subscribe { power_threshold_wt: 1.0 }).await.unwrap(), Response::Ok);
+        assert_eq!(client.next_event().await.unwrap(), Event::StateChanged(true));
+        assert_eq!(client.next_event().await.unwrap(), Event::PowerChanged(None));
+        assert_eq!(client.next_event().await.unwrap(), Event::StateChanged(false));
+        let no_event = tokio::time::timeout(Duration::from_millis(50), client.next_event()).await;
+        assert!(no_event.is_err());
+    }
+
+    #[tokio::test]
+    async fn client_rejects_unsupported_version() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let _ = Framed::new(&mut stream, StpCodec::new()).next().await;
+            let outdated = format!("hi_client;{};switch", protocol::MIN_PROTOCOL_VERSION - 1);
+            stream.write_all(&protocol::wrap_message(outdated)).await.unwrap();
+        });
+        assert!(matches!(ClientStp::connect(addr).await, Err(ConnectError::BadHandshake(_))));
+    }
+
+    #[tokio::test]
+    async fn authenticate_with_key() {
+        let key = PreSharedKey::from_hex("00112233445566778899aabbccddeeff").unwrap();
+        let server = ServerStp::bind("127.0.0.1:0").await.unwrap().with_key(key.clone());
+        let addr = server.local_addr().unwrap();
+        let handle = tokio::spawn(async move {
+            let rejected = server.accept().await.map(|_| ());
+            let accepted = server.accept().await.map(|_| ());
+            (rejected, accepted)
+        });
+        let wrong_key = ConnectOptions::new().with_key(PreSharedKey::new("guess"));
+        assert!(matches!(ClientStp::connect_with(addr, &wrong_key).await, Err(ConnectError::Unauthorized(_))));
+        assert!(ClientStp::connect_with(addr, &ConnectOptions::new().with_key(key)).await.is_ok());
+        let (rejected, accepted) = handle.await.unwrap();
+        assert!(matches!(rejected, Err(ConnectError::Unauthorized(_))));
+        assert!(accepted.is_ok());
+    }
+
+    #[tokio::test]
+    async fn serve_in_memory() {
+        let (listener, connector) = async_memory_listener();
+        let server = ServerStp::new(listener).with_key(PreSharedKey::new("hub"));
+        tokio::spawn(async move {
+            let mut connection = server.accept().await.unwrap();
+            assert_eq!(connection.recv().await.unwrap(), Request::GetDescription);
+            connection.reply(&Response::Description("socket@hall".to_string())).await.unwrap();
+        });
+        let options = ConnectOptions::new().with_key(PreSharedKey::new("hub"));
+        let mut client = ClientStp::connect_over(connector.connect().unwrap(), &options).await.unwrap();
+        assert_eq!(client.request(&Request::GetDescription).await.unwrap(), Response::Description("socket@hall".to_string()));
+    }
+
+    #[tokio::test]
+    async fn detect_dead_client() {
+        let (listener, connector) = async_memory_listener();
+        let server = ServerStp::new(listener).with_heartbeat(Heartbeat::new(Duration::from_millis(20), 2));
+        let handle = tokio::spawn(async move {
+            let mut connection = server.accept().await.unwrap();
+            connection.recv().await
+        });
+        // client does not read, so pings stay unanswered
+        let _client = ClientStp::connect_over(connector.connect().unwrap(), &ConnectOptions::new()).await.unwrap();
+        assert!(matches!(handle.await.unwrap(), Err(RecvError::PeerTimeout(2))));
+    }
+
+    #[tokio::test]
+    async fn limit_connections() {
+        let (listener, connector) = async_memory_listener();
+        let server = ServerStp::new(listener).with_limits(ServerLimits::new(1));
+        let shutdown = ShutdownHandle::new();
+        tokio::spawn(server.run(shutdown.clone(), |mut connection| async move {
+            while connection.recv().await.is_ok() {
+                let _ = connection.reply(&Response::Ok).await;
+            }
+        }));
+        let mut first = ClientStp::connect_over(connector.connect().unwrap(), &ConnectOptions::new()).await.unwrap();
+        assert_eq!(first.request(&Request::TurnOn).await.unwrap(), Response::Ok);
+        let stream = connector.connect().unwrap();
+        let second = tokio::spawn(async move { ClientStp::connect_over(stream, &ConnectOptions::new()).await.map(|_| ()) });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!second.is_finished());
+        drop(first);
+        assert!(second.await.unwrap().is_ok());
+        shutdown.shutdown();
+    }
+
+    #[tokio::test]
+    async fn idle_timeout_and_graceful_shutdown() {
+        let (listener, connector) = async_memory_listener();
+        let server = ServerStp::new(listener).with_limits(ServerLimits::default().with_idle_timeout(Duration::from_millis(50)));
+        let shutdown = ShutdownHandle::new();
+        let (errors_tx, mut errors_rx) = tokio::sync::mpsc::unbounded_channel();
+        let running = tokio::spawn(server.run(shutdown.clone(), move |mut connection| {
+            let errors_tx = errors_tx.clone();
+            async move {
+                loop {
+                    match connection.recv().await {
+                        Ok(_) => {
+                            tokio::time::sleep(Duration::from_millis(30)).await;
+                            connection.reply(&Response::Ok).await.unwrap();
+                        }
+                        Err(e) => {
+                            errors_tx.send(e).unwrap();
+                            break;
+                        }
+                    }
+                }
+            }
+        }));
+        let mut idle = ClientStp::connect_over(connector.connect().unwrap(), &ConnectOptions::new()).await.unwrap();
+        assert!(idle.next_event().await.is_err());
+        assert!(matches!(errors_rx.recv().await, Some(RecvError::IdleTimeout(_))));
+
+        let mut client = ClientStp::connect_over(connector.connect().unwrap(), &ConnectOptions::new()).await.unwrap();
+        // shutdown while the request is in flight
+        let (response, _) = tokio::join!(client.request(&Request::TurnOff), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            shutdown.shutdown();
+        });
+        assert_eq!(response.unwrap(), Response::Ok);
+        assert!(matches!(errors_rx.recv().await, Some(RecvError::ShuttingDown)));
+        running.await.unwrap().unwrap();
+        assert!(client.request(&Request::GetState).await.is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn serve_unix_socket() {
+        let path = std::env::temp_dir().join(format!("stp-test-async-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let server = ServerStp::bind_unix(&path).unwrap();
+        tokio::spawn(async move {
+            let mut connection = server.accept().await.unwrap();
+            assert_eq!(connection.recv().await.unwrap(), Request::TurnOn);
+            connection.reply(&Response::Ok).await.unwrap();
+        });
+        let mut client = ClientStp::connect_unix(&path, &ConnectOptions::new()).await.unwrap();
+        assert_eq!(client.request(&Request::TurnOn).await.unwrap(), Response::Ok);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn serve_with_handler() {
+        let (listener, connector) = async_memory_listener();
+        let shutdown = ShutdownHandle::new();
+        let server = tokio::spawn(ServerStp::new(listener).serve(shutdown.clone(), Lamp::default()));
+        let mut first = ClientStp::connect_over(connector.connect().unwrap(), &ConnectOptions::new()).await.unwrap();
+        let mut second = ClientStp::connect_over(connector.connect().unwrap(), &ConnectOptions::new()).await.unwrap();
+        assert_eq!(first.request(&Request::Subscribe { power_threshold_wt: 10.0 }).await.unwrap(), Response::Ok);
+        assert_eq!(first.next_event().await.unwrap(), Event::StateChanged(false));
+        // the handler is shared by connections
+        assert_eq!(second.request(&Request::TurnOn).await.unwrap(), Response::Ok);
+        assert_eq!(first.request(&Request::GetState).await.unwrap(), Response::State(true));
+        let err = second.send_request("dim").await.unwrap_err();
+        assert_eq!(err.remote().unwrap().code, ErrorCode::BadRequest);
+        // the connection is still usable after the error
+        assert_eq!(second.request(&Request::GetState).await.unwrap(), Response::State(true));
+        shutdown.shutdown();
+        server.await.unwrap().unwrap();
+    }
+
+    /// Fails every other accept
+    struct FlakyListener {
+        inner: AsyncMemoryListener,
+        failed: Mutex<bool>,
+    }
+
+    impl AsyncListener for FlakyListener {
+        type Stream = DuplexStream;
+
+        async fn accept(&self) -> io::Result<Self::Stream> {
+            let fail = {
+                let mut failed = self.failed.lock().unwrap();
+                *failed = !*failed;
+                *failed
+            };
+            if fail {
+                return Err(io::Error::other("too many open files"));
+            }
+            self.inner.accept().await
+        }
+    }
+
+    #[tokio::test]
+    async fn keep_accepting_after_error() {
+        let (listener, connector) = async_memory_listener();
+        let listener = FlakyListener { inner: listener, failed: Mutex::new(false) };
+        let shutdown = ShutdownHandle::new();
+        let server = tokio::spawn(ServerStp::new(listener).serve(shutdown.clone(), Lamp::default()));
+        for _ in 0..2 {
+            let mut client = ClientStp::connect_over(connector.connect().unwrap(), &ConnectOptions::new()).await.unwrap();
+            assert_eq!(client.request(&Request::TurnOn).await.unwrap(), Response::Ok);
+        }
+        shutdown.shutdown();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn pipelined_clients() {
+        let (listener, connector) = async_memory_listener();
+        let shutdown = ShutdownHandle::new();
+        let server = tokio::spawn(ServerStp::new(listener).serve(shutdown.clone(), Lamp::default()));
+        let client = ClientStp::connect_over(connector.connect().unwrap(), &ConnectOptions::new()).await.unwrap().into_handle();
+        assert!(client.capabilities().contains(&Capability::Pipeline));
+        let other = client.clone();
+        let (on, dim) = tokio::join!(client.request(&Request::TurnOn), other.request(&Request::GetDescription));
+        assert_eq!(on.unwrap(), Response::Ok);
+        assert_eq!(dim.unwrap_err().remote().unwrap().code, ErrorCode::Unsupported);
+
+        let mut events = other.events();
+        assert_eq!(client.request(&Request::Subscribe { power_threshold_wt: 10.0 }).await.unwrap(), Response::Ok);
+        assert_eq!(events.recv().await.unwrap(), Event::StateChanged(true));
+        assert_eq!(other.send_request("dim").await.unwrap_err().remote().unwrap().code, ErrorCode::BadRequest);
+
+        shutdown.shutdown();
+        server.await.unwrap().unwrap();
+        assert!(client.request(&Request::GetState).await.unwrap_err().is_connection_lost());
+        assert!(!other.is_connected());
+    }
+
+    #[tokio::test]
+    async fn route_responses_out_of_order() {
+        let (listener, connector) = async_memory_listener();
+        let server = ServerStp::new(listener);
+        tokio::spawn(async move {
+            let mut connection = server.accept().await.unwrap();
+            let mut requests = Vec::new();
+            for _ in 0..2 {
+                let frame = recv_frame(&mut connection.framed, &mut connection.session).await.unwrap();
+                requests.push(connection.session.untag(frame).unwrap());
+            }
+            // the second request is answered first
+            for (id, request) in requests.into_iter().rev() {
+                let frame = connection.session.tag(id.unwrap(), Frame { kind: FrameKind::Message, payload: request.payload });
+                connection.framed.send(frame).await.unwrap();
+            }
+        });
+        let client = ClientStp::connect_over(connector.connect().unwrap(), &ConnectOptions::new()).await.unwrap().into_handle();
+        let (first, second) = tokio::join!(client.send_request("first"), client.send_request("second"));
+        assert_eq!(first.unwrap(), "first");
+        assert_eq!(second.unwrap(), "second");
+    }
+
+    #[tokio::test]
+    async fn enforce_rate_limit() {
+        let (listener, connector) = async_memory_listener();
+        let shutdown = ShutdownHandle::new();
+        let limits = ConnectionLimits::new().with_rate_limit(RateLimit::new(0.01).unwrap().with_burst(2).unwrap());
+        tokio::spawn(ServerStp::new(listener).with_connection_limits(limits).serve(shutdown.clone(), Lamp::default()));
+        let mut client = ClientStp::connect_over(connector.connect().unwrap(), &ConnectOptions::new()).await.unwrap();
+        assert_eq!(client.request(&Request::TurnOn).await.unwrap(), Response::Ok);
+        assert_eq!(client.request(&Request::GetState).await.unwrap(), Response::State(true));
+        let err = client.request(&Request::GetState).await.unwrap_err();
+        assert_eq!(err.remote().unwrap().code, ErrorCode::LimitExceeded);
+        assert!(client.request(&Request::GetState).await.unwrap_err().is_connection_lost());
+        shutdown.shutdown();
+    }
+}
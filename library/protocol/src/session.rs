@@ -0,0 +1,419 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::auth;
+use crate::auth::{AUTH_ACCEPTED, AUTH_REJECTED, PreSharedKey};
+use crate::errors::{ConnectError, ConnectResult, RecvError};
+use crate::heartbeat::{Heartbeat, HeartbeatAction, HeartbeatMonitor};
+use crate::message::{ErrorReply, Event};
+use crate::protocol::{Capability, Frame, FrameKind, Hello};
+
+/// Time given to the peer for every handshake message, both blocking and async
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Request id prefix of the payload in pipelined session: u32, big endian
+pub const REQUEST_ID_LEN: usize = 4;
+/// Id of the error reply not related to a request, e.g. the request is too large to read its id
+pub const UNKNOWN_REQUEST_ID: u32 = 0;
+
+#[derive(Debug, PartialEq)]
+pub enum Progress {
+    /// Wait for the next message of the peer
+    Continue,
+    /// Handshake is completed with the negotiated parameters
+    Done(Hello),
+}
+
+/// Reaction on the handshake message of the peer
+#[derive(Debug)]
+pub struct Step {
+    /// Message to send before handling `progress`, sent even if the handshake failed
+    pub send: Option<String>,
+    pub progress: ConnectResult<Progress>,
+}
+
+impl Step {
+    fn fail(e: ConnectError) -> Self {
+        Self { send: None, progress: Err(e) }
+    }
+}
+
+/// Client side of the handshake. Send [`ClientHandshake::request`], then feed replies until done.
+#[derive(Debug)]
+pub struct ClientHandshake {
+    hello: Hello,
+    key: Option<PreSharedKey>,
+    /// Negotiated, waiting for the authentication reply
+    negotiated: Option<Hello>,
+}
+
+impl ClientHandshake {
+    pub fn new(capabilities: &[Capability], key: Option<PreSharedKey>) -> Self {
+        Self { hello: Hello::new(capabilities), key, negotiated: None }
+    }
+
+    pub fn request(&self) -> String {
+        self.hello.request_msg()
+    }
+
+    pub fn on_message(&mut self, msg: &str) -> Step {
+        match self.negotiated.take() {
+            Some(hello) => { Step { send: None, progress: auth::check_auth_reply(msg).map(|_| Progress::Done(hello)) } }
+            None => { self.on_hello(msg).unwrap_or_else(Step::fail) }
+        }
+    }
+
+    fn on_hello(&mut self, msg: &str) -> ConnectResult<Step> {
+        let server_hello = Hello::parse_respond(msg).map_err(ConnectError::BadHandshake)?;
+        let hello = self.hello.negotiate(&server_hello).map_err(ConnectError::BadHandshake)?;
+        match auth::answer_challenge(server_hello.challenge.as_deref(), self.key.as_ref())? {
+            Some(auth_msg) => {
+                self.negotiated = Some(hello);
+                Ok(Step { send: Some(auth_msg), progress: Ok(Progress::Continue) })
+            }
+            None => { Ok(Step { send: None, progress: Ok(Progress::Done(hello)) }) }
+        }
+    }
+}
+
+/// Server side of the handshake, feed client messages until done
+#[derive(Debug)]
+pub struct ServerHandshake {
+    hello: Hello,
+    key: Option<PreSharedKey>,
+    negotiated: Option<Hello>,
+}
+
+impl ServerHandshake {
+    /// Clients are challenged if the key is set
+    pub fn new(capabilities: &[Capability], key: Option<PreSharedKey>) -> Self {
+        let mut hello = Hello::new(capabilities);
+        if key.is_some() {
+            hello = hello.with_challenge(auth::new_challenge());
+        }
+        Self { hello, key, negotiated: None }
+    }
+
+    pub fn on_message(&mut self, msg: &str) -> Step {
+        match self.negotiated.take() {
+            Some(hello) => { self.on_auth(msg, hello) }
+            None => { self.on_hello(msg) }
+        }
+    }
+
+    fn on_hello(&mut self, msg: &str) -> Step {
+        let client_hello = match Hello::parse_request(msg) {
+            Ok(client_hello) => { client_hello }
+            Err(e) => { return Step::fail(ConnectError::BadHandshake(e)) }
+        };
+        // respond even on incompatible version, so the client is able to report the reason
+        let send = Some(self.hello.respond_msg());
+        let progress = self.hello.negotiate(&client_hello).map_err(ConnectError::BadHandshake).map(|hello| {
+            if self.key.is_some() {
+                self.negotiated = Some(hello);
+                Progress::Continue
+            } else {
+                Progress::Done(hello)
+            }
+        });
+        Step { send, progress }
+    }
+
+    fn on_auth(&self, msg: &str, hello: Hello) -> Step {
+        let accepted = match (&self.key, &self.hello.challenge) {
+            (Some(key), Some(challenge)) => { key.check_auth_request(challenge, msg) }
+            _ => { false }
+        };
+        if accepted {
+            Step { send: Some(AUTH_ACCEPTED.to_string()), progress: Ok(Progress::Done(hello)) }
+        } else {
+            Step { send: Some(AUTH_REJECTED.to_string()), progress: Err(ConnectError::Unauthorized("bad client key".to_string())) }
+        }
+    }
+}
+
+/// Reaction on the frame received after handshake
+#[derive(Debug, PartialEq)]
+pub enum Received {
+    /// Message or event for the caller
+    Frame(Frame),
+    /// Send to the peer and keep reading
+    Reply(Frame),
+    /// Keepalive, keep reading
+    Nothing,
+}
+
+/// Reaction on the heartbeat deadline
+#[derive(Debug)]
+pub enum Timeout {
+    Wait,
+    Send(Frame),
+    /// Close the connection and report the error
+    Close(RecvError),
+}
+
+/// Established connection of either side: keepalive and routing of received frames
+#[derive(Debug)]
+pub struct Session {
+    hello: Hello,
+    heartbeat: Option<HeartbeatMonitor>,
+}
+
+impl Session {
+    pub fn new(hello: Hello, heartbeat: Option<Heartbeat>) -> Self {
+        Self { hello, heartbeat: heartbeat.map(HeartbeatMonitor::new) }
+    }
+
+    /// Parameters negotiated in handshake
+    pub fn hello(&self) -> &Hello {
+        &self.hello
+    }
+
+    /// Time to call [`Session::on_timeout`] if nothing is received, `None` without heartbeat
+    pub fn deadline(&self) -> Option<Instant> {
+        self.heartbeat.as_ref().map(HeartbeatMonitor::deadline)
+    }
+
+    /// Requests are tagged with ids, see [`Capability::Pipeline`]
+    pub fn is_pipelined(&self) -> bool {
+        self.hello.capabilities.contains(&Capability::Pipeline)
+    }
+
+    /// Prefix request, response or error reply with the id in pipelined session
+    pub fn tag(&self, id: u32, mut frame: Frame) -> Frame {
+        if self.is_pipelined() {
+            let mut payload = Vec::with_capacity(REQUEST_ID_LEN + frame.payload.len());
+            payload.extend_from_slice(&id.to_be_bytes());
+            payload.extend_from_slice(&frame.payload);
+            frame.payload = payload;
+        }
+        frame
+    }
+
+    /// Strip the request id in pipelined session, `None` otherwise. Events are not tagged.
+    pub fn untag(&self, mut frame: Frame) -> Result<(Option<u32>, Frame), RecvError> {
+        if !self.is_pipelined() || frame.kind == FrameKind::Event {
+            return Ok((None, frame));
+        }
+        if frame.payload.len() < REQUEST_ID_LEN {
+            return Err(RecvError::BadMessage("missing request id".to_string()));
+        }
+        let payload = frame.payload.split_off(REQUEST_ID_LEN);
+        let id = u32::from_be_bytes(frame.payload.try_into().expect("length is checked"));
+        frame.payload = payload;
+        Ok((Some(id), frame))
+    }
+
+    pub fn on_frame(&mut self, frame: Frame) -> Received {
+        if let Some(monitor) = self.heartbeat.as_mut() {
+            monitor.received();
+        }
+        match frame.kind {
+            FrameKind::Ping => { Received::Reply(Frame { kind: FrameKind::Pong, payload: frame.payload }) }
+            FrameKind::Pong => { Received::Nothing }
+            FrameKind::Message | FrameKind::Event | FrameKind::Error => { Received::Frame(frame) }
+        }
+    }
+
+    pub fn on_timeout(&mut self, now: Instant) -> Timeout {
+        let Some(monitor) = self.heartbeat.as_mut() else {
+            return Timeout::Wait;
+        };
+        match monitor.on_timeout(now) {
+            HeartbeatAction::Wait => { Timeout::Wait }
+            HeartbeatAction::Ping => { Timeout::Send(Frame { kind: FrameKind::Ping, payload: Vec::new() }) }
+            HeartbeatAction::Dead(missed) => { Timeout::Close(RecvError::PeerTimeout(missed)) }
+        }
+    }
+}
+
+/// Client request/response state: events pushed before the response are queued
+#[derive(Debug, Default)]
+pub struct ClientState {
+    events: VecDeque<Event>,
+    /// Id of the last request
+    request_id: u32,
+}
+
+impl ClientState {
+    /// Frame of the next request, the response to it is awaited
+    pub fn request_frame(&mut self, session: &Session, msg: &str) -> Frame {
+        self.request_id = next_request_id(self.request_id);
+        session.tag(self.request_id, Frame { kind: FrameKind::Message, payload: msg.as_bytes().to_vec() })
+    }
+
+    /// Response payload, `None` if the frame is an event or a late response and the response is still awaited
+    pub fn on_response_frame(&mut self, session: &Session, frame: Frame) -> Result<Option<String>, RecvError> {
+        if frame.kind == FrameKind::Event {
+            self.events.push_back(decode_event(frame.payload)?);
+            return Ok(None);
+        }
+        let (id, frame) = session.untag(frame)?;
+        // response to the cancelled request
+        if id.is_some_and(|id| id != self.request_id && id != UNKNOWN_REQUEST_ID) {
+            return Ok(None);
+        }
+        response_payload(frame).map(Some)
+    }
+
+    /// Frame received while waiting for events, `None` for a late response in pipelined session
+    pub fn on_event_frame(&mut self, session: &Session, frame: Frame) -> Result<Option<Event>, RecvError> {
+        match frame.kind {
+            FrameKind::Event => { decode_event(frame.payload).map(Some) }
+            _ if session.is_pipelined() => { Ok(None) }
+            FrameKind::Error => { Err(decode_error(frame.payload)) }
+            _ => { Err(RecvError::Other("Unexpected response without request".to_string())) }
+        }
+    }
+
+    pub fn pop_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+}
+
+pub(crate) fn decode_event(payload: Vec<u8>) -> Result<Event, RecvError> {
+    let msg = String::from_utf8(payload).map_err(|_| RecvError::BadEncoding)?;
+    Event::decode(&msg)
+}
+
+/// Request ids start from 1 and wrap around, skipping [`UNKNOWN_REQUEST_ID`]
+pub fn next_request_id(id: u32) -> u32 {
+    id.checked_add(1).unwrap_or(1)
+}
+
+/// Payload of the response frame, [`RecvError::Remote`] for the error reply
+pub(crate) fn response_payload(frame: Frame) -> Result<String, RecvError> {
+    match frame.kind {
+        FrameKind::Error => { Err(decode_error(frame.payload)) }
+        _ => { String::from_utf8(frame.payload).map_err(|_| RecvError::BadEncoding) }
+    }
+}
+
+/// Error reply of the peer, or the reason it is not decodable
+fn decode_error(payload: Vec<u8>) -> RecvError {
+    let reply = String::from_utf8(payload).map_err(|_| RecvError::BadEncoding).and_then(|msg| ErrorReply::decode(&msg));
+    match reply {
+        Ok(reply) => { RecvError::Remote(reply) }
+        Err(e) => { e }
+    }
+}
+
+/// Handshake message of the peer, the error reply of the server rejecting the client is reported as is
+pub(crate) fn handshake_payload(frame: Result<Frame, RecvError>) -> ConnectResult<String> {
+    match frame.and_then(response_payload) {
+        Ok(msg) => { Ok(msg) }
+        Err(RecvError::Remote(reply)) => { Err(ConnectError::Rejected(reply)) }
+        Err(e) => { Err(ConnectError::BadHandshake(e.to_string())) }
+    }
+}
+
+pub(crate) fn message_payload(frame: Frame) -> Result<String, RecvError> {
+    if frame.kind != FrameKind::Message {
+        return Err(RecvError::Other(format!("Unexpected {:?} frame", frame.kind)));
+    }
+    String::from_utf8(frame.payload).map_err(|_| RecvError::BadEncoding)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::message::ErrorCode;
+    use crate::protocol::PROTOCOL_VERSION;
+
+    use super::*;
+
+    /// Run both handshakes against each other
+    fn handshake(client: &mut ClientHandshake, server: &mut ServerHandshake) -> (ConnectResult<Progress>, ConnectResult<Progress>) {
+        let mut to_server = client.request();
+        loop {
+            let server_step = server.on_message(&to_server);
+            let server_done = !matches!(server_step.progress, Ok(Progress::Continue));
+            let Some(to_client) = server_step.send else {
+                return (Ok(Progress::Continue), server_step.progress);
+            };
+            let client_step = client.on_message(&to_client);
+            match client_step.send {
+                Some(msg) if !server_done => { to_server = msg }
+                _ => { return (client_step.progress, server_step.progress) }
+            }
+        }
+    }
+
+    #[test]
+    fn negotiate() {
+        let mut client = ClientHandshake::new(&[Capability::Switch, Capability::Subscribe], None);
+        let mut server = ServerHandshake::new(&[Capability::Switch, Capability::Power], None);
+        let (client_res, server_res) = handshake(&mut client, &mut server);
+        let expected = Hello { version: PROTOCOL_VERSION, capabilities: vec![Capability::Switch], challenge: None };
+        assert_eq!(client_res.unwrap(), Progress::Done(expected.clone()));
+        assert_eq!(server_res.unwrap(), Progress::Done(expected));
+    }
+
+    #[test]
+    fn authenticate() {
+        let key = PreSharedKey::new("key");
+        let mut client = ClientHandshake::new(&Capability::ALL, Some(key.clone()));
+        let mut server = ServerHandshake::new(&Capability::ALL, Some(key));
+        let (client_res, server_res) = handshake(&mut client, &mut server);
+        assert!(matches!(client_res, Ok(Progress::Done(_))));
+        assert!(matches!(server_res, Ok(Progress::Done(_))));
+
+        let mut client = ClientHandshake::new(&Capability::ALL, Some(PreSharedKey::new("guess")));
+        let mut server = ServerHandshake::new(&Capability::ALL, Some(PreSharedKey::new("key")));
+        let (client_res, server_res) = handshake(&mut client, &mut server);
+        assert!(matches!(client_res, Err(ConnectError::Unauthorized(_))));
+        assert!(matches!(server_res, Err(ConnectError::Unauthorized(_))));
+    }
+
+    #[test]
+    fn reject_bad_requests() {
+        let mut server = ServerHandshake::new(&Capability::ALL, None);
+        let step = server.on_message("hello");
+        assert!(step.send.is_none());
+        assert!(matches!(step.progress, Err(ConnectError::BadHandshake(_))));
+        // outdated client still gets the respond
+        let step = ServerHandshake::new(&Capability::ALL, None).on_message("hi_server;0;switch");
+        assert!(step.send.is_some());
+        assert!(matches!(step.progress, Err(ConnectError::BadHandshake(_))));
+        let step = ClientHandshake::new(&Capability::ALL, None).on_message("hi_client;0;switch");
+        assert!(matches!(step.progress, Err(ConnectError::BadHandshake(_))));
+    }
+
+    #[test]
+    fn route_frames() {
+        let mut session = Session::new(Hello::new(&Capability::ALL), Some(Heartbeat::new(Duration::from_secs(1), 1)));
+        let ping = Frame { kind: FrameKind::Ping, payload: b"1".to_vec() };
+        assert_eq!(session.on_frame(ping), Received::Reply(Frame { kind: FrameKind::Pong, payload: b"1".to_vec() }));
+        assert_eq!(session.on_frame(Frame { kind: FrameKind::Pong, payload: vec![] }), Received::Nothing);
+        let deadline = session.deadline().unwrap();
+        assert!(matches!(session.on_timeout(deadline), Timeout::Send(Frame { kind: FrameKind::Ping, .. })));
+        assert!(matches!(session.on_timeout(deadline + Duration::from_secs(1)), Timeout::Close(RecvError::PeerTimeout(1))));
+
+        let session = Session::new(Hello::new(&[Capability::Subscribe]), None);
+        let mut state = ClientState::default();
+        let event = Frame { kind: FrameKind::Event, payload: Event::StateChanged(true).encode().into_bytes() };
+        assert_eq!(state.on_response_frame(&session, event).unwrap(), None);
+        assert_eq!(state.on_response_frame(&session, Frame { kind: FrameKind::Message, payload: b"ok".to_vec() }).unwrap(), Some("ok".to_string()));
+        assert!(state.pop_event().is_some());
+        let error = Frame { kind: FrameKind::Error, payload: ErrorReply::new(ErrorCode::Offline, "no power").encode().into_bytes() };
+        assert!(matches!(state.on_response_frame(&session, error), Err(RecvError::Remote(ErrorReply { code: ErrorCode::Offline, .. }))));
+        assert!(state.on_event_frame(&session, Frame { kind: FrameKind::Message, payload: vec![] }).is_err());
+    }
+
+    #[test]
+    fn tag_requests() {
+        let session = Session::new(Hello::new(&Capability::ALL), None);
+        let mut state = ClientState::default();
+        let first = state.request_frame(&session, "first");
+        assert_eq!(session.untag(first.clone()).unwrap(), (Some(1), Frame { kind: FrameKind::Message, payload: b"first".to_vec() }));
+        let second = state.request_frame(&session, "second");
+        // late response to the first request is skipped
+        assert_eq!(state.on_response_frame(&session, first).unwrap(), None);
+        assert_eq!(state.on_response_frame(&session, second).unwrap(), Some("second".to_string()));
+        assert!(matches!(session.untag(Frame { kind: FrameKind::Message, payload: vec![0, 1] }), Err(RecvError::BadMessage(_))));
+        assert_eq!(next_request_id(u32::MAX), 1);
+
+        let plain = Session::new(Hello::new(&[Capability::Switch]), None);
+        assert_eq!(plain.tag(7, Frame { kind: FrameKind::Message, payload: b"x".to_vec() }).payload, b"x".to_vec());
+    }
+}
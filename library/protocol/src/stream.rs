@@ -0,0 +1,183 @@
+use std::io;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::ops::Deref;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use rustls::{ClientConnection, ServerConnection, StreamOwned};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::record::Recorder;
+
+/// Blocking STP stream: plain transport or TLS over the transport
+#[derive(Debug)]
+pub enum StpStream<S: Read + Write = TcpStream> {
+    Plain(S),
+    TlsClient(Box<StreamOwned<ClientConnection, S>>),
+    TlsServer(Box<StreamOwned<ServerConnection, S>>),
+    /// Frames of the stream are recorded after TLS decryption
+    Recorded(Box<StpStream<S>>, Recorder),
+}
+
+impl<S: Read + Write> StpStream<S> {
+    /// Underlying transport, e.g. to set timeouts
+    pub fn get_ref(&self) -> &S {
+        match self {
+            StpStream::Plain(s) => { s }
+            StpStream::TlsClient(s) => { s.get_ref() }
+            StpStream::TlsServer(s) => { s.get_ref() }
+            StpStream::Recorded(s, _) => { s.get_ref() }
+        }
+    }
+
+    pub fn is_tls(&self) -> bool {
+        match self {
+            StpStream::Plain(_) => { false }
+            StpStream::TlsClient(_) | StpStream::TlsServer(_) => { true }
+            StpStream::Recorded(s, _) => { s.is_tls() }
+        }
+    }
+
+    /// Record frames read and written by the stream
+    pub fn recorded(self, recorder: Recorder) -> Self {
+        StpStream::Recorded(Box::new(self), recorder)
+    }
+}
+
+impl<S: Read + Write> Deref for StpStream<S> {
+    type Target = S;
+
+    fn deref(&self) -> &Self::Target {
+        self.get_ref()
+    }
+}
+
+impl<S: Read + Write> Read for StpStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            StpStream::Plain(s) => { s.read(buf) }
+            StpStream::TlsClient(s) => { s.read(buf) }
+            StpStream::TlsServer(s) => { s.read(buf) }
+            StpStream::Recorded(s, recorder) => {
+                let n = s.read(buf)?;
+                recorder.read(&buf[..n]);
+                Ok(n)
+            }
+        }
+    }
+}
+
+impl<S: Read + Write> Write for StpStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            StpStream::Plain(s) => { s.write(buf) }
+            StpStream::TlsClient(s) => { s.write(buf) }
+            StpStream::TlsServer(s) => { s.write(buf) }
+            StpStream::Recorded(s, recorder) => {
+                let n = s.write(buf)?;
+                recorder.written(&buf[..n]);
+                Ok(n)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            StpStream::Plain(s) => { s.flush() }
+            StpStream::TlsClient(s) => { s.flush() }
+            StpStream::TlsServer(s) => { s.flush() }
+            StpStream::Recorded(s, _) => { s.flush() }
+        }
+    }
+}
+
+/// Async STP stream: plain transport or TLS over the transport
+#[derive(Debug)]
+pub enum AsyncStpStream<S = tokio::net::TcpStream> {
+    Plain(S),
+    Tls(Box<tokio_rustls::TlsStream<S>>),
+    /// Frames of the stream are recorded after TLS decryption
+    Recorded(Box<AsyncStpStream<S>>, Recorder),
+}
+
+impl<S> AsyncStpStream<S> {
+    /// Underlying transport
+    pub fn get_ref(&self) -> &S {
+        match self {
+            AsyncStpStream::Plain(s) => { s }
+            AsyncStpStream::Tls(s) => { s.get_ref().0 }
+            AsyncStpStream::Recorded(s, _) => { s.get_ref() }
+        }
+    }
+
+    pub fn is_tls(&self) -> bool {
+        match self {
+            AsyncStpStream::Plain(_) => { false }
+            AsyncStpStream::Tls(_) => { true }
+            AsyncStpStream::Recorded(s, _) => { s.is_tls() }
+        }
+    }
+
+    /// Record frames read and written by the stream
+    pub fn recorded(self, recorder: Recorder) -> Self {
+        AsyncStpStream::Recorded(Box::new(self), recorder)
+    }
+}
+
+impl<S> Deref for AsyncStpStream<S> {
+    type Target = S;
+
+    fn deref(&self) -> &Self::Target {
+        self.get_ref()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for AsyncStpStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AsyncStpStream::Plain(s) => { Pin::new(s).poll_read(cx, buf) }
+            AsyncStpStream::Tls(s) => { Pin::new(s).poll_read(cx, buf) }
+            AsyncStpStream::Recorded(s, recorder) => {
+                let filled = buf.filled().len();
+                let poll = Pin::new(&mut **s).poll_read(cx, buf);
+                if let Poll::Ready(Ok(())) = poll {
+                    recorder.read(&buf.filled()[filled..]);
+                }
+                poll
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for AsyncStpStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            AsyncStpStream::Plain(s) => { Pin::new(s).poll_write(cx, buf) }
+            AsyncStpStream::Tls(s) => { Pin::new(s).poll_write(cx, buf) }
+            AsyncStpStream::Recorded(s, recorder) => {
+                let poll = Pin::new(&mut **s).poll_write(cx, buf);
+                if let Poll::Ready(Ok(n)) = poll {
+                    recorder.written(&buf[..n]);
+                }
+                poll
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AsyncStpStream::Plain(s) => { Pin::new(s).poll_flush(cx) }
+            AsyncStpStream::Tls(s) => { Pin::new(s).poll_flush(cx) }
+            AsyncStpStream::Recorded(s, _) => { Pin::new(&mut **s).poll_flush(cx) }
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AsyncStpStream::Plain(s) => { Pin::new(s).poll_shutdown(cx) }
+            AsyncStpStream::Tls(s) => { Pin::new(s).poll_shutdown(cx) }
+            AsyncStpStream::Recorded(s, _) => { Pin::new(&mut **s).poll_shutdown(cx) }
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use crate::message::Event;
+
+/// Server side state of the client subscription.
+/// Remembers the last reported values and produces events when they change.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subscription {
+    power_threshold_wt: f32,
+    last_state: Option<bool>,
+    last_power_wt: Option<Option<f32>>,
+}
+
+impl Subscription {
+    pub fn new(power_threshold_wt: f32) -> Self {
+        Self { power_threshold_wt: power_threshold_wt.abs(), last_state: None, last_power_wt: None }
+    }
+
+    pub fn power_threshold_wt(&self) -> f32 {
+        self.power_threshold_wt
+    }
+
+    /// Events to push for the current device values.
+    /// The first call after subscribing reports both values.
+    pub fn update(&mut self, state: bool, power_wt: Option<f32>) -> Vec<Event> {
+        let mut events = Vec::new();
+        if self.last_state != Some(state) {
+            self.last_state = Some(state);
+            events.push(Event::StateChanged(state));
+        }
+        let power_changed = match (self.last_power_wt, power_wt) {
+            (None, _) => { true }
+            (Some(Some(last)), Some(current)) => { (current - last).abs() >= self.power_threshold_wt && current != last }
+            (Some(last), current) => { last.is_some() != current.is_some() }
+        };
+        if power_changed {
+            self.last_power_wt = Some(power_wt);
+            events.push(Event::PowerChanged(power_wt));
+        }
+        events
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_update_reports_all() {
+        let mut subscription = Subscription::new(10.0);
+        assert_eq!(subscription.update(false, Some(0.0)), vec![Event::StateChanged(false), Event::PowerChanged(Some(0.0))]);
+        assert_eq!(subscription.update(false, Some(0.0)), vec![]);
+    }
+
+    #[test]
+    fn power_threshold() {
+        let mut subscription = Subscription::new(10.0);
+        subscription.update(true, Some(100.0));
+        assert_eq!(subscription.update(true, Some(105.0)), vec![]);
+        // compared with the last reported value, not with the last seen one
+        assert_eq!(subscription.update(true, Some(110.0)), vec![Event::PowerChanged(Some(110.0))]);
+        assert_eq!(subscription.update(true, Some(101.0)), vec![]);
+        assert_eq!(subscription.update(true, None), vec![Event::PowerChanged(None)]);
+        assert_eq!(subscription.update(false, Some(0.0)), vec![Event::StateChanged(false), Event::PowerChanged(Some(0.0))]);
+    }
+
+    #[test]
+    fn zero_threshold() {
+        let mut subscription = Subscription::new(0.0);
+        subscription.update(true, Some(1.0));
+        assert_eq!(subscription.update(true, Some(1.0)), vec![]);
+        assert_eq!(subscription.update(true, Some(1.5)), vec![Event::PowerChanged(Some(1.5))]);
+    }
+}
@@ -0,0 +1,234 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::pki_types::pem::PemObject;
+use rustls::server::WebPkiClientVerifier;
+
+use crate::errors::{TlsError, TlsResult};
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn root_store(roots: Vec<CertificateDer<'static>>) -> TlsResult<RootCertStore> {
+    let mut store = RootCertStore::empty();
+    for root in roots {
+        store.add(root)?;
+    }
+    Ok(store)
+}
+
+/// Read all certificates from PEM file
+pub fn load_certs<P: AsRef<Path>>(path: P) -> TlsResult<Vec<CertificateDer<'static>>> {
+    CertificateDer::pem_file_iter(path)
+        .map_err(|e| TlsError::Pem(e.to_string()))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| TlsError::Pem(e.to_string()))
+}
+
+/// Read the first private key from PEM file
+pub fn load_private_key<P: AsRef<Path>>(path: P) -> TlsResult<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(|e| TlsError::Pem(e.to_string()))
+}
+
+/// TLS settings of the STP server
+#[derive(Debug, Clone)]
+pub struct TlsServerConfig {
+    config: Arc<ServerConfig>,
+}
+
+impl TlsServerConfig {
+    /// Server certificate without client authentication
+    pub fn new(cert_chain: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> TlsResult<Self> {
+        let config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(cert_chain, key)?;
+        Ok(Self { config: Arc::new(config) })
+    }
+
+    /// Server certificate, clients must present a certificate signed by one of `client_roots`
+    pub fn with_client_auth(
+        cert_chain: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+        client_roots: Vec<CertificateDer<'static>>,
+    ) -> TlsResult<Self> {
+        let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(root_store(client_roots)?), provider())
+            .build()
+            .map_err(|e| TlsError::Verifier(e.to_string()))?;
+        let config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(cert_chain, key)?;
+        Ok(Self { config: Arc::new(config) })
+    }
+
+    pub fn from_pem_files<P: AsRef<Path>>(cert_path: P, key_path: P) -> TlsResult<Self> {
+        Self::new(load_certs(cert_path)?, load_private_key(key_path)?)
+    }
+
+    pub fn config(&self) -> Arc<ServerConfig> {
+        self.config.clone()
+    }
+}
+
+/// TLS settings of the STP client
+#[derive(Debug, Clone)]
+pub struct TlsClientConfig {
+    config: Arc<ClientConfig>,
+    server_name: ServerName<'static>,
+}
+
+impl TlsClientConfig {
+    /// Trust server certificates signed by one of `roots`.
+    /// `server_name` is checked against the server certificate.
+    pub fn new(roots: Vec<CertificateDer<'static>>, server_name: &str) -> TlsResult<Self> {
+        let config = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(root_store(roots)?)
+            .with_no_client_auth();
+        Self::with_config(config, server_name)
+    }
+
+    /// Same as [`TlsClientConfig::new`], also presents the client certificate to the server
+    pub fn with_client_cert(
+        roots: Vec<CertificateDer<'static>>,
+        server_name: &str,
+        cert_chain: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+    ) -> TlsResult<Self> {
+        let config = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(root_store(roots)?)
+            .with_client_auth_cert(cert_chain, key)?;
+        Self::with_config(config, server_name)
+    }
+
+    fn with_config(config: ClientConfig, server_name: &str) -> TlsResult<Self> {
+        let server_name = ServerName::try_from(server_name.to_string())
+            .map_err(|_| TlsError::ServerName(server_name.to_string()))?;
+        Ok(Self { config: Arc::new(config), server_name })
+    }
+
+    pub fn config(&self) -> Arc<ClientConfig> {
+        self.config.clone()
+    }
+
+    pub fn server_name(&self) -> ServerName<'static> {
+        self.server_name.clone()
+    }
+}
+
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::thread;
+
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use rustls::pki_types::PrivatePkcs8KeyDer;
+
+    use crate::message::{Request, Response};
+
+    use super::*;
+
+    pub(crate) struct TestPki {
+        pub ca: CertificateDer<'static>,
+        pub server_cert: CertificateDer<'static>,
+        pub server_key: PrivateKeyDer<'static>,
+        pub client_cert: CertificateDer<'static>,
+        pub client_key: PrivateKeyDer<'static>,
+    }
+
+    /// Self-signed CA with server certificate for `localhost` and client certificate
+    pub(crate) fn test_pki() -> TestPki {
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca_key = KeyPair::generate().unwrap();
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let server_key = KeyPair::generate().unwrap();
+        let server_cert = CertificateParams::new(vec!["localhost".to_string()]).unwrap()
+            .signed_by(&server_key, &ca, &ca_key).unwrap();
+        let client_key = KeyPair::generate().unwrap();
+        let client_cert = CertificateParams::new(vec!["client".to_string()]).unwrap()
+            .signed_by(&client_key, &ca, &ca_key).unwrap();
+        TestPki {
+            ca: ca.der().clone(),
+            server_cert: server_cert.der().clone(),
+            server_key: PrivatePkcs8KeyDer::from(server_key.serialize_der()).into(),
+            client_cert: client_cert.der().clone(),
+            client_key: PrivatePkcs8KeyDer::from(client_key.serialize_der()).into(),
+        }
+    }
+
+    #[test]
+    fn std_request_over_tls() {
+        use crate::client_std::ClientStp;
+        use crate::server_std::ServerStp;
+
+        let pki = test_pki();
+        let tls = TlsServerConfig::new(vec![pki.server_cert.clone()], pki.server_key.clone_key()).unwrap();
+        let server = ServerStp::bind_tls("127.0.0.1:0", tls).unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let mut connection = server.incoming().next().unwrap().unwrap();
+            assert_eq!(connection.recv().unwrap(), Request::GetDescription);
+            connection.reply(&Response::Description("socket@kitchen".to_string())).unwrap();
+        });
+        let tls = TlsClientConfig::new(vec![pki.ca.clone()], "localhost").unwrap();
+        let mut client = ClientStp::connect_tls(addr, &tls).unwrap();
+        assert_eq!(client.request(&Request::GetDescription).unwrap(), Response::Description("socket@kitchen".to_string()));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn std_untrusted_server() {
+        use crate::client_std::ClientStp;
+        use crate::server_std::ServerStp;
+
+        let pki = test_pki();
+        let other_pki = test_pki();
+        let tls = TlsServerConfig::new(vec![pki.server_cert.clone()], pki.server_key.clone_key()).unwrap();
+        let server = ServerStp::bind_tls("127.0.0.1:0", tls).unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            assert!(server.incoming().next().unwrap().is_err());
+        });
+        let tls = TlsClientConfig::new(vec![other_pki.ca], "localhost").unwrap();
+        assert!(ClientStp::connect_tls(addr, &tls).is_err());
+        handle.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn tokio_client_auth() {
+        use crate::client_tokio::ClientStp;
+        use crate::server_tokio::ServerStp;
+
+        let pki = test_pki();
+        let tls = TlsServerConfig::with_client_auth(vec![pki.server_cert.clone()], pki.server_key.clone_key(), vec![pki.ca.clone()]).unwrap();
+        let server = ServerStp::bind_tls("127.0.0.1:0", tls).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = tokio::spawn(async move {
+            // client without certificate is rejected
+            assert!(server.accept().await.is_err());
+            let mut connection = server.accept().await.unwrap();
+            assert_eq!(connection.recv().await.unwrap(), Request::GetState);
+            connection.reply(&Response::State(true)).await.unwrap();
+        });
+
+        let anonymous = TlsClientConfig::new(vec![pki.ca.clone()], "localhost").unwrap();
+        assert!(ClientStp::connect_tls(addr, &anonymous).await.is_err());
+
+        let tls = TlsClientConfig::with_client_cert(vec![pki.ca.clone()], "localhost", vec![pki.client_cert.clone()], pki.client_key.clone_key()).unwrap();
+        let mut client = ClientStp::connect_tls(addr, &tls).await.unwrap();
+        assert_eq!(client.request(&Request::GetState).await.unwrap(), Response::State(true));
+        handle.await.unwrap();
+    }
+
+    #[test]
+    fn bad_server_name() {
+        assert!(matches!(TlsClientConfig::new(vec![], "not a host name"), Err(TlsError::ServerName(_))));
+    }
+}
@@ -0,0 +1,318 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, Shutdown, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{Arc, Condvar, mpsc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
+
+/// Blocking byte stream able to carry STP: TCP, Unix socket or in-memory pipe
+pub trait Transport: Read + Write {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn read_timeout(&self) -> io::Result<Option<Duration>>;
+    /// Close both directions, the peer reads the end of stream
+    fn shutdown(&self) -> io::Result<()>;
+}
+
+/// Source of incoming blocking transports for [`crate::server_std::ServerStp`]
+pub trait Listener {
+    type Stream: Transport;
+
+    fn accept(&self) -> io::Result<Self::Stream>;
+
+    /// Address of the client for per-IP limits, `None` for local transports
+    fn peer_ip(_stream: &Self::Stream) -> Option<IpAddr> {
+        None
+    }
+}
+
+/// Async byte stream able to carry STP: TCP, Unix socket or in-memory duplex
+pub trait AsyncTransport: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncTransport for T {}
+
+/// Source of incoming async transports for [`crate::server_tokio::ServerStp`]
+pub trait AsyncListener {
+    type Stream: AsyncTransport;
+
+    fn accept(&self) -> impl Future<Output=io::Result<Self::Stream>> + Send;
+
+    /// Address of the client for per-IP limits, `None` for local transports
+    fn peer_ip(_stream: &Self::Stream) -> Option<IpAddr> {
+        None
+    }
+}
+
+impl Transport for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        TcpStream::read_timeout(self)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept(&self) -> io::Result<Self::Stream> {
+        TcpListener::accept(self).map(|(s, _)| s)
+    }
+
+    fn peer_ip(stream: &Self::Stream) -> Option<IpAddr> {
+        stream.peer_addr().ok().map(|addr| addr.ip())
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        UnixStream::read_timeout(self)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+#[cfg(unix)]
+impl Listener for UnixListener {
+    type Stream = UnixStream;
+
+    fn accept(&self) -> io::Result<Self::Stream> {
+        UnixListener::accept(self).map(|(s, _)| s)
+    }
+}
+
+impl AsyncListener for tokio::net::TcpListener {
+    type Stream = tokio::net::TcpStream;
+
+    async fn accept(&self) -> io::Result<Self::Stream> {
+        tokio::net::TcpListener::accept(self).await.map(|(s, _)| s)
+    }
+
+    fn peer_ip(stream: &Self::Stream) -> Option<IpAddr> {
+        stream.peer_addr().ok().map(|addr| addr.ip())
+    }
+}
+
+#[cfg(unix)]
+impl AsyncListener for tokio::net::UnixListener {
+    type Stream = tokio::net::UnixStream;
+
+    async fn accept(&self) -> io::Result<Self::Stream> {
+        tokio::net::UnixListener::accept(self).await.map(|(s, _)| s)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Pipe {
+    buff: VecDeque<u8>,
+    closed: bool,
+}
+
+type SharedPipe = Arc<(Mutex<Pipe>, Condvar)>;
+
+/// One end of the blocking in-memory pipe, see [`memory_pair`]
+#[derive(Debug)]
+pub struct MemoryStream {
+    rx: SharedPipe,
+    tx: SharedPipe,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+/// Two connected ends of the blocking in-memory pipe
+pub fn memory_pair() -> (MemoryStream, MemoryStream) {
+    let a: SharedPipe = Arc::default();
+    let b: SharedPipe = Arc::default();
+    (
+        MemoryStream { rx: a.clone(), tx: b.clone(), read_timeout: Mutex::new(None) },
+        MemoryStream { rx: b, tx: a, read_timeout: Mutex::new(None) },
+    )
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = *self.read_timeout.lock().unwrap();
+        let (pipe, cvar) = &*self.rx;
+        let mut pipe = pipe.lock().unwrap();
+        while pipe.buff.is_empty() && !pipe.closed {
+            pipe = match timeout {
+                Some(timeout) => {
+                    let (pipe, res) = cvar.wait_timeout(pipe, timeout).unwrap();
+                    if res.timed_out() && pipe.buff.is_empty() {
+                        return Err(io::Error::from(ErrorKind::WouldBlock));
+                    }
+                    pipe
+                }
+                None => { cvar.wait(pipe).unwrap() }
+            };
+        }
+        let n = buf.len().min(pipe.buff.len());
+        for (dst, src) in buf.iter_mut().zip(pipe.buff.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (pipe, cvar) = &*self.tx;
+        let mut pipe = pipe.lock().unwrap();
+        if pipe.closed {
+            return Err(io::Error::from(ErrorKind::BrokenPipe));
+        }
+        pipe.buff.extend(buf);
+        cvar.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl MemoryStream {
+    fn close(&self) {
+        for shared in [&self.rx, &self.tx] {
+            let (pipe, cvar) = &**shared;
+            pipe.lock().unwrap().closed = true;
+            cvar.notify_all();
+        }
+    }
+}
+
+impl Drop for MemoryStream {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl Transport for MemoryStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        if timeout == Some(Duration::ZERO) {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "zero timeout"));
+        }
+        *self.read_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(*self.read_timeout.lock().unwrap())
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.close();
+        Ok(())
+    }
+}
+
+/// In-memory listener, accepts streams opened by [`MemoryConnector`]
+#[derive(Debug)]
+pub struct MemoryListener {
+    rx: Mutex<mpsc::Receiver<MemoryStream>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MemoryConnector {
+    tx: mpsc::Sender<MemoryStream>,
+}
+
+pub fn memory_listener() -> (MemoryListener, MemoryConnector) {
+    let (tx, rx) = mpsc::channel();
+    (MemoryListener { rx: Mutex::new(rx) }, MemoryConnector { tx })
+}
+
+impl MemoryConnector {
+    pub fn connect(&self) -> io::Result<MemoryStream> {
+        let (client, server) = memory_pair();
+        self.tx.send(server).map_err(|_| io::Error::from(ErrorKind::ConnectionRefused))?;
+        Ok(client)
+    }
+}
+
+impl Listener for MemoryListener {
+    type Stream = MemoryStream;
+
+    fn accept(&self) -> io::Result<Self::Stream> {
+        self.rx.lock().unwrap().recv().map_err(|_| io::Error::from(ErrorKind::ConnectionAborted))
+    }
+}
+
+/// Async in-memory listener, accepts streams opened by [`AsyncMemoryConnector`]
+#[derive(Debug)]
+pub struct AsyncMemoryListener {
+    rx: tokio::sync::Mutex<tokio::sync::mpsc::UnboundedReceiver<DuplexStream>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AsyncMemoryConnector {
+    tx: tokio::sync::mpsc::UnboundedSender<DuplexStream>,
+}
+
+/// Size of the in-memory pipe buffer of [`AsyncMemoryConnector`]
+const DUPLEX_BUFFER: usize = 64 * 1024;
+
+pub fn async_memory_listener() -> (AsyncMemoryListener, AsyncMemoryConnector) {
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    (AsyncMemoryListener { rx: tokio::sync::Mutex::new(rx) }, AsyncMemoryConnector { tx })
+}
+
+impl AsyncMemoryConnector {
+    pub fn connect(&self) -> io::Result<DuplexStream> {
+        let (client, server) = tokio::io::duplex(DUPLEX_BUFFER);
+        self.tx.send(server).map_err(|_| io::Error::from(ErrorKind::ConnectionRefused))?;
+        Ok(client)
+    }
+}
+
+impl AsyncListener for AsyncMemoryListener {
+    type Stream = DuplexStream;
+
+    async fn accept(&self) -> io::Result<Self::Stream> {
+        self.rx.lock().await.recv().await.ok_or(io::Error::from(ErrorKind::ConnectionAborted))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn memory_pipe() {
+        let (mut a, mut b) = memory_pair();
+        a.write_all(b"ping").unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(b.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"ping");
+
+        b.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        assert_eq!(b.read(&mut buf).unwrap_err().kind(), ErrorKind::WouldBlock);
+
+        let writer = thread::spawn(move || {
+            a.write_all(b"pong").unwrap();
+        });
+        writer.join().unwrap();
+        assert_eq!(b.read(&mut buf).unwrap(), 4);
+        // the other end is dropped
+        assert_eq!(b.read(&mut buf).unwrap(), 0);
+        assert_eq!(b.write(b"x").unwrap_err().kind(), ErrorKind::BrokenPipe);
+    }
+}
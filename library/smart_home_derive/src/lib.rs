@@ -17,7 +17,7 @@ pub fn described(input: TokenStream) -> TokenStream {
         // Check if the struct has named fields
         if let Fields::Named(fields) = data.fields {
             // Iterate over the fields and check if a field named "description" exists
-            let has_field = fields.named.iter().any(|field| field.ident.as_ref().map_or(false, |ident| ident == "description"));
+            let has_field = fields.named.iter().any(|field| field.ident.as_ref().is_some_and(|ident| ident == "description"));
             // If the field does not exist, raise a compilation error
 
             if !has_field {
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+
+/// Server on a free port of 127.0.0.1 for device simulators in tests.
+/// Every connection is served on its own thread. Stops and breaks the connections on drop.
+pub struct LocalServer {
+    addr: SocketAddr,
+    state: Arc<ServerState>,
+}
+
+#[derive(Default)]
+struct ServerState {
+    /// Connections being served, by id
+    connections: Mutex<HashMap<u64, TcpStream>>,
+    next_id: AtomicU64,
+    stopped: AtomicBool,
+}
+
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl LocalServer {
+    pub fn start<F>(serve: F) -> io::Result<Self>
+    where
+        F: Fn(TcpStream) + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(ServerState::default());
+        let accepting = state.clone();
+        let serve = Arc::new(serve);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if accepting.stopped.load(Ordering::Relaxed) {
+                    break;
+                }
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let id = accepting.next_id.fetch_add(1, Ordering::Relaxed);
+                if let Ok(clone) = stream.try_clone() {
+                    lock(&accepting.connections).insert(id, clone);
+                }
+                let serve = serve.clone();
+                let state = accepting.clone();
+                thread::spawn(move || {
+                    serve(stream);
+                    lock(&state.connections).remove(&id);
+                });
+            }
+        });
+        Ok(Self { addr, state })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Break the connections of the clients, as a restarted device does
+    pub fn drop_connections(&self) {
+        for (_, stream) in lock(&self.state.connections).drain() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+impl Drop for LocalServer {
+    fn drop(&mut self) {
+        self.state.stopped.store(true, Ordering::Relaxed);
+        // wake up the accepting thread
+        let _ = TcpStream::connect(self.addr);
+        self.drop_connections();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    #[test]
+    fn forget_closed_connections() {
+        let server = LocalServer::start(|mut stream| {
+            let _ = stream.read(&mut [0; 1]);
+        }).unwrap();
+        let clients: Vec<TcpStream> = (0..3).map(|_| TcpStream::connect(server.addr()).unwrap()).collect();
+        let live = |count: usize| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while lock(&server.state.connections).len() != count {
+                assert!(Instant::now() < deadline, "{} connections expected", count);
+                thread::sleep(Duration::from_millis(1));
+            }
+        };
+        live(3);
+        drop(clients);
+        live(0);
+    }
+}
@@ -1,4 +1,5 @@
 pub mod traits;
 pub mod types;
 pub mod traits_async;
-
+#[cfg(any(test, feature = "simulators"))]
+pub mod local_server;
@@ -0,0 +1,205 @@
+use std::io;
+
+use serde_json::{json, Value};
+use thiserror::Error;
+
+use crate::common::traits::device::ErrorSm;
+use crate::common::traits_async::device::Err;
+
+/// TCP port of Kasa plugs
+pub const KASA_PORT: u16 = 9999;
+/// Big-endian length of the encrypted message
+pub const LEN_PREFIX: usize = 4;
+/// Longer messages are treated as garbage, sysinfo is about 1 KiB
+pub const MAX_MESSAGE_LEN: usize = 64 * 1024;
+const INITIAL_KEY: u8 = 171;
+
+#[derive(Debug, Error)]
+pub enum KasaError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("bad Kasa message: {0}")]
+    BadMessage(String),
+    /// Non-zero `err_code` of the plug
+    #[error("Kasa error {code}: {message}")]
+    Device { code: i64, message: String },
+}
+
+impl From<KasaError> for ErrorSm {
+    fn from(e: KasaError) -> Self {
+        ErrorSm { msg: e.to_string() }
+    }
+}
+
+impl From<KasaError> for Err {
+    fn from(e: KasaError) -> Self {
+        Err { msg: e.to_string() }
+    }
+}
+
+/// Autokey XOR: every byte is the key of the next one
+pub fn encrypt(plain: &[u8]) -> Vec<u8> {
+    let mut key = INITIAL_KEY;
+    plain.iter().map(|byte| {
+        key ^= byte;
+        key
+    }).collect()
+}
+
+pub fn decrypt(cipher: &[u8]) -> Vec<u8> {
+    let mut key = INITIAL_KEY;
+    cipher.iter().map(|byte| {
+        let plain = key ^ byte;
+        key = *byte;
+        plain
+    }).collect()
+}
+
+/// Length prefixed encrypted JSON
+pub fn encode(json: &str) -> Vec<u8> {
+    let cipher = encrypt(json.as_bytes());
+    let mut message = Vec::with_capacity(LEN_PREFIX + cipher.len());
+    message.extend_from_slice(&(cipher.len() as u32).to_be_bytes());
+    message.extend_from_slice(&cipher);
+    message
+}
+
+pub fn message_len(prefix: [u8; LEN_PREFIX]) -> Result<usize, KasaError> {
+    let len = u32::from_be_bytes(prefix) as usize;
+    if len > MAX_MESSAGE_LEN {
+        return Err(KasaError::BadMessage(format!("message of {} bytes", len)));
+    }
+    Ok(len)
+}
+
+/// JSON of the decrypted message
+pub fn decode(cipher: &[u8]) -> Result<Value, KasaError> {
+    serde_json::from_slice(&decrypt(cipher)).map_err(|e| KasaError::BadMessage(e.to_string()))
+}
+
+/// Commands used by the drivers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    SetRelayState(bool),
+    GetSysinfo,
+    /// Real-time readings of the energy meter, HS110 and alike
+    GetRealtime,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    Ok,
+    Sysinfo(Sysinfo),
+    /// Watts, `None` if the plug has no energy meter
+    Realtime(Option<f32>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sysinfo {
+    /// Name given in the Kasa app
+    pub alias: String,
+    pub model: String,
+    pub relay_state: bool,
+}
+
+impl Command {
+    /// Module and method of the JSON request
+    pub fn path(&self) -> (&'static str, &'static str) {
+        match self {
+            Command::SetRelayState(_) => { ("system", "set_relay_state") }
+            Command::GetSysinfo => { ("system", "get_sysinfo") }
+            Command::GetRealtime => { ("emeter", "get_realtime") }
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        let (module, method) = self.path();
+        let args = match self {
+            Command::SetRelayState(on) => { json!({ "state": *on as u8 }) }
+            _ => { json!({}) }
+        };
+        json!({ module: { method: args } }).to_string()
+    }
+
+    /// Reply of the plug to the command
+    pub fn parse_reply(&self, reply: &Value) -> Result<Reply, KasaError> {
+        let (module, method) = self.path();
+        let bad = |msg: &str| KasaError::BadMessage(format!("{} in reply to {}.{}: {}", msg, module, method, reply));
+        let module_reply = reply.get(module).ok_or_else(|| bad("no module"))?;
+        // plugs without the module answer at the module level
+        if let Err(e) = check_err_code(module_reply) {
+            return match self {
+                Command::GetRealtime => { Ok(Reply::Realtime(None)) }
+                _ => { Err(e) }
+            };
+        }
+        let result = module_reply.get(method).ok_or_else(|| bad("no method"))?;
+        check_err_code(result)?;
+        match self {
+            Command::SetRelayState(_) => { Ok(Reply::Ok) }
+            Command::GetSysinfo => {
+                let text = |field: &str| result.get(field).and_then(Value::as_str).unwrap_or_default().to_string();
+                let relay_state = result.get("relay_state").and_then(Value::as_u64).ok_or_else(|| bad("no relay_state"))?;
+                Ok(Reply::Sysinfo(Sysinfo { alias: text("alias"), model: text("model"), relay_state: relay_state != 0 }))
+            }
+            Command::GetRealtime => {
+                // hardware v1 reports watts, v2 milliwatts
+                let watts = match (result.get("power").and_then(Value::as_f64), result.get("power_mw").and_then(Value::as_f64)) {
+                    (Some(watts), _) => { watts }
+                    (None, Some(milliwatts)) => { milliwatts / 1000.0 }
+                    (None, None) => { return Err(bad("no power")) }
+                };
+                Ok(Reply::Realtime(Some(watts as f32)))
+            }
+        }
+    }
+}
+
+/// Missing `err_code` is success
+fn check_err_code(reply: &Value) -> Result<(), KasaError> {
+    match reply.get("err_code").and_then(Value::as_i64) {
+        None | Some(0) => { Ok(()) }
+        Some(code) => {
+            let message = reply.get("err_msg").and_then(Value::as_str).unwrap_or("unknown error").to_string();
+            Err(KasaError::Device { code, message })
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn autokey_xor() {
+        let json = Command::GetSysinfo.encode();
+        assert_eq!(json, r#"{"system":{"get_sysinfo":{}}}"#);
+        let message = encode(&json);
+        assert_eq!(message[..LEN_PREFIX], [0, 0, 0, 29]);
+        // well known prefix of every Kasa request
+        assert_eq!(message[LEN_PREFIX..LEN_PREFIX + 3], [0xd0, 0xf2, 0x81]);
+        assert_eq!(message_len(message[..LEN_PREFIX].try_into().unwrap()).unwrap(), 29);
+        assert_eq!(decode(&message[LEN_PREFIX..]).unwrap(), serde_json::from_str::<Value>(&json).unwrap());
+        assert!(message_len([0xff, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn parse_replies() {
+        let reply = json!({ "system": { "get_sysinfo": { "alias": "Kettle", "model": "HS110(EU)", "relay_state": 1, "err_code": 0 } } });
+        let sysinfo = Sysinfo { alias: "Kettle".to_string(), model: "HS110(EU)".to_string(), relay_state: true };
+        assert_eq!(Command::GetSysinfo.parse_reply(&reply).unwrap(), Reply::Sysinfo(sysinfo));
+
+        let v1 = json!({ "emeter": { "get_realtime": { "power": 12.5, "err_code": 0 } } });
+        assert_eq!(Command::GetRealtime.parse_reply(&v1).unwrap(), Reply::Realtime(Some(12.5)));
+        let v2 = json!({ "emeter": { "get_realtime": { "power_mw": 2500, "err_code": 0 } } });
+        assert_eq!(Command::GetRealtime.parse_reply(&v2).unwrap(), Reply::Realtime(Some(2.5)));
+        let no_meter = json!({ "emeter": { "err_code": -1, "err_msg": "module not support" } });
+        assert_eq!(Command::GetRealtime.parse_reply(&no_meter).unwrap(), Reply::Realtime(None));
+
+        let failed = json!({ "system": { "set_relay_state": { "err_code": -3, "err_msg": "invalid argument" } } });
+        let error = Command::SetRelayState(true).parse_reply(&failed).unwrap_err();
+        assert_eq!(error.to_string(), "Kasa error -3: invalid argument");
+        assert!(matches!(Command::GetSysinfo.parse_reply(&json!({})), Err(KasaError::BadMessage(_))));
+    }
+}
@@ -0,0 +1,166 @@
+use std::io;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::common::traits::Described;
+use crate::common::traits::device::{OptReplay, PowerConsumptionMeter, Replay, Switchable};
+use crate::devices::kasa::frame::{decode, encode, message_len, Command, KasaError, Reply, Sysinfo, LEN_PREFIX};
+use crate::devices::socket::SocketTrait;
+
+/// Time to wait for a reply, the command fails after it
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// TP-Link Kasa HS1xx plug, power is read from the energy meter if the plug has one.
+/// Some firmware closes the connection after a reply, the command is then retried on a new one.
+pub struct KasaPlug {
+    addr: Vec<SocketAddr>,
+    timeout: Duration,
+    stream: Option<TcpStream>,
+}
+
+impl KasaPlug {
+    /// Connect to the plug, e.g. `(host, KASA_PORT)`
+    pub fn connect<Addr: ToSocketAddrs>(addr: Addr) -> io::Result<Self> {
+        let addr: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        let mut plug = Self { addr, timeout: DEFAULT_TIMEOUT, stream: None };
+        plug.stream()?;
+        Ok(plug)
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self.stream = None;
+        self
+    }
+
+    pub fn sysinfo(&mut self) -> Result<Sysinfo, KasaError> {
+        match self.command(Command::GetSysinfo)? {
+            Reply::Sysinfo(sysinfo) => { Ok(sysinfo) }
+            reply => { Err(Self::unexpected(reply)) }
+        }
+    }
+
+    fn stream(&mut self) -> io::Result<&mut TcpStream> {
+        if self.stream.is_none() {
+            let stream = TcpStream::connect(&self.addr[..])?;
+            stream.set_read_timeout(Some(self.timeout))?;
+            stream.set_write_timeout(Some(self.timeout))?;
+            self.stream = Some(stream);
+        }
+        Ok(self.stream.as_mut().expect("connected above"))
+    }
+
+    pub fn command(&mut self, command: Command) -> Result<Reply, KasaError> {
+        let reused = self.stream.is_some();
+        let mut result = self.transact(command);
+        if reused && matches!(result, Err(KasaError::Io(_))) {
+            self.stream = None;
+            result = self.transact(command);
+        }
+        if let Err(KasaError::Io(_) | KasaError::BadMessage(_)) = result {
+            self.stream = None;
+        }
+        result
+    }
+
+    fn transact(&mut self, command: Command) -> Result<Reply, KasaError> {
+        let stream = self.stream()?;
+        stream.write_all(&encode(&command.encode()))?;
+        let mut prefix = [0; LEN_PREFIX];
+        stream.read_exact(&mut prefix)?;
+        let mut message = vec![0; message_len(prefix)?];
+        stream.read_exact(&mut message)?;
+        command.parse_reply(&decode(&message)?)
+    }
+
+    fn unexpected(reply: Reply) -> KasaError {
+        KasaError::BadMessage(format!("Unexpected reply: {:?}", reply))
+    }
+
+    fn set_relay(&mut self, on: bool) -> Replay<bool> {
+        match self.command(Command::SetRelayState(on))? {
+            Reply::Ok => { Ok(true) }
+            reply => { Err(Self::unexpected(reply).into()) }
+        }
+    }
+}
+
+impl PowerConsumptionMeter for KasaPlug {
+    fn power_consumption_wt(&mut self) -> OptReplay<f32> {
+        match self.command(Command::GetRealtime)? {
+            Reply::Realtime(power) => { Ok(power) }
+            reply => { Err(Self::unexpected(reply).into()) }
+        }
+    }
+}
+
+impl Switchable for KasaPlug {
+    fn turn_on(&mut self) -> Replay<bool> {
+        self.set_relay(true)
+    }
+
+    fn turn_off(&mut self) -> Replay<bool> {
+        self.set_relay(false)
+    }
+
+    fn current_state(&mut self) -> Replay<bool> {
+        Ok(self.sysinfo()?.relay_state)
+    }
+}
+
+impl Described for KasaPlug {
+    /// Alias given in the Kasa app
+    fn description(&mut self) -> String {
+        match self.sysinfo() {
+            Ok(sysinfo) => { sysinfo.alias }
+            Err(err) => { err.to_string() }
+        }
+    }
+}
+
+impl SocketTrait for KasaPlug {}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::devices::kasa::simulator::KasaSimulator;
+
+    use super::*;
+
+    #[test]
+    fn switch_and_meter() {
+        let simulator = KasaSimulator::start().unwrap();
+        simulator.set_alias("Kettle");
+        simulator.set_load(Some(1800.0));
+        let mut plug = KasaPlug::connect(simulator.addr()).unwrap();
+        assert_eq!(plug.description(), "Kettle");
+        assert_eq!(plug.sysinfo().unwrap().model, "HS110(EU)");
+
+        assert!(!plug.current_state().unwrap());
+        assert_eq!(plug.power_consumption_wt().unwrap(), Some(0.0));
+        assert!(plug.turn_on().unwrap());
+        assert!(simulator.relay_on());
+        assert!(plug.current_state().unwrap());
+        assert_eq!(plug.power_consumption_wt().unwrap(), Some(1800.0));
+        simulator.set_milliwatts(true);
+        assert_eq!(plug.power_consumption_wt().unwrap(), Some(1800.0));
+        simulator.set_load(None);
+        assert_eq!(plug.power_consumption_wt().unwrap(), None);
+        assert!(plug.turn_off().unwrap());
+        assert!(!simulator.relay_on());
+    }
+
+    #[test]
+    fn reconnect_after_close() {
+        let simulator = KasaSimulator::start().unwrap();
+        simulator.set_close_after_reply(true);
+        let mut plug = KasaPlug::connect(simulator.addr()).unwrap();
+        for _ in 0..3 {
+            assert!(plug.turn_on().unwrap());
+            assert!(plug.current_state().unwrap());
+        }
+        drop(simulator);
+        assert!(plug.current_state().is_err());
+    }
+}
@@ -0,0 +1,152 @@
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpStream, ToSocketAddrs};
+use tokio::time::timeout;
+
+use crate::common::traits_async::Described;
+use crate::common::traits_async::device::{OptReplay, PowerConsumptionMeter, Replay, Switchable};
+use crate::devices::kasa::frame::{decode, encode, message_len, Command, KasaError, Reply, Sysinfo, LEN_PREFIX};
+use crate::devices::kasa::kasa_std::DEFAULT_TIMEOUT;
+use crate::devices::socket::SocketTraitAsync;
+
+/// TP-Link Kasa HS1xx plug, power is read from the energy meter if the plug has one.
+/// Some firmware closes the connection after a reply, the command is then retried on a new one.
+pub struct KasaPlug {
+    addr: Vec<SocketAddr>,
+    timeout: Duration,
+    stream: Option<TcpStream>,
+}
+
+impl KasaPlug {
+    /// Connect to the plug, e.g. `(host, KASA_PORT)`
+    pub async fn connect<Addr: ToSocketAddrs>(addr: Addr) -> io::Result<Self> {
+        let addr: Vec<SocketAddr> = lookup_host(addr).await?.collect();
+        let mut plug = Self { addr, timeout: DEFAULT_TIMEOUT, stream: None };
+        plug.stream().await?;
+        Ok(plug)
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub async fn sysinfo(&mut self) -> Result<Sysinfo, KasaError> {
+        match self.command(Command::GetSysinfo).await? {
+            Reply::Sysinfo(sysinfo) => { Ok(sysinfo) }
+            reply => { Err(Self::unexpected(reply)) }
+        }
+    }
+
+    async fn stream(&mut self) -> io::Result<&mut TcpStream> {
+        if self.stream.is_none() {
+            self.stream = Some(TcpStream::connect(&self.addr[..]).await?);
+        }
+        Ok(self.stream.as_mut().expect("connected above"))
+    }
+
+    pub async fn command(&mut self, command: Command) -> Result<Reply, KasaError> {
+        let reused = self.stream.is_some();
+        let mut result = self.transact_in_time(command).await;
+        if reused && matches!(result, Err(KasaError::Io(_))) {
+            self.stream = None;
+            result = self.transact_in_time(command).await;
+        }
+        if let Err(KasaError::Io(_) | KasaError::BadMessage(_)) = result {
+            self.stream = None;
+        }
+        result
+    }
+
+    async fn transact_in_time(&mut self, command: Command) -> Result<Reply, KasaError> {
+        match timeout(self.timeout, self.transact(command)).await {
+            Ok(result) => { result }
+            Err(_) => { Err(io::Error::new(io::ErrorKind::TimedOut, "no reply from the plug").into()) }
+        }
+    }
+
+    async fn transact(&mut self, command: Command) -> Result<Reply, KasaError> {
+        let stream = self.stream().await?;
+        stream.write_all(&encode(&command.encode())).await?;
+        let mut prefix = [0; LEN_PREFIX];
+        stream.read_exact(&mut prefix).await?;
+        let mut message = vec![0; message_len(prefix)?];
+        stream.read_exact(&mut message).await?;
+        command.parse_reply(&decode(&message)?)
+    }
+
+    fn unexpected(reply: Reply) -> KasaError {
+        KasaError::BadMessage(format!("Unexpected reply: {:?}", reply))
+    }
+
+    async fn set_relay(&mut self, on: bool) -> Replay<bool> {
+        match self.command(Command::SetRelayState(on)).await? {
+            Reply::Ok => { Ok(true) }
+            reply => { Err(Self::unexpected(reply).into()) }
+        }
+    }
+}
+
+#[async_trait]
+impl PowerConsumptionMeter for KasaPlug {
+    async fn power_consumption_wt(&mut self) -> OptReplay<f32> {
+        match self.command(Command::GetRealtime).await? {
+            Reply::Realtime(power) => { Ok(power) }
+            reply => { Err(Self::unexpected(reply).into()) }
+        }
+    }
+}
+
+#[async_trait]
+impl Switchable for KasaPlug {
+    async fn turn_on(&mut self) -> Replay<bool> {
+        self.set_relay(true).await
+    }
+
+    async fn turn_off(&mut self) -> Replay<bool> {
+        self.set_relay(false).await
+    }
+
+    async fn current_state(&mut self) -> Replay<bool> {
+        Ok(self.sysinfo().await?.relay_state)
+    }
+}
+
+#[async_trait]
+impl Described for KasaPlug {
+    /// Alias given in the Kasa app
+    async fn description(&mut self) -> String {
+        match self.sysinfo().await {
+            Ok(sysinfo) => { sysinfo.alias }
+            Err(err) => { err.to_string() }
+        }
+    }
+}
+
+impl SocketTraitAsync for KasaPlug {}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::devices::kasa::simulator::KasaSimulator;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn switch_and_meter() {
+        let simulator = KasaSimulator::start().unwrap();
+        simulator.set_load(Some(60.0));
+        simulator.set_close_after_reply(true);
+        let mut plug = KasaPlug::connect(simulator.addr()).await.unwrap();
+        assert_eq!(plug.description().await, "Simulated plug");
+        assert!(plug.turn_on().await.unwrap());
+        assert!(plug.current_state().await.unwrap());
+        assert_eq!(plug.power_consumption_wt().await.unwrap(), Some(60.0));
+        assert!(plug.turn_off().await.unwrap());
+        assert!(!simulator.relay_on());
+    }
+}
@@ -0,0 +1,5 @@
+pub mod frame;
+pub mod kasa_std;
+pub mod kasa_tokio;
+#[cfg(any(test, feature = "simulators"))]
+pub mod simulator;
@@ -0,0 +1,148 @@
+use std::io;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+
+use serde_json::{json, Value};
+
+use crate::common::local_server::{lock, LocalServer};
+use crate::devices::kasa::frame::{decode, encode, message_len, LEN_PREFIX};
+
+/// Kasa plug on a local port for tests, answers sysinfo, relay and energy meter commands.
+/// Stops on drop.
+pub struct KasaSimulator {
+    server: LocalServer,
+    plug: Arc<Mutex<Plug>>,
+}
+
+struct Plug {
+    alias: String,
+    model: String,
+    relay_on: bool,
+    /// Watts drawn while on, `None` - no energy meter as in HS100
+    load_wt: Option<f32>,
+    /// Report `power_mw` as hardware v2 does
+    milliwatts: bool,
+    /// Close the connection after every reply as some firmware does
+    close_after_reply: bool,
+}
+
+impl Default for Plug {
+    fn default() -> Self {
+        Self {
+            alias: "Simulated plug".to_string(),
+            model: "HS110(EU)".to_string(),
+            relay_on: false,
+            load_wt: Some(0.0),
+            milliwatts: false,
+            close_after_reply: false,
+        }
+    }
+}
+
+impl KasaSimulator {
+    /// Listen on a free port of 127.0.0.1, the plug is off with an empty meter
+    pub fn start() -> io::Result<Self> {
+        let plug = Arc::new(Mutex::new(Plug::default()));
+        let state = plug.clone();
+        let server = LocalServer::start(move |stream| {
+            let _ = serve(&state, stream);
+        })?;
+        Ok(Self { server, plug })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.server.addr()
+    }
+
+    pub fn relay_on(&self) -> bool {
+        lock(&self.plug).relay_on
+    }
+
+    pub fn set_alias(&self, alias: &str) {
+        lock(&self.plug).alias = alias.to_string();
+    }
+
+    /// Watts drawn while on, `None` removes the energy meter
+    pub fn set_load(&self, load_wt: Option<f32>) {
+        lock(&self.plug).load_wt = load_wt;
+    }
+
+    pub fn set_milliwatts(&self, milliwatts: bool) {
+        lock(&self.plug).milliwatts = milliwatts;
+    }
+
+    pub fn set_close_after_reply(&self, close: bool) {
+        lock(&self.plug).close_after_reply = close;
+    }
+}
+
+fn serve(plug: &Mutex<Plug>, mut stream: TcpStream) -> io::Result<()> {
+    loop {
+        let mut prefix = [0; LEN_PREFIX];
+        stream.read_exact(&mut prefix)?;
+        let len = message_len(prefix).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut message = vec![0; len];
+        stream.read_exact(&mut message)?;
+        let request = decode(&message).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut plug = lock(plug);
+        let reply = plug.handle(&request);
+        stream.write_all(&encode(&reply.to_string()))?;
+        if plug.close_after_reply {
+            return stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+impl Plug {
+    /// Every method of every module in the request is answered
+    fn handle(&mut self, request: &Value) -> Value {
+        let mut reply = json!({});
+        let Some(modules) = request.as_object() else {
+            return reply;
+        };
+        for (module, methods) in modules {
+            let methods = methods.as_object().cloned().unwrap_or_default();
+            reply[module] = match module.as_str() {
+                "system" => { methods.iter().map(|(method, args)| (method.clone(), self.system(method, args))).collect() }
+                "emeter" if self.load_wt.is_some() => { methods.keys().map(|method| (method.clone(), self.emeter(method))).collect() }
+                _ => { json!({ "err_code": -1, "err_msg": "module not support" }) }
+            };
+        }
+        reply
+    }
+
+    fn system(&mut self, method: &str, args: &Value) -> Value {
+        match method {
+            "get_sysinfo" => {
+                json!({
+                    "alias": self.alias,
+                    "model": self.model,
+                    "relay_state": self.relay_on as u8,
+                    "err_code": 0,
+                })
+            }
+            "set_relay_state" => {
+                match args.get("state").and_then(Value::as_u64) {
+                    Some(state) => {
+                        self.relay_on = state != 0;
+                        json!({ "err_code": 0 })
+                    }
+                    None => { json!({ "err_code": -3, "err_msg": "invalid argument" }) }
+                }
+            }
+            _ => { json!({ "err_code": -2, "err_msg": "member not support" }) }
+        }
+    }
+
+    fn emeter(&self, method: &str) -> Value {
+        if method != "get_realtime" {
+            return json!({ "err_code": -2, "err_msg": "member not support" });
+        }
+        let power = if self.relay_on { self.load_wt.unwrap_or_default() } else { 0.0 };
+        match self.milliwatts {
+            true => { json!({ "power_mw": (power * 1000.0).round() as u64, "err_code": 0 }) }
+            false => { json!({ "power": power, "err_code": 0 }) }
+        }
+    }
+}
@@ -1,5 +1,10 @@
 pub mod socket;
+pub mod socket_handler;
 pub mod thermometer;
 pub mod stubs;
 pub mod socket_tcp;
 pub mod thermometer_udp;
+pub mod modbus_tcp;
+pub mod kasa;
+#[cfg(feature = "shelly")]
+pub mod shelly;
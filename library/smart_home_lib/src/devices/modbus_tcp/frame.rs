@@ -0,0 +1,311 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+
+use thiserror::Error;
+
+use crate::common::traits::device::ErrorSm;
+use crate::common::traits_async::device::Err;
+
+/// MBAP header: transaction id, protocol id, length, unit id
+pub const MBAP_HEADER_LEN: usize = 7;
+const MODBUS_PROTOCOL_ID: u16 = 0;
+/// Function code and data, limited by the 260 bytes ADU of Modbus TCP
+pub const MAX_PDU_LEN: usize = 253;
+const MAX_READ_COILS: u16 = 2000;
+const MAX_READ_REGISTERS: u16 = 125;
+/// Set in the function code of exception responses
+const EXCEPTION_FLAG: u8 = 0x80;
+
+const READ_COILS: u8 = 0x01;
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const READ_INPUT_REGISTERS: u8 = 0x04;
+const WRITE_SINGLE_COIL: u8 = 0x05;
+const COIL_ON: u16 = 0xFF00;
+const COIL_OFF: u16 = 0x0000;
+
+#[derive(Debug, Error)]
+pub enum ModbusError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("bad Modbus frame: {0}")]
+    BadFrame(String),
+    /// Exception response of the device
+    #[error("Modbus exception: {0}")]
+    Exception(ExceptionCode),
+}
+
+impl From<ModbusError> for ErrorSm {
+    fn from(e: ModbusError) -> Self {
+        ErrorSm { msg: e.to_string() }
+    }
+}
+
+impl From<ModbusError> for Err {
+    fn from(e: ModbusError) -> Self {
+        Err { msg: e.to_string() }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionCode {
+    IllegalFunction,
+    IllegalDataAddress,
+    IllegalDataValue,
+    ServerDeviceFailure,
+    /// Gateway got no response from the device behind it
+    GatewayTargetFailedToRespond,
+    Other(u8),
+}
+
+impl ExceptionCode {
+    pub fn as_byte(&self) -> u8 {
+        match self {
+            ExceptionCode::IllegalFunction => { 0x01 }
+            ExceptionCode::IllegalDataAddress => { 0x02 }
+            ExceptionCode::IllegalDataValue => { 0x03 }
+            ExceptionCode::ServerDeviceFailure => { 0x04 }
+            ExceptionCode::GatewayTargetFailedToRespond => { 0x0B }
+            ExceptionCode::Other(code) => { *code }
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Self {
+        match byte {
+            0x01 => { ExceptionCode::IllegalFunction }
+            0x02 => { ExceptionCode::IllegalDataAddress }
+            0x03 => { ExceptionCode::IllegalDataValue }
+            0x04 => { ExceptionCode::ServerDeviceFailure }
+            0x0B => { ExceptionCode::GatewayTargetFailedToRespond }
+            code => { ExceptionCode::Other(code) }
+        }
+    }
+}
+
+impl Display for ExceptionCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExceptionCode::IllegalFunction => { write!(f, "illegal function") }
+            ExceptionCode::IllegalDataAddress => { write!(f, "illegal data address") }
+            ExceptionCode::IllegalDataValue => { write!(f, "illegal data value") }
+            ExceptionCode::ServerDeviceFailure => { write!(f, "server device failure") }
+            ExceptionCode::GatewayTargetFailedToRespond => { write!(f, "gateway target device failed to respond") }
+            ExceptionCode::Other(code) => { write!(f, "code {:#04x}", code) }
+        }
+    }
+}
+
+/// Functions used by the drivers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request {
+    ReadCoils { address: u16, count: u16 },
+    ReadHoldingRegisters { address: u16, count: u16 },
+    ReadInputRegisters { address: u16, count: u16 },
+    WriteSingleCoil { address: u16, value: bool },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    Coils(Vec<bool>),
+    Registers(Vec<u16>),
+    /// Echo of the request
+    WriteSingleCoil { address: u16, value: bool },
+}
+
+impl Request {
+    pub fn function(&self) -> u8 {
+        match self {
+            Request::ReadCoils { .. } => { READ_COILS }
+            Request::ReadHoldingRegisters { .. } => { READ_HOLDING_REGISTERS }
+            Request::ReadInputRegisters { .. } => { READ_INPUT_REGISTERS }
+            Request::WriteSingleCoil { .. } => { WRITE_SINGLE_COIL }
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let (address, value) = match *self {
+            Request::ReadCoils { address, count } => { (address, count) }
+            Request::ReadHoldingRegisters { address, count } => { (address, count) }
+            Request::ReadInputRegisters { address, count } => { (address, count) }
+            Request::WriteSingleCoil { address, value } => { (address, if value { COIL_ON } else { COIL_OFF }) }
+        };
+        let mut pdu = vec![self.function()];
+        pdu.extend_from_slice(&address.to_be_bytes());
+        pdu.extend_from_slice(&value.to_be_bytes());
+        pdu
+    }
+
+    /// Server side, the error is the exception to reply with
+    pub fn decode(pdu: &[u8]) -> Result<Self, ExceptionCode> {
+        let function = *pdu.first().ok_or(ExceptionCode::IllegalFunction)?;
+        if !matches!(function, READ_COILS | READ_HOLDING_REGISTERS | READ_INPUT_REGISTERS | WRITE_SINGLE_COIL) {
+            return Err(ExceptionCode::IllegalFunction);
+        }
+        if pdu.len() != 5 {
+            return Err(ExceptionCode::IllegalDataValue);
+        }
+        let address = u16::from_be_bytes([pdu[1], pdu[2]]);
+        let value = u16::from_be_bytes([pdu[3], pdu[4]]);
+        let count = |max: u16| {
+            match value {
+                1.. if value <= max => { Ok(value) }
+                _ => { Err(ExceptionCode::IllegalDataValue) }
+            }
+        };
+        match function {
+            READ_COILS => { Ok(Request::ReadCoils { address, count: count(MAX_READ_COILS)? }) }
+            READ_HOLDING_REGISTERS => { Ok(Request::ReadHoldingRegisters { address, count: count(MAX_READ_REGISTERS)? }) }
+            READ_INPUT_REGISTERS => { Ok(Request::ReadInputRegisters { address, count: count(MAX_READ_REGISTERS)? }) }
+            _ => {
+                let value = match value {
+                    COIL_ON => { true }
+                    COIL_OFF => { false }
+                    _ => { return Err(ExceptionCode::IllegalDataValue) }
+                };
+                Ok(Request::WriteSingleCoil { address, value })
+            }
+        }
+    }
+}
+
+impl Response {
+    pub fn encode(&self, function: u8) -> Vec<u8> {
+        let mut pdu = vec![function];
+        match self {
+            Response::Coils(coils) => {
+                let bytes: Vec<u8> = coils.chunks(8)
+                    .map(|byte| byte.iter().enumerate().fold(0, |acc, (bit, on)| acc | ((*on as u8) << bit)))
+                    .collect();
+                pdu.push(bytes.len() as u8);
+                pdu.extend_from_slice(&bytes);
+            }
+            Response::Registers(registers) => {
+                pdu.push((registers.len() * 2) as u8);
+                for register in registers {
+                    pdu.extend_from_slice(&register.to_be_bytes());
+                }
+            }
+            Response::WriteSingleCoil { address, value } => {
+                pdu.extend_from_slice(&address.to_be_bytes());
+                pdu.extend_from_slice(&if *value { COIL_ON } else { COIL_OFF }.to_be_bytes());
+            }
+        }
+        pdu
+    }
+
+    /// Client side: response to the request, exception responses are [`ModbusError::Exception`]
+    pub fn decode(request: &Request, pdu: &[u8]) -> Result<Self, ModbusError> {
+        let bad = |msg: &str| ModbusError::BadFrame(format!("{} in response to {:?}", msg, request));
+        let function = *pdu.first().ok_or_else(|| bad("empty PDU"))?;
+        if function == request.function() | EXCEPTION_FLAG {
+            let code = *pdu.get(1).ok_or_else(|| bad("exception without code"))?;
+            return Err(ModbusError::Exception(ExceptionCode::from_byte(code)));
+        }
+        if function != request.function() {
+            return Err(bad(&format!("function {:#04x}", function)));
+        }
+        let data = &pdu[1..];
+        match *request {
+            Request::ReadCoils { count, .. } => {
+                let bytes = counted(data).filter(|bytes| bytes.len() == (count as usize).div_ceil(8)).ok_or_else(|| bad("wrong byte count"))?;
+                Ok(Response::Coils((0..count as usize).map(|bit| bytes[bit / 8] & (1 << (bit % 8)) != 0).collect()))
+            }
+            Request::ReadHoldingRegisters { count, .. } | Request::ReadInputRegisters { count, .. } => {
+                let bytes = counted(data).filter(|bytes| bytes.len() == count as usize * 2).ok_or_else(|| bad("wrong byte count"))?;
+                Ok(Response::Registers(bytes.chunks(2).map(|word| u16::from_be_bytes([word[0], word[1]])).collect()))
+            }
+            Request::WriteSingleCoil { address, value } => {
+                let echo = Request::WriteSingleCoil { address, value }.encode();
+                if pdu != echo.as_slice() {
+                    return Err(bad("wrong echo"));
+                }
+                Ok(Response::WriteSingleCoil { address, value })
+            }
+        }
+    }
+}
+
+/// Data after the byte count, `None` if the count is wrong
+fn counted(data: &[u8]) -> Option<&[u8]> {
+    let (count, bytes) = data.split_first()?;
+    (bytes.len() == *count as usize).then_some(bytes)
+}
+
+pub fn exception(function: u8, code: ExceptionCode) -> Vec<u8> {
+    vec![function | EXCEPTION_FLAG, code.as_byte()]
+}
+
+/// Application data unit: MBAP header and PDU
+pub fn encode_adu(transaction_id: u16, unit_id: u8, pdu: &[u8]) -> Vec<u8> {
+    let mut adu = Vec::with_capacity(MBAP_HEADER_LEN + pdu.len());
+    adu.extend_from_slice(&transaction_id.to_be_bytes());
+    adu.extend_from_slice(&MODBUS_PROTOCOL_ID.to_be_bytes());
+    adu.extend_from_slice(&(pdu.len() as u16 + 1).to_be_bytes());
+    adu.push(unit_id);
+    adu.extend_from_slice(pdu);
+    adu
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub transaction_id: u16,
+    pub unit_id: u8,
+    /// Bytes following the header
+    pub pdu_len: usize,
+}
+
+impl Header {
+    pub fn parse(header: &[u8; MBAP_HEADER_LEN]) -> Result<Self, ModbusError> {
+        let protocol_id = u16::from_be_bytes([header[2], header[3]]);
+        if protocol_id != MODBUS_PROTOCOL_ID {
+            return Err(ModbusError::BadFrame(format!("protocol id {}", protocol_id)));
+        }
+        let pdu_len = (u16::from_be_bytes([header[4], header[5]]) as usize).saturating_sub(1);
+        if pdu_len == 0 || pdu_len > MAX_PDU_LEN {
+            return Err(ModbusError::BadFrame(format!("PDU length {}", pdu_len)));
+        }
+        Ok(Self { transaction_id: u16::from_be_bytes([header[0], header[1]]), unit_id: header[6], pdu_len })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_and_decode() {
+        let request = Request::ReadCoils { address: 3, count: 10 };
+        assert_eq!(request.encode(), [0x01, 0x00, 0x03, 0x00, 0x0A]);
+        assert_eq!(Request::decode(&request.encode()), Ok(request));
+        let coils: Vec<bool> = (0..10).map(|bit| bit % 3 == 0).collect();
+        let pdu = Response::Coils(coils.clone()).encode(request.function());
+        assert_eq!(pdu, [0x01, 0x02, 0b0100_1001, 0b10]);
+        assert_eq!(Response::decode(&request, &pdu).unwrap(), Response::Coils(coils));
+
+        let request = Request::ReadInputRegisters { address: 0x10, count: 2 };
+        let pdu = Response::Registers(vec![0x1234, 0xABCD]).encode(request.function());
+        assert_eq!(pdu, [0x04, 0x04, 0x12, 0x34, 0xAB, 0xCD]);
+        assert_eq!(Response::decode(&request, &pdu).unwrap(), Response::Registers(vec![0x1234, 0xABCD]));
+        assert!(matches!(Response::decode(&request, &pdu[..5]), Err(ModbusError::BadFrame(_))));
+
+        let request = Request::WriteSingleCoil { address: 1, value: true };
+        assert_eq!(request.encode(), [0x05, 0x00, 0x01, 0xFF, 0x00]);
+        assert_eq!(Response::decode(&request, &request.encode()).unwrap(), Response::WriteSingleCoil { address: 1, value: true });
+        let error = Response::decode(&request, &exception(request.function(), ExceptionCode::IllegalDataAddress)).unwrap_err();
+        assert_eq!(error.to_string(), "Modbus exception: illegal data address");
+
+        assert_eq!(Request::decode(&[0x2B, 0, 0, 0, 1]), Err(ExceptionCode::IllegalFunction));
+        assert_eq!(Request::decode(&[0x03, 0, 0, 0, 126]), Err(ExceptionCode::IllegalDataValue));
+        assert_eq!(Request::decode(&[0x05, 0, 0, 0x12, 0x34]), Err(ExceptionCode::IllegalDataValue));
+    }
+
+    #[test]
+    fn mbap_header() {
+        let adu = encode_adu(0x0102, 7, &[0x01, 0x00, 0x00, 0x00, 0x01]);
+        assert_eq!(adu[..MBAP_HEADER_LEN], [0x01, 0x02, 0x00, 0x00, 0x00, 0x06, 0x07]);
+        let header = Header::parse(adu[..MBAP_HEADER_LEN].try_into().unwrap()).unwrap();
+        assert_eq!(header, Header { transaction_id: 0x0102, unit_id: 7, pdu_len: 5 });
+        assert!(Header::parse(&[0, 1, 0, 1, 0, 6, 1]).is_err());
+        assert!(Header::parse(&[0, 1, 0, 0, 0x10, 0, 1]).is_err());
+    }
+}
@@ -0,0 +1,6 @@
+pub mod frame;
+pub mod register_map;
+pub mod modbus_std;
+pub mod modbus_tokio;
+#[cfg(any(test, feature = "simulators"))]
+pub mod simulator;
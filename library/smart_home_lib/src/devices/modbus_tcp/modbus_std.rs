@@ -0,0 +1,185 @@
+use std::io;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::common::traits::Described;
+use crate::common::traits::device::{OptReplay, PowerConsumptionMeter, Replay, Switchable};
+use crate::devices::modbus_tcp::frame::{encode_adu, Header, ModbusError, Request, Response, MBAP_HEADER_LEN};
+use crate::devices::modbus_tcp::register_map::RegisterMap;
+use crate::devices::socket::SocketTrait;
+
+/// Time to wait for a response, the request fails after it
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Relay with optional power meter over Modbus TCP, see [`RegisterMap`].
+/// A broken connection is reopened on the next request.
+pub struct ModbusTcp {
+    addr: Vec<SocketAddr>,
+    map: RegisterMap,
+    description: String,
+    timeout: Duration,
+    stream: Option<TcpStream>,
+    transaction_id: u16,
+}
+
+impl ModbusTcp {
+    pub fn connect<Addr: ToSocketAddrs>(addr: Addr, map: RegisterMap) -> io::Result<Self> {
+        let addr: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        let description = match addr.first() {
+            Some(first) => { format!("Modbus TCP relay {}", first) }
+            None => { return Err(io::Error::new(io::ErrorKind::InvalidInput, "no address to connect")) }
+        };
+        let mut device = Self { addr, map, description, timeout: DEFAULT_TIMEOUT, stream: None, transaction_id: 0 };
+        device.stream()?;
+        Ok(device)
+    }
+
+    pub fn with_description<Desc: Into<String>>(mut self, description: Desc) -> Self {
+        self.description = description.into();
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self.stream = None;
+        self
+    }
+
+    pub fn register_map(&self) -> &RegisterMap {
+        &self.map
+    }
+
+    fn stream(&mut self) -> io::Result<&mut TcpStream> {
+        if self.stream.is_none() {
+            let stream = TcpStream::connect(&self.addr[..])?;
+            stream.set_read_timeout(Some(self.timeout))?;
+            stream.set_write_timeout(Some(self.timeout))?;
+            stream.set_nodelay(true)?;
+            self.stream = Some(stream);
+        }
+        Ok(self.stream.as_mut().expect("connected above"))
+    }
+
+    /// Send the request to the unit of the register map and wait for the response
+    pub fn request(&mut self, request: Request) -> Result<Response, ModbusError> {
+        self.transaction_id = self.transaction_id.wrapping_add(1);
+        let result = self.transact(request);
+        // the stream is out of sync after IO errors and timeouts
+        if let Err(ModbusError::Io(_) | ModbusError::BadFrame(_)) = result {
+            self.stream = None;
+        }
+        result
+    }
+
+    fn transact(&mut self, request: Request) -> Result<Response, ModbusError> {
+        let transaction_id = self.transaction_id;
+        let adu = encode_adu(transaction_id, self.map.unit_id, &request.encode());
+        let stream = self.stream()?;
+        stream.write_all(&adu)?;
+        loop {
+            let mut header = [0; MBAP_HEADER_LEN];
+            stream.read_exact(&mut header)?;
+            let header = Header::parse(&header)?;
+            let mut pdu = vec![0; header.pdu_len];
+            stream.read_exact(&mut pdu)?;
+            // late response to a timed out request
+            if header.transaction_id == transaction_id {
+                return Response::decode(&request, &pdu);
+            }
+        }
+    }
+
+    fn unexpected(resp: Response) -> ModbusError {
+        ModbusError::BadFrame(format!("Unexpected response: {:?}", resp))
+    }
+
+    fn write_relay(&mut self, value: bool) -> Replay<bool> {
+        match self.request(Request::WriteSingleCoil { address: self.map.relay_coil, value })? {
+            Response::WriteSingleCoil { .. } => { Ok(true) }
+            resp => { Err(Self::unexpected(resp).into()) }
+        }
+    }
+}
+
+impl PowerConsumptionMeter for ModbusTcp {
+    fn power_consumption_wt(&mut self) -> OptReplay<f32> {
+        let Some(power) = self.map.power else {
+            return Ok(None);
+        };
+        match self.request(power.read_request())? {
+            Response::Registers(registers) => { Ok(Some(power.decode(&registers)?)) }
+            resp => { Err(Self::unexpected(resp).into()) }
+        }
+    }
+}
+
+impl Switchable for ModbusTcp {
+    fn turn_on(&mut self) -> Replay<bool> {
+        self.write_relay(true)
+    }
+
+    fn turn_off(&mut self) -> Replay<bool> {
+        self.write_relay(false)
+    }
+
+    fn current_state(&mut self) -> Replay<bool> {
+        match self.request(Request::ReadCoils { address: self.map.relay_coil, count: 1 })? {
+            Response::Coils(coils) if coils.len() == 1 => { Ok(coils[0]) }
+            resp => { Err(Self::unexpected(resp).into()) }
+        }
+    }
+}
+
+impl Described for ModbusTcp {
+    fn description(&mut self) -> String {
+        self.description.clone()
+    }
+}
+
+impl SocketTrait for ModbusTcp {}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::devices::modbus_tcp::register_map::{RegisterFormat, ValueRegister};
+    use crate::devices::modbus_tcp::simulator::ModbusSimulator;
+
+    use super::*;
+
+    #[test]
+    fn relay_with_meter() {
+        let simulator = ModbusSimulator::start().unwrap();
+        let power = ValueRegister::input(0x20).with_format(RegisterFormat::U32).with_scale(0.1);
+        let map = RegisterMap::new(5).with_unit_id(3).with_power(power);
+        let mut relay = ModbusTcp::connect(simulator.addr(), map).unwrap().with_description("boiler");
+        assert_eq!(relay.description(), "boiler");
+
+        assert!(!relay.current_state().unwrap());
+        assert!(relay.turn_on().unwrap());
+        assert!(simulator.coil(5));
+        assert!(relay.current_state().unwrap());
+        simulator.set_value(&power, 1999.5);
+        assert_eq!(relay.power_consumption_wt().unwrap(), Some(1999.5));
+        assert!(relay.turn_off().unwrap());
+        assert!(!simulator.coil(5));
+
+        let mut no_meter = ModbusTcp::connect(simulator.addr(), RegisterMap::new(6)).unwrap();
+        assert_eq!(no_meter.power_consumption_wt().unwrap(), None);
+    }
+
+    #[test]
+    fn exceptions_and_reconnection() {
+        let simulator = ModbusSimulator::start().unwrap();
+        let mut relay = ModbusTcp::connect(simulator.addr(), RegisterMap::new(0)).unwrap();
+        simulator.set_offline(true);
+        let error = relay.turn_on().unwrap_err();
+        assert_eq!(error.msg, "Modbus exception: gateway target device failed to respond");
+        simulator.set_offline(false);
+        assert!(relay.turn_on().unwrap());
+
+        simulator.drop_connections();
+        assert!(relay.current_state().is_err());
+        assert!(relay.current_state().unwrap());
+    }
+}
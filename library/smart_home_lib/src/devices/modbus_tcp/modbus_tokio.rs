@@ -0,0 +1,173 @@
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpStream, ToSocketAddrs};
+use tokio::time::timeout;
+
+use crate::common::traits_async::Described;
+use crate::common::traits_async::device::{OptReplay, PowerConsumptionMeter, Replay, Switchable};
+use crate::devices::modbus_tcp::frame::{encode_adu, Header, ModbusError, Request, Response, MBAP_HEADER_LEN};
+use crate::devices::modbus_tcp::modbus_std::DEFAULT_TIMEOUT;
+use crate::devices::modbus_tcp::register_map::RegisterMap;
+use crate::devices::socket::SocketTraitAsync;
+
+/// Relay with optional power meter over Modbus TCP, see [`RegisterMap`].
+/// A broken connection is reopened on the next request.
+pub struct ModbusTcp {
+    addr: Vec<SocketAddr>,
+    map: RegisterMap,
+    description: String,
+    timeout: Duration,
+    stream: Option<TcpStream>,
+    transaction_id: u16,
+}
+
+impl ModbusTcp {
+    pub async fn connect<Addr: ToSocketAddrs>(addr: Addr, map: RegisterMap) -> io::Result<Self> {
+        let addr: Vec<SocketAddr> = lookup_host(addr).await?.collect();
+        let description = match addr.first() {
+            Some(first) => { format!("Modbus TCP relay {}", first) }
+            None => { return Err(io::Error::new(io::ErrorKind::InvalidInput, "no address to connect")) }
+        };
+        let mut device = Self { addr, map, description, timeout: DEFAULT_TIMEOUT, stream: None, transaction_id: 0 };
+        device.stream().await?;
+        Ok(device)
+    }
+
+    pub fn with_description<Desc: Into<String>>(mut self, description: Desc) -> Self {
+        self.description = description.into();
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn register_map(&self) -> &RegisterMap {
+        &self.map
+    }
+
+    async fn stream(&mut self) -> io::Result<&mut TcpStream> {
+        if self.stream.is_none() {
+            let stream = TcpStream::connect(&self.addr[..]).await?;
+            stream.set_nodelay(true)?;
+            self.stream = Some(stream);
+        }
+        Ok(self.stream.as_mut().expect("connected above"))
+    }
+
+    /// Send the request to the unit of the register map and wait for the response
+    pub async fn request(&mut self, request: Request) -> Result<Response, ModbusError> {
+        self.transaction_id = self.transaction_id.wrapping_add(1);
+        let result = match timeout(self.timeout, self.transact(request)).await {
+            Ok(result) => { result }
+            Err(_) => { Err(io::Error::new(io::ErrorKind::TimedOut, "no response from the device").into()) }
+        };
+        // the stream is out of sync after IO errors and timeouts
+        if let Err(ModbusError::Io(_) | ModbusError::BadFrame(_)) = result {
+            self.stream = None;
+        }
+        result
+    }
+
+    async fn transact(&mut self, request: Request) -> Result<Response, ModbusError> {
+        let transaction_id = self.transaction_id;
+        let adu = encode_adu(transaction_id, self.map.unit_id, &request.encode());
+        let stream = self.stream().await?;
+        stream.write_all(&adu).await?;
+        loop {
+            let mut header = [0; MBAP_HEADER_LEN];
+            stream.read_exact(&mut header).await?;
+            let header = Header::parse(&header)?;
+            let mut pdu = vec![0; header.pdu_len];
+            stream.read_exact(&mut pdu).await?;
+            // late response to a timed out request
+            if header.transaction_id == transaction_id {
+                return Response::decode(&request, &pdu);
+            }
+        }
+    }
+
+    fn unexpected(resp: Response) -> ModbusError {
+        ModbusError::BadFrame(format!("Unexpected response: {:?}", resp))
+    }
+
+    async fn write_relay(&mut self, value: bool) -> Replay<bool> {
+        match self.request(Request::WriteSingleCoil { address: self.map.relay_coil, value }).await? {
+            Response::WriteSingleCoil { .. } => { Ok(true) }
+            resp => { Err(Self::unexpected(resp).into()) }
+        }
+    }
+}
+
+#[async_trait]
+impl PowerConsumptionMeter for ModbusTcp {
+    async fn power_consumption_wt(&mut self) -> OptReplay<f32> {
+        let Some(power) = self.map.power else {
+            return Ok(None);
+        };
+        match self.request(power.read_request()).await? {
+            Response::Registers(registers) => { Ok(Some(power.decode(&registers)?)) }
+            resp => { Err(Self::unexpected(resp).into()) }
+        }
+    }
+}
+
+#[async_trait]
+impl Switchable for ModbusTcp {
+    async fn turn_on(&mut self) -> Replay<bool> {
+        self.write_relay(true).await
+    }
+
+    async fn turn_off(&mut self) -> Replay<bool> {
+        self.write_relay(false).await
+    }
+
+    async fn current_state(&mut self) -> Replay<bool> {
+        match self.request(Request::ReadCoils { address: self.map.relay_coil, count: 1 }).await? {
+            Response::Coils(coils) if coils.len() == 1 => { Ok(coils[0]) }
+            resp => { Err(Self::unexpected(resp).into()) }
+        }
+    }
+}
+
+#[async_trait]
+impl Described for ModbusTcp {
+    async fn description(&mut self) -> String {
+        self.description.clone()
+    }
+}
+
+impl SocketTraitAsync for ModbusTcp {}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::devices::modbus_tcp::register_map::ValueRegister;
+    use crate::devices::modbus_tcp::simulator::ModbusSimulator;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn relay_with_meter() {
+        let simulator = ModbusSimulator::start().unwrap();
+        let power = ValueRegister::holding(7).with_scale(0.5);
+        let mut relay = ModbusTcp::connect(simulator.addr(), RegisterMap::new(2).with_power(power)).await.unwrap();
+        simulator.set_value(&power, 150.0);
+        assert!(relay.turn_on().await.unwrap());
+        assert!(relay.current_state().await.unwrap());
+        assert_eq!(relay.power_consumption_wt().await.unwrap(), Some(150.0));
+
+        simulator.set_offline(true);
+        assert!(relay.turn_off().await.unwrap_err().msg.contains("failed to respond"));
+        simulator.set_offline(false);
+        simulator.drop_connections();
+        assert!(relay.turn_off().await.is_err());
+        assert!(relay.turn_off().await.unwrap());
+        assert!(!simulator.coil(2));
+    }
+}
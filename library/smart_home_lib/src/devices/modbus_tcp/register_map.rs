@@ -0,0 +1,161 @@
+use crate::devices::modbus_tcp::frame::{ModbusError, Request};
+
+/// Register table holding a value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterKind {
+    /// Read only, function 0x04
+    Input,
+    /// Read-write, function 0x03
+    Holding,
+}
+
+/// Encoding of the value in 16-bit registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterFormat {
+    U16,
+    I16,
+    /// Two registers, high word first unless [`ValueRegister::word_swap`]
+    U32,
+    I32,
+    /// IEEE 754 in two registers
+    F32,
+}
+
+/// Numeric value of a meter, e.g. watts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ValueRegister {
+    pub kind: RegisterKind,
+    pub address: u16,
+    pub format: RegisterFormat,
+    /// Low word first for 32-bit formats
+    pub word_swap: bool,
+    /// Value = raw * scale
+    pub scale: f32,
+}
+
+impl ValueRegister {
+    pub fn input(address: u16) -> Self {
+        Self { kind: RegisterKind::Input, address, format: RegisterFormat::U16, word_swap: false, scale: 1.0 }
+    }
+
+    pub fn holding(address: u16) -> Self {
+        Self { kind: RegisterKind::Holding, ..Self::input(address) }
+    }
+
+    pub fn with_format(mut self, format: RegisterFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn with_word_swap(mut self, word_swap: bool) -> Self {
+        self.word_swap = word_swap;
+        self
+    }
+
+    pub fn with_scale(mut self, scale: f32) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    /// Registers occupied by the value
+    pub fn count(&self) -> u16 {
+        match self.format {
+            RegisterFormat::U16 | RegisterFormat::I16 => { 1 }
+            RegisterFormat::U32 | RegisterFormat::I32 | RegisterFormat::F32 => { 2 }
+        }
+    }
+
+    pub fn read_request(&self) -> Request {
+        let (address, count) = (self.address, self.count());
+        match self.kind {
+            RegisterKind::Input => { Request::ReadInputRegisters { address, count } }
+            RegisterKind::Holding => { Request::ReadHoldingRegisters { address, count } }
+        }
+    }
+
+    /// Scaled value of the registers read with [`ValueRegister::read_request`]
+    pub fn decode(&self, registers: &[u16]) -> Result<f32, ModbusError> {
+        if registers.len() != self.count() as usize {
+            return Err(ModbusError::BadFrame(format!("expected {} registers, got {}", self.count(), registers.len())));
+        }
+        let word32 = || {
+            let (high, low) = if self.word_swap { (registers[1], registers[0]) } else { (registers[0], registers[1]) };
+            (high as u32) << 16 | low as u32
+        };
+        let raw = match self.format {
+            RegisterFormat::U16 => { registers[0] as f32 }
+            RegisterFormat::I16 => { registers[0] as i16 as f32 }
+            RegisterFormat::U32 => { word32() as f32 }
+            RegisterFormat::I32 => { word32() as i32 as f32 }
+            RegisterFormat::F32 => { f32::from_bits(word32()) }
+        };
+        Ok(raw * self.scale)
+    }
+
+    /// Registers holding the value, rounded to the format. For simulators.
+    pub fn encode(&self, value: f32) -> Vec<u16> {
+        let raw = value / self.scale;
+        let word32 = match self.format {
+            RegisterFormat::U16 => { return vec![raw.round() as u16] }
+            RegisterFormat::I16 => { return vec![raw.round() as i16 as u16] }
+            RegisterFormat::U32 => { raw.round() as u32 }
+            RegisterFormat::I32 => { raw.round() as i32 as u32 }
+            RegisterFormat::F32 => { raw.to_bits() }
+        };
+        let (high, low) = ((word32 >> 16) as u16, word32 as u16);
+        if self.word_swap { vec![low, high] } else { vec![high, low] }
+    }
+}
+
+/// Where a Modbus relay with optional power meter keeps its data
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegisterMap {
+    /// Device behind the gateway, `1` by default
+    pub unit_id: u8,
+    /// Coil switching the relay
+    pub relay_coil: u16,
+    /// Power consumption in watts, `None` if the device has no meter
+    pub power: Option<ValueRegister>,
+}
+
+impl RegisterMap {
+    pub fn new(relay_coil: u16) -> Self {
+        Self { unit_id: 1, relay_coil, power: None }
+    }
+
+    pub fn with_unit_id(mut self, unit_id: u8) -> Self {
+        self.unit_id = unit_id;
+        self
+    }
+
+    pub fn with_power(mut self, power: ValueRegister) -> Self {
+        self.power = Some(power);
+        self
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn value_formats() {
+        let watts = ValueRegister::input(0).with_scale(0.1);
+        assert_eq!(watts.decode(&[12345]).unwrap(), 1234.5);
+        assert_eq!(watts.encode(1234.5), [12345]);
+        assert_eq!(ValueRegister::input(0).with_format(RegisterFormat::I16).decode(&[0xFFFE]).unwrap(), -2.0);
+
+        let wide = ValueRegister::holding(0).with_format(RegisterFormat::U32);
+        assert_eq!(wide.read_request(), Request::ReadHoldingRegisters { address: 0, count: 2 });
+        assert_eq!(wide.decode(&[0x0001, 0x0002]).unwrap(), 65538.0);
+        assert_eq!(wide.with_word_swap(true).decode(&[0x0002, 0x0001]).unwrap(), 65538.0);
+        assert_eq!(wide.with_word_swap(true).encode(65538.0), [0x0002, 0x0001]);
+        assert!(wide.decode(&[1]).is_err());
+
+        let float = ValueRegister::input(0).with_format(RegisterFormat::F32);
+        assert_eq!(float.decode(&float.encode(-42.25)).unwrap(), -42.25);
+        let signed = ValueRegister::input(0).with_format(RegisterFormat::I32).with_scale(0.01);
+        assert_eq!(signed.decode(&signed.encode(-1500.0)).unwrap(), -1500.0);
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+
+use crate::common::local_server::{lock, LocalServer};
+use crate::devices::modbus_tcp::frame::{encode_adu, exception, ExceptionCode, Header, Request, Response, MBAP_HEADER_LEN};
+use crate::devices::modbus_tcp::register_map::{RegisterKind, ValueRegister};
+
+/// Modbus TCP server on a local port for tests: coils and registers in memory, zero if never set.
+/// Answers any unit id. Stops on drop.
+pub struct ModbusSimulator {
+    server: LocalServer,
+    tables: Arc<Mutex<Tables>>,
+}
+
+#[derive(Default)]
+struct Tables {
+    coils: HashMap<u16, bool>,
+    input: HashMap<u16, u16>,
+    holding: HashMap<u16, u16>,
+    offline: bool,
+}
+
+impl ModbusSimulator {
+    /// Listen on a free port of 127.0.0.1
+    pub fn start() -> io::Result<Self> {
+        let tables = Arc::new(Mutex::new(Tables::default()));
+        let state = tables.clone();
+        let server = LocalServer::start(move |stream| {
+            let _ = serve(&state, stream);
+        })?;
+        Ok(Self { server, tables })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.server.addr()
+    }
+
+    pub fn coil(&self, address: u16) -> bool {
+        lock(&self.tables).coils.get(&address).copied().unwrap_or_default()
+    }
+
+    pub fn set_coil(&self, address: u16, value: bool) {
+        lock(&self.tables).coils.insert(address, value);
+    }
+
+    pub fn set_registers(&self, kind: RegisterKind, address: u16, values: &[u16]) {
+        let mut tables = lock(&self.tables);
+        let table = match kind {
+            RegisterKind::Input => { &mut tables.input }
+            RegisterKind::Holding => { &mut tables.holding }
+        };
+        for (offset, value) in values.iter().enumerate() {
+            table.insert(address.wrapping_add(offset as u16), *value);
+        }
+    }
+
+    /// Store the value as the register map describes it
+    pub fn set_value(&self, register: &ValueRegister, value: f32) {
+        self.set_registers(register.kind, register.address, &register.encode(value));
+    }
+
+    /// Answer every request with an exception, as a gateway with the device unreachable
+    pub fn set_offline(&self, offline: bool) {
+        lock(&self.tables).offline = offline;
+    }
+
+    /// Break the connections of the clients, as a restarted device does
+    pub fn drop_connections(&self) {
+        self.server.drop_connections();
+    }
+}
+
+fn serve(tables: &Mutex<Tables>, mut stream: TcpStream) -> io::Result<()> {
+    loop {
+        let mut header = [0; MBAP_HEADER_LEN];
+        stream.read_exact(&mut header)?;
+        let header = Header::parse(&header).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut pdu = vec![0; header.pdu_len];
+        stream.read_exact(&mut pdu)?;
+        let reply = match Request::decode(&pdu) {
+            Ok(request) => {
+                match lock(tables).handle(request) {
+                    Ok(response) => { response.encode(request.function()) }
+                    Err(code) => { exception(request.function(), code) }
+                }
+            }
+            Err(code) => { exception(pdu[0], code) }
+        };
+        stream.write_all(&encode_adu(header.transaction_id, header.unit_id, &reply))?;
+    }
+}
+
+impl Tables {
+    fn handle(&mut self, request: Request) -> Result<Response, ExceptionCode> {
+        if self.offline {
+            return Err(ExceptionCode::GatewayTargetFailedToRespond);
+        }
+        let addresses = |address: u16, count: u16| {
+            match address.checked_add(count - 1) {
+                Some(last) => { Ok(address..=last) }
+                None => { Err(ExceptionCode::IllegalDataAddress) }
+            }
+        };
+        match request {
+            Request::ReadCoils { address, count } => {
+                Ok(Response::Coils(addresses(address, count)?.map(|address| self.coils.get(&address).copied().unwrap_or_default()).collect()))
+            }
+            Request::ReadInputRegisters { address, count } => {
+                Ok(Response::Registers(addresses(address, count)?.map(|address| self.input.get(&address).copied().unwrap_or_default()).collect()))
+            }
+            Request::ReadHoldingRegisters { address, count } => {
+                Ok(Response::Registers(addresses(address, count)?.map(|address| self.holding.get(&address).copied().unwrap_or_default()).collect()))
+            }
+            Request::WriteSingleCoil { address, value } => {
+                self.coils.insert(address, value);
+                Ok(Response::WriteSingleCoil { address, value })
+            }
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use digest_auth::{AuthContext, AuthorizationHeader};
+use serde_json::{json, Value};
+use tiny_http::{Header, Request, Response, Server};
+
+use crate::common::local_server::lock;
+use crate::devices::shelly::rpc::{RPC_PATH, SHELLY_USER};
+
+const DEVICE_ID: &str = "shellyplusplugs-mock";
+
+/// Shelly Plus plug with the switch 0 on a local port, answers the RPC methods of the drivers.
+/// Stops on drop.
+pub struct ShellyMock {
+    addr: SocketAddr,
+    server: Arc<Server>,
+    plug: Arc<Mutex<Plug>>,
+}
+
+#[derive(Default)]
+struct Plug {
+    output: bool,
+    /// Watts drawn while on
+    load_wt: f32,
+    name: Option<String>,
+    /// Digest authentication is required if set
+    password: Option<String>,
+    nonce: u32,
+    /// 401 replies sent
+    unauthorized: usize,
+    /// Time to wait before every reply
+    delay: Duration,
+}
+
+impl ShellyMock {
+    /// Listen on a free port of 127.0.0.1, the plug is off without authentication
+    pub fn start() -> Self {
+        let server = Arc::new(Server::http("127.0.0.1:0").expect("free port"));
+        let addr = server.server_addr().to_ip().expect("IP listener");
+        let plug = Arc::new(Mutex::new(Plug::default()));
+        let serving = server.clone();
+        let state = plug.clone();
+        thread::spawn(move || {
+            for request in serving.incoming_requests() {
+                let state = state.clone();
+                thread::spawn(move || serve(&state, request));
+            }
+        });
+        Self { addr, server, plug }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn output(&self) -> bool {
+        lock(&self.plug).output
+    }
+
+    pub fn set_load(&self, load_wt: f32) {
+        lock(&self.plug).load_wt = load_wt;
+    }
+
+    pub fn set_name(&self, name: Option<&str>) {
+        lock(&self.plug).name = name.map(str::to_string);
+    }
+
+    pub fn set_password(&self, password: Option<&str>) {
+        lock(&self.plug).password = password.map(str::to_string);
+    }
+
+    pub fn set_delay(&self, delay: Duration) {
+        lock(&self.plug).delay = delay;
+    }
+
+    /// Expire the nonce given out so far as the device does from time to time
+    pub fn rotate_nonce(&self) {
+        lock(&self.plug).nonce += 1;
+    }
+
+    pub fn unauthorized(&self) -> usize {
+        lock(&self.plug).unauthorized
+    }
+}
+
+impl Drop for ShellyMock {
+    fn drop(&mut self) {
+        self.server.unblock();
+    }
+}
+
+fn serve(plug: &Mutex<Plug>, mut request: Request) {
+    let mut body = String::new();
+    let _ = request.as_reader().read_to_string(&mut body);
+    let authorization = request.headers().iter()
+        .find(|header| header.field.equiv("Authorization"))
+        .map(|header| header.value.to_string());
+    let (response, delay) = {
+        let mut plug = lock(plug);
+        let response = match request.url() == RPC_PATH {
+            true => { plug.handle(authorization.as_deref(), &body) }
+            false => { Response::from_string("Not Found").with_status_code(404) }
+        };
+        (response, plug.delay)
+    };
+    thread::sleep(delay);
+    let _ = request.respond(response);
+}
+
+impl Plug {
+    fn handle(&mut self, authorization: Option<&str>, body: &str) -> Response<Cursor<Vec<u8>>> {
+        if !self.authorized(authorization, body) {
+            self.unauthorized += 1;
+            let challenge = format!(r#"Digest qop="auth", realm="{}", nonce="{:08x}", algorithm=SHA-256"#, DEVICE_ID, self.nonce);
+            let header = Header::from_bytes("WWW-Authenticate", challenge).expect("valid header");
+            return Response::from_string(r#"{"code":401,"message":"unauthorized"}"#).with_status_code(401).with_header(header);
+        }
+        let request: Value = serde_json::from_str(body).unwrap_or_default();
+        let id = request.get("id").cloned().unwrap_or_default();
+        let method = request.get("method").and_then(Value::as_str).unwrap_or_default();
+        let params = request.get("params").cloned().unwrap_or_default();
+        let (status, reply) = match self.call(method, &params) {
+            Ok(result) => { (200, json!({ "id": id, "src": DEVICE_ID, "result": result })) }
+            Err((code, message)) => { (500, json!({ "id": id, "src": DEVICE_ID, "error": { "code": code, "message": message } })) }
+        };
+        Response::from_string(reply.to_string()).with_status_code(status)
+    }
+
+    /// The response is recomputed with the known password as the device does
+    fn authorized(&self, authorization: Option<&str>, body: &str) -> bool {
+        let Some(password) = &self.password else {
+            return true;
+        };
+        let Some(Ok(header)) = authorization.map(AuthorizationHeader::parse) else {
+            return false;
+        };
+        if header.username != SHELLY_USER || header.nonce != format!("{:08x}", self.nonce) {
+            return false;
+        }
+        let mut expected = header.clone();
+        expected.digest(&AuthContext::new_post(SHELLY_USER, password.as_str(), header.uri.as_str(), Some(body.as_bytes())));
+        expected.response == header.response
+    }
+
+    fn call(&mut self, method: &str, params: &Value) -> Result<Value, (i64, String)> {
+        if method == "Shelly.GetDeviceInfo" {
+            return Ok(json!({ "id": DEVICE_ID, "name": self.name, "model": "SNPL-00112EU", "gen": 2 }));
+        }
+        match params.get("id").and_then(Value::as_u64) {
+            Some(0) => {}
+            Some(id) => { return Err((-105, format!("Argument 'id', value {} not found!", id))) }
+            None => { return Err((-103, "Missing required argument 'id'!".to_string())) }
+        }
+        match method {
+            "Switch.Set" => {
+                let on = params.get("on").and_then(Value::as_bool).ok_or((-103, "Missing required argument 'on'!".to_string()))?;
+                let was_on = self.output;
+                self.output = on;
+                Ok(json!({ "was_on": was_on }))
+            }
+            "Switch.GetStatus" => {
+                let apower = if self.output { self.load_wt } else { 0.0 };
+                Ok(json!({ "id": 0, "source": "HTTP", "output": self.output, "apower": apower, "voltage": 230.0 }))
+            }
+            _ => { Err((404, format!("No handler for {}", method))) }
+        }
+    }
+}
@@ -0,0 +1,5 @@
+pub mod rpc;
+pub mod shelly_std;
+pub mod shelly_tokio;
+#[cfg(test)]
+mod mock;
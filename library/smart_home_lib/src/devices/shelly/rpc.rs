@@ -0,0 +1,257 @@
+use std::time::Duration;
+
+use digest_auth::{AuthContext, WwwAuthenticateHeader};
+use serde_json::{json, Value};
+use thiserror::Error;
+
+use crate::common::traits::device::ErrorSm;
+use crate::common::traits_async::device::Err;
+
+/// JSON-RPC endpoint of Gen2 devices, requests are POSTed to it
+pub const RPC_PATH: &str = "/rpc";
+/// Gen2 devices accept only this user
+pub const SHELLY_USER: &str = "admin";
+/// Time to wait for a reply, the call fails after it
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Error)]
+pub enum ShellyError {
+    /// Transport failure or an HTTP status without an RPC reply
+    #[error("HTTP error: {0}")]
+    Http(String),
+    #[error("unauthorized: {0}")]
+    Unauthorized(String),
+    #[error("bad Shelly reply: {0}")]
+    BadReply(String),
+    /// `error` member of the reply
+    #[error("Shelly error {code}: {message}")]
+    Rpc { code: i64, message: String },
+}
+
+impl From<ShellyError> for ErrorSm {
+    fn from(e: ShellyError) -> Self {
+        ErrorSm { msg: e.to_string() }
+    }
+}
+
+impl From<ShellyError> for Err {
+    fn from(e: ShellyError) -> Self {
+        Err { msg: e.to_string() }
+    }
+}
+
+/// Settings of a plug connection
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShellyOptions {
+    /// Switch component of the device, plugs have only the switch 0
+    pub switch_id: u32,
+    pub timeout: Duration,
+    /// Password of the `admin` user, required if the device has authentication enabled
+    pub password: Option<String>,
+}
+
+impl Default for ShellyOptions {
+    fn default() -> Self {
+        Self { switch_id: 0, timeout: DEFAULT_TIMEOUT, password: None }
+    }
+}
+
+impl ShellyOptions {
+    pub fn with_switch_id(mut self, switch_id: u32) -> Self {
+        self.switch_id = switch_id;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_password<Password: Into<String>>(mut self, password: Password) -> Self {
+        self.password = Some(password.into());
+        self
+    }
+}
+
+/// Methods used by the drivers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    SwitchSet(bool),
+    SwitchGetStatus,
+    GetDeviceInfo,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    Ok,
+    Status(SwitchStatus),
+    DeviceInfo(DeviceInfo),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SwitchStatus {
+    pub output: bool,
+    /// Active power in watts, `None` on switches without a meter
+    pub apower: Option<f32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceInfo {
+    /// Unique id, e.g. `shellyplusplugs-a8032ab12345`
+    pub id: String,
+    /// Name given in the Shelly app, `None` if not set
+    pub name: Option<String>,
+    pub model: String,
+}
+
+impl Method {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Method::SwitchSet(_) => { "Switch.Set" }
+            Method::SwitchGetStatus => { "Switch.GetStatus" }
+            Method::GetDeviceInfo => { "Shelly.GetDeviceInfo" }
+        }
+    }
+
+    /// JSON-RPC request with the given request id
+    pub fn encode(&self, id: u64, switch_id: u32) -> String {
+        let params = match self {
+            Method::SwitchSet(on) => { json!({ "id": switch_id, "on": on }) }
+            Method::SwitchGetStatus => { json!({ "id": switch_id }) }
+            Method::GetDeviceInfo => { json!({}) }
+        };
+        json!({ "id": id, "method": self.name(), "params": params }).to_string()
+    }
+
+    /// Reply of the device to the request with the given id
+    pub fn parse_reply(&self, id: u64, body: &str) -> Result<Reply, ShellyError> {
+        let bad = |msg: &str| ShellyError::BadReply(format!("{} in reply to {}: {}", msg, self.name(), body));
+        let reply: Value = serde_json::from_str(body).map_err(|e| bad(&e.to_string()))?;
+        if let Some(error) = reply.get("error") {
+            let code = error.get("code").and_then(Value::as_i64).unwrap_or_default();
+            let message = error.get("message").and_then(Value::as_str).unwrap_or("unknown error").to_string();
+            return Err(ShellyError::Rpc { code, message });
+        }
+        if reply.get("id").and_then(Value::as_u64) != Some(id) {
+            return Err(bad("wrong id"));
+        }
+        let result = reply.get("result").ok_or_else(|| bad("no result"))?;
+        match self {
+            Method::SwitchSet(_) => { Ok(Reply::Ok) }
+            Method::SwitchGetStatus => {
+                let output = result.get("output").and_then(Value::as_bool).ok_or_else(|| bad("no output"))?;
+                let apower = result.get("apower").and_then(Value::as_f64).map(|watts| watts as f32);
+                Ok(Reply::Status(SwitchStatus { output, apower }))
+            }
+            Method::GetDeviceInfo => {
+                let text = |field: &str| result.get(field).and_then(Value::as_str).map(str::to_string);
+                let id = text("id").ok_or_else(|| bad("no id"))?;
+                Ok(Reply::DeviceInfo(DeviceInfo { id, name: text("name"), model: text("model").unwrap_or_default() }))
+            }
+        }
+    }
+}
+
+/// Request ids and the digest challenge of one device, the HTTP part is left to the drivers.
+/// The challenge is kept and answered up front until the device rejects its nonce.
+#[derive(Debug)]
+pub struct RpcSession {
+    switch_id: u32,
+    password: Option<String>,
+    challenge: Option<WwwAuthenticateHeader>,
+    id: u64,
+}
+
+impl RpcSession {
+    pub fn new(options: &ShellyOptions) -> Self {
+        Self { switch_id: options.switch_id, password: options.password.clone(), challenge: None, id: 0 }
+    }
+
+    /// Body of the next request
+    pub fn request(&mut self, method: Method) -> String {
+        self.id = self.id.wrapping_add(1);
+        method.encode(self.id, self.switch_id)
+    }
+
+    /// `Authorization` header for the body, `None` until the device asks for it
+    pub fn authorization(&mut self, body: &str) -> Result<Option<String>, ShellyError> {
+        let (Some(password), Some(challenge)) = (&self.password, &mut self.challenge) else {
+            return Ok(None);
+        };
+        let context = AuthContext::new_post(SHELLY_USER, password.as_str(), RPC_PATH, Some(body.as_bytes()));
+        match challenge.respond(&context) {
+            Ok(authorization) => { Ok(Some(authorization.to_header_string())) }
+            Err(e) => { Err(ShellyError::Unauthorized(e.to_string())) }
+        }
+    }
+
+    /// Reply to the last request, `None` - send it again with the new challenge
+    pub fn response(&mut self, method: Method, status: u16, www_authenticate: Option<&str>, body: &str, retried: bool) -> Result<Option<Reply>, ShellyError> {
+        match status {
+            401 if retried => { Err(ShellyError::Unauthorized("wrong password".to_string())) }
+            401 => {
+                if self.password.is_none() {
+                    return Err(ShellyError::Unauthorized("the device requires a password".to_string()));
+                }
+                let header = www_authenticate.ok_or_else(|| ShellyError::Unauthorized("no digest challenge".to_string()))?;
+                let challenge = digest_auth::parse(header).map_err(|e| ShellyError::Unauthorized(e.to_string()))?;
+                self.challenge = Some(challenge);
+                Ok(None)
+            }
+            // RPC errors come with 4xx and 5xx statuses
+            200..=299 => { method.parse_reply(self.id, body).map(Some) }
+            _ => {
+                match method.parse_reply(self.id, body) {
+                    Err(ShellyError::BadReply(_)) => { Err(ShellyError::Http(format!("status {}", status))) }
+                    result => { result.map(Some) }
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_and_replies() {
+        let request: Value = serde_json::from_str(&Method::SwitchSet(true).encode(7, 1)).unwrap();
+        assert_eq!(request, json!({ "id": 7, "method": "Switch.Set", "params": { "id": 1, "on": true } }));
+
+        let status = r#"{"id":3,"src":"shellyplusplugs-a8032ab12345","result":{"id":0,"output":true,"apower":12.5}}"#;
+        assert_eq!(Method::SwitchGetStatus.parse_reply(3, status).unwrap(), Reply::Status(SwitchStatus { output: true, apower: Some(12.5) }));
+        assert!(matches!(Method::SwitchGetStatus.parse_reply(4, status), Err(ShellyError::BadReply(_))));
+        let no_meter = r#"{"id":3,"result":{"id":0,"output":false}}"#;
+        assert_eq!(Method::SwitchGetStatus.parse_reply(3, no_meter).unwrap(), Reply::Status(SwitchStatus { output: false, apower: None }));
+
+        let info = r#"{"id":1,"result":{"id":"shellyplusplugs-a8032ab12345","name":null,"model":"SNPL-00112EU"}}"#;
+        let expected = DeviceInfo { id: "shellyplusplugs-a8032ab12345".to_string(), name: None, model: "SNPL-00112EU".to_string() };
+        assert_eq!(Method::GetDeviceInfo.parse_reply(1, info).unwrap(), Reply::DeviceInfo(expected));
+
+        let failed = r#"{"id":2,"error":{"code":-105,"message":"Argument 'id', value 1 not found!"}}"#;
+        let error = Method::SwitchSet(false).parse_reply(2, failed).unwrap_err();
+        assert_eq!(error.to_string(), "Shelly error -105: Argument 'id', value 1 not found!");
+        assert!(matches!(Method::SwitchSet(false).parse_reply(2, "<html>"), Err(ShellyError::BadReply(_))));
+    }
+
+    #[test]
+    fn digest_challenge() {
+        let challenge = r#"Digest qop="auth", realm="shellyplusplugs-a8032ab12345", nonce="60dc59c6", algorithm=SHA-256"#;
+        let mut session = RpcSession::new(&ShellyOptions::default());
+        let body = session.request(Method::SwitchGetStatus);
+        assert_eq!(session.authorization(&body).unwrap(), None);
+        assert!(matches!(session.response(Method::SwitchGetStatus, 401, Some(challenge), "", false), Err(ShellyError::Unauthorized(_))));
+
+        let mut session = RpcSession::new(&ShellyOptions::default().with_password("secret"));
+        let body = session.request(Method::SwitchGetStatus);
+        assert!(session.response(Method::SwitchGetStatus, 401, Some(challenge), "", false).unwrap().is_none());
+        let authorization = session.authorization(&body).unwrap().unwrap();
+        assert!(authorization.starts_with("Digest "));
+        assert!(authorization.contains(r#"username="admin""#));
+        assert!(authorization.contains("algorithm=SHA-256"));
+        assert!(session.response(Method::SwitchGetStatus, 401, Some(challenge), "", true).is_err());
+        assert!(matches!(session.response(Method::SwitchGetStatus, 502, None, "Bad Gateway", false), Err(ShellyError::Http(_))));
+    }
+}
@@ -0,0 +1,165 @@
+use ureq::{Agent, AgentBuilder};
+
+use crate::common::traits::Described;
+use crate::common::traits::device::{OptReplay, PowerConsumptionMeter, Replay, Switchable};
+use crate::devices::shelly::rpc::{DeviceInfo, Method, Reply, RpcSession, ShellyError, ShellyOptions, SwitchStatus, RPC_PATH};
+use crate::devices::socket::SocketTrait;
+
+/// Shelly Plus plug or any other Gen2 switch over HTTP JSON-RPC.
+/// Digest authentication is answered when the device asks for it.
+pub struct ShellyPlug {
+    url: String,
+    agent: Agent,
+    session: RpcSession,
+}
+
+impl ShellyPlug {
+    /// Connect to the plug at `host` or `host:port` and read its device info
+    pub fn connect(host: &str, options: ShellyOptions) -> Result<Self, ShellyError> {
+        let agent = AgentBuilder::new().timeout(options.timeout).build();
+        let url = format!("http://{}{}", host, RPC_PATH);
+        let mut plug = Self { url, agent, session: RpcSession::new(&options) };
+        plug.device_info()?;
+        Ok(plug)
+    }
+
+    pub fn device_info(&mut self) -> Result<DeviceInfo, ShellyError> {
+        match self.call(Method::GetDeviceInfo)? {
+            Reply::DeviceInfo(info) => { Ok(info) }
+            reply => { Err(Self::unexpected(reply)) }
+        }
+    }
+
+    pub fn switch_status(&mut self) -> Result<SwitchStatus, ShellyError> {
+        match self.call(Method::SwitchGetStatus)? {
+            Reply::Status(status) => { Ok(status) }
+            reply => { Err(Self::unexpected(reply)) }
+        }
+    }
+
+    pub fn call(&mut self, method: Method) -> Result<Reply, ShellyError> {
+        let body = self.session.request(method);
+        let mut retried = false;
+        loop {
+            let mut request = self.agent.post(&self.url).set("Content-Type", "application/json");
+            if let Some(authorization) = self.session.authorization(&body)? {
+                request = request.set("Authorization", &authorization);
+            }
+            let response = match request.send_string(&body) {
+                Ok(response) | Err(ureq::Error::Status(_, response)) => { response }
+                Err(e) => { return Err(ShellyError::Http(e.to_string())) }
+            };
+            let status = response.status();
+            let www_authenticate = response.header("WWW-Authenticate").map(str::to_string);
+            let text = response.into_string().map_err(|e| ShellyError::Http(e.to_string()))?;
+            if let Some(reply) = self.session.response(method, status, www_authenticate.as_deref(), &text, retried)? {
+                return Ok(reply);
+            }
+            retried = true;
+        }
+    }
+
+    fn unexpected(reply: Reply) -> ShellyError {
+        ShellyError::BadReply(format!("Unexpected reply: {:?}", reply))
+    }
+
+    fn set_output(&mut self, on: bool) -> Replay<bool> {
+        match self.call(Method::SwitchSet(on))? {
+            Reply::Ok => { Ok(true) }
+            reply => { Err(Self::unexpected(reply).into()) }
+        }
+    }
+}
+
+impl PowerConsumptionMeter for ShellyPlug {
+    fn power_consumption_wt(&mut self) -> OptReplay<f32> {
+        Ok(self.switch_status()?.apower)
+    }
+}
+
+impl Switchable for ShellyPlug {
+    fn turn_on(&mut self) -> Replay<bool> {
+        self.set_output(true)
+    }
+
+    fn turn_off(&mut self) -> Replay<bool> {
+        self.set_output(false)
+    }
+
+    fn current_state(&mut self) -> Replay<bool> {
+        Ok(self.switch_status()?.output)
+    }
+}
+
+impl Described for ShellyPlug {
+    /// Name given in the Shelly app, the device id if there is none
+    fn description(&mut self) -> String {
+        match self.device_info() {
+            Ok(info) => { info.name.unwrap_or(info.id) }
+            Err(err) => { err.to_string() }
+        }
+    }
+}
+
+impl SocketTrait for ShellyPlug {}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::devices::shelly::mock::ShellyMock;
+
+    use super::*;
+
+    #[test]
+    fn switch_and_meter() {
+        let mock = ShellyMock::start();
+        mock.set_load(40.5);
+        let mut plug = ShellyPlug::connect(&mock.addr().to_string(), ShellyOptions::default()).unwrap();
+        assert_eq!(plug.description(), "shellyplusplugs-mock");
+        mock.set_name(Some("Desk lamp"));
+        assert_eq!(plug.description(), "Desk lamp");
+
+        assert!(!plug.current_state().unwrap());
+        assert_eq!(plug.power_consumption_wt().unwrap(), Some(0.0));
+        assert!(plug.turn_on().unwrap());
+        assert!(mock.output());
+        assert!(plug.current_state().unwrap());
+        assert_eq!(plug.power_consumption_wt().unwrap(), Some(40.5));
+        assert!(plug.turn_off().unwrap());
+        assert!(!mock.output());
+    }
+
+    #[test]
+    fn digest_auth() {
+        let mock = ShellyMock::start();
+        mock.set_password(Some("secret"));
+        let host = mock.addr().to_string();
+        let error = ShellyPlug::connect(&host, ShellyOptions::default()).err().unwrap();
+        assert!(matches!(error, ShellyError::Unauthorized(_)));
+        let error = ShellyPlug::connect(&host, ShellyOptions::default().with_password("wrong")).err().unwrap();
+        assert_eq!(error.to_string(), "unauthorized: wrong password");
+
+        let mut plug = ShellyPlug::connect(&host, ShellyOptions::default().with_password("secret")).unwrap();
+        assert!(plug.turn_on().unwrap());
+        mock.rotate_nonce();
+        assert!(plug.current_state().unwrap());
+        assert_eq!(mock.unauthorized(), 5);
+    }
+
+    #[test]
+    fn switch_id_and_timeout() {
+        let mock = ShellyMock::start();
+        let host = mock.addr().to_string();
+        let mut plug = ShellyPlug::connect(&host, ShellyOptions::default().with_switch_id(1)).unwrap();
+        let error = plug.turn_on().unwrap_err();
+        assert_eq!(error.msg, "Shelly error -105: Argument 'id', value 1 not found!");
+
+        let mut plug = ShellyPlug::connect(&host, ShellyOptions::default().with_timeout(Duration::from_millis(100))).unwrap();
+        mock.set_delay(Duration::from_millis(500));
+        assert!(plug.current_state().unwrap_err().msg.starts_with("HTTP error"));
+        mock.set_delay(Duration::ZERO);
+        assert!(!plug.current_state().unwrap());
+    }
+}
@@ -0,0 +1,131 @@
+use async_trait::async_trait;
+use reqwest::Client;
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
+
+use crate::common::traits_async::Described;
+use crate::common::traits_async::device::{OptReplay, PowerConsumptionMeter, Replay, Switchable};
+use crate::devices::shelly::rpc::{DeviceInfo, Method, Reply, RpcSession, ShellyError, ShellyOptions, SwitchStatus, RPC_PATH};
+use crate::devices::socket::SocketTraitAsync;
+
+/// Shelly Plus plug or any other Gen2 switch over HTTP JSON-RPC.
+/// Digest authentication is answered when the device asks for it.
+pub struct ShellyPlug {
+    url: String,
+    client: Client,
+    session: RpcSession,
+}
+
+impl ShellyPlug {
+    /// Connect to the plug at `host` or `host:port` and read its device info
+    pub async fn connect(host: &str, options: ShellyOptions) -> Result<Self, ShellyError> {
+        let client = Client::builder().timeout(options.timeout).build().map_err(|e| ShellyError::Http(e.to_string()))?;
+        let url = format!("http://{}{}", host, RPC_PATH);
+        let mut plug = Self { url, client, session: RpcSession::new(&options) };
+        plug.device_info().await?;
+        Ok(plug)
+    }
+
+    pub async fn device_info(&mut self) -> Result<DeviceInfo, ShellyError> {
+        match self.call(Method::GetDeviceInfo).await? {
+            Reply::DeviceInfo(info) => { Ok(info) }
+            reply => { Err(Self::unexpected(reply)) }
+        }
+    }
+
+    pub async fn switch_status(&mut self) -> Result<SwitchStatus, ShellyError> {
+        match self.call(Method::SwitchGetStatus).await? {
+            Reply::Status(status) => { Ok(status) }
+            reply => { Err(Self::unexpected(reply)) }
+        }
+    }
+
+    pub async fn call(&mut self, method: Method) -> Result<Reply, ShellyError> {
+        let body = self.session.request(method);
+        let mut retried = false;
+        loop {
+            let mut request = self.client.post(&self.url).header(CONTENT_TYPE, "application/json").body(body.clone());
+            if let Some(authorization) = self.session.authorization(&body)? {
+                request = request.header(AUTHORIZATION, authorization);
+            }
+            let response = request.send().await.map_err(|e| ShellyError::Http(e.to_string()))?;
+            let status = response.status().as_u16();
+            let www_authenticate = response.headers().get(WWW_AUTHENTICATE).and_then(|value| value.to_str().ok()).map(str::to_string);
+            let text = response.text().await.map_err(|e| ShellyError::Http(e.to_string()))?;
+            if let Some(reply) = self.session.response(method, status, www_authenticate.as_deref(), &text, retried)? {
+                return Ok(reply);
+            }
+            retried = true;
+        }
+    }
+
+    fn unexpected(reply: Reply) -> ShellyError {
+        ShellyError::BadReply(format!("Unexpected reply: {:?}", reply))
+    }
+
+    async fn set_output(&mut self, on: bool) -> Replay<bool> {
+        match self.call(Method::SwitchSet(on)).await? {
+            Reply::Ok => { Ok(true) }
+            reply => { Err(Self::unexpected(reply).into()) }
+        }
+    }
+}
+
+#[async_trait]
+impl PowerConsumptionMeter for ShellyPlug {
+    async fn power_consumption_wt(&mut self) -> OptReplay<f32> {
+        Ok(self.switch_status().await?.apower)
+    }
+}
+
+#[async_trait]
+impl Switchable for ShellyPlug {
+    async fn turn_on(&mut self) -> Replay<bool> {
+        self.set_output(true).await
+    }
+
+    async fn turn_off(&mut self) -> Replay<bool> {
+        self.set_output(false).await
+    }
+
+    async fn current_state(&mut self) -> Replay<bool> {
+        Ok(self.switch_status().await?.output)
+    }
+}
+
+#[async_trait]
+impl Described for ShellyPlug {
+    /// Name given in the Shelly app, the device id if there is none
+    async fn description(&mut self) -> String {
+        match self.device_info().await {
+            Ok(info) => { info.name.unwrap_or(info.id) }
+            Err(err) => { err.to_string() }
+        }
+    }
+}
+
+impl SocketTraitAsync for ShellyPlug {}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::devices::shelly::mock::ShellyMock;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn switch_with_digest_auth() {
+        let mock = ShellyMock::start();
+        mock.set_password(Some("secret"));
+        mock.set_load(7.25);
+        let host = mock.addr().to_string();
+        assert!(ShellyPlug::connect(&host, ShellyOptions::default()).await.is_err());
+        let mut plug = ShellyPlug::connect(&host, ShellyOptions::default().with_password("secret")).await.unwrap();
+        assert_eq!(plug.description().await, "shellyplusplugs-mock");
+        assert!(plug.turn_on().await.unwrap());
+        assert!(plug.current_state().await.unwrap());
+        mock.rotate_nonce();
+        assert_eq!(plug.power_consumption_wt().await.unwrap(), Some(7.25));
+        assert!(plug.turn_off().await.unwrap());
+        assert!(!mock.output());
+    }
+}
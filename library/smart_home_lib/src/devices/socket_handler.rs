@@ -0,0 +1,147 @@
+use std::fmt::Display;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use protocol::handler::{AsyncHandler, DeviceState, Handler, HandlerResult};
+use protocol::message::{ErrorCode, ErrorReply, Request, Response};
+
+use crate::devices::socket::{SocketTrait, SocketTraitAsync};
+
+/// Serves STP requests with a blocking socket, both by std and tokio servers.
+/// Tokio servers call the socket on the blocking thread pool.
+pub struct SocketHandler<S> {
+    socket: Arc<Mutex<S>>,
+}
+
+impl<S: SocketTrait> SocketHandler<S> {
+    pub fn new(socket: S) -> Self {
+        Self { socket: Arc::new(Mutex::new(socket)) }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, S> {
+        self.socket.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<S: SocketTrait> Handler for SocketHandler<S> {
+    fn handle(&mut self, request: Request) -> HandlerResult {
+        handle_request(&mut *self.lock(), request)
+    }
+
+    fn state(&mut self) -> Option<DeviceState> {
+        device_state(&mut *self.lock())
+    }
+}
+
+impl<S: SocketTrait + Send + 'static> AsyncHandler for SocketHandler<S> {
+    async fn handle(&self, request: Request) -> HandlerResult {
+        let socket = self.socket.clone();
+        tokio::task::spawn_blocking(move || handle_request(&mut *socket.lock().unwrap_or_else(PoisonError::into_inner), request))
+            .await
+            .unwrap_or_else(|e| Err(ErrorReply::new(ErrorCode::Internal, e.to_string())))
+    }
+
+    async fn state(&self) -> Option<DeviceState> {
+        let socket = self.socket.clone();
+        tokio::task::spawn_blocking(move || device_state(&mut *socket.lock().unwrap_or_else(PoisonError::into_inner)))
+            .await
+            .ok()
+            .flatten()
+    }
+}
+
+/// Serves STP requests with an async socket by tokio servers
+pub struct SocketHandlerAsync<S> {
+    socket: tokio::sync::Mutex<S>,
+}
+
+impl<S: SocketTraitAsync> SocketHandlerAsync<S> {
+    pub fn new(socket: S) -> Self {
+        Self { socket: tokio::sync::Mutex::new(socket) }
+    }
+}
+
+impl<S: SocketTraitAsync + Send + 'static> AsyncHandler for SocketHandlerAsync<S> {
+    async fn handle(&self, request: Request) -> HandlerResult {
+        let mut socket = self.socket.lock().await;
+        match request {
+            Request::TurnOn => { reply(socket.turn_on().await.map(|_| Response::Ok)) }
+            Request::TurnOff => { reply(socket.turn_off().await.map(|_| Response::Ok)) }
+            Request::GetState => { reply(socket.current_state().await.map(Response::State)) }
+            Request::GetPowerConsumptionWt => { reply(socket.power_consumption_wt().await.map(Response::PowerConsumptionWt)) }
+            Request::GetDescription => { Ok(Response::Description(socket.description().await)) }
+            Request::Subscribe { .. } | Request::Unsubscribe => { not_device_request() }
+        }
+    }
+
+    async fn state(&self) -> Option<DeviceState> {
+        let mut socket = self.socket.lock().await;
+        let on = socket.current_state().await.ok()?;
+        let power_wt = socket.power_consumption_wt().await.ok()?;
+        Some(DeviceState { on, power_wt })
+    }
+}
+
+fn handle_request<S: SocketTrait>(socket: &mut S, request: Request) -> HandlerResult {
+    match request {
+        Request::TurnOn => { reply(socket.turn_on().map(|_| Response::Ok)) }
+        Request::TurnOff => { reply(socket.turn_off().map(|_| Response::Ok)) }
+        Request::GetState => { reply(socket.current_state().map(Response::State)) }
+        Request::GetPowerConsumptionWt => { reply(socket.power_consumption_wt().map(Response::PowerConsumptionWt)) }
+        Request::GetDescription => { Ok(Response::Description(socket.description())) }
+        Request::Subscribe { .. } | Request::Unsubscribe => { not_device_request() }
+    }
+}
+
+/// `None` if the device is offline, nothing to report
+fn device_state<S: SocketTrait>(socket: &mut S) -> Option<DeviceState> {
+    let on = socket.current_state().ok()?;
+    let power_wt = socket.power_consumption_wt().ok()?;
+    Some(DeviceState { on, power_wt })
+}
+
+/// Socket operations fail only when the device does not respond
+fn reply<E: Display>(result: Result<Response, E>) -> HandlerResult {
+    result.map_err(|e| ErrorReply::new(ErrorCode::Offline, e.to_string()))
+}
+
+/// Subscriptions are served by the connection, not by the device
+fn not_device_request() -> HandlerResult {
+    Err(ErrorReply::new(ErrorCode::Unsupported, "Not a device request"))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use protocol::client_std::ConnectOptions;
+    use protocol::client_tokio::ClientStp;
+    use protocol::server_tokio::{ServerStp, ShutdownHandle};
+    use protocol::transport::async_memory_listener;
+
+    use crate::devices::stubs::socket_stub::SocketStub;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn serve_socket_stub() {
+        let (listener, connector) = async_memory_listener();
+        let stub = SocketStub::new_with_wrap("Hall".to_string(), |stub| stub);
+        let shutdown = ShutdownHandle::new();
+        tokio::spawn(ServerStp::new(listener).serve(shutdown.clone(), SocketHandler::new(stub)));
+        let mut client = ClientStp::connect_over(connector.connect().unwrap(), &ConnectOptions::new()).await.unwrap();
+        assert_eq!(client.request(&Request::TurnOn).await.unwrap(), Response::Ok);
+        assert_eq!(client.request(&Request::GetState).await.unwrap(), Response::State(true));
+        assert_eq!(client.request(&Request::GetPowerConsumptionWt).await.unwrap(), Response::PowerConsumptionWt(Some(2000.0)));
+        assert_eq!(client.request(&Request::GetDescription).await.unwrap(), Response::Description("Hall".to_string()));
+        shutdown.shutdown();
+    }
+
+    #[test]
+    fn offline_socket() {
+        let mut stub = SocketStub::new_with_wrap("Hall".to_string(), |stub| stub);
+        stub.online(false);
+        let mut handler = SocketHandler::new(stub);
+        assert_eq!(Handler::handle(&mut handler, Request::TurnOn).unwrap_err().code, ErrorCode::Offline);
+        assert_eq!(Handler::handle(&mut handler, Request::Unsubscribe).unwrap_err().code, ErrorCode::Unsupported);
+        assert_eq!(Handler::state(&mut handler), None);
+    }
+}
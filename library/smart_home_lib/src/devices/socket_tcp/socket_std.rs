@@ -1,81 +1,198 @@
-use std::net::ToSocketAddrs;
+use std::net::{TcpStream, ToSocketAddrs};
 
-use protocol::client_std::{ClientStp, RequestResult};
+use protocol::client_std::{ClientStp, ConnectOptions, ReconnectingClient};
 use protocol::errors::ConnectResult;
+use protocol::message::{Request, Response};
+use protocol::reconnect::{Backoff, ConnectionState};
+use protocol::transport::Transport;
 
 use crate::common::traits::Described;
 use crate::common::traits::device::{OptReplay, PowerConsumptionMeter, Replay, Switchable};
 use crate::common::traits::device::ErrorSm;
 use crate::devices::socket::SocketTrait;
 
-pub struct SocketTcp {
-    client: ClientStp,
+/// Smart socket over STP. Reconnects if the socket server restarts.
+pub struct SocketTcp<S: Transport = TcpStream> {
+    client: ReconnectingClient<S>,
 }
 
 impl SocketTcp {
     pub fn new<Addr: ToSocketAddrs>(addr: Addr) -> ConnectResult<Self> {
-        Ok(Self { client: ClientStp::connect(addr)? })
+        Self::connect_with(addr, &ConnectOptions::new())
     }
 
-    fn handle_result(result: RequestResult) -> Replay<bool> {
-        match result {
-            Ok(_) => {
-                // println!("{}", resp);
-                Ok(true)
-            }
-            Err(err) => {
-                // println!("{}", err);
-                Err(ErrorSm { msg: err.to_string() })
-            }
-        }
+    /// Connect with TLS and/or device key, see [`ConnectOptions`]
+    pub fn connect_with<Addr: ToSocketAddrs>(addr: Addr, options: &ConnectOptions) -> ConnectResult<Self> {
+        Ok(Self { client: ReconnectingClient::connect_with(addr, options)? })
+    }
+}
+
+impl<S: Transport> SocketTcp<S> {
+    /// Socket over any transport, e.g. Unix socket or in-memory pipe. `connector` opens a new connection.
+    pub fn with_connector<F>(connector: F) -> ConnectResult<Self>
+    where
+        F: FnMut() -> ConnectResult<ClientStp<S>> + Send + 'static,
+    {
+        Ok(Self { client: ReconnectingClient::with_connector(connector)? })
+    }
+
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.client = self.client.with_backoff(backoff);
+        self
+    }
+
+    /// Called on every change of the connection state
+    pub fn with_state_listener<F>(mut self, listener: F) -> Self
+    where
+        F: FnMut(ConnectionState) + Send + 'static,
+    {
+        self.client = self.client.with_state_listener(listener);
+        self
+    }
+
+    pub fn connection_state(&self) -> ConnectionState {
+        self.client.state()
+    }
+
+    fn request(&mut self, request: Request) -> Result<Response, ErrorSm> {
+        self.client.request(&request).map_err(|err| {
+            // error replies of the server carry the reason reported by the device
+            let msg = match err.remote() {
+                Some(reply) => { reply.message.clone() }
+                None => { err.to_string() }
+            };
+            ErrorSm { msg }
+        })
+    }
+
+    fn unexpected(resp: Response) -> ErrorSm {
+        ErrorSm { msg: format!("Unexpected response: {:?}", resp) }
     }
 }
 
-impl PowerConsumptionMeter for SocketTcp {
+impl<S: Transport> PowerConsumptionMeter for SocketTcp<S> {
     fn power_consumption_wt(&mut self) -> OptReplay<f32> {
-        let result = self.client.send_request("get_power_consumption_wt");
-        match result {
-            Ok(val) => { Ok(Some(val.parse::<f32>().unwrap())) }
-            Err(err) => {
-                println!("{}", err);
-                Err(ErrorSm { msg: err.to_string() })
-            }
+        match self.request(Request::GetPowerConsumptionWt)? {
+            Response::PowerConsumptionWt(pwr) => { Ok(pwr) }
+            resp => { Err(Self::unexpected(resp)) }
         }
     }
 }
 
-impl Switchable for SocketTcp {
+impl<S: Transport> Switchable for SocketTcp<S> {
     fn turn_on(&mut self) -> Replay<bool> {
-        let result = self.client.send_request("turn_on");
-        Self::handle_result(result)
+        match self.request(Request::TurnOn)? {
+            Response::Ok => { Ok(true) }
+            resp => { Err(Self::unexpected(resp)) }
+        }
     }
 
     fn turn_off(&mut self) -> Replay<bool> {
-        let result = self.client.send_request("turn_off");
-        Self::handle_result(result)
+        match self.request(Request::TurnOff)? {
+            Response::Ok => { Ok(true) }
+            resp => { Err(Self::unexpected(resp)) }
+        }
     }
 
     fn current_state(&mut self) -> Replay<bool> {
-        match self.client.send_request("get_state") {
-            Ok(repl) => {
-                Ok(repl.eq("state: on"))
-            }
-            Err(e) => { Err(ErrorSm { msg: e.to_string() }) }
+        match self.request(Request::GetState)? {
+            Response::State(state) => { Ok(state) }
+            resp => { Err(Self::unexpected(resp)) }
         }
     }
 }
 
-impl Described for SocketTcp {
+impl<S: Transport> Described for SocketTcp<S> {
     fn description(&mut self) -> String {
-        let result = self.client.send_request("get_description");
-        match result {
-            Ok(val) => { val }
-            Err(err) => {
-                err.to_string()
-            }
+        match self.request(Request::GetDescription) {
+            Ok(Response::Description(desc)) => { desc }
+            Ok(resp) => { Self::unexpected(resp).to_string() }
+            Err(err) => { err.to_string() }
         }
     }
 }
 
-impl SocketTrait for SocketTcp {}
+impl<S: Transport> SocketTrait for SocketTcp<S> {}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
 
+    use protocol::message::{ErrorCode, ErrorReply};
+    use protocol::server_std::ServerStp;
+    use protocol::transport::{memory_listener, MemoryStream};
+
+    use super::*;
+
+    /// Serve one in-memory client, answering requests by the given replies or error replies
+    fn serve_replies(replies: Vec<Result<String, ErrorReply>>) -> SocketTcp<MemoryStream> {
+        let (listener, connector) = memory_listener();
+        let server = ServerStp::new(listener);
+        thread::spawn(move || {
+            let mut connection = server.incoming().next().unwrap().unwrap();
+            for reply in replies {
+                connection.revc_request().unwrap();
+                match reply {
+                    Ok(reply) => { connection.send_response(reply).unwrap() }
+                    Err(error) => { connection.reply_error(&error).unwrap() }
+                }
+            }
+        });
+        SocketTcp::with_connector(move || ClientStp::connect_over(connector.connect()?, &ConnectOptions::new())).unwrap()
+    }
+
+    #[test]
+    fn typed_replies() {
+        let mut socket = serve_replies(vec![
+            Ok(Response::Ok.encode()),
+            Ok(Response::State(true).encode()),
+            Ok(Response::PowerConsumptionWt(Some(2000.0)).encode()),
+            Ok(Response::PowerConsumptionWt(None).encode()),
+            Ok(Response::Description("kitchen".to_string()).encode()),
+        ]);
+        assert!(socket.turn_on().unwrap());
+        assert!(socket.current_state().unwrap());
+        assert_eq!(socket.power_consumption_wt().unwrap(), Some(2000.0));
+        assert_eq!(socket.power_consumption_wt().unwrap(), None);
+        assert_eq!(socket.description(), "kitchen");
+    }
+
+    #[test]
+    fn error_and_malformed_replies() {
+        let mut socket = serve_replies(vec![
+            Err(ErrorReply::new(ErrorCode::Offline, "Device not respond")),
+            Ok(Response::Ok.encode()),
+            Ok("Unknown power_consumption".to_string()),
+        ]);
+        assert_eq!(socket.turn_on().unwrap_err().msg, "Device not respond");
+        assert!(socket.current_state().is_err());
+        assert!(socket.power_consumption_wt().is_err());
+    }
+
+    #[test]
+    fn survives_server_restart() {
+        let (listener, connector) = memory_listener();
+        let server = ServerStp::new(listener);
+        let (dropped_tx, dropped_rx) = mpsc::channel();
+        thread::spawn(move || {
+            for state in [true, false] {
+                // connection is dropped after one request
+                let mut connection = server.incoming().next().unwrap().unwrap();
+                connection.recv().unwrap();
+                connection.reply(&Response::State(state)).unwrap();
+                drop(connection);
+                let _ = dropped_tx.send(());
+            }
+        });
+        let mut socket = SocketTcp::with_connector(move || ClientStp::connect_over(connector.connect()?, &ConnectOptions::new()))
+            .unwrap()
+            .with_backoff(Backoff::new(Duration::from_millis(1), Duration::from_millis(10)));
+        assert!(socket.current_state().unwrap());
+        dropped_rx.recv().unwrap();
+        assert!(!socket.current_state().unwrap());
+        assert_eq!(socket.connection_state(), ConnectionState::Connected);
+    }
+}
@@ -1,5 +1,5 @@
 use std::ffi::{c_char, c_void, CStr};
-use std::io::{Error, ErrorKind};
+use std::io::Error;
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -7,6 +7,8 @@ use std::thread;
 use std::thread::sleep;
 use std::time::Duration;
 
+use protocol::reconnect::ConnectionState;
+
 use crate::common::traits::Described;
 use crate::common::traits::device::{ErrorSm, OptReplay, PowerConsumptionMeter, Replay, Switchable};
 use crate::devices::socket::SocketTrait;
@@ -15,6 +17,7 @@ use crate::devices::socket_tcp::socket_std::SocketTcp;
 pub struct SocketTcpWrapper {
     thread_stop: Arc<AtomicBool>,
     socket: Arc<Mutex<(SocketTcp, SocketData)>>,
+    state: Arc<Mutex<ConnectionState>>,
 }
 
 impl SocketTcpWrapper {
@@ -24,23 +27,41 @@ impl SocketTcpWrapper {
     {
         let thread_stop = Arc::new(AtomicBool::default());
         let thread_stop_cloned = thread_stop.clone();
-        let socket_tcp = SocketTcp::new(addr).map_err(|_| Error::new(ErrorKind::Other, "connection error"))?;
+        let state = Arc::new(Mutex::new(ConnectionState::Connected));
+        let state_cloned = state.clone();
+        let socket_tcp = SocketTcp::new(addr)
+            .map_err(|_| Error::other("connection error"))?
+            .with_state_listener(move |s| {
+                if let Ok(mut state) = state_cloned.lock() {
+                    *state = s;
+                }
+            });
 
         let socket = Arc::new(Mutex::new((socket_tcp, SocketData::default())));
         let socket_cloned = socket.clone();
-        let _ = thread::spawn(move || -> Result<(), ErrorSm> {
+        let _ = thread::spawn(move || {
             loop {
                 if thread_stop_cloned.load(Ordering::SeqCst) {
-                    return Ok(());
+                    return;
                 }
                 sleep(update_period);
                 if let Ok(mut socket) = socket_cloned.lock() {
-                    socket.1.last_received_state = socket.0.current_state()?;
-                    socket.1.last_received_pwr = socket.0.power_consumption_wt()?;
+                    // errors are skipped, the client reconnects on the next poll
+                    if let Ok(state) = socket.0.current_state() {
+                        socket.1.last_received_state = state;
+                    }
+                    if let Ok(pwr) = socket.0.power_consumption_wt() {
+                        socket.1.last_received_pwr = pwr;
+                    }
                 }
             }
         });
-        Ok(Self { thread_stop, socket })
+        Ok(Self { thread_stop, socket, state })
+    }
+
+    /// Known without waiting for the polling thread, which holds the socket while reconnecting
+    pub fn connection_state(&self) -> ConnectionState {
+        self.state.lock().map(|s| *s).unwrap_or(ConnectionState::Offline)
     }
 }
 
@@ -124,10 +145,7 @@ pub unsafe extern "C" fn turn_off(socket: *mut c_void) -> bool {
 #[no_mangle]
 pub unsafe extern "C" fn power_consumption_wt(socket: *mut c_void) -> f32 {
     let s = &mut *socket.cast::<SocketTcpWrapper>();
-    match s.power_consumption_wt().unwrap() {
-        Some(v) => { v }
-        None => { 0.0 }
-    }
+    s.power_consumption_wt().unwrap().unwrap_or(0.0)
 }
 /// # Safety
 #[no_mangle]
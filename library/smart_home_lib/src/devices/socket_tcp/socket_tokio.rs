@@ -1,80 +1,151 @@
+use std::future::Future;
+
 use async_trait::async_trait;
-use tokio::net::ToSocketAddrs;
+use tokio::net::{TcpStream, ToSocketAddrs};
 
-use protocol::client_tokio::{ClientStp, RequestResult};
+use protocol::client_tokio::{ClientStp, ReconnectingClient};
+use protocol::client_std::ConnectOptions;
 use protocol::errors::ConnectResult;
+use protocol::message::{Request, Response};
+use protocol::reconnect::{Backoff, ConnectionState};
+use protocol::transport::AsyncTransport;
 
 use crate::common::traits_async::Described;
 use crate::common::traits_async::device::{OptReplay, PowerConsumptionMeter, Replay, Switchable};
 use crate::common::traits_async::device::Err;
 use crate::devices::socket::SocketTraitAsync;
 
-pub struct SocketTcp {
-    client: ClientStp,
+/// Smart socket over STP. Reconnects if the socket server restarts.
+pub struct SocketTcp<S: AsyncTransport = TcpStream> {
+    client: ReconnectingClient<S>,
 }
 
 impl SocketTcp {
     pub async fn new<Addr: ToSocketAddrs>(addr: Addr) -> ConnectResult<Self> {
-        Ok(Self { client: ClientStp::connect(addr).await? })
+        Self::connect_with(addr, &ConnectOptions::new()).await
     }
 
-    fn handle_result(result: RequestResult) -> Replay<bool> {
-        match result {
-            Ok(resp) => {
-                println!("{}", resp);
-                Ok(true)
-            }
-            Err(err) => {
-                println!("{}", err);
-                Err(Err { msg: err.to_string() })
-            }
-        }
+    /// Connect with TLS and/or device key, see [`ConnectOptions`]
+    pub async fn connect_with<Addr: ToSocketAddrs>(addr: Addr, options: &ConnectOptions) -> ConnectResult<Self> {
+        Ok(Self { client: ReconnectingClient::connect_with(addr, options).await? })
+    }
+}
+
+impl<S: AsyncTransport> SocketTcp<S> {
+    /// Socket over any transport, e.g. Unix socket or in-memory pipe. `connector` opens a new connection.
+    pub async fn with_connector<F, Fut>(connector: F) -> ConnectResult<Self>
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output=ConnectResult<ClientStp<S>>> + Send + 'static,
+    {
+        Ok(Self { client: ReconnectingClient::with_connector(connector).await? })
+    }
+
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.client = self.client.with_backoff(backoff);
+        self
+    }
+
+    pub fn connection_state(&self) -> ConnectionState {
+        self.client.state()
+    }
+
+    async fn request(&mut self, request: Request) -> Result<Response, Err> {
+        self.client.request(&request).await.map_err(|err| {
+            // error replies of the server carry the reason reported by the device
+            let msg = match err.remote() {
+                Some(reply) => { reply.message.clone() }
+                None => { err.to_string() }
+            };
+            Err { msg }
+        })
+    }
+
+    fn unexpected(resp: Response) -> Err {
+        Err { msg: format!("Unexpected response: {:?}", resp) }
     }
 }
 #[async_trait]
-impl PowerConsumptionMeter for SocketTcp {
+impl<S: AsyncTransport> PowerConsumptionMeter for SocketTcp<S> {
     async fn power_consumption_wt(&mut self) -> OptReplay<f32> {
-        let result = self.client.send_request("get_power_consumption_wt").await;
-        match result {
-            Ok(val) => { Ok(Some(val.parse::<f32>().unwrap())) }
-            Err(err) => {
-                println!("{}", err);
-                Err(Err { msg: err.to_string() })
-            }
+        match self.request(Request::GetPowerConsumptionWt).await? {
+            Response::PowerConsumptionWt(pwr) => { Ok(pwr) }
+            resp => { Err(Self::unexpected(resp)) }
         }
     }
 }
 
 #[async_trait]
-impl Switchable for SocketTcp {
+impl<S: AsyncTransport> Switchable for SocketTcp<S> {
     async fn turn_on(&mut self) -> Replay<bool> {
-        let result = self.client.send_request("turn_on").await;
-        Self::handle_result(result)
+        match self.request(Request::TurnOn).await? {
+            Response::Ok => { Ok(true) }
+            resp => { Err(Self::unexpected(resp)) }
+        }
     }
 
     async fn turn_off(&mut self) -> Replay<bool> {
-        let result = self.client.send_request("turn_off").await;
-        Self::handle_result(result)
+        match self.request(Request::TurnOff).await? {
+            Response::Ok => { Ok(true) }
+            resp => { Err(Self::unexpected(resp)) }
+        }
     }
 
     async fn current_state(&mut self) -> Replay<bool> {
-        let result = self.client.send_request("get_state").await;
-        Self::handle_result(result)
+        match self.request(Request::GetState).await? {
+            Response::State(state) => { Ok(state) }
+            resp => { Err(Self::unexpected(resp)) }
+        }
     }
 }
 
 #[async_trait]
-impl Described for SocketTcp {
+impl<S: AsyncTransport> Described for SocketTcp<S> {
     async fn description(&mut self) -> String {
-        let result = self.client.send_request("get_description").await;
-        match result {
-            Ok(val) => { val }
-            Err(err) => {
-                err.to_string()
-            }
+        match self.request(Request::GetDescription).await {
+            Ok(Response::Description(desc)) => { desc }
+            Ok(resp) => { Self::unexpected(resp).to_string() }
+            Err(err) => { err.to_string() }
         }
     }
 }
 
-impl SocketTraitAsync for SocketTcp {}
+impl<S: AsyncTransport> SocketTraitAsync for SocketTcp<S> {}
 
+
+#[cfg(test)]
+mod tests {
+    use protocol::message::{ErrorCode, ErrorReply};
+    use protocol::server_tokio::ServerStp;
+    use protocol::transport::async_memory_listener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn error_and_malformed_replies() {
+        let (listener, connector) = async_memory_listener();
+        let server = ServerStp::new(listener);
+        tokio::spawn(async move {
+            let mut connection = server.accept().await.unwrap();
+            let replies = [
+                Ok(Response::State(false).encode()),
+                Err(ErrorReply::new(ErrorCode::Offline, "Device not respond")),
+                Ok("state: on".to_string()),
+            ];
+            for reply in replies {
+                connection.revc_request().await.unwrap();
+                match reply {
+                    Ok(reply) => { connection.send_response(reply).await.unwrap() }
+                    Err(error) => { connection.reply_error(&error).await.unwrap() }
+                }
+            }
+        });
+        let mut socket = SocketTcp::with_connector(move || {
+            let stream = connector.connect();
+            async move { ClientStp::connect_over(stream?, &ConnectOptions::new()).await }
+        }).await.unwrap();
+        assert!(!socket.current_state().await.unwrap());
+        assert_eq!(socket.turn_off().await.unwrap_err().msg, "Device not respond");
+        assert!(socket.current_state().await.is_err());
+    }
+}
@@ -21,9 +21,8 @@ impl ThermometerUdp {
     where
         T: ToSocketAddrs,
     {
-        let socket = UdpSocket::bind(addr).map_err(|e| {
+        let socket = UdpSocket::bind(addr).inspect_err(|_| {
             println!("Error. udp socket bind failed");
-            e
         })?;
         socket.set_read_timeout(Some(Duration::from_secs(1)))?;
         let thread_stop = Arc::new(AtomicBool::default());
@@ -41,10 +40,10 @@ impl ThermometerUdp {
                     continue;
                 }
                 let mgs_raw = &buf[..len];
-                let msgs_vec = protocol::protocol::unwrap_message(std::str::from_utf8(mgs_raw).unwrap()).unwrap_or(vec!["".to_string()]);
+                let msgs_vec = protocol::protocol::unwrap_message(mgs_raw).unwrap_or_default();
                 if let Ok(mut thermometer) = thermometer_cloned.lock() {
                     for msg in msgs_vec {
-                        if let Ok(temp_c) = f32::from_str(msg.as_str()) {
+                        if let Ok(Ok(temp_c)) = std::str::from_utf8(&msg).map(f32::from_str) {
                             thermometer.update_temp_c(temp_c)
                         }
                     }
@@ -78,8 +78,10 @@ pub struct Room<T: DeviceTypes> {
     devices: LinkedList<Device<T>>,
 }
 
+#[cfg(test)]
 struct RoomStub;
 
+#[cfg(test)]
 impl DeviceTypes for RoomStub {
     type Socket = SocketStub;
     type Thermometer = ThermometerStub;
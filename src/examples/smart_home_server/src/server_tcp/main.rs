@@ -2,6 +2,7 @@ use std::{thread, time};
 use std::error::Error;
 use std::net::SocketAddr;
 
+use protocol::client_std::ConnectOptions;
 use smart_home_lib::common::traits::Described;
 use smart_home_lib::common::traits::device::{PowerConsumptionMeter, Switchable, Thermometer};
 use smart_home_lib::devices::socket_tcp::socket_std::SocketTcp;
@@ -13,7 +14,7 @@ fn main() -> Result<(), Box<dyn Error>> {
     thread::sleep(time::Duration::from_secs(1));
     println!("Connecting to smart socket over tcp...");
     let addr: SocketAddr = "127.0.0.1:55331".parse()?;
-    let mut socket_tcp = SocketTcp::new(addr)?;
+    let mut socket_tcp = SocketTcp::connect_with(addr, &ConnectOptions::from_env(&addr.to_string())?)?;
 
     println!("> Request socket description");
     let resp = socket_tcp.description();
@@ -27,7 +28,7 @@ fn main() -> Result<(), Box<dyn Error>> {
     println!("Current temperature:  {}", thermometer_udp.temperature_deg_celsius()?.unwrap_or(0.0));
 
     println!("> Request socket state");
-    let _ = socket_tcp.current_state()?;
+    println!("socket is on: {}", socket_tcp.current_state()?);
 
     println!("> Request socket power consumption");
     match socket_tcp.power_consumption_wt()? {
@@ -47,10 +48,10 @@ fn main() -> Result<(), Box<dyn Error>> {
 
 
     println!("> Request socket state");
-    let _ = socket_tcp.current_state()?;
+    println!("socket is on: {}", socket_tcp.current_state()?);
 
     thread::sleep(time::Duration::from_secs(1));
     println!("Current temperature:  {}", thermometer_udp.temperature_deg_celsius()?.unwrap_or(0.0));
 
     Ok(())
-}
\ No newline at end of file
+}
@@ -4,6 +4,7 @@ use std::time::Duration;
 
 use tokio::time::sleep;
 
+use protocol::client_std::ConnectOptions;
 use smart_home_lib::common::traits_async::Described;
 use smart_home_lib::common::traits_async::device::{PowerConsumptionMeter, Switchable, Thermometer};
 use smart_home_lib::devices::socket_tcp::socket_tokio::SocketTcp;
@@ -15,7 +16,8 @@ async fn main() -> Result<(), Box<dyn Error>> {
     sleep(Duration::from_millis(500)).await;
     println!("Connecting to smart socket over tcp...");
     let addr: SocketAddr = "127.0.0.1:55331".parse()?;
-    let (thermometer_udp, socket_tcp) = tokio::join!(ThermometerUdp::new("127.0.0.1:34255"), SocketTcp::new(addr));
+    let options = ConnectOptions::from_env(&addr.to_string())?;
+    let (thermometer_udp, socket_tcp) = tokio::join!(ThermometerUdp::new("127.0.0.1:34255"), SocketTcp::connect_with(addr, &options));
     let (thermometer_udp, mut socket_tcp) = (thermometer_udp?, socket_tcp?);
     println!("> Request socket description");
     let resp = socket_tcp.description().await;
@@ -25,7 +27,8 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     sleep(Duration::from_millis(500)).await;
     println!("> Request socket state");
-    let (temp_c, _) = tokio::join!(thermometer_udp.temperature_deg_celsius(), socket_tcp.current_state());
+    let (temp_c, state) = tokio::join!(thermometer_udp.temperature_deg_celsius(), socket_tcp.current_state());
+    println!("socket is on: {}", state?);
     println!("Current temperature:  {:?}", temp_c?);
 
     sleep(Duration::from_millis(500)).await;
@@ -44,7 +47,8 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let _ = socket_tcp.turn_off().await?;
     sleep(Duration::from_millis(500)).await;
     println!("> Request socket state");
-    let (_, temp_c) = tokio::join!(socket_tcp.current_state(), thermometer_udp.temperature_deg_celsius());
+    let (state, temp_c) = tokio::join!(socket_tcp.current_state(), thermometer_udp.temperature_deg_celsius());
+    println!("socket is on: {}", state?);
     println!("Current temperature:  {:?}", temp_c?);
     Ok(())
 }
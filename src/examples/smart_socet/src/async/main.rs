@@ -1,85 +1,40 @@
 use std::error::Error;
-use std::io::ErrorKind;
 use std::net::SocketAddr;
-use std::ops::DerefMut;
-use std::sync::Arc;
+use std::time::Duration;
 
-use tokio::sync::Mutex;
-
-use protocol::client_std::{RequestError, RequestResult};
-use protocol::errors::RecvError;
-use protocol::server_tokio::{ServerStp, StpConnection};
-use smart_home_lib::devices::socket::SocketTrait;
+use protocol::auth::PreSharedKey;
+use protocol::heartbeat::Heartbeat;
+use protocol::server_tokio::{ServerLimits, ServerStp, ShutdownHandle};
+use smart_home_lib::devices::socket_handler::SocketHandler;
 use smart_home_lib::devices::stubs::socket_stub::SocketStub;
 
+const MAX_CLIENTS: usize = 16;
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let addr: SocketAddr = "127.0.0.1:55331".parse()?;
     println!("SmartSocket server_tcp running at addr {}", addr);
-    let socket_stub = SocketStub::new_with_wrap(
-        "Kitchen socket via tcp".to_string(),
-        |x| { Arc::new(Mutex::new(x)) },
-    );
+    let socket_stub = SocketStub::new_with_wrap("Kitchen socket via tcp".to_string(), |x| x);
 
-    let server = ServerStp::bind(addr).await?;
-    loop {
-        let mut connection = server.incoming().await?;
-        let socket = socket_stub.clone();
-        println!("client connected from: {}", connection.peer_addr().unwrap());
-        tokio::spawn(async move {
-            loop {
-                let mut locked_socket = socket.lock().await;
-                match process(&mut connection, locked_socket.deref_mut()).await {
-                    Ok(_) => {}
-                    Err(RequestError::Recv(RecvError::Io(e))) if e.kind() == ErrorKind::BrokenPipe => {
-                        println!("client {} disconnected", connection.peer_addr().unwrap());
-                        break;
-                    }
-                    Err(e) => { println!("Error: {}", e) }
-                }
-            }
-        });
+    // drop half-open connections of vanished clients
+    let mut server = ServerStp::bind(addr).await?
+        .with_heartbeat(Heartbeat::default())
+        .with_limits(ServerLimits::new(MAX_CLIENTS).with_idle_timeout(IDLE_TIMEOUT));
+    if let Some(key) = PreSharedKey::from_env()? {
+        println!("clients must authenticate with the device key");
+        server = server.with_key(key);
     }
-}
-
-async fn process<Socket>(conn: &mut StpConnection, socket: &mut Socket) -> RequestResult
-where
-    Socket: SocketTrait,
-{
-    let req = conn.revc_request().await?;
-    println!("{}", req);
-
-    let resp: String = match req.as_str() {
-        "turn_on" => {
-            match socket.turn_on() {
-                Ok(_) => { "ok".to_string() }
-                Err(e) => { e.to_string() }
-            }
-        }
-        "turn_off" => {
-            match socket.turn_off() {
-                Ok(_) => { "ok".to_string() }
-                Err(e) => { e.to_string() }
-            }
-        }
-        "get_state" => {
-            match socket.current_state() {
-                Ok(state) => { if state { "state: on".to_string() } else { "state: off".to_string() } }
-                Err(e) => { e.to_string() }
-            }
-        }
-        "get_power_consumption_wt" => {
-            match socket.power_consumption_wt() {
-                Ok(Some(pwr)) => { format!("{}", pwr) }
-                Err(e) => { e.to_string() }
-                _ => { "Unknown power_consumption".to_string() }
-            }
-        }
-        "get_description" => {
-            socket.description()
+    let shutdown = ShutdownHandle::new();
+    let ctrl_c = shutdown.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            println!("shutting down...");
+            ctrl_c.shutdown();
         }
-        _ => { "Unknown request".to_string() }
-    };
-    conn.send_response(resp).await?;
-    Ok("".to_string())
+    });
+    let metrics = server.metrics();
+    server.serve(shutdown, SocketHandler::new(socket_stub)).await?;
+    print!("{}", metrics.render_prometheus());
+    Ok(())
 }
@@ -12,7 +12,7 @@ fn main() -> Result<(), Box<dyn Error>> {
         let duration = start.elapsed();
         let simulated_temp_deg = 25.0 + 10.0 * (duration.as_secs() as f32 * 0.3).sin();
         let msg = protocol::protocol::wrap_message(format!("{}", simulated_temp_deg));
-        socket.send_to(msg.as_ref(), "127.0.0.1:34255")?;
+        socket.send_to(&msg, "127.0.0.1:34255")?;
         thread::sleep(time::Duration::from_secs(1));
     }
 }
\ No newline at end of file
@@ -40,7 +40,7 @@ impl Sandbox for SocketWidget {
         }
     }
 
-    fn view(&self) -> Element<Message> {
+    fn view(&self) -> Element<'_, Message> {
         let mut pwr_toggle_btn = None;
         let mut state_field = None;
         let mut pwr_field = None;
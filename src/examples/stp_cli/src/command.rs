@@ -0,0 +1,156 @@
+use std::time::Duration;
+
+use protocol::message::{Request, Response};
+
+pub const HELP: &str = "\
+Commands:
+  on | turn_on                 turn the socket on
+  off | turn_off               turn the socket off
+  state | get_state            current state
+  power | get_power_consumption_wt
+                               current power consumption
+  describe | get_description   socket description
+  subscribe [threshold_wt]     push events on state and power change, default threshold 1 W
+  unsubscribe                  stop pushing events
+  events [secs]                wait for pushed events, default 5 seconds
+  send <json>                  send the message payload as is
+  raw [on|off]                 print frames as sent and received on the wire
+  history                      numbered command history, repeat with !n or !!
+  help                         this text
+  quit | exit                  leave";
+
+const DEFAULT_THRESHOLD_WT: f32 = 1.0;
+const DEFAULT_EVENTS_WAIT: Duration = Duration::from_secs(5);
+
+/// Parsed command line of one-shot and REPL modes
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Request(Request),
+    /// Message payload sent without encoding
+    Send(String),
+    /// Wait for pushed events
+    Events(Duration),
+    /// Switch raw frame mode, `None` toggles
+    Raw(Option<bool>),
+    History,
+    Help,
+    Quit,
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let line = line.trim();
+        let (name, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+        let no_args = |command: Command| {
+            match rest.is_empty() {
+                true => { Ok(command) }
+                false => { Err(format!("`{}` takes no arguments", name)) }
+            }
+        };
+        match name {
+            "on" | "turn_on" => { no_args(Command::Request(Request::TurnOn)) }
+            "off" | "turn_off" => { no_args(Command::Request(Request::TurnOff)) }
+            "state" | "get_state" => { no_args(Command::Request(Request::GetState)) }
+            "power" | "get_power_consumption_wt" => { no_args(Command::Request(Request::GetPowerConsumptionWt)) }
+            "describe" | "get_description" => { no_args(Command::Request(Request::GetDescription)) }
+            "subscribe" => {
+                let power_threshold_wt = match rest {
+                    "" => { DEFAULT_THRESHOLD_WT }
+                    threshold => { threshold.parse().map_err(|_| format!("bad threshold `{}`", threshold))? }
+                };
+                Ok(Command::Request(Request::Subscribe { power_threshold_wt }))
+            }
+            "unsubscribe" => { no_args(Command::Request(Request::Unsubscribe)) }
+            "events" => {
+                let wait = match rest {
+                    "" => { DEFAULT_EVENTS_WAIT }
+                    secs => { parse_secs(secs)? }
+                };
+                Ok(Command::Events(wait))
+            }
+            "send" if rest.is_empty() => { Err("`send` needs the message payload".to_string()) }
+            "send" => { Ok(Command::Send(rest.to_string())) }
+            "raw" => {
+                match rest {
+                    "" => { Ok(Command::Raw(None)) }
+                    "on" => { Ok(Command::Raw(Some(true))) }
+                    "off" => { Ok(Command::Raw(Some(false))) }
+                    other => { Err(format!("expected `on` or `off`, got `{}`", other)) }
+                }
+            }
+            "history" => { no_args(Command::History) }
+            "help" | "?" => { Ok(Command::Help) }
+            "quit" | "exit" => { no_args(Command::Quit) }
+            other => { Err(format!("unknown command `{}`, try `help`", other)) }
+        }
+    }
+}
+
+/// Seconds, fractions allowed
+pub fn parse_secs(secs: &str) -> Result<Duration, String> {
+    secs.parse::<f64>().ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .filter(|secs| !secs.is_zero())
+        .ok_or_else(|| format!("bad number of seconds `{}`", secs))
+}
+
+pub fn format_response(response: &Response) -> String {
+    match response {
+        Response::Ok => { "ok".to_string() }
+        Response::State(on) => { format_state(*on).to_string() }
+        Response::PowerConsumptionWt(power) => { format_power(*power) }
+        Response::Description(description) => { description.clone() }
+    }
+}
+
+pub fn format_state(on: bool) -> &'static str {
+    if on { "on" } else { "off" }
+}
+
+pub fn format_power(power: Option<f32>) -> String {
+    match power {
+        Some(power) => { format!("{} W", power) }
+        None => { "unknown".to_string() }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_requests() {
+        assert_eq!(Command::parse("on").unwrap(), Command::Request(Request::TurnOn));
+        assert_eq!(Command::parse("  turn_off ").unwrap(), Command::Request(Request::TurnOff));
+        assert_eq!(Command::parse("get_power_consumption_wt").unwrap(), Command::Request(Request::GetPowerConsumptionWt));
+        assert_eq!(Command::parse("subscribe").unwrap(), Command::Request(Request::Subscribe { power_threshold_wt: DEFAULT_THRESHOLD_WT }));
+        assert_eq!(Command::parse("subscribe 2.5").unwrap(), Command::Request(Request::Subscribe { power_threshold_wt: 2.5 }));
+        assert!(Command::parse("subscribe much").is_err());
+        assert!(Command::parse("state now").is_err());
+        assert!(Command::parse("dim").is_err());
+    }
+
+    #[test]
+    fn parse_cli_commands() {
+        assert_eq!(Command::parse("send {\"turn_on\": null}").unwrap(), Command::Send("{\"turn_on\": null}".to_string()));
+        assert!(Command::parse("send").is_err());
+        assert_eq!(Command::parse("events").unwrap(), Command::Events(DEFAULT_EVENTS_WAIT));
+        assert_eq!(Command::parse("events 0.5").unwrap(), Command::Events(Duration::from_millis(500)));
+        assert_eq!(Command::parse("raw").unwrap(), Command::Raw(None));
+        assert_eq!(Command::parse("raw off").unwrap(), Command::Raw(Some(false)));
+        assert!(Command::parse("raw maybe").is_err());
+        assert_eq!(Command::parse("?").unwrap(), Command::Help);
+        assert_eq!(Command::parse("exit").unwrap(), Command::Quit);
+    }
+
+    #[test]
+    fn parse_seconds() {
+        assert_eq!(parse_secs("2").unwrap(), Duration::from_secs(2));
+        assert_eq!(parse_secs("0.25").unwrap(), Duration::from_millis(250));
+        for bad in ["0", "-1", "NaN", "inf", "soon", ""] {
+            assert!(parse_secs(bad).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Entries kept between sessions
+const MAX_ENTRIES: usize = 500;
+
+/// REPL command history, persisted to `~/.stp_history`
+pub struct History {
+    entries: Vec<String>,
+    file: Option<PathBuf>,
+    /// Oldest entries were dropped, so the file is rewritten instead of appended
+    trimmed: bool,
+}
+
+impl History {
+    /// History of the previous sessions, empty if the file is missing
+    pub fn load() -> Self {
+        let file = std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".stp_history"));
+        Self::open(file)
+    }
+
+    fn open(file: Option<PathBuf>) -> Self {
+        let entries: Vec<String> = file.as_ref()
+            .and_then(|path| File::open(path).ok())
+            .map(|file| BufReader::new(file).lines().map_while(Result::ok).collect())
+            .unwrap_or_default();
+        let mut history = Self { entries, file, trimmed: false };
+        history.trim();
+        history
+    }
+
+    /// Drop the oldest entries above [`MAX_ENTRIES`]
+    fn trim(&mut self) {
+        let extra = self.entries.len().saturating_sub(MAX_ENTRIES);
+        if extra > 0 {
+            self.entries.drain(..extra);
+            self.trimmed = true;
+        }
+    }
+
+    pub fn entries(&self) -> &[String] {
+        &self.entries
+    }
+
+    /// Replace `!!` with the last command and `!n` with the command number `n`
+    pub fn expand(&self, line: &str) -> Result<String, String> {
+        let Some(reference) = line.strip_prefix('!') else {
+            return Ok(line.to_string());
+        };
+        let entry = match reference {
+            "!" => { self.entries.last() }
+            n => {
+                let n: usize = n.parse().map_err(|_| format!("bad history reference `{}`", line))?;
+                n.checked_sub(1).and_then(|index| self.entries.get(index))
+            }
+        };
+        entry.cloned().ok_or_else(|| format!("no command `{}` in history", line))
+    }
+
+    /// Remember the command, repeats of the last one are skipped. Failing to save is not fatal.
+    pub fn push(&mut self, line: &str) {
+        if self.entries.last().is_some_and(|last| last == line) {
+            return;
+        }
+        self.entries.push(line.to_string());
+        self.trim();
+        if let Some(path) = &self.file {
+            if let Err(e) = self.save(path) {
+                eprintln!("history is not saved to {}: {}", path.display(), e);
+                self.file = None;
+            }
+        }
+    }
+
+    /// Append the last entry, or rewrite the file without the dropped ones
+    fn save(&self, path: &Path) -> io::Result<()> {
+        if self.trimmed {
+            let mut file = BufWriter::new(File::create(path)?);
+            for entry in &self.entries {
+                writeln!(file, "{}", entry)?;
+            }
+            return file.flush();
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{}", self.entries.last().map(String::as_str).unwrap_or_default())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_entries(entries: &[&str]) -> History {
+        History { entries: entries.iter().map(|entry| entry.to_string()).collect(), file: None, trimmed: false }
+    }
+
+    #[test]
+    fn expand_references() {
+        let history = with_entries(&["on", "state", "power"]);
+        assert_eq!(history.expand("off").unwrap(), "off");
+        assert_eq!(history.expand("!!").unwrap(), "power");
+        assert_eq!(history.expand("!1").unwrap(), "on");
+        assert_eq!(history.expand("!3").unwrap(), "power");
+        assert!(history.expand("!0").is_err());
+        assert!(history.expand("!4").is_err());
+        assert!(history.expand("!x").is_err());
+        assert!(with_entries(&[]).expand("!!").is_err());
+    }
+
+    #[test]
+    fn skip_repeats() {
+        let mut history = with_entries(&[]);
+        history.push("on");
+        history.push("on");
+        history.push("off");
+        assert_eq!(history.entries(), ["on", "off"]);
+    }
+
+    #[test]
+    fn cap_saved_history() {
+        let path = std::env::temp_dir().join(format!("stp-history-{}", std::process::id()));
+        std::fs::write(&path, "describe\n").unwrap();
+        let mut history = History::open(Some(path.clone()));
+        assert_eq!(history.entries(), ["describe"]);
+        for n in 0..MAX_ENTRIES + 10 {
+            history.push(&format!("subscribe {}", n));
+        }
+        assert_eq!(history.entries().len(), MAX_ENTRIES);
+        let saved = History::open(Some(path.clone()));
+        assert_eq!(saved.entries(), history.entries());
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), MAX_ENTRIES);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,304 @@
+use std::error::Error;
+use std::io;
+use std::io::{BufRead, Write};
+use std::net::TcpStream;
+use std::process::ExitCode;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use protocol::client_std::{ClientStp, ConnectOptions};
+use protocol::errors::{ConnectError, RequestError};
+use protocol::message::{Request, Response};
+
+use crate::command::{format_power, format_response, format_state, parse_secs, Command, HELP};
+use crate::history::History;
+use crate::wire::{RawMode, Wire};
+
+mod command;
+mod history;
+mod wire;
+
+const USAGE: &str = "\
+Usage: stp-cli <addr> [--raw] [command [args]]
+       stp-cli <addr> [--raw] --watch <secs>
+
+Without a command starts an interactive session, `help` lists the commands.
+Device key is taken from the `STP_KEYS` key file or `STP_PSK` env variable.";
+
+#[derive(Debug)]
+struct Args {
+    addr: String,
+    raw: bool,
+    watch: Option<Duration>,
+    command: Option<String>,
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Args, String> {
+    let mut addr = None;
+    let mut raw = false;
+    let mut watch = None;
+    let mut command: Vec<String> = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--raw" if command.is_empty() => { raw = true }
+            "--watch" if command.is_empty() => {
+                let secs = args.next().ok_or("`--watch` needs the interval in seconds")?;
+                watch = Some(parse_secs(&secs)?);
+            }
+            "-h" | "--help" => { return Err(String::new()) }
+            _ if addr.is_none() => { addr = Some(arg) }
+            _ => { command.push(arg) }
+        }
+    }
+    let addr = addr.ok_or("address of the server is required")?;
+    if watch.is_some() && !command.is_empty() {
+        return Err("`--watch` does not take a command".to_string());
+    }
+    let command = (!command.is_empty()).then(|| command.join(" "));
+    Ok(Args { addr, raw, watch, command })
+}
+
+fn main() -> Result<ExitCode, Box<dyn Error>> {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => { args }
+        Err(e) => {
+            if !e.is_empty() {
+                eprintln!("{}\n", e);
+            }
+            eprintln!("{}", USAGE);
+            return Ok(ExitCode::FAILURE);
+        }
+    };
+    let options = ConnectOptions::from_env(&args.addr)?;
+    let raw = RawMode::default();
+    raw.set(args.raw);
+    let mut cli = Cli { addr: args.addr, options, raw, client: None };
+    if let Some(interval) = args.watch {
+        watch(&mut cli, interval);
+    }
+    match args.command {
+        Some(command) => {
+            let ok = match Command::parse(&command) {
+                Ok(command) => { cli.run(command, None) }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    false
+                }
+            };
+            Ok(if ok { ExitCode::SUCCESS } else { ExitCode::FAILURE })
+        }
+        None => {
+            repl(&mut cli)?;
+            Ok(ExitCode::SUCCESS)
+        }
+    }
+}
+
+/// Connection to the server, established on the first command and after it was lost
+struct Cli {
+    addr: String,
+    options: ConnectOptions,
+    raw: RawMode,
+    client: Option<ClientStp<Wire<TcpStream>>>,
+}
+
+impl Cli {
+    fn client(&mut self) -> Result<&mut ClientStp<Wire<TcpStream>>, ConnectError> {
+        if self.client.is_none() {
+            let stream = TcpStream::connect(&self.addr)?;
+            self.client = Some(ClientStp::connect_over(self.raw.wrap(stream), &self.options)?);
+        }
+        Ok(self.client.as_mut().expect("connected above"))
+    }
+
+    fn request(&mut self, request: &Request) -> Result<Response, RequestError> {
+        let result = self.client()?.request(request);
+        self.forget_lost(result)
+    }
+
+    fn send(&mut self, payload: &str) -> Result<String, RequestError> {
+        let result = self.client()?.send_request(payload);
+        self.forget_lost(result)
+    }
+
+    fn forget_lost<T>(&mut self, result: Result<T, RequestError>) -> Result<T, RequestError> {
+        if result.as_ref().is_err_and(RequestError::is_connection_lost) {
+            self.client = None;
+        }
+        result
+    }
+
+    /// Perform the command and print the outcome. `false` if it failed.
+    fn run(&mut self, command: Command, history: Option<&History>) -> bool {
+        let ok = match command {
+            Command::Request(request) => { self.report(|cli| cli.request(&request).map(|resp| format_response(&resp))) }
+            Command::Send(payload) => { self.report(|cli| cli.send(&payload)) }
+            Command::Events(wait) => { self.wait_events(wait) }
+            Command::Raw(enabled) => {
+                let enabled = enabled.unwrap_or(!self.raw.is_enabled());
+                self.raw.set(enabled);
+                println!("raw mode {}", format_state(enabled));
+                true
+            }
+            Command::History => {
+                for (n, entry) in history.map(History::entries).unwrap_or_default().iter().enumerate() {
+                    println!("{:>4}  {}", n + 1, entry);
+                }
+                true
+            }
+            Command::Help => {
+                println!("{}", HELP);
+                true
+            }
+            Command::Quit => { true }
+        };
+        self.print_events();
+        ok
+    }
+
+    fn report<F>(&mut self, request: F) -> bool
+    where
+        F: FnOnce(&mut Self) -> Result<String, RequestError>,
+    {
+        match request(self) {
+            Ok(resp) => {
+                println!("{}", resp);
+                true
+            }
+            Err(e) => {
+                eprintln!("error: {}", e);
+                false
+            }
+        }
+    }
+
+    fn wait_events(&mut self, wait: Duration) -> bool {
+        let deadline = Instant::now() + wait;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return true;
+            }
+            let client = match self.client() {
+                Ok(client) => { client }
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    return false;
+                }
+            };
+            match client.next_event(Some(left)) {
+                Ok(Some(event)) => { println!("event: {:?}", event) }
+                Ok(None) => { return true }
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    self.client = None;
+                    return false;
+                }
+            }
+        }
+    }
+
+    /// Events received with responses
+    fn print_events(&mut self) {
+        if let Some(client) = self.client.as_mut() {
+            while let Some(event) = client.try_event() {
+                println!("event: {:?}", event);
+            }
+        }
+    }
+}
+
+fn repl(cli: &mut Cli) -> io::Result<()> {
+    let mut history = History::load();
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("stp {}> ", cli.addr);
+        io::stdout().flush()?;
+        let Some(line) = lines.next().transpose()? else {
+            println!();
+            return Ok(());
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let line = match history.expand(line) {
+            Ok(expanded) if expanded != line => {
+                println!("{}", expanded);
+                expanded
+            }
+            Ok(line) => { line }
+            Err(e) => {
+                eprintln!("{}", e);
+                continue;
+            }
+        };
+        history.push(&line);
+        match Command::parse(&line) {
+            Ok(Command::Quit) => { return Ok(()) }
+            Ok(command) => { cli.run(command, Some(&history)); }
+            Err(e) => { eprintln!("{}", e) }
+        }
+    }
+}
+
+/// Poll state and power until interrupted, connection failures are retried on the next poll
+fn watch(cli: &mut Cli, interval: Duration) -> ! {
+    let start = Instant::now();
+    loop {
+        let polled = cli.request(&Request::GetState).and_then(|state| {
+            let power = cli.request(&Request::GetPowerConsumptionWt)?;
+            Ok((state, power))
+        });
+        let elapsed = start.elapsed().as_secs_f32();
+        match polled {
+            Ok((Response::State(on), Response::PowerConsumptionWt(power))) => {
+                println!("[{:>8.1}s] state: {}, power: {}", elapsed, format_state(on), format_power(power));
+            }
+            Ok((state, power)) => { eprintln!("[{:>8.1}s] unexpected responses {:?}, {:?}", elapsed, state, power) }
+            Err(e) => { eprintln!("[{:>8.1}s] error: {}", elapsed, e) }
+        }
+        cli.print_events();
+        thread::sleep(interval);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parse_command_line() {
+        let args = parse(&["127.0.0.1:55331"]).unwrap();
+        assert_eq!(args.addr, "127.0.0.1:55331");
+        assert!(!args.raw && args.watch.is_none() && args.command.is_none());
+
+        let args = parse(&["--raw", "127.0.0.1:55331", "subscribe", "2"]).unwrap();
+        assert!(args.raw);
+        assert_eq!(args.command.as_deref(), Some("subscribe 2"));
+
+        // options after the command belong to it
+        let args = parse(&["127.0.0.1:55331", "send", "--raw"]).unwrap();
+        assert!(!args.raw);
+        assert_eq!(args.command.as_deref(), Some("send --raw"));
+
+        let args = parse(&["127.0.0.1:55331", "--watch", "1.5"]).unwrap();
+        assert_eq!(args.watch, Some(Duration::from_millis(1500)));
+    }
+
+    #[test]
+    fn reject_bad_command_line() {
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["--raw"]).is_err());
+        assert!(parse(&["127.0.0.1:55331", "--watch"]).is_err());
+        assert!(parse(&["127.0.0.1:55331", "--watch", "0"]).is_err());
+        assert!(parse(&["127.0.0.1:55331", "--watch", "1", "state"]).is_err());
+        assert_eq!(parse(&["127.0.0.1:55331", "--help"]).unwrap_err(), "");
+    }
+}
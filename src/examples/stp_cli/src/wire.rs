@@ -0,0 +1,151 @@
+use std::io;
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use protocol::protocol::{wrap_frame, Frame, FrameDecoder};
+use protocol::transport::Transport;
+
+/// Raw frame mode switch shared by the CLI and its connections
+#[derive(Debug, Clone, Default)]
+pub struct RawMode(Arc<AtomicBool>);
+
+impl RawMode {
+    pub fn is_enabled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, enabled: bool) {
+        self.0.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn wrap<T>(&self, transport: T) -> Wire<T> {
+        Wire {
+            inner: transport,
+            mode: self.clone(),
+            sent: frame_decoder(),
+            received: frame_decoder(),
+        }
+    }
+}
+
+/// Frames of any size are printed
+fn frame_decoder() -> FrameDecoder {
+    FrameDecoder::with_max_frame_len(usize::MAX)
+}
+
+/// Transport printing the frames while [`RawMode`] is enabled
+pub struct Wire<T> {
+    inner: T,
+    mode: RawMode,
+    sent: FrameDecoder,
+    received: FrameDecoder,
+}
+
+impl<T> Wire<T> {
+    fn dump(&mut self, sent: bool, data: &[u8]) {
+        let (decoder, arrow) = match sent {
+            true => { (&mut self.sent, ">>") }
+            false => { (&mut self.received, "<<") }
+        };
+        decoder.extend(data);
+        // frames are decoded even when disabled, so enabling starts at a frame boundary
+        loop {
+            match decoder.decode() {
+                Ok(Some(frame)) => {
+                    if self.mode.is_enabled() {
+                        print_frame(arrow, &frame);
+                    }
+                }
+                Ok(None) => { break }
+                Err(e) => {
+                    if self.mode.is_enabled() {
+                        println!("{} {}, dropped {} bytes", arrow, e, decoder.pending());
+                    }
+                    // buffered bytes never become a frame, decoding starts over with the next data
+                    *decoder = frame_decoder();
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Header and payload bytes in hex, then the payload as text
+fn print_frame(arrow: &str, frame: &Frame) {
+    let bytes: Vec<String> = wrap_frame(frame.kind, &frame.payload).iter().map(|byte| format!("{:02x}", byte)).collect();
+    println!("{} {:?} {}", arrow, frame.kind, bytes.join(" "));
+    println!("   {}", String::from_utf8_lossy(&frame.payload).escape_debug());
+}
+
+impl<T: Read> Read for Wire<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.dump(false, &buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Wire<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.dump(true, &buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Transport> Transport for Wire<T> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        self.inner.read_timeout()
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.inner.shutdown()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use protocol::protocol::{wrap_message, FrameKind};
+
+    use super::*;
+
+    #[test]
+    fn pass_data_through() {
+        let raw = RawMode::default();
+        let mut wire = raw.wrap(Vec::new());
+        let frame = wrap_frame(FrameKind::Ping, "1");
+        wire.write_all(&frame[..3]).unwrap();
+        assert_eq!(wire.sent.pending(), 3);
+        wire.write_all(&frame[3..]).unwrap();
+        assert_eq!(wire.sent.pending(), 0);
+        assert_eq!(wire.inner, frame);
+
+        let mut wire = raw.wrap(io::Cursor::new(wrap_message("hi_client;1;")));
+        let mut buff = Vec::new();
+        wire.read_to_end(&mut buff).unwrap();
+        assert_eq!(buff, wrap_message("hi_client;1;"));
+        assert_eq!(wire.received.pending(), 0);
+    }
+
+    #[test]
+    fn reset_after_bad_frame() {
+        let raw = RawMode::default();
+        raw.set(true);
+        let mut wire = raw.wrap(Vec::new());
+        wire.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        assert_eq!(wire.sent.pending(), 0);
+        wire.write_all(&wrap_message("on")).unwrap();
+        assert_eq!(wire.sent.pending(), 0);
+    }
+}
@@ -6,6 +6,7 @@ use serde::{Deserialize, Serialize};
 
 use crate::error::{CustomError, CustomResult};
 
+#[allow(dead_code)]
 #[derive(Clone, Serialize, Deserialize)]
 pub struct HouseData {
     name: String,